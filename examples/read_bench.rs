@@ -0,0 +1,75 @@
+//! Times hashing big files and reading them back from the store, both raw and
+//! compressed, to compare the buffered reads with memory-mapped ones:
+//!
+//!     cargo run --release --example read_bench
+//!     cargo run --release --example read_bench --features mmap
+//!
+//! The size in MB can be given as an argument, 256 by default.
+use ink::{CommitResult, Repository};
+
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+
+fn main() {
+    let size_mb: usize = env::args()
+        .nth(1)
+        .map(|size| size.parse().expect("size should be a number of MB"))
+        .unwrap_or(256);
+
+    let tmpdir = tempfile::tempdir().unwrap();
+    let ink_dir = tmpdir.path().join(".ink");
+    ink::init(tmpdir.path()).unwrap();
+    fs::write(ink_dir.join("config"), "[storage]\nuncompressed = bin\n").unwrap();
+    let repo = Repository::open(&ink_dir).unwrap();
+
+    let content: Vec<u8> = (0..size_mb * 1024 * 1024)
+        .map(|i| (i as u32).wrapping_mul(2654435761).rotate_right(13) as u8)
+        .collect();
+    fs::write(tmpdir.path().join("big.bin"), &content).unwrap();
+    let mut compressed = content.clone();
+    compressed[0] ^= 1;
+    fs::write(tmpdir.path().join("big.dat"), &compressed).unwrap();
+
+    let commit = match repo.commit("big", Default::default()).unwrap() {
+        CommitResult::Created(outcome) => outcome.commit,
+        CommitResult::NothingToCommit => unreachable!(),
+    };
+    let (raw, compressed) = (&commit.files()[0], &commit.files()[1]);
+
+    let hash = time(|| {
+        assert!(repo.status().unwrap().edits.is_empty());
+    });
+    let read_raw = time(|| {
+        assert_eq!(raw.read_content(&ink_dir).unwrap().len(), content.len());
+    });
+    let read_compressed = time(|| {
+        assert_eq!(
+            compressed.read_content(&ink_dir).unwrap().len(),
+            content.len()
+        );
+    });
+
+    println!(
+        "2 files of {} MB, mmap {}",
+        size_mb,
+        if cfg!(feature = "mmap") { "on" } else { "off" }
+    );
+    println!("hash worktree:   {:?}", hash);
+    println!("read raw:        {:?}", read_raw);
+    println!("read compressed: {:?}", read_compressed);
+}
+
+/// The fastest of a few runs
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap()
+}
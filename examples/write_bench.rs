@@ -0,0 +1,48 @@
+//! Times committing many small files at each durability level, to see what
+//! syncing costs on the disk the temporary directory is on:
+//!
+//!     cargo run --release --example write_bench
+//!
+//! The number of files can be given as an argument, 200 by default. Each
+//! level makes a few commits, each changing every file.
+use ink::{CommitResult, Repository};
+
+use std::env;
+use std::fs;
+use std::time::{Duration, Instant};
+
+const COMMITS: u32 = 5;
+
+fn main() {
+    let files: usize = env::args()
+        .nth(1)
+        .map(|files| files.parse().expect("files should be a number"))
+        .unwrap_or(200);
+
+    println!("{} commits of {} files", COMMITS, files);
+    for level in &["none", "metadata", "full"] {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let ink_dir = tmpdir.path().join(".ink");
+        ink::init(tmpdir.path()).unwrap();
+        let config = format!("[storage]\ndurability = {}\n", level);
+        fs::write(ink_dir.join("config"), config).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let mut total = Duration::default();
+        for commit in 0..COMMITS {
+            for file in 0..files {
+                let content = format!("file {} in commit {}\n", file, commit);
+                fs::write(tmpdir.path().join(file.to_string()), content).unwrap();
+            }
+
+            let start = Instant::now();
+            match repo.commit("bench", Default::default()).unwrap() {
+                CommitResult::Created(_) => {}
+                CommitResult::NothingToCommit => unreachable!(),
+            }
+            total += start.elapsed();
+        }
+
+        println!("{:<9} {:?} per commit", level, total / COMMITS);
+    }
+}
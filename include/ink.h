@@ -0,0 +1,42 @@
+/*
+ * C interface to ink, built with `cargo build --features ffi`.
+ * Kept in sync by hand with src/ffi.rs, which documents the ownership rules:
+ * handles and returned strings belong to the caller and are released with
+ * the matching *_free function, and failures return NULL or -1 with details
+ * available from ink_last_error().
+ */
+#ifndef INK_H
+#define INK_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct Repository ink_repo;
+typedef struct InkLog ink_log;
+
+const char *ink_last_error(void);
+void ink_string_free(char *s);
+
+ink_repo *ink_repo_open(const char *path);
+void ink_repo_free(ink_repo *repo);
+
+/* 0: committed, 1: nothing to commit, -1: failure */
+int ink_commit(const ink_repo *repo, const char *message, char **hash_out);
+char *ink_status(const ink_repo *repo);
+
+ink_log *ink_log_open(const ink_repo *repo);
+/* 1: wrote the next commit, 0: finished, -1: failure */
+int ink_log_next(ink_log *log, char **hash_out, char **message_out);
+void ink_log_free(ink_log *log);
+
+char *ink_diff_unified(const char *original,
+                       const char *modified,
+                       const char *original_name,
+                       const char *modified_name);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* INK_H */
@@ -0,0 +1,120 @@
+"""Python bindings for ink.
+
+These load the C ABI from the ink shared library (built with
+``cargo build --features python``) through ctypes. Set ``INK_LIBRARY`` to
+the library's path to use a build outside of this package.
+"""
+import ctypes
+import os
+import sys
+from collections import namedtuple
+
+__all__ = ["InkError", "Repository", "Commit", "Diff", "Status"]
+
+
+def _library_path():
+    if "INK_LIBRARY" in os.environ:
+        return os.environ["INK_LIBRARY"]
+
+    name = {"darwin": "libink.dylib", "win32": "ink.dll"}.get(sys.platform, "libink.so")
+    return os.path.join(os.path.dirname(__file__), name)
+
+
+_lib = ctypes.CDLL(_library_path())
+_repo_p = ctypes.c_void_p
+_log_p = ctypes.c_void_p
+_str_out = ctypes.POINTER(ctypes.c_void_p)
+
+_lib.ink_last_error.restype = ctypes.c_char_p
+_lib.ink_string_free.argtypes = [ctypes.c_void_p]
+_lib.ink_repo_open.argtypes = [ctypes.c_char_p]
+_lib.ink_repo_open.restype = _repo_p
+_lib.ink_repo_free.argtypes = [_repo_p]
+_lib.ink_commit.argtypes = [_repo_p, ctypes.c_char_p, _str_out]
+_lib.ink_status.argtypes = [_repo_p]
+_lib.ink_status.restype = ctypes.c_void_p
+_lib.ink_log_open.argtypes = [_repo_p]
+_lib.ink_log_open.restype = _log_p
+_lib.ink_log_next.argtypes = [_log_p, _str_out, _str_out]
+_lib.ink_log_free.argtypes = [_log_p]
+_lib.ink_diff_unified.argtypes = [ctypes.c_char_p] * 4
+_lib.ink_diff_unified.restype = ctypes.c_void_p
+
+
+class InkError(Exception):
+    """Raised when an ink operation fails"""
+
+
+def _check(result):
+    if result is None or result == -1:
+        raise InkError(_lib.ink_last_error().decode())
+    return result
+
+
+def _take_string(ptr):
+    """Copy a string returned by ink and release the original"""
+    _check(ptr)
+    try:
+        return ctypes.cast(ptr, ctypes.c_char_p).value.decode()
+    finally:
+        _lib.ink_string_free(ptr)
+
+
+Commit = namedtuple("Commit", ["hash", "message"])
+Status = namedtuple("Status", ["status", "path"])
+
+
+class Diff:
+    """Line diff of two texts"""
+
+    def __init__(self, original, modified):
+        self.original = original
+        self.modified = modified
+
+    def unified(self, original_name="a", modified_name="b"):
+        return _take_string(
+            _lib.ink_diff_unified(
+                self.original.encode(),
+                self.modified.encode(),
+                original_name.encode(),
+                modified_name.encode(),
+            )
+        )
+
+
+class Repository:
+    """The ink repository containing a path"""
+
+    def __init__(self, path="."):
+        self._repo = _check(_lib.ink_repo_open(os.fspath(path).encode()))
+
+    def __del__(self):
+        if getattr(self, "_repo", None):
+            _lib.ink_repo_free(self._repo)
+            self._repo = None
+
+    def commit(self, message=""):
+        """Commit the working directory, returning None if nothing changed"""
+        hash_out = ctypes.c_void_p()
+        result = _check(_lib.ink_commit(self._repo, message.encode(), ctypes.byref(hash_out)))
+        if result == 1:
+            return None
+        return Commit(_take_string(hash_out), message)
+
+    def status(self):
+        """Changes in the working directory since the current commit"""
+        lines = _take_string(_lib.ink_status(self._repo)).splitlines()
+        return [Status(*line.split(" ", 1)) for line in lines]
+
+    def log(self):
+        """Iterate over the history of the current commit, newest first"""
+        log = _check(_lib.ink_log_open(self._repo))
+        try:
+            while True:
+                hash_out = ctypes.c_void_p()
+                message_out = ctypes.c_void_p()
+                if _check(_lib.ink_log_next(log, ctypes.byref(hash_out), ctypes.byref(message_out))) == 0:
+                    return
+                yield Commit(_take_string(hash_out), _take_string(message_out))
+        finally:
+            _lib.ink_log_free(log)
@@ -0,0 +1,25 @@
+"""Builds the ink shared library with cargo and bundles it into the wheel."""
+import glob
+import os
+import shutil
+import subprocess
+
+from setuptools import setup
+from setuptools.command.build_py import build_py
+
+CRATE_DIR = os.path.dirname(os.path.dirname(os.path.abspath(__file__)))
+
+
+class BuildWithCargo(build_py):
+    def run(self):
+        subprocess.check_call(
+            ["cargo", "build", "--release", "--features", "python"], cwd=CRATE_DIR
+        )
+        release_dir = os.path.join(CRATE_DIR, "target", "release")
+        for name in ("libink.so", "libink.dylib", "ink.dll"):
+            for path in glob.glob(os.path.join(release_dir, name)):
+                shutil.copy(path, os.path.join(os.path.dirname(__file__), "ink"))
+        super().run()
+
+
+setup(cmdclass={"build_py": BuildWithCargo})
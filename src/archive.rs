@@ -0,0 +1,377 @@
+//! Reading the files of a tar archive, to commit them without extracting
+//! them first.
+//!
+//! Only what is needed to import a snapshot is understood: regular files and
+//! directories in ustar, GNU and pax archives, including GNU long names and
+//! pax `path` records. Anything else, like a link or a device, is an error
+//! rather than being left out without notice.
+use crate::commit::Stored;
+use crate::filedata::FileData;
+use crate::filter::ContentFilters;
+use crate::ignore::IgnoreRules;
+use crate::repository::Repository;
+use crate::{atomic, objects, paths, snapshot, InkError};
+
+use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+const BLOCK: usize = 512;
+
+/// A regular file read from an archive
+pub(crate) struct ArchiveFile {
+    pub path: PathBuf,
+    pub mode: u32,
+    pub content: Vec<u8>,
+}
+
+/// The regular files of a tar archive, in the order they are stored
+pub(crate) struct TarFiles<R: Read> {
+    reader: R,
+    done: bool,
+}
+
+impl<R: Read> TarFiles<R> {
+    pub(crate) fn new(reader: R) -> TarFiles<R> {
+        TarFiles {
+            reader,
+            done: false,
+        }
+    }
+
+    fn next_file(&mut self) -> Result<Option<ArchiveFile>, InkError> {
+        // a long name from a GNU or pax entry applies to the entry after it
+        let mut long_name: Option<Vec<u8>> = None;
+
+        loop {
+            let mut header = [0; BLOCK];
+            if !read_block(&mut self.reader, &mut header)? || header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            check_checksum(&header)?;
+
+            let size = parse_number(&header[124..136])?;
+            let kind = header[156];
+            match kind {
+                b'0' | b'\0' | b'7' | b'5' | b'L' | b'x' | b'g' => {}
+                _ => return Err("Archive has an entry that isn't a file or directory".into()),
+            }
+
+            let mut content = Vec::new();
+            (&mut self.reader).take(size).read_to_end(&mut content)?;
+            if (content.len() as u64) < size {
+                return Err("Archive ends in the middle of an entry".into());
+            }
+            let padding = (BLOCK - content.len() % BLOCK) % BLOCK;
+            io::copy(
+                &mut (&mut self.reader).take(padding as u64),
+                &mut io::sink(),
+            )?;
+
+            match kind {
+                b'L' => long_name = Some(trim_nul(&content).to_vec()),
+                b'x' => {
+                    if let Some(path) = pax_path(&content)? {
+                        long_name = Some(path);
+                    }
+                }
+                // global pax headers set defaults this reader doesn't use
+                b'g' => {}
+                b'5' => long_name = None,
+                _ => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None => header_name(&header),
+                    };
+                    let mode = parse_number(&header[100..108])? as u32;
+
+                    return Ok(Some(ArchiveFile {
+                        path: archive_path(&name)?,
+                        // stored as a file's full unix mode, like the worktree's
+                        mode: 0o100000 | (mode & 0o7777),
+                        content,
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for TarFiles<R> {
+    type Item = Result<ArchiveFile, InkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let file = self.next_file().transpose();
+        if !matches!(file, Some(Ok(_))) {
+            self.done = true;
+        }
+        file
+    }
+}
+
+/// Store the content of every file in an archive, returning the files to
+/// commit and what was stored. Content goes through the clean filters of its
+/// path, as if it was committed from the working directory, and a path
+/// given more than once keeps its last content. Paths in an ink directory
+/// or that aren't valid project paths are refused, and paths a snapshot of
+/// the working directory would leave out are skipped.
+pub(crate) fn store_files<R: Read>(
+    reader: R,
+    repo: &Repository,
+) -> Result<(Vec<FileData>, Stored), InkError> {
+    let ink_root = repo.ink_root();
+    let filters = ContentFilters::load(ink_root, repo.work_tree())?;
+    let ignore = IgnoreRules::load(ink_root)?;
+    let store = snapshot::large_store_within(ink_root, repo.work_tree())?;
+    let mut files = BTreeMap::new();
+    let mut stored = Stored::default();
+    let mut seen = HashSet::new();
+
+    for file in TarFiles::new(reader) {
+        let file = file?;
+        if file
+            .path
+            .components()
+            .any(|part| part.as_os_str() == repo.dir_name())
+        {
+            return Err("Archive has a path in an ink directory".into());
+        }
+        paths::validate(&file.path, repo.dir_name())?;
+        if store
+            .as_ref()
+            .is_some_and(|store| repo.work_tree().join(&file.path).starts_with(store))
+            || excluded(&file.path, &ignore)
+        {
+            continue;
+        }
+        let content = filters.clean(&file.path, file.content)?;
+        let (hash, bytes) = objects::store_content(ink_root, &content, &file.path)?;
+        match bytes {
+            Some(bytes) => {
+                stored.files_written += 1;
+                stored.bytes_stored += bytes;
+            }
+            None if seen.contains(&hash) => stored.deduplicated += 1,
+            None => stored.skipped += 1,
+        }
+        seen.insert(hash);
+
+        let data = FileData::from_content(&file.path, file.mode, hash);
+        files.insert(file.path, data);
+    }
+
+    Ok((files.into_values().collect(), stored))
+}
+
+/// Whether a path, or a directory it is in, is a temporary file or ignored,
+/// so would be left out of a snapshot of the working directory
+fn excluded(path: &Path, ignore: &IgnoreRules) -> bool {
+    path.ancestors()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .any(|dir| atomic::is_temp(dir) || ignore.is_ignored(dir))
+}
+
+/// Fill `block` from the reader, returning false if it was already at its end
+fn read_block<R: Read>(reader: &mut R, block: &mut [u8; BLOCK]) -> Result<bool, InkError> {
+    let mut read = 0;
+    while read < BLOCK {
+        match reader.read(&mut block[read..])? {
+            0 if read == 0 => return Ok(false),
+            0 => return Err("Archive ends in the middle of a header".into()),
+            n => read += n,
+        }
+    }
+
+    Ok(true)
+}
+
+/// The checksum is the sum of the header's bytes, with its own field taken
+/// as spaces
+fn check_checksum(header: &[u8; BLOCK]) -> Result<(), InkError> {
+    let expected = parse_number(&header[148..156])?;
+    let sum: u64 = header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b } as u64)
+        .sum();
+
+    if sum != expected {
+        return Err("Archive has a header with a bad checksum".into());
+    }
+
+    Ok(())
+}
+
+/// Parse a numeric header field: octal text, or big-endian binary when its
+/// top bit is set, as GNU tar writes large sizes
+fn parse_number(field: &[u8]) -> Result<u64, InkError> {
+    if field.first().is_some_and(|b| b & 0x80 != 0) {
+        let mut number: u64 = (field[0] & 0x7f) as u64;
+        for &b in &field[1..] {
+            number = number
+                .checked_mul(256)
+                .ok_or("Archive has a number too large to read")?
+                + b as u64;
+        }
+        return Ok(number);
+    }
+
+    let text = std::str::from_utf8(trim_nul(field))
+        .map_err(|_| "Archive has a header with an invalid number")?
+        .trim();
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|_| "Archive has a header with an invalid number".into())
+}
+
+/// The name of an entry from its header, joined to the ustar prefix if it
+/// has one
+fn header_name(header: &[u8; BLOCK]) -> Vec<u8> {
+    let name = trim_nul(&header[0..100]);
+    let prefix = trim_nul(&header[345..500]);
+
+    // GNU archives use the prefix field for other things
+    if &header[257..263] != b"ustar\0" || prefix.is_empty() {
+        return name.to_vec();
+    }
+
+    let mut full = prefix.to_vec();
+    full.push(b'/');
+    full.extend_from_slice(name);
+    full
+}
+
+/// The `path` record of a pax header, made of `<length> <key>=<value>\n`
+/// records
+fn pax_path(mut records: &[u8]) -> Result<Option<Vec<u8>>, InkError> {
+    let mut path = None;
+    while !records.is_empty() {
+        let space = records
+            .iter()
+            .position(|&b| b == b' ')
+            .ok_or("Archive has an invalid pax header")?;
+        let len: usize = std::str::from_utf8(&records[..space])
+            .ok()
+            .and_then(|len| len.parse().ok())
+            .filter(|&len| len > space && len <= records.len())
+            .ok_or("Archive has an invalid pax header")?;
+        let record = &records[space + 1..len];
+        let record = record.strip_suffix(b"\n").unwrap_or(record);
+
+        if let Some(value) = record.strip_prefix(b"path=") {
+            path = Some(value.to_vec());
+        }
+        records = &records[len..];
+    }
+
+    Ok(path)
+}
+
+fn trim_nul(bytes: &[u8]) -> &[u8] {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    &bytes[..end]
+}
+
+/// A path from an archive as a path in the working directory. Paths that
+/// would land outside of it are refused.
+fn archive_path(name: &[u8]) -> Result<PathBuf, InkError> {
+    let name = std::str::from_utf8(name).map_err(|_| "Archive has a path that isn't UTF-8")?;
+
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            _ => return Err("Archive has a path outside of the working directory".into()),
+        }
+    }
+
+    if path.as_os_str().is_empty() {
+        return Err("Archive has an entry with an empty path".into());
+    }
+
+    Ok(path)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A ustar header for an entry, with a valid checksum
+    fn header(name: &str, kind: u8, mode: u32, size: usize) -> Vec<u8> {
+        let mut header = vec![0; BLOCK];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(format!("{:07o}", mode).as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[156] = kind;
+        header[257..263].copy_from_slice(b"ustar\0");
+
+        header[148..156].copy_from_slice(b"        ");
+        let sum: u32 = header.iter().map(|&b| b as u32).sum();
+        header[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        header
+    }
+
+    fn entry(archive: &mut Vec<u8>, name: &str, kind: u8, mode: u32, content: &[u8]) {
+        archive.extend(header(name, kind, mode, content.len()));
+        archive.extend_from_slice(content);
+        archive.resize(archive.len().div_ceil(BLOCK) * BLOCK, 0);
+    }
+
+    /// Make a tar archive of files, each a path, mode and content
+    pub(crate) fn tar(files: &[(&str, u32, &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, mode, content) in files {
+            entry(&mut archive, name, b'0', *mode, content);
+        }
+        archive.resize(archive.len() + 2 * BLOCK, 0);
+        archive
+    }
+
+    #[test]
+    fn read_tar_files() {
+        let long = "d/".repeat(60) + "long";
+        let mut archive = Vec::new();
+        entry(&mut archive, "./dir/", b'5', 0o755, b"");
+        entry(&mut archive, "./dir/a", b'0', 0o644, b"first");
+        entry(&mut archive, "././@LongLink", b'L', 0, long.as_bytes());
+        entry(&mut archive, "ignored", b'0', 0o755, b"second");
+        let pax = b"20 path=pax/renamed\n";
+        entry(&mut archive, "pax", b'x', 0, pax);
+        entry(&mut archive, "not-this", b'0', 0o600, b"");
+        archive.resize(archive.len() + 2 * BLOCK, 0);
+
+        let files = TarFiles::new(&archive[..])
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let files: Vec<_> = files
+            .iter()
+            .map(|f| (f.path.to_str().unwrap(), f.mode, &f.content[..]))
+            .collect();
+        assert_eq!(
+            files,
+            vec![
+                ("dir/a", 0o100644, &b"first"[..]),
+                (long.as_str(), 0o100755, &b"second"[..]),
+                ("pax/renamed", 0o100600, &b""[..]),
+            ]
+        );
+
+        // links, escaping paths and damaged archives are refused
+        let mut link = Vec::new();
+        entry(&mut link, "link", b'2', 0o777, b"");
+        assert!(TarFiles::new(&link[..]).next().unwrap().is_err());
+        let escaping = tar(&[("../outside", 0o644, b"")]);
+        assert!(TarFiles::new(&escaping[..]).next().unwrap().is_err());
+        let mut damaged = tar(&[("a", 0o644, b"content")]);
+        damaged[0] = b'b';
+        assert!(TarFiles::new(&damaged[..]).next().unwrap().is_err());
+        let truncated = &tar(&[("a", 0o644, b"content")])[..BLOCK + 3];
+        assert!(TarFiles::new(truncated).next().unwrap().is_err());
+    }
+}
@@ -0,0 +1,313 @@
+//! Repository operations that run off the calling thread, enabled with the
+//! `async` feature.
+//!
+//! Each operation is queued to run on a background thread and hands back a
+//! `Task`, which is a `Future` usable with any executor, or can be waited on
+//! directly. At most `MAX_RUNNING` operations run at once, and the rest wait
+//! their turn. Inside an operation, file content is stored and checked out on
+//! a bounded number of worker threads.
+//!
+//! An operation can't be cancelled: dropping its `Task` only drops the
+//! result, and the operation still runs to the end.
+use crate::commit::{Commit, CommitDiff};
+use crate::{CheckoutOptions, CheckoutOutcome, CommitOptions, CommitResult, InkError, Repository};
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::thread;
+
+/// How many operations run at once
+const MAX_RUNNING: usize = 4;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Operations waiting for a thread, and how many threads are running them
+struct Queue {
+    jobs: VecDeque<Job>,
+    running: usize,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    jobs: VecDeque::new(),
+    running: 0,
+});
+
+/// Queue a job, starting a thread for it if fewer than `MAX_RUNNING` are
+fn queue(job: Job) {
+    let mut queue = QUEUE.lock().unwrap();
+    queue.jobs.push_back(job);
+    if queue.running < MAX_RUNNING {
+        queue.running += 1;
+        thread::spawn(work);
+    }
+}
+
+/// Run queued jobs until there are none left
+fn work() {
+    loop {
+        let job = {
+            let mut queue = QUEUE.lock().unwrap();
+            match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => {
+                    queue.running -= 1;
+                    return;
+                }
+            }
+        };
+        job();
+    }
+}
+
+/// The result of an operation running in the background. Dropping it
+/// doesn't stop the operation.
+pub struct Task<T> {
+    shared: Arc<(Mutex<TaskState<T>>, Condvar)>,
+}
+
+struct TaskState<T> {
+    result: Option<Result<T, InkError>>,
+    waker: Option<Waker>,
+}
+
+impl<T: Send + 'static> Task<T> {
+    fn spawn<F>(f: F) -> Task<T>
+    where
+        F: FnOnce() -> Result<T, InkError> + Send + 'static,
+    {
+        let shared = Arc::new((
+            Mutex::new(TaskState {
+                result: None,
+                waker: None,
+            }),
+            Condvar::new(),
+        ));
+
+        let task_shared = Arc::clone(&shared);
+        queue(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f))
+                .unwrap_or_else(|_| Err("Background task panicked".into()));
+
+            let (state, finished) = &*task_shared;
+            let mut state = state.lock().unwrap();
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+            finished.notify_all();
+        }));
+
+        Task { shared }
+    }
+}
+
+impl<T> Task<T> {
+    /// Block the current thread until the task finishes
+    pub fn wait(self) -> Result<T, InkError> {
+        let (state, finished) = &*self.shared;
+        let mut state = state.lock().unwrap();
+        loop {
+            if let Some(result) = state.result.take() {
+                return result;
+            }
+            state = finished.wait(state).unwrap();
+        }
+    }
+}
+
+impl<T> Future for Task<T> {
+    type Output = Result<T, InkError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.shared.0.lock().unwrap();
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// An ink repository whose operations don't block the calling thread.
+/// Events are not reported, as sinks are not shared between threads.
+#[derive(Debug, Clone)]
+pub struct AsyncRepository {
+    ink_root: PathBuf,
+    work_tree: PathBuf,
+    dir_name: String,
+    jobs: usize,
+}
+
+impl AsyncRepository {
+    /// Open the repository with the given `.ink` directory
+    pub fn open(ink_root: &Path) -> Result<AsyncRepository, InkError> {
+        let repo = Repository::open(ink_root)?;
+        let jobs = thread::available_parallelism().map_or(1, |n| n.get());
+        Ok(AsyncRepository::from(&repo).with_concurrency(jobs))
+    }
+
+    /// Store and check out up to `jobs` files at once. Defaults to the
+    /// number of available cores when opened with `open`, or to the
+    /// repository's own when made from one.
+    pub fn with_concurrency(mut self, jobs: usize) -> AsyncRepository {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    pub fn ink_root(&self) -> &Path {
+        &self.ink_root
+    }
+
+    fn run<T, F>(&self, f: F) -> Task<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(Repository) -> Result<T, InkError> + Send + 'static,
+    {
+        let ink_root = self.ink_root.clone();
+        let work_tree = self.work_tree.clone();
+        let dir_name = self.dir_name.clone();
+        let jobs = self.jobs;
+        Task::spawn(move || {
+            let repo = Repository::open(&ink_root)?.with_layout(dir_name, Some(work_tree))?;
+            f(repo.with_jobs(jobs))
+        })
+    }
+
+    /// Like `Repository::commit`
+    pub fn commit(&self, message: &str, options: CommitOptions) -> Task<CommitResult> {
+        let message = message.to_string();
+        self.run(move |repo| repo.commit(&message, options))
+    }
+
+    /// Like `Repository::status`
+    pub fn status(&self) -> Task<CommitDiff> {
+        self.run(|repo| repo.status())
+    }
+
+    /// Like `Repository::go`
+    pub fn go(&self, to: Commit, options: CheckoutOptions) -> Task<CheckoutOutcome> {
+        self.run(move |repo| repo.go(to, options))
+    }
+}
+
+impl From<&Repository> for AsyncRepository {
+    fn from(repo: &Repository) -> AsyncRepository {
+        AsyncRepository {
+            ink_root: repo.ink_root().to_path_buf(),
+            work_tree: repo.work_tree().to_path_buf(),
+            dir_name: repo.dir_name().to_string(),
+            jobs: repo.jobs(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Wake;
+    use std::time::Duration;
+
+    struct ThreadWaker(thread::Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+
+        loop {
+            match future.as_mut().poll(&mut cx) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn tasks_run_a_few_at_a_time() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let most = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<Task<()>> = (0..MAX_RUNNING * 3)
+            .map(|_| {
+                let (running, most) = (Arc::clone(&running), Arc::clone(&most));
+                Task::spawn(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    most.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(10));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            task.wait().unwrap();
+        }
+        assert!(most.load(Ordering::SeqCst) <= MAX_RUNNING);
+    }
+
+    #[test]
+    fn async_commit_and_go() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        crate::init(tmpdir_path).unwrap();
+        let repo = AsyncRepository::open(&tmpdir_path.join(".ink"))
+            .unwrap()
+            .with_concurrency(2);
+
+        for n in 0..5 {
+            fs::write(tmpdir_path.join(n.to_string()), n.to_string()).unwrap();
+        }
+
+        let first =
+            crate::testkit::created(block_on(repo.commit("first", Default::default()))).commit;
+
+        for n in 0..5 {
+            fs::write(tmpdir_path.join(n.to_string()), "changed").unwrap();
+        }
+        repo.commit("second", Default::default()).wait().unwrap();
+        assert!(block_on(repo.status()).unwrap().edits.is_empty());
+
+        block_on(repo.go(first, Default::default())).unwrap();
+        for n in 0..5 {
+            assert_eq!(
+                fs::read_to_string(tmpdir_path.join(n.to_string())).unwrap(),
+                n.to_string()
+            );
+        }
+    }
+
+    #[test]
+    fn async_commit_with_separate_work_tree() {
+        let tmpdir = crate::testkit::temp_dir();
+        let work_tree = tmpdir.path().join("work");
+        fs::create_dir(&work_tree).unwrap();
+        let ink_root = crate::init(tmpdir.path()).unwrap();
+        let repo =
+            Repository::open_with(&ink_root, crate::OpenOptions::new().work_tree(&work_tree))
+                .unwrap();
+        let repo = AsyncRepository::from(&repo);
+
+        fs::write(work_tree.join("a"), "in the work tree").unwrap();
+        fs::write(tmpdir.path().join("b"), "beside the ink dir").unwrap();
+        let commit =
+            crate::testkit::created(repo.commit("first", Default::default()).wait()).commit;
+        let paths: Vec<&Path> = commit.files().iter().map(|f| f.path()).collect();
+        assert_eq!(paths, [Path::new("a")]);
+        assert!(repo.status().wait().unwrap().edits.is_empty());
+    }
+}
@@ -0,0 +1,162 @@
+//! Temporary files that take the place of another file once written, so
+//! the file is never seen half written.
+//!
+//! They are made in the same directory as the file they replace, since a
+//! rename is only atomic within one filesystem, and named with a `.ink-`
+//! prefix and a random suffix, so they never take a name the user has.
+//! Until persisted they are deleted when dropped, so an error part way
+//! through leaves nothing behind.
+//!
+//! A temporary file made elsewhere, like in `TMPDIR`, may be on another
+//! filesystem than where it goes, and can't be renamed there. `persist`
+//! then copies it beside its target and syncs it first, so the target is
+//! still replaced by a rename.
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+
+/// The prefix of every temporary file's name
+pub(crate) const TEMP_PREFIX: &str = ".ink-";
+/// How many random letters and digits follow the prefix
+const TEMP_RANDOM_LEN: usize = 6;
+
+/// Whether a path is named exactly like a temporary file, which may be left
+/// behind by a write that was interrupted. A user's file that only starts
+/// with the prefix, like `.ink-notes`, isn't one.
+#[cfg(feature = "repo")]
+pub(crate) fn is_temp(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.strip_prefix(TEMP_PREFIX))
+        .is_some_and(|random| {
+            random.len() == TEMP_RANDOM_LEN && random.bytes().all(|b| b.is_ascii_alphanumeric())
+        })
+}
+
+/// A new temporary file in the same directory as `path`, to be persisted
+/// onto it
+pub fn temp_file_beside(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Path has no parent directory",
+            ))
+        }
+    };
+
+    tempfile::Builder::new()
+        .prefix(TEMP_PREFIX)
+        .rand_bytes(TEMP_RANDOM_LEN)
+        .tempfile_in(dir)
+}
+
+/// Move a temporary file onto `path`, replacing whatever is there. Falls
+/// back to copying it if the two are on different filesystems.
+pub fn persist(tmp: TempPath, path: &Path) -> io::Result<()> {
+    match tmp.persist(path) {
+        Ok(()) => Ok(()),
+        Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+            copy_into_place(err.path, path)
+        }
+        Err(err) => Err(err.error),
+    }
+}
+
+/// Copy a temporary file onto `path` by way of a synced copy beside it,
+/// deleting the original once it is in place
+fn copy_into_place(tmp: TempPath, path: &Path) -> io::Result<()> {
+    let beside = temp_file_beside(path)?.into_temp_path();
+    let metadata = fs::symlink_metadata(&tmp)?;
+
+    if metadata.file_type().is_symlink() {
+        fs::remove_file(&beside)?;
+        symlink(&fs::read_link(&tmp)?, &beside)?;
+    } else {
+        let mut copy = OpenOptions::new().write(true).open(&beside)?;
+        io::copy(&mut File::open(&tmp)?, &mut copy)?;
+        copy.set_permissions(metadata.permissions())?;
+        copy.sync_all()?;
+    }
+
+    beside.persist(path).map_err(|err| err.error)?;
+    tmp.close()
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Symbolic links can't be made on this system",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_into_place, persist, temp_file_beside};
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn temp_files_are_beside_and_cleaned_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+
+        let tmp = temp_file_beside(&target).unwrap();
+        assert_eq!(tmp.path().parent(), Some(dir.path()));
+        assert!(tmp
+            .path()
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+            .starts_with(".ink-"));
+        drop(tmp);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+
+        let tmp = temp_file_beside(&target).unwrap();
+        fs::write(tmp.path(), "written").unwrap();
+        tmp.persist(&target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "written");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn copies_when_it_cant_rename() {
+        let staging = tempfile::tempdir().unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("file");
+        fs::write(&target, "old").unwrap();
+
+        // as `persist` does for a file on another filesystem
+        let tmp = temp_file_beside(&staging.path().join("x")).unwrap();
+        fs::write(tmp.path(), "new").unwrap();
+        copy_into_place(tmp.into_temp_path(), &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert_eq!(fs::read_dir(staging.path()).unwrap().count(), 0);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        #[cfg(unix)]
+        {
+            let tmp = temp_file_beside(&staging.path().join("x"))
+                .unwrap()
+                .into_temp_path();
+            fs::remove_file(&tmp).unwrap();
+            std::os::unix::fs::symlink("elsewhere", &tmp).unwrap();
+            copy_into_place(tmp, &target).unwrap();
+            assert_eq!(fs::read_link(&target).unwrap(), Path::new("elsewhere"));
+        }
+
+        let tmp = temp_file_beside(&target).unwrap();
+        fs::write(tmp.path(), "renamed").unwrap();
+        persist(tmp.into_temp_path(), &target).unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "renamed");
+    }
+}
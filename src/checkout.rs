@@ -0,0 +1,442 @@
+//! Switching the working directory to another commit, all or nothing.
+//!
+//! A checkout runs in phases. First the edits are planned and checked
+//! against the working directory, so one that can't be applied, like a file
+//! going where a directory with other files in it is, fails before anything
+//! changes. Files to delete are moved aside, directories they leave empty
+//! that new files replace are removed, missing directories are made, and
+//! the new content of every file is written to a temporary file next to
+//! where it goes. Only then are the files being replaced moved aside and the
+//! new ones renamed into place. Files whose permissions alone change keep
+//! their content and have their mode set. The old files are deleted once
+//! everything is in place. If a step fails, the steps already done are undone in reverse,
+//! leaving the working directory as it was.
+use crate::commit::Edit;
+use crate::filedata::{FileData, FileKind};
+use crate::filter::ContentFilters;
+use crate::paths;
+use crate::repository::CheckoutOutcome;
+use crate::storage::StoragePolicy;
+use crate::{atomic, utils, InkError};
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempPath;
+
+/// A checkout that has been applied to the working directory, but can
+/// still be rolled back
+#[derive(Default)]
+pub(crate) struct Checkout {
+    outcome: CheckoutOutcome,
+    /// Files moved out of the way, with where they were
+    moved: Vec<(PathBuf, TempPath)>,
+    /// Files renamed into place
+    placed: Vec<PathBuf>,
+    /// Directories made, outermost first
+    dirs: Vec<PathBuf>,
+    /// Directories emptied by deletes and removed, innermost first
+    removed: Vec<PathBuf>,
+    /// Files whose mode was set, with the permissions they had
+    chmodded: Vec<(PathBuf, fs::Permissions)>,
+}
+
+impl Checkout {
+    /// Apply edits to the working directory in `project_dir`, writing up to
+    /// `jobs` files at once. On failure everything done is rolled back.
+    pub(crate) fn apply(
+        ink_root: &Path,
+        project_dir: &Path,
+        dir_name: &str,
+        edits: &[Edit],
+        jobs: usize,
+    ) -> Result<Checkout, InkError> {
+        let mut checkout = Checkout::default();
+        match checkout.run(ink_root, project_dir, dir_name, edits, jobs) {
+            Ok(()) => Ok(checkout),
+            Err(err) => {
+                checkout.rollback();
+                Err(err)
+            }
+        }
+    }
+
+    fn run(
+        &mut self,
+        ink_root: &Path,
+        project_dir: &Path,
+        dir_name: &str,
+        edits: &[Edit],
+        jobs: usize,
+    ) -> Result<(), InkError> {
+        // a checkout that can't be applied fails before anything is done
+        let plan = Plan::new(project_dir, dir_name, edits)?;
+
+        for f in &plan.deletes {
+            // out of any directory about to be removed, so it can be
+            let path = project_dir.join(f.path());
+            let beside = plan
+                .emptied
+                .iter()
+                .rev()
+                .find(|dir| path.starts_with(dir))
+                .unwrap_or(&path);
+            self.move_aside(&path, beside)?;
+            self.outcome.files_deleted += 1;
+        }
+
+        for dir in &plan.emptied {
+            fs::remove_dir(dir)?;
+            self.removed.push(dir.clone());
+        }
+
+        // make the directories new files go in, outermost first
+        for f in &plan.writes {
+            let mut missing: Vec<_> = f
+                .path()
+                .ancestors()
+                .skip(1)
+                .map(|dir| project_dir.join(dir))
+                .take_while(|dir| !dir.exists())
+                .collect();
+            while let Some(dir) = missing.pop() {
+                fs::create_dir(&dir)?;
+                self.dirs.push(dir);
+            }
+        }
+        self.outcome.dirs_created = self.dirs.len();
+
+        // staged files are removed when dropped, so a failure leaves none
+        let filters = ContentFilters::load(ink_root, project_dir)?;
+        let policy = StoragePolicy::load(ink_root)?;
+        let staged = utils::map_bounded(&plan.writes, jobs, |f| {
+            let target = project_dir.join(f.path());
+            let staged = temp_path_next_to(&target)?;
+            f.write_to(ink_root, &staged, &filters, &policy)?;
+            Ok((target, staged))
+        })?;
+
+        for (target, staged) in staged {
+            if fs::symlink_metadata(&target).is_ok() {
+                self.move_aside(&target, &target)?;
+            }
+
+            atomic::persist(staged, &target)?;
+            self.placed.push(target);
+            self.outcome.files_changed += 1;
+        }
+
+        for f in &plan.chmods {
+            let target = project_dir.join(f.path());
+            let permissions = fs::metadata(&target)?.permissions();
+            utils::set_file_mode(&target, f.permissions())?;
+            self.chmodded.push((target, permissions));
+            self.outcome.files_changed += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Move a file to a temporary path next to `beside`
+    fn move_aside(&mut self, path: &Path, beside: &Path) -> Result<(), InkError> {
+        let aside = temp_path_next_to(beside)?;
+        fs::rename(path, &aside)?;
+        self.moved.push((path.to_path_buf(), aside));
+        Ok(())
+    }
+
+    /// Delete the files that were replaced or deleted, keeping the checkout
+    pub(crate) fn finish(self) -> CheckoutOutcome {
+        // moved files are deleted as their temporary paths are dropped
+        self.outcome
+    }
+
+    /// Undo the checkout, putting back every file as it was. This is best
+    /// effort: a step that fails to undo doesn't stop the others, and a file
+    /// that can't be moved back is left where it was moved to.
+    pub(crate) fn rollback(self) {
+        for (path, permissions) in self.chmodded.iter().rev() {
+            let _ = fs::set_permissions(path, permissions.clone());
+        }
+        for path in self.placed.iter().rev() {
+            let _ = fs::remove_file(path);
+        }
+
+        // directories go before the files moved aside come back, as a file
+        // may have been where a directory was made, or the other way round
+        for dir in self.dirs.iter().rev() {
+            let _ = fs::remove_dir(dir);
+        }
+        for dir in self.removed.iter().rev() {
+            let _ = fs::create_dir(dir);
+        }
+
+        for (path, aside) in self.moved.into_iter().rev() {
+            let _ = fs::rename(&aside, &path);
+            // either way, nothing is left to delete
+            let _ = aside.keep();
+        }
+    }
+}
+
+/// The order a checkout's edits are applied in, checked against the working
+/// directory before any of them are
+struct Plan<'a> {
+    /// Files to delete, innermost first
+    deletes: Vec<&'a FileData>,
+    /// Directories that files replace, left empty by the deletes, innermost
+    /// first
+    emptied: Vec<PathBuf>,
+    /// Files to write, by path
+    writes: Vec<&'a FileData>,
+    /// Files to set the mode of, keeping their content
+    chmods: Vec<&'a FileData>,
+}
+
+impl<'a> Plan<'a> {
+    fn new(project_dir: &Path, dir_name: &str, edits: &'a [Edit]) -> Result<Plan<'a>, InkError> {
+        let mut deletes = Vec::new();
+        let mut writes = Vec::new();
+        let mut chmods = Vec::new();
+        let mut paths = HashSet::new();
+        for edit in edits {
+            paths::validate(edit.path(), dir_name)?;
+            if !paths.insert(edit.path()) {
+                return Err("Checkout changes the same path twice".into());
+            }
+            match edit {
+                Edit::Delete(f) => deletes.push(f),
+                // a link's mode can't be set without following it
+                Edit::ChmodOnly { modified: f, .. } if f.kind() == FileKind::File => chmods.push(f),
+                Edit::Insert(f)
+                | Edit::Modify { modified: f, .. }
+                | Edit::TypeChange { modified: f, .. }
+                | Edit::ChmodOnly { modified: f, .. } => writes.push(f),
+            }
+        }
+        deletes.sort_by(|a, b| b.path().cmp(a.path()));
+        writes.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let deleted: HashSet<&Path> = deletes.iter().map(|f| f.path()).collect();
+        let written: HashSet<&Path> = writes.iter().map(|f| f.path()).collect();
+
+        let mut emptied = Vec::new();
+        for f in &writes {
+            // every directory the file goes in must be one, or be free to be
+            for dir in f.path().ancestors().skip(1) {
+                if dir.as_os_str().is_empty() {
+                    continue;
+                }
+                if written.contains(dir) {
+                    return Err("Checkout writes a file where a directory must go".into());
+                }
+                let blocked = fs::metadata(project_dir.join(dir)).is_ok_and(|m| !m.is_dir());
+                if blocked && !deleted.contains(dir) {
+                    return Err("A file is in the way of a directory a new file goes in".into());
+                }
+            }
+
+            // and a directory where the file goes must be emptied first
+            let target = project_dir.join(f.path());
+            if fs::symlink_metadata(&target).is_ok_and(|m| m.is_dir()) {
+                let start = emptied.len();
+                collect_emptied(project_dir, &target, &deleted, &mut emptied)?;
+                emptied[start..].reverse();
+            }
+        }
+
+        Ok(Plan {
+            deletes,
+            emptied,
+            writes,
+            chmods,
+        })
+    }
+}
+
+/// Add `dir` and the directories in it to `emptied`, outermost first, if
+/// only files being deleted are in them
+fn collect_emptied(
+    project_dir: &Path,
+    dir: &Path,
+    deleted: &HashSet<&Path>,
+    emptied: &mut Vec<PathBuf>,
+) -> Result<(), InkError> {
+    emptied.push(dir.to_path_buf());
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if fs::symlink_metadata(&path)?.is_dir() {
+            collect_emptied(project_dir, &path, deleted, emptied)?;
+        } else if !deleted.contains(path.strip_prefix(project_dir).unwrap_or(&path)) {
+            return Err("A directory with files still in it is in the way of a new file".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// A new temporary path in the same directory as `path`, so it can be
+/// renamed to and from `path` atomically
+fn temp_path_next_to(path: &Path) -> Result<TempPath, InkError> {
+    Ok(atomic::temp_file_beside(path)?.into_temp_path())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testkit::TestRepo;
+    use std::fs;
+
+    #[test]
+    fn failed_checkout_rolls_back() {
+        let repo = TestRepo::new()
+            .file("a", "first")
+            .file("b", "b")
+            .commit("first")
+            .file("a", "second")
+            .remove("b")
+            .file("x", "x")
+            .commit("second");
+        let second = repo.head();
+        let first = crate::commit::Commit::from(&second.parents()[0], repo.ink_root()).unwrap();
+        repo.repo().go(first, Default::default()).unwrap();
+
+        // a file not in the worktree keeps its directory from being
+        // replaced by a file
+        fs::create_dir(repo.path().join("x")).unwrap();
+        fs::write(repo.path().join("x").join("keep"), "keep").unwrap();
+        assert!(repo.repo().go(second, Default::default()).is_err());
+
+        assert_eq!(repo.read("a"), b"first");
+        assert_eq!(repo.read("b"), b"b");
+        assert_eq!(repo.head().message(), "first");
+        let mut names: Vec<_> = fs::read_dir(repo.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".ink", "a", "b", "x"]);
+    }
+
+    #[test]
+    fn files_and_directories_replace_each_other() {
+        let repo = TestRepo::new()
+            .file("a", "file")
+            .file("b/c/d", "deep")
+            .commit("files");
+        let files = repo.head();
+        let repo = repo.remove("a").remove("b/c/d");
+        fs::remove_dir_all(repo.path().join("b")).unwrap();
+        let repo = repo
+            .file("a/inner", "now a directory")
+            .file("b", "now a file")
+            .commit("directories");
+        let dirs = repo.head();
+
+        repo.repo().go(files, Default::default()).unwrap();
+        assert_eq!(repo.read("a"), b"file");
+        assert_eq!(repo.read("b/c/d"), b"deep");
+
+        repo.repo().go(dirs, Default::default()).unwrap();
+        assert_eq!(repo.read("a/inner"), b"now a directory");
+        assert_eq!(repo.read("b"), b"now a file");
+        let mut names: Vec<_> = fs::read_dir(repo.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, [".ink", "a", "b"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn file_types_change() {
+        use std::path::Path;
+
+        let repo = TestRepo::new()
+            .file("file", "a file")
+            .file("dir/inner", "in a directory")
+            .symlink("link", "file")
+            .symlink("dangling", "missing")
+            .commit("before");
+        let before = repo.head();
+        let repo = repo.remove("file").remove("dir/inner").remove("link");
+        fs::remove_dir(repo.path().join("dir")).unwrap();
+        let repo = repo
+            .remove("dangling")
+            .symlink("file", "link")
+            .symlink("dir", "link")
+            .file("link", "a file now")
+            .file("dangling/inner", "a directory now")
+            .commit("after");
+        let after = repo.head();
+
+        let diff = before.diff(&after);
+        let statuses: Vec<(char, &Path)> = diff
+            .edits
+            .iter()
+            .map(|edit| (edit.status(), edit.path()))
+            .collect();
+        assert_eq!(
+            statuses,
+            [
+                ('D', Path::new("dir/inner")),
+                ('D', Path::new("dangling")),
+                ('A', Path::new("dangling/inner")),
+                ('A', Path::new("dir")),
+                ('T', Path::new("file")),
+                ('T', Path::new("link")),
+            ]
+        );
+
+        let link = |path: &str| fs::read_link(repo.path().join(path)).unwrap();
+        repo.repo().go(before, Default::default()).unwrap();
+        assert_eq!(repo.read("file"), b"a file");
+        assert_eq!(repo.read("dir/inner"), b"in a directory");
+        assert_eq!(link("link"), Path::new("file"));
+        assert_eq!(link("dangling"), Path::new("missing"));
+
+        repo.repo().go(after, Default::default()).unwrap();
+        assert_eq!(link("file"), Path::new("link"));
+        assert_eq!(link("dir"), Path::new("link"));
+        assert_eq!(repo.read("link"), b"a file now");
+        assert_eq!(repo.read("dangling/inner"), b"a directory now");
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permission_changes_keep_content() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let repo = TestRepo::new().file("script", "echo hi");
+        let path = repo.path().join("script");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        let repo = repo.commit("plain");
+        let plain = repo.head();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        let statuses: Vec<char> = repo
+            .repo()
+            .status()
+            .unwrap()
+            .edits
+            .iter()
+            .map(|edit| edit.status())
+            .collect();
+        assert_eq!(statuses, ['P']);
+        let repo = repo.commit("executable");
+        let executable = repo.head();
+
+        // the file is the same one, with only its mode set
+        let inode = fs::metadata(&path).unwrap().ino();
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        let outcome = repo.repo().go(plain, Default::default()).unwrap();
+        assert_eq!(outcome.files_changed, 1);
+        assert_eq!(mode(), 0o644);
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        assert_eq!(repo.read("script"), b"echo hi");
+
+        repo.repo().go(executable, Default::default()).unwrap();
+        assert_eq!(mode(), 0o755);
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+    }
+}
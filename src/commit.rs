@@ -1,26 +1,89 @@
-use std::collections::HashMap;
-use std::fs::{self, File};
-use std::path::Path;
-use std::time::SystemTime;
-
-use crate::filedata::FileData;
-use crate::graph::CommitGraph;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::archive;
+use crate::diff::Diff;
+use crate::filedata::{self, FileData};
+use crate::filter::ContentFilters;
+use crate::graph::{render, CommitGraph};
+use crate::identity::{IdHasher, InkObject, ObjectId, Tree};
+use crate::large_files::LargeFiles;
+use crate::metrics::Timings;
+use crate::repository::Repository;
+use crate::storage::StoragePolicy;
 use crate::utils;
+use crate::vfs::{OsFs, VirtualFs};
 use crate::{InkError, COMMIT_EXT};
 
 use custom_debug_derive::Debug;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use sha2::{Digest, Sha256};
 
+/// Metadata key of who wrote a commit's changes
+pub const AUTHOR_KEY: &str = "author";
+/// Metadata key of who made the commit, when several people share a
+/// repository
+pub const COMMITTER_KEY: &str = "committer";
+
 /// Struct to hold information about a commit
-/// to work with in ink. Stores filedata and time
-/// of commit
+/// to work with in ink. Stores filedata, parents, time and
+/// offset from UTC, message and metadata of commit
 #[derive(Debug, Serialize, PartialEq, Eq)]
 pub struct Commit {
+    /// Identity of the commit: the tree hash, parents, time, offset, message and metadata
     #[debug(with = "utils::hex_fmt")]
     #[serde(skip)]
     hash: [u8; 32],
-    // TODO: store these as a hash set with custom hash trait for ink id hashes
+    /// Hash of only the files in the commit
+    #[debug(with = "utils::hex_fmt")]
+    #[serde(skip)]
+    tree_hash: [u8; 32],
+    files: FileSet,
+    // kept in the commit as well as the graph, so history can be recovered
+    // from commit objects alone
+    #[debug(with = "utils::hex_list_fmt")]
+    parents: Vec<[u8; 32]>,
+    time: u64,
+    /// The committer's offset from UTC, in seconds east of it
+    offset: i32,
+    message: String,
+    /// Free-form key-value fields for integrations, e.g. CI run IDs
+    metadata: BTreeMap<String, String>,
+    /// How the commit object is written, which decides its hash
+    #[serde(skip)]
+    format: Format,
+}
+
+/// Commit objects start with this and then their format version as a
+/// big-endian `u32`. Objects from before there were versions start with how
+/// many files they hold instead, which is never this.
+const COMMIT_MAGIC: &[u8; 4] = b"inkc";
+const COMMIT_VERSION: u32 = 1;
+
+/// The ways a commit object can be written
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum Format {
+    /// Only the files and time, from before commits had parents or
+    /// messages. Such a commit's hash is of its files' hashes and its time,
+    /// and its parents are only in the graph.
+    Legacy,
+    /// `COMMIT_MAGIC`, the version and the whole commit
+    #[default]
+    Versioned,
+}
+
+/// Serialized representation of a commit written before formats were
+/// versioned
+#[derive(Deserialize)]
+struct LegacyRepr {
     files: Vec<FileData>,
     time: u64,
 }
@@ -29,38 +92,122 @@ pub struct Commit {
 #[derive(Serialize, Deserialize, Default)]
 struct CommitRepr {
     files: Vec<FileData>,
+    parents: Vec<[u8; 32]>,
     time: u64,
+    offset: i32,
+    message: String,
+    metadata: BTreeMap<String, String>,
 }
 
 impl CommitRepr {
-    fn to_commit(mut self) -> Commit {
-        // TODO: pull the hashing into a trait for all ink objects
-        self.files.sort();
-
-        let mut hasher = Sha256::new();
+    /// Read the object of the commit with the given hash, at `path`, in
+    /// either format. A legacy commit's parents are looked up in the graph,
+    /// if it can be read.
+    fn decode(
+        fs: &dyn VirtualFs,
+        bytes: &[u8],
+        hash: &[u8; 32],
+        path: &Path,
+        ink_root: &Path,
+    ) -> Result<(CommitRepr, Format), InkError> {
+        let malformed = |problem| InkError::Malformed {
+            path: path.to_path_buf(),
+            problem,
+        };
 
-        for file in &self.files {
-            hasher.update(file.hash());
-        }
+        let body = match bytes.strip_prefix(COMMIT_MAGIC) {
+            Some(rest) if rest.len() >= 4 => {
+                let (version, body) = rest.split_at(4);
+                if version != COMMIT_VERSION.to_be_bytes() {
+                    return Err(malformed(
+                        "the commit is in a format this version of ink can't read",
+                    ));
+                }
+                body
+            }
+            Some(_) => return Err(malformed("the commit object is cut short")),
+            None => {
+                let legacy: LegacyRepr = bincode::deserialize(bytes)
+                    .map_err(|_| malformed("a commit object must hold a commit"))?;
+                let parents = match CommitGraph::read(fs, ink_root) {
+                    Ok(graph) => graph.parents(hash).to_vec(),
+                    Err(_) => Vec::new(),
+                };
+                let repr = CommitRepr {
+                    files: legacy.files,
+                    parents,
+                    time: legacy.time,
+                    ..CommitRepr::default()
+                };
+                return Ok((repr, Format::Legacy));
+            }
+        };
 
-        hasher.update(self.time.to_be_bytes());
+        let repr = bincode::deserialize(body)
+            .map_err(|_| malformed("a commit object must hold a commit"))?;
+        Ok((repr, Format::Versioned))
+    }
 
-        let hash = hasher.finalize().into();
+    fn into_commit(self, format: Format) -> Commit {
+        // kept as they were written, duplicates and all, so the commit's
+        // hash still matches
+        let files = FileSet::stored(self.files);
 
-        Commit {
-            hash,
-            files: self.files,
+        let mut commit = Commit {
+            hash: [0; 32],
+            tree_hash: Tree::new(files.as_slice()).object_id(),
+            files,
+            parents: self.parents,
             time: self.time,
-        }
+            offset: self.offset,
+            message: self.message,
+            metadata: self.metadata,
+            format,
+        };
+        commit.hash = commit.identity_hash();
+
+        commit
     }
 }
 
+/// What writing a commit's files stored
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Stored {
+    pub files_written: usize,
+    /// Bytes taken up by the newly stored content, after compression
+    pub bytes_stored: u64,
+    /// Files whose content was already stored
+    pub skipped: usize,
+    /// Files with the same content as another file in the commit, which was
+    /// only read and stored once
+    pub deduplicated: usize,
+    /// Time spent hashing and compressing the new content, added up across
+    /// the jobs storing it
+    pub hashing: Duration,
+    pub compressing: Duration,
+}
+
+/// The hashes of every commit object in the commit directory
+pub fn stored_hashes(ink_root: &Path) -> Result<Vec<[u8; 32]>, InkError> {
+    let mut hashes = Vec::new();
+    for entry in fs::read_dir(ink_root.join(COMMIT_EXT))? {
+        let name = entry?.file_name();
+        let hash: [u8; 32] = hex::decode(name.to_string_lossy().as_bytes())
+            .map_err(|_| "Commit object has an invalid name")?
+            .try_into()
+            .map_err(|_| "Commit object has an invalid name")?;
+        hashes.push(hash);
+    }
+
+    Ok(hashes)
+}
+
 pub fn commit_hash_from_prefix(ink_root: &Path, prefix: &[u8]) -> Result<[u8; 32], InkError> {
     if prefix.len() > 32 {
         return Err("invalid commit hash prefix: too long".into());
     }
 
-    let graph = CommitGraph::get(&ink_root)?;
+    let graph = CommitGraph::get(ink_root)?;
     let all_hashes: Vec<&[u8; 32]> = graph.commit_hashes();
 
     let candidates: Vec<&&[u8; 32]> = all_hashes
@@ -76,73 +223,218 @@ pub fn commit_hash_from_prefix(ink_root: &Path, prefix: &[u8]) -> Result<[u8; 32
         return Err("Too many possible commits with the given prefix".into());
     }
 
-    Ok((*candidates[0]).clone())
+    Ok(**candidates[0])
 }
 
 impl Commit {
     /// Creates and writes a new commit from data in the given directory with the
-    /// given timestamp
+    /// given parents, timestamp and message. The repository has the default
+    /// layout, with its working directory containing its ink directory.
     pub(crate) fn new<P: AsRef<Path>>(
         files: Vec<P>,
+        parents: Vec<[u8; 32]>,
         timestamp: SystemTime,
+        message: &str,
         ink_root: &Path,
     ) -> Result<Commit, InkError> {
         // get FileData objects for each file
-        let mut files = files
+        let filters = ContentFilters::load(ink_root, &crate::default_work_tree(ink_root)?)?;
+        let files = files
             .iter()
-            .map(|filepath| FileData::new(filepath.as_ref(), ink_root))
+            .map(|filepath| FileData::new(filepath.as_ref(), crate::DEFAULT_DIR_NAME, &filters))
             .collect::<Result<Vec<FileData>, InkError>>()?;
 
+        Commit::from_files(files, parents, timestamp, message)
+    }
+
+    /// Creates a commit from already hashed files with the given parents,
+    /// timestamp and message, without touching the disk
+    pub(crate) fn from_files(
+        files: Vec<FileData>,
+        parents: Vec<[u8; 32]>,
+        timestamp: SystemTime,
+        message: &str,
+    ) -> Result<Commit, InkError> {
         // get SystemTime, convert to seconds.
         let now = timestamp
             .duration_since(SystemTime::UNIX_EPOCH)
             .map_err(|_| "Cannot commit before unix epoch.")?
             .as_secs();
 
-        files.sort();
+        let files = FileSet::new(files);
 
-        let mut hasher = Sha256::new();
+        let mut commit = Commit {
+            hash: [0; 32],
+            tree_hash: Tree::new(files.as_slice()).object_id(),
+            files,
+            parents,
+            time: now,
+            offset: 0,
+            message: message.to_string(),
+            metadata: BTreeMap::new(),
+            format: Format::Versioned,
+        };
+        commit.hash = commit.identity_hash();
+
+        Ok(commit)
+    }
+
+    /// Creates a commit from the files in a tar archive with the given
+    /// parents, timestamp and message. The content of the files is stored,
+    /// as if they were committed from the working directory, but the commit
+    /// object isn't written.
+    pub fn from_archive<R: Read>(
+        reader: R,
+        parents: Vec<[u8; 32]>,
+        timestamp: SystemTime,
+        message: &str,
+        repo: &Repository,
+    ) -> Result<Commit, InkError> {
+        let (files, _) = archive::store_files(reader, repo)?;
+        Commit::from_files(files, parents, timestamp, message)
+    }
+
+    /// Set the committer's offset from UTC, which changes the commit's hash
+    pub(crate) fn with_offset(mut self, offset: i32) -> Commit {
+        self.offset = offset;
+        self.hash = self.identity_hash();
+        self
+    }
+
+    /// Replace the metadata fields of a commit, which changes its hash
+    pub(crate) fn with_metadata(mut self, metadata: BTreeMap<String, String>) -> Commit {
+        self.metadata = metadata;
+        self.hash = self.identity_hash();
+        self
+    }
+
+    /// Hash the parts of the commit that make up its identity
+    fn identity_hash(&self) -> ObjectId {
+        let mut hasher = IdHasher::new();
 
-        for file in &files {
-            hasher.update(file.hash());
+        if self.format == Format::Legacy {
+            for file in self.files() {
+                hasher.id(&file.object_id());
+            }
+            hasher.u64(self.time);
+            return hasher.finish();
         }
 
-        hasher.update(now.to_be_bytes());
+        hasher.id(&self.tree_hash);
 
-        let hash = hasher.finalize().into();
+        for parent in &self.parents {
+            hasher.id(parent);
+        }
 
-        Ok(Commit {
-            hash,
-            files,
-            time: now,
-        })
+        // the message and metadata are length prefixed, so one can't run
+        // into the next
+        hasher
+            .u64(self.time)
+            .i32(self.offset)
+            .field(self.message.as_bytes());
+        for (key, value) in &self.metadata {
+            hasher.field(key.as_bytes()).field(value.as_bytes());
+        }
+
+        hasher.finish()
+    }
+
+    /// Write the commit, reading content that isn't stored yet from the
+    /// directory containing the ink directory
+    pub(crate) fn write(&self, ink_root: &Path) -> Result<Stored, InkError> {
+        self.write_with_jobs(ink_root, &crate::default_work_tree(ink_root)?, 1)
     }
 
-    pub(crate) fn write(&self, ink_root: &Path) -> Result<(), InkError> {
-        for file in &self.files {
-            file.write(ink_root)?;
+    /// Write the commit, storing the content of up to `jobs` files at once
+    /// from the given working directory
+    pub(crate) fn write_with_jobs(
+        &self,
+        ink_root: &Path,
+        project_dir: &Path,
+        jobs: usize,
+    ) -> Result<Stored, InkError> {
+        let filters = ContentFilters::load(ink_root, project_dir)?;
+        let large_files = LargeFiles::load(ink_root)?;
+        let policy = StoragePolicy::load(ink_root)?;
+
+        // content shared by several paths is only read and stored once
+        let mut seen = HashSet::new();
+        let unique: Vec<&FileData> = self
+            .files()
+            .iter()
+            .filter(|file| seen.insert(file.content_hash()))
+            .collect();
+        let timings = Timings::default();
+        let written = utils::map_bounded(&unique, jobs, |file| {
+            file.write(ink_root, &filters, &large_files, &policy, &timings)
+        })?;
+
+        // written after its content, so a synced commit has all it needs
+        let commit_file_path = ink_root.join(COMMIT_EXT).join(hex::encode(self.hash));
+        policy
+            .durability()
+            .write(&commit_file_path, &self.encode()?)?;
+
+        let mut stored = Stored {
+            deduplicated: self.files.len() - unique.len(),
+            hashing: timings.hashing(),
+            compressing: timings.compressing(),
+            ..Stored::default()
+        };
+        for bytes in written {
+            match bytes {
+                Some(bytes) => {
+                    stored.files_written += 1;
+                    stored.bytes_stored += bytes;
+                }
+                None => stored.skipped += 1,
+            }
         }
 
+        Ok(stored)
+    }
+
+    /// Write only the commit object, not the content of its files
+    pub fn write_object(&self, fs: &dyn VirtualFs, ink_root: &Path) -> Result<(), InkError> {
         let commit_file_path = ink_root.join(COMMIT_EXT).join(hex::encode(self.hash));
 
-        fs::write(commit_file_path, bincode::serialize(&self)?)?;
+        fs.write(&commit_file_path, &self.encode()?)?;
 
         Ok(())
     }
 
+    /// The commit object's bytes, in the format it was read in or, for a
+    /// new commit, the current one
+    pub(crate) fn encode(&self) -> Result<Vec<u8>, InkError> {
+        match self.format {
+            Format::Legacy => Ok(bincode::serialize(&(&self.files, self.time))?),
+            Format::Versioned => {
+                let mut bytes = COMMIT_MAGIC.to_vec();
+                bytes.extend_from_slice(&COMMIT_VERSION.to_be_bytes());
+                bincode::serialize_into(&mut bytes, self)?;
+                Ok(bytes)
+            }
+        }
+    }
+
     /// Deserialize a commit object from its hash.
     /// Throws an error if the given hash does not match the actual hash of the commit
     /// or if the given commit does not exist.
     pub fn from(hash: &[u8; 32], ink_root: &Path) -> Result<Commit, InkError> {
+        Commit::read(&OsFs, hash, ink_root)
+    }
+
+    /// Like `from`, but reads the commit object through the given filesystem
+    pub fn read(fs: &dyn VirtualFs, hash: &[u8; 32], ink_root: &Path) -> Result<Commit, InkError> {
         let commit_file_path = ink_root.join(COMMIT_EXT).join(hex::encode(hash));
 
-        if !(commit_file_path.exists() && commit_file_path.is_file()) {
+        if !fs.is_file(&commit_file_path) {
             return Err("Given commit hash does not exist on disk".into());
         }
 
-        let reader = File::open(commit_file_path)?;
-        let commit: CommitRepr = bincode::deserialize_from(reader)?;
-        let commit = commit.to_commit();
+        let bytes = fs.read(&commit_file_path)?;
+        let (commit, format) = CommitRepr::decode(fs, &bytes, hash, &commit_file_path, ink_root)?;
+        let commit = commit.into_commit(format);
 
         if *hash != commit.hash {
             return Err("Actual hash of commit does not match given hash of commit".into());
@@ -155,45 +447,261 @@ impl Commit {
         self.hash
     }
 
+    /// Hash of only the files in the commit, so identical trees committed
+    /// at different times can be compared
+    pub fn tree_hash(&self) -> [u8; 32] {
+        self.tree_hash
+    }
+
+    /// Hashes of the commits this commit was made on top of
+    pub fn parents(&self) -> &[[u8; 32]] {
+        &self.parents
+    }
+
+    pub fn time(&self) -> u64 {
+        self.time
+    }
+
+    /// The committer's offset from UTC, in seconds east of it
+    pub fn offset(&self) -> i32 {
+        self.offset
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn metadata(&self) -> &BTreeMap<String, String> {
+        &self.metadata
+    }
+
+    /// Who wrote the commit's changes, if it was recorded
+    pub fn author(&self) -> Option<&str> {
+        self.metadata.get(AUTHOR_KEY).map(String::as_str)
+    }
+
+    /// Who made the commit, if it was recorded
+    pub fn committer(&self) -> Option<&str> {
+        self.metadata.get(COMMITTER_KEY).map(String::as_str)
+    }
+
+    /// The files, ordered by their hashes
+    pub fn files(&self) -> &[FileData] {
+        self.files.as_slice()
+    }
+
+    /// The file at a path relative to the project directory
+    pub fn file(&self, path: &Path) -> Option<&FileData> {
+        self.files.get(path)
+    }
+
+    /// Whether the commit has the file, with the same path, permissions and
+    /// content
+    pub fn contains(&self, file: &FileData) -> bool {
+        self.files.contains(file)
+    }
+
     /// Creates the diff to transform self -> other
     pub fn diff(&self, other: &Commit) -> CommitDiff {
-        let mut edits = vec![];
+        diff_with(
+            self.files(),
+            |path| self.files.get(path),
+            other.files(),
+            |path| other.files.get(path),
+        )
+    }
 
-        let self_hashes = self
-            .files
-            .iter()
-            .map(|f| (f.path(), f))
-            .collect::<HashMap<&Path, &FileData>>();
+    /// The changes the commit made to its first parent, or none if it has
+    /// no parents
+    pub fn changes(&self, ink_root: &Path) -> Result<CommitDiff, InkError> {
+        Ok(match self.parents.first() {
+            Some(parent) => Commit::from(parent, ink_root)?.diff(self),
+            None => CommitDiff { edits: vec![] },
+        })
+    }
+}
 
-        let other_hashes = other
-            .files
-            .iter()
-            .map(|f| (f.path(), f))
-            .collect::<HashMap<&Path, &FileData>>();
-
-        // TODO: this should not be checking for the combination of
-        // index and path, just path -> get the relevant file
-        for (path, file) in &other_hashes {
-            if !self_hashes.contains_key(path) {
-                edits.push(Edit::Insert((*file).clone()));
-            } else {
-                let original = self_hashes.get(path).unwrap();
-                if file.hash() != original.hash() {
-                    edits.push(Edit::Modify {
-                        original: (*original).clone(),
-                        modified: (*file).clone(),
-                    });
-                }
-            }
+impl InkObject for Commit {
+    /// The hash of the tree, parents, time, offset, message and metadata
+    fn object_id(&self) -> ObjectId {
+        self.hash
+    }
+}
+
+impl fmt::Display for Commit {
+    /// The short hash, date, number of files and indented message
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "commit {}", render::short_hash(&self.hash))?;
+        writeln!(f, "Date:  {}", crate::time::format(self.time, self.offset))?;
+        writeln!(f, "Files: {}", self.files.len())?;
+        writeln!(f)?;
+        for line in self.message.lines() {
+            writeln!(f, "    {}", line)?;
         }
+        Ok(())
+    }
+}
+
+/// Creates the diff to transform one set of files into another. The edits
+/// are in the order they can be applied in: deletes first, in reverse path
+/// order so the files in a directory go before any file at the directory's
+/// path, letting a file replace a directory that has been emptied, then the
+/// rest by path.
+pub(crate) fn diff_files(from: &[FileData], to: &[FileData]) -> CommitDiff {
+    let from_paths = from
+        .iter()
+        .map(|f| (f.path(), f))
+        .collect::<HashMap<&Path, &FileData>>();
 
-        for (path, file) in self_hashes {
-            if !other_hashes.contains_key(path) {
-                edits.push(Edit::Delete(file.clone()));
+    let to_paths = to
+        .iter()
+        .map(|f| (f.path(), f))
+        .collect::<HashMap<&Path, &FileData>>();
+
+    diff_with(
+        from,
+        |path| from_paths.get(path).copied(),
+        to,
+        |path| to_paths.get(path).copied(),
+    )
+}
+
+/// `diff_files` with a way to look up each side's file at a path. A file
+/// that isn't the one looked up at its path is a duplicate, and left out.
+fn diff_with<'a, F, T>(
+    from: &'a [FileData],
+    from_get: F,
+    to: &'a [FileData],
+    to_get: T,
+) -> CommitDiff
+where
+    F: Fn(&Path) -> Option<&'a FileData>,
+    T: Fn(&Path) -> Option<&'a FileData>,
+{
+    let mut edits = vec![];
+
+    for file in to {
+        if !to_get(file.path()).is_some_and(|found| ptr::eq(found, file)) {
+            continue;
+        }
+
+        match from_get(file.path()) {
+            None => edits.push(Edit::Insert(file.clone())),
+            Some(original) if file.kind() != original.kind() => edits.push(Edit::TypeChange {
+                original: original.clone(),
+                modified: file.clone(),
+            }),
+            Some(original) if file.content_hash() != original.content_hash() => {
+                edits.push(Edit::Modify {
+                    original: original.clone(),
+                    modified: file.clone(),
+                })
+            }
+            Some(original) if file.permissions() != original.permissions() => {
+                edits.push(Edit::ChmodOnly {
+                    original: original.clone(),
+                    modified: file.clone(),
+                })
             }
+            Some(_) => {}
         }
+    }
 
-        CommitDiff { edits }
+    for file in from {
+        if from_get(file.path()).is_some_and(|found| ptr::eq(found, file))
+            && to_get(file.path()).is_none()
+        {
+            edits.push(Edit::Delete(file.clone()));
+        }
+    }
+
+    edits.sort_by(|a, b| match (a, b) {
+        (Edit::Delete(a), Edit::Delete(b)) => b.path().cmp(a.path()),
+        (Edit::Delete(_), _) => Ordering::Less,
+        (_, Edit::Delete(_)) => Ordering::Greater,
+        (a, b) => a.path().cmp(b.path()),
+    });
+
+    CommitDiff { edits }
+}
+
+/// The files of a commit, ordered by their hashes, with their paths and
+/// hashes indexed so looking one up takes constant time
+#[derive(Clone)]
+struct FileSet {
+    files: Vec<FileData>,
+    paths: HashMap<PathBuf, usize>,
+    ids: HashSet<ObjectId>,
+}
+
+impl FileSet {
+    /// A set of new files. Of files given more than once at a path, only
+    /// the last is kept.
+    fn new(files: Vec<FileData>) -> FileSet {
+        let mut last = HashMap::new();
+        for (index, file) in files.iter().enumerate() {
+            last.insert(file.path().to_path_buf(), index);
+        }
+        let files = files
+            .into_iter()
+            .enumerate()
+            .filter(|(index, file)| last[file.path()] == *index)
+            .map(|(_, file)| file)
+            .collect();
+
+        FileSet::stored(files)
+    }
+
+    /// The files of a stored commit, kept as they are. A path given more
+    /// than once looks up the last of its files once they are ordered.
+    fn stored(mut files: Vec<FileData>) -> FileSet {
+        files.sort();
+        let paths = files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| (file.path().to_path_buf(), index))
+            .collect();
+        let ids = files.iter().map(|file| file.object_id()).collect();
+
+        FileSet { files, paths, ids }
+    }
+
+    fn as_slice(&self) -> &[FileData] {
+        &self.files
+    }
+
+    fn get(&self, path: &Path) -> Option<&FileData> {
+        self.paths.get(path).map(|&index| &self.files[index])
+    }
+
+    fn contains(&self, file: &FileData) -> bool {
+        self.ids.contains(&file.object_id())
+    }
+
+    fn len(&self) -> usize {
+        self.files.len()
+    }
+}
+
+// only the files themselves, in order, so a commit reads and writes as the
+// list it always was
+impl Serialize for FileSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.files.serialize(serializer)
+    }
+}
+
+impl PartialEq for FileSet {
+    fn eq(&self, other: &FileSet) -> bool {
+        self.files == other.files
+    }
+}
+
+impl Eq for FileSet {}
+
+impl fmt::Debug for FileSet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(&self.files).finish()
     }
 }
 
@@ -202,7 +710,184 @@ pub struct CommitDiff {
     pub edits: Vec<Edit>,
 }
 
+/// The changes to the lines of one file
 #[derive(Debug)]
+pub struct LineDiff {
+    pub path: PathBuf,
+    /// As given by `Edit::status`
+    pub status: char,
+    pub original: Vec<String>,
+    pub modified: Vec<String>,
+    pub diff: Diff,
+}
+
+/// A changed path and its status, without the changes themselves
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NameStatus {
+    /// As given by `Edit::status`, or `R` for a rename
+    pub status: char,
+    pub path: PathBuf,
+    /// For a rename, the path the file had before
+    pub from: Option<PathBuf>,
+}
+
+impl CommitDiff {
+    /// The changed paths, ordered by path, found without reading any
+    /// content. A file deleted while another is added with the same content
+    /// is listed once, as renamed to the added path.
+    pub fn name_status(&self) -> Vec<NameStatus> {
+        let mut deleted: Vec<&FileData> = self
+            .edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Delete(file) => Some(file),
+                _ => None,
+            })
+            .collect();
+        deleted.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let mut inserted: Vec<&FileData> = self
+            .edits
+            .iter()
+            .filter_map(|edit| match edit {
+                Edit::Insert(file) => Some(file),
+                _ => None,
+            })
+            .collect();
+        inserted.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let mut names = Vec::new();
+        for file in inserted {
+            let from = deleted
+                .iter()
+                .position(|deleted| deleted.content_hash() == file.content_hash())
+                .map(|i| deleted.remove(i).path().to_path_buf());
+            names.push(NameStatus {
+                status: if from.is_some() { 'R' } else { 'A' },
+                path: file.path().to_path_buf(),
+                from,
+            });
+        }
+
+        for file in deleted {
+            names.push(NameStatus {
+                status: 'D',
+                path: file.path().to_path_buf(),
+                from: None,
+            });
+        }
+
+        for edit in &self.edits {
+            if let Edit::Modify { .. } | Edit::TypeChange { .. } | Edit::ChmodOnly { .. } = edit {
+                names.push(NameStatus {
+                    status: edit.status(),
+                    path: edit.path().to_path_buf(),
+                    from: None,
+                });
+            }
+        }
+
+        names.sort_by(|a, b| a.path.cmp(&b.path));
+        names
+    }
+
+    /// A hash of the changes themselves, the same for any two diffs making
+    /// the same changes to the same paths, like `git patch-id`. Line
+    /// numbers and whitespace within lines are left out, so a change
+    /// applied on top of other changes to a file still matches.
+    pub fn patch_id(&self, ink_root: &Path) -> Result<[u8; 32], InkError> {
+        let mut hasher = Sha256::new();
+        let mut update = |part: &[u8]| {
+            hasher.update((part.len() as u64).to_be_bytes());
+            hasher.update(part);
+        };
+
+        for line_diff in self.compute_line_diffs(ink_root, None, false)? {
+            let line_diff = line_diff?;
+            update(line_diff.path.to_string_lossy().as_bytes());
+            for edit in line_diff.diff.edits() {
+                for (sign, lines) in &[("-", &edit.original.content), ("+", &edit.modified.content)]
+                {
+                    for line in lines.iter() {
+                        let line: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+                        update(format!("{}{}", sign, line).as_bytes());
+                    }
+                }
+            }
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Diff the lines of every changed file, yielding them ordered by path
+    /// as they are ready. Given a `worktree`, modified files are read from
+    /// that working directory, as for the diff given by `status`. With `par`,
+    /// files are diffed across as many threads as there are cores.
+    pub fn compute_line_diffs(
+        &self,
+        ink_root: &Path,
+        worktree: Option<&Path>,
+        par: bool,
+    ) -> Result<impl Iterator<Item = Result<LineDiff, InkError>> + Send, InkError> {
+        self.compute_line_diffs_with(ink_root, worktree, par, Diff::from)
+    }
+
+    /// Like `compute_line_diffs`, creating each diff with the given function
+    pub fn compute_line_diffs_with<F>(
+        &self,
+        ink_root: &Path,
+        worktree: Option<&Path>,
+        par: bool,
+        diff: F,
+    ) -> Result<impl Iterator<Item = Result<LineDiff, InkError>> + Send, InkError>
+    where
+        F: Fn(&[String], &[String]) -> Diff + Send + Sync + 'static,
+    {
+        let jobs = if par {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            1
+        };
+
+        let mut edits = self.edits.clone();
+        edits.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let policy = StoragePolicy::load(ink_root)?;
+        let ink_root = ink_root.to_path_buf();
+        let worktree = worktree.map(Path::to_path_buf);
+        Ok(utils::map_streamed(edits, jobs, move |edit| {
+            let (original, modified) = match &edit {
+                Edit::Insert(file) => (None, Some(file)),
+                Edit::Delete(file) => (Some(file), None),
+                Edit::Modify { original, modified }
+                | Edit::TypeChange { original, modified }
+                | Edit::ChmodOnly { original, modified } => (Some(original), Some(modified)),
+            };
+
+            let original = match original {
+                Some(file) => utils::to_lines(&file.read_content_with(&ink_root, &policy)?),
+                None => vec![],
+            };
+            let modified = match (modified, &worktree) {
+                (Some(file), Some(project_dir)) => {
+                    utils::to_lines(&filedata::read_worktree(&project_dir.join(file.path()))?)
+                }
+                (Some(file), None) => utils::to_lines(&file.read_content_with(&ink_root, &policy)?),
+                (None, _) => vec![],
+            };
+
+            Ok(LineDiff {
+                path: edit.path().to_path_buf(),
+                status: edit.status(),
+                diff: diff(&original, &modified),
+                original,
+                modified,
+            })
+        }))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Edit {
     Insert(FileData),
     Delete(FileData),
@@ -210,6 +895,39 @@ pub enum Edit {
         original: FileData,
         modified: FileData,
     },
+    /// A file that became a symbolic link, or a link that became a file
+    TypeChange {
+        original: FileData,
+        modified: FileData,
+    },
+    /// A file whose permissions changed but whose content didn't
+    ChmodOnly {
+        original: FileData,
+        modified: FileData,
+    },
+}
+
+impl Edit {
+    /// Single letter status of the edit, as shown by `ink status`
+    pub fn status(&self) -> char {
+        match self {
+            Edit::Insert(_) => 'A',
+            Edit::Delete(_) => 'D',
+            Edit::Modify { .. } => 'M',
+            Edit::TypeChange { .. } => 'T',
+            Edit::ChmodOnly { .. } => 'P',
+        }
+    }
+
+    /// The path of the file the edit changes
+    pub fn path(&self) -> &Path {
+        match self {
+            Edit::Insert(f) | Edit::Delete(f) => f.path(),
+            Edit::Modify { modified, .. }
+            | Edit::TypeChange { modified, .. }
+            | Edit::ChmodOnly { modified, .. } => modified.path(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -218,6 +936,7 @@ mod tests {
     use crate::filedata::tests::get_filedata;
     use std::convert::TryInto;
     use std::fmt::Debug;
+    use std::fs::{self, File};
     use std::io::Write;
     use std::path::PathBuf;
     use std::time::Duration;
@@ -231,7 +950,7 @@ mod tests {
 
     // timestamp: seconds after unix epoch
     fn env_setup(timestamp: u64) -> CommitInfo {
-        let tmpdir = tempfile::tempdir_in("./test_tmp_files").unwrap();
+        let tmpdir = crate::testkit::temp_dir();
         let tmpdir_path = tmpdir.path();
 
         crate::init(tmpdir_path).unwrap();
@@ -263,18 +982,30 @@ mod tests {
     fn new_commit() {
         let info = env_setup(1379995200);
 
-        let commit = Commit::new(info.paths, info.time, &info.tmpdir.path().join(".ink"));
+        let commit = Commit::new(
+            info.paths,
+            vec![],
+            info.time,
+            "a message",
+            &info.tmpdir.path().join(".ink"),
+        );
         let commit = commit.unwrap();
         assert_eq!(
             commit,
             Commit {
                 hash: hex::decode(
-                    "b27b7b5bdd38f0d8c35734bd54f941e41674e1f516c9e0ec5092800565686626"
+                    "8a9d6780034e895ec4676cd65fba1f1c6e4813525142ad221a7b74c54e1456b1"
+                )
+                .unwrap()
+                .try_into()
+                .unwrap(),
+                tree_hash: hex::decode(
+                    "1857e45a18f42371baa66e8ddccf8b457324346090ac3e2a31bd609ca9bf03ce"
                 )
                 .unwrap()
                 .try_into()
                 .unwrap(),
-                files: vec![
+                files: FileSet::stored(vec![
                     get_filedata(
                         "778e3e48cbd97193fce773a4be3a1adf528c38340ed90d71993135db104c06dd",
                         "example2",
@@ -287,30 +1018,74 @@ mod tests {
                         33188,
                         "ca7f87917e4f5029f81ec74d6711f1c587dca0fe91ec82b87bb77aeb15e6566d"
                     )
-                ],
-                time: 1379995200
+                ]),
+                parents: vec![],
+                time: 1379995200,
+                offset: 0,
+                message: "a message".to_string(),
+                metadata: BTreeMap::new(),
+                format: Format::Versioned,
             }
         );
     }
 
+    #[test]
+    fn tree_hash_ignores_time() {
+        let info = env_setup(1379995200);
+        let ink_dir = info.tmpdir.path().join(".ink");
+
+        let commit =
+            Commit::new(info.paths.clone(), vec![], info.time, "a message", &ink_dir).unwrap();
+        let later = Commit::new(
+            info.paths,
+            vec![],
+            info.time + Duration::from_secs(10),
+            "a message",
+            &ink_dir,
+        )
+        .unwrap();
+
+        assert_eq!(commit.tree_hash(), later.tree_hash());
+        assert_ne!(commit.hash(), later.hash());
+    }
+
+    #[test]
+    fn metadata_changes_hash() {
+        let info = env_setup(1379995200);
+        let ink_dir = info.tmpdir.path().join(".ink");
+
+        let commit = Commit::new(info.paths, vec![], info.time, "a message", &ink_dir).unwrap();
+        let plain_hash = commit.hash();
+
+        let mut metadata = BTreeMap::new();
+        metadata.insert("ci-run".to_string(), "42".to_string());
+        let commit = commit.with_metadata(metadata);
+        assert_ne!(commit.hash(), plain_hash);
+
+        commit.write(&ink_dir).unwrap();
+        let read_commit = Commit::from(&commit.hash(), &ink_dir).unwrap();
+        assert_eq!(read_commit.metadata().get("ci-run").unwrap(), "42");
+    }
+
     #[test]
     fn write_commit() {
         let info = env_setup(1379995200);
         let ink_dir = info.tmpdir.path().join(".ink");
 
-        let commit = Commit::new(info.paths, info.time, &ink_dir).unwrap();
+        let commit = Commit::new(info.paths, vec![], info.time, "a message", &ink_dir).unwrap();
         commit.write(&ink_dir).unwrap();
 
         let commit_path = ink_dir
             .join("commit")
-            .join("b27b7b5bdd38f0d8c35734bd54f941e41674e1f516c9e0ec5092800565686626");
+            .join("8a9d6780034e895ec4676cd65fba1f1c6e4813525142ad221a7b74c54e1456b1");
 
         assert!(commit_path.exists());
 
-        let commit_repr: CommitRepr =
-            bincode::deserialize_from(File::open(commit_path).unwrap()).unwrap();
+        let bytes = fs::read(&commit_path).unwrap();
+        assert!(bytes.starts_with(COMMIT_MAGIC));
+        let commit_repr: CommitRepr = bincode::deserialize(&bytes[8..]).unwrap();
 
-        assert_eq!(commit, commit_repr.to_commit());
+        assert_eq!(commit, commit_repr.into_commit(Format::Versioned));
     }
 
     #[test]
@@ -318,10 +1093,10 @@ mod tests {
         let info = env_setup(1379995200);
         let ink_dir = info.tmpdir.path().join(".ink");
 
-        let commit = Commit::new(info.paths, info.time, &ink_dir).unwrap();
+        let commit = Commit::new(info.paths, vec![], info.time, "a message", &ink_dir).unwrap();
         commit.write(&ink_dir).unwrap();
         let read_commit = Commit::from(
-            &hex::decode("b27b7b5bdd38f0d8c35734bd54f941e41674e1f516c9e0ec5092800565686626")
+            &hex::decode("8a9d6780034e895ec4676cd65fba1f1c6e4813525142ad221a7b74c54e1456b1")
                 .unwrap()
                 .try_into()
                 .unwrap(),
@@ -337,9 +1112,9 @@ mod tests {
         let info = env_setup(1379995200);
         let ink_dir = info.tmpdir.path().join(".ink");
 
-        let _commit = Commit::new(info.paths, info.time, &ink_dir).unwrap();
+        let _commit = Commit::new(info.paths, vec![], info.time, "a message", &ink_dir).unwrap();
         let read_commit = Commit::from(
-            &hex::decode("a27b7b5bdd38f0d8c35734bd54f941e41674e1f516c9e0ec5092800565686626")
+            &hex::decode("a7451f3716f6a190ad5444b5bdbc724a5d5a1462322e85dd290cd08a64f945fd")
                 .unwrap()
                 .try_into()
                 .unwrap(),
@@ -357,15 +1132,15 @@ mod tests {
         let info = env_setup(1379995200);
         let ink_dir = info.tmpdir.path().join(".ink");
 
-        let commit = Commit::new(info.paths, info.time, &ink_dir);
+        let commit = Commit::new(info.paths, vec![], info.time, "a message", &ink_dir);
         let mut commit = commit.unwrap();
 
         let commit_file_path = ink_dir.join(COMMIT_EXT).join(hex::encode(commit.hash));
         commit.time = 1379995210;
-        fs::write(commit_file_path, bincode::serialize(&commit).unwrap()).unwrap();
+        fs::write(commit_file_path, commit.encode().unwrap()).unwrap();
 
         let read_commit = Commit::from(
-            &hex::decode("b27b7b5bdd38f0d8c35734bd54f941e41674e1f516c9e0ec5092800565686626")
+            &hex::decode("8a9d6780034e895ec4676cd65fba1f1c6e4813525142ad221a7b74c54e1456b1")
                 .unwrap()
                 .try_into()
                 .unwrap(),
@@ -380,4 +1155,213 @@ mod tests {
             _ => panic!("wrong kind of error"),
         };
     }
+
+    #[test]
+    fn edits_in_apply_order() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a/x", "x")
+            .file("a/y", "y")
+            .file("b", "b")
+            .file("d", "d")
+            .commit("first");
+        let first = repo.head();
+        let repo = repo.remove("a/x").remove("a/y");
+        fs::remove_dir(repo.path().join("a")).unwrap();
+        let repo = repo
+            .file("a", "now a file")
+            .file("b", "changed")
+            .remove("d")
+            .file("c", "c")
+            .commit("second");
+        let second = repo.head();
+
+        for _ in 0..10 {
+            let edits: Vec<(char, PathBuf)> = first
+                .diff(&second)
+                .edits
+                .iter()
+                .map(|edit| (edit.status(), edit.path().to_path_buf()))
+                .collect();
+            assert_eq!(
+                edits,
+                [
+                    ('D', PathBuf::from("d")),
+                    ('D', PathBuf::from("a/y")),
+                    ('D', PathBuf::from("a/x")),
+                    ('A', PathBuf::from("a")),
+                    ('M', PathBuf::from("b")),
+                    ('A', PathBuf::from("c")),
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn line_diffs_in_path_order() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = crate::Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir_path.join("b"), b"one\ntwo\n").unwrap();
+        fs::write(tmpdir_path.join("c"), b"gone\n").unwrap();
+        repo.commit("first", Default::default()).unwrap();
+        fs::write(tmpdir_path.join("b"), b"one\n2\n").unwrap();
+        fs::remove_file(tmpdir_path.join("c")).unwrap();
+        fs::write(tmpdir_path.join("a"), b"new\n").unwrap();
+
+        let status = repo.status().unwrap();
+        let diffs = status
+            .compute_line_diffs(&ink_dir, Some(repo.work_tree()), true)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let summary: Vec<(char, &Path, usize)> = diffs
+            .iter()
+            .map(|d| (d.status, d.path.as_path(), d.diff.edits().len()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ('A', Path::new("a"), 1),
+                ('M', Path::new("b"), 1),
+                ('D', Path::new("c"), 1)
+            ]
+        );
+        assert_eq!(diffs[1].modified, ["one", "2"]);
+
+        let serial = status
+            .compute_line_diffs(&ink_dir, Some(repo.work_tree()), false)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        for (a, b) in diffs.iter().zip(&serial) {
+            assert_eq!(a.diff.edits(), b.diff.edits());
+        }
+    }
+
+    #[test]
+    fn name_status_finds_renames() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "moved")
+            .file("b", "changed")
+            .file("c", "deleted")
+            .commit("first");
+        let first = repo.head();
+        let repo = repo
+            .remove("a")
+            .file("z", "moved")
+            .file("b", "changed again")
+            .remove("c")
+            .file("d", "added")
+            .commit("second");
+
+        let names: Vec<_> = first
+            .diff(&repo.head())
+            .name_status()
+            .into_iter()
+            .map(|name| (name.status, name.path, name.from))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ('M', PathBuf::from("b"), None),
+                ('D', PathBuf::from("c"), None),
+                ('A', PathBuf::from("d"), None),
+                ('R', PathBuf::from("z"), Some(PathBuf::from("a"))),
+            ]
+        );
+    }
+
+    #[test]
+    fn files_are_looked_up_by_path_and_id() {
+        let file = |path: &str, content: u8| {
+            FileData::from_content(Path::new(path), 0o100644, [content; 32])
+        };
+        let commit = Commit::from_files(
+            vec![file("a", 1), file("b", 2), file("a", 3)],
+            vec![],
+            SystemTime::UNIX_EPOCH,
+            "",
+        )
+        .unwrap();
+
+        // the last file given at a path is kept
+        assert_eq!(commit.files().len(), 2);
+        assert_eq!(commit.file(Path::new("a")), Some(&file("a", 3)));
+        assert!(commit.contains(&file("b", 2)));
+        assert!(!commit.contains(&file("a", 1)));
+        assert!(!commit.contains(&file("c", 2)));
+        assert_eq!(commit.file(Path::new("c")), None);
+
+        let other = Commit::from_files(
+            vec![file("a", 3), file("c", 4)],
+            vec![],
+            SystemTime::UNIX_EPOCH,
+            "",
+        )
+        .unwrap();
+        let statuses: Vec<char> = commit.diff(&other).edits.iter().map(Edit::status).collect();
+        assert_eq!(statuses, ['D', 'A']);
+        assert_eq!(
+            commit.diff(&other).edits.len(),
+            diff_files(commit.files(), other.files()).edits.len()
+        );
+    }
+
+    #[test]
+    fn permission_only_changes_are_told_apart() {
+        let file = |path: &str, permissions: u32, content: u8| {
+            FileData::from_content(Path::new(path), permissions, [content; 32])
+        };
+        let from = [file("a", 0o100644, 1), file("b", 0o100644, 2)];
+        let to = [file("a", 0o100755, 1), file("b", 0o100755, 3)];
+
+        let edits = diff_files(&from, &to).edits;
+        assert_eq!(edits.len(), 2);
+        assert!(matches!(&edits[0], Edit::ChmodOnly { modified, .. } if modified == &to[0]));
+        assert!(matches!(&edits[1], Edit::Modify { modified, .. } if modified == &to[1]));
+        assert!(diff_files(&from, &from).edits.is_empty());
+    }
+
+    #[test]
+    fn legacy_commits_are_read() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "a")
+            .commit("first");
+        let ink_dir = repo.ink_root();
+        let parent = repo.head();
+        let file = parent.files()[0].clone();
+
+        // written before commits had a format version: only files and time,
+        // named by a hash of the files' hashes and the time
+        let mut hasher = Sha256::new();
+        hasher.update(file.hash());
+        hasher.update(7u64.to_be_bytes());
+        let hash: [u8; 32] = hasher.finalize().into();
+        let bytes = bincode::serialize(&(vec![file], 7u64)).unwrap();
+        fs::write(ink_dir.join(COMMIT_EXT).join(hex::encode(hash)), &bytes).unwrap();
+
+        // its parents are only in the graph
+        let legacy = Commit::from(&hash, ink_dir).unwrap();
+        assert_eq!(legacy.parents(), &[] as &[[u8; 32]]);
+        let mut graph = CommitGraph::get(ink_dir).unwrap();
+        graph.add_commit(&parent, &legacy).unwrap();
+        graph.write().unwrap();
+
+        let legacy = Commit::from(&hash, ink_dir).unwrap();
+        assert_eq!(legacy.hash(), hash);
+        assert_eq!(legacy.parents(), &[parent.hash()]);
+        assert_eq!(legacy.time(), 7);
+        assert_eq!(legacy.message(), "");
+        assert_eq!(legacy.encode().unwrap(), bytes);
+
+        // an object that isn't a commit is malformed
+        fs::write(ink_dir.join(COMMIT_EXT).join(hex::encode(hash)), b"inkc").unwrap();
+        assert!(matches!(
+            Commit::from(&hash, ink_dir),
+            Err(InkError::Malformed { .. })
+        ));
+    }
 }
@@ -0,0 +1,149 @@
+//! Repository settings, read from `.ink/config`.
+//!
+//! The file is made of `key = value` lines grouped under `[section]` or
+//! `[section "label"]` headers, e.g.
+//!
+//! ```text
+//! [core]
+//! text = auto
+//!
+//! [path "*.bat"]
+//! text = crlf
+//! ```
+//!
+//! Blank lines and lines starting with `#` are ignored. A missing file is the
+//! same as an empty one.
+use crate::{InkError, CONFIG_FILE};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    sections: Vec<Section>,
+}
+
+/// One `[name "label"]` header and the values under it
+#[derive(Debug, PartialEq)]
+pub struct Section {
+    name: String,
+    label: Option<String>,
+    values: Vec<(String, String)>,
+}
+
+impl Section {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.label.as_deref()
+    }
+
+    /// The value of a key, the last one given if it was set more than once
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(k, _)| k == key)
+            .map(|(_, v)| v.as_str())
+    }
+}
+
+impl Config {
+    pub fn load(ink_root: &Path) -> Result<Config, InkError> {
+        match fs::read_to_string(ink_root.join(CONFIG_FILE)) {
+            Ok(text) => Config::parse(&text),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Config, InkError> {
+        let mut sections = Vec::new();
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(header) = line.strip_prefix('[') {
+                let header = header
+                    .strip_suffix(']')
+                    .ok_or("Config section header is missing a closing ]")?
+                    .trim();
+
+                let (name, label) = match header.split_once(' ') {
+                    Some((name, label)) => {
+                        let label = label
+                            .trim()
+                            .strip_prefix('"')
+                            .and_then(|l| l.strip_suffix('"'))
+                            .ok_or("Config section label must be quoted")?;
+                        (name, Some(label.to_string()))
+                    }
+                    None => (header, None),
+                };
+
+                sections.push(Section {
+                    name: name.to_string(),
+                    label,
+                    values: Vec::new(),
+                });
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or("Config lines must be a section header or key = value")?;
+            let section = sections
+                .last_mut()
+                .ok_or("Config values must be inside a section")?;
+            section
+                .values
+                .push((key.trim().to_string(), value.trim().to_string()));
+        }
+
+        Ok(Config { sections })
+    }
+
+    /// The value of a key in a section without a label
+    pub fn get(&self, section: &str, key: &str) -> Option<&str> {
+        self.sections
+            .iter()
+            .rev()
+            .filter(|s| s.name == section && s.label.is_none())
+            .find_map(|s| s.get(key))
+    }
+
+    /// Every section with the given name, in the order they appear
+    pub fn sections<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Section> + 'a {
+        self.sections.iter().filter(move |s| s.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_config() {
+        let config = Config::parse(
+            "# settings\n[core]\ntext = auto\n\n[path \"*.bat\"]\ntext=crlf\n[core]\ntext = lf\n",
+        )
+        .unwrap();
+
+        assert_eq!(config.get("core", "text"), Some("lf"));
+        assert_eq!(config.get("path", "text"), None);
+
+        let paths: Vec<_> = config
+            .sections("path")
+            .map(|s| (s.label().unwrap(), s.get("text").unwrap()))
+            .collect();
+        assert_eq!(paths, vec![("*.bat", "crlf")]);
+
+        assert!(Config::parse("text = auto").is_err());
+        assert!(Config::parse("[path *.bat]").is_err());
+    }
+}
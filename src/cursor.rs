@@ -1,4 +1,5 @@
 use crate::commit::Commit;
+use crate::storage::Durability;
 use crate::{InkError, CURSOR_FILE};
 use std::convert::TryInto;
 use std::fs::{self, File};
@@ -10,14 +11,18 @@ pub fn init(ink_root: &Path) -> Result<(), InkError> {
 }
 
 pub fn set(ink_root: &Path, commit: &Commit) -> Result<(), InkError> {
-    fs::write(ink_root.join(CURSOR_FILE), commit.hash())?;
+    Durability::load(ink_root)?.write(&ink_root.join(CURSOR_FILE), &commit.hash())?;
     Ok(())
 }
 
 pub fn get(ink_root: &Path) -> Result<Commit, InkError> {
-    let hash: [u8; 32] = match fs::read(ink_root.join(CURSOR_FILE))?.try_into() {
+    let path = ink_root.join(CURSOR_FILE);
+    let hash: [u8; 32] = match fs::read(&path)?.try_into() {
         Ok(hash) => Ok(hash),
-        Err(_) => Err(InkError::Err("Cursor hash is wrong length")),
+        Err(_) => Err(InkError::Malformed {
+            path,
+            problem: "the cursor must be the hash of a commit",
+        }),
     }?;
 
     Commit::from(&hash, ink_root)
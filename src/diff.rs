@@ -1,41 +1,179 @@
 //! Tools for creating diffs, done through the `Diff` struct
 mod algo;
 mod edit;
+mod merge;
 mod parser;
-
-use edit::{Edit, Operation};
-use std::error::Error;
+pub mod render;
+mod script;
+mod stream;
+
+pub use algo::myers::DEFAULT_MAX_COST;
+pub use algo::tokens::IntraLine;
+pub use algo::{Myers, Tokens};
+pub use edit::{Edit, EditChunkBuilder, EditError, HalfEdit, Operation};
+pub use merge::{merge3, MergeRegion, MergeResult};
+pub use parser::ParseError;
+pub use script::ScriptHeader;
 use std::fs::{self, File};
-use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+pub use stream::{EditStream, DEFAULT_BLOCK_LINES};
+
+use crate::{atomic, InkError};
+
+/// An algorithm for finding the edits between two sequences of lines.
+///
+/// Edits must be in order, not overlap, and use 0-indexed line numbers into
+/// `a` for `original` and `b` for `modified`, as the built-in engines do.
+pub trait DiffEngine {
+    fn diff(&self, a: &[&str], b: &[&str]) -> Vec<Edit>;
+}
 
 /// Struct that holds the diff of two files.
 ///
 /// Constructs and holds a sequence of `Edit`.
+#[derive(Debug)]
 pub struct Diff {
     edits: Vec<Edit>,
+    approximate: bool,
 }
 
 impl Diff {
     /// Create a diff from two files using the Myers' Diff Algorithm.
     pub fn from<S: AsRef<str>>(a: &[S], b: &[S]) -> Diff {
-        let edits = algo::myers::from(a, b);
+        Diff::with_max_cost(a, b, algo::myers::DEFAULT_MAX_COST)
+    }
+
+    /// Create a diff, limiting how much work is spent finding the shortest
+    /// one. Past the limit, the lines between the common start and end of
+    /// the files are replaced as a whole, and the diff is marked approximate.
+    pub fn with_max_cost<S: AsRef<str>>(a: &[S], b: &[S], max_cost: usize) -> Diff {
+        let (edits, approximate) = algo::myers::from_with_max_cost(a, b, max_cost);
 
-        Diff { edits }
+        Diff { edits, approximate }
     }
 
-    /// Deserialize an edit script to create a diff
-    pub fn from_edit_script<S: AsRef<str>>(edit_script: S) -> Result<Diff, Box<dyn Error>> {
-        let mut remainder = edit_script.as_ref();
-        let mut edits = Vec::new();
+    /// Create a diff from two files by comparing code tokens rather than
+    /// whole lines. Also returns the changed byte ranges within the lines of
+    /// each edit, in the same order as the edits.
+    pub fn by_tokens<S: AsRef<str>>(a: &[S], b: &[S]) -> (Diff, Vec<IntraLine>) {
+        let (edits, ranges) = algo::tokens::from(a, b);
+
+        (
+            Diff {
+                edits,
+                approximate: false,
+            },
+            ranges,
+        )
+    }
 
-        while !remainder.is_empty() {
-            let (r, e) = Edit::parse_edit_script(remainder)?;
-            edits.push(e);
-            remainder = r;
+    /// Diff two files read from readers a block of lines at a time, so they
+    /// never need to fit in memory. The edits are given in order as they are
+    /// found, and are the same as those of `from` unless lines moved further
+    /// than a block. See `EditStream`.
+    pub fn stream<A: BufRead, B: BufRead>(a: A, b: B) -> EditStream<A, B> {
+        EditStream::new(a, b)
+    }
+
+    /// Create a diff from two files with the given engine
+    pub fn with_engine<S: AsRef<str>>(engine: &dyn DiffEngine, a: &[S], b: &[S]) -> Diff {
+        let a: Vec<&str> = a.iter().map(AsRef::as_ref).collect();
+        let b: Vec<&str> = b.iter().map(AsRef::as_ref).collect();
+
+        Diff {
+            edits: engine.diff(&a, &b),
+            approximate: false,
         }
+    }
+
+    /// The edits making up the diff, in order
+    pub fn edits(&self) -> &[Edit] {
+        &self.edits
+    }
+
+    /// Whether the diff gave up on finding the smallest set of edits
+    pub fn is_approximate(&self) -> bool {
+        self.approximate
+    }
+
+    /// Deserialize an edit script in either version to create a diff
+    pub fn from_edit_script<S: AsRef<str>>(edit_script: S) -> Result<Diff, InkError> {
+        Ok(Diff::read_edit_script(edit_script)?.0)
+    }
 
-        Ok(Diff { edits })
+    /// Deserialize an edit script in either version, along with its header.
+    /// A version 1 script has an empty header.
+    pub fn read_edit_script<S: AsRef<str>>(
+        edit_script: S,
+    ) -> Result<(Diff, ScriptHeader), ParseError> {
+        Diff::parse_edit_script(edit_script.as_ref(), false)
+    }
+
+    /// Deserialize an edit script, also rejecting edits that can't be
+    /// applied: ones whose operation doesn't match the lines they change,
+    /// and ones out of order or overlapping. Meant for scripts written or
+    /// changed by hand, where the error says where the script went wrong.
+    pub fn from_edit_script_strict<S: AsRef<str>>(edit_script: S) -> Result<Diff, ParseError> {
+        Ok(Diff::parse_edit_script(edit_script.as_ref(), true)?.0)
+    }
+
+    fn parse_edit_script(
+        edit_script: &str,
+        strict: bool,
+    ) -> Result<(Diff, ScriptHeader), ParseError> {
+        let (edits, header) = if edit_script.starts_with(script::VERSION_LINE) {
+            script::read(edit_script, strict).map_err(|err| err.locate(edit_script))?
+        } else {
+            let mut remainder = edit_script;
+            let mut edits: Vec<Edit> = Vec::new();
+
+            while !remainder.is_empty() {
+                let (r, e) =
+                    Edit::parse_edit_script(remainder).map_err(|err| err.locate(edit_script))?;
+                if strict {
+                    e.check(edits.last(), remainder)
+                        .map_err(|err| err.locate(edit_script))?;
+                }
+                edits.push(e);
+                remainder = r;
+            }
+            (edits, ScriptHeader::default())
+        };
+
+        let diff = Diff {
+            edits,
+            approximate: false,
+        };
+        Ok((diff, header))
+    }
+
+    /// Read a unified diff of one file, as `diff -u` and `git diff` write
+    /// it, so patches from other tools can be applied
+    pub fn from_unified<S: AsRef<str>>(patch: S) -> Result<Diff, ParseError> {
+        let patch = patch.as_ref();
+        let edits = parser::read_unified(patch).map_err(|err| err.locate(patch))?;
+
+        Ok(Diff {
+            edits,
+            approximate: false,
+        })
+    }
+
+    /// Read an ed script, as `diff -e` writes it. Ed scripts don't hold the
+    /// lines they delete or change, so those are taken from `original`, the
+    /// lines of the file the script is for.
+    pub fn from_ed_script<S: AsRef<str>, L: AsRef<str>>(
+        script: S,
+        original: &[L],
+    ) -> Result<Diff, ParseError> {
+        let script = script.as_ref();
+        let edits = parser::read_ed(script, original).map_err(|err| err.locate(script))?;
+
+        Ok(Diff {
+            edits,
+            approximate: false,
+        })
     }
 
     /// Serialize an 'edit script' for the diff.
@@ -48,28 +186,64 @@ impl Diff {
             .join("\n")
     }
 
+    /// Serialize an edit script in version 2 of the format, which can hold
+    /// any content and starts with a header describing the file. See
+    /// `ScriptHeader`.
+    pub fn edit_script_v2(&self, header: &ScriptHeader) -> String {
+        script::write(&self.edits, header)
+    }
+
     /// Applies a series of edits to a file
-    /// Goes line by line through the file to add edits in a tmp file,
-    /// then overwriting the normal file with the tmp file.
-    fn apply_edits(edits: &[Edit], file_path: &Path) -> Result<(), Box<dyn Error>> {
+    /// Copies the file up to the first edit and after the last as it is, and
+    /// goes line by line between them to add edits in a tmp file, then
+    /// overwrites the normal file with the tmp file.
+    fn apply_edits(edits: &[Edit], file_path: &Path) -> Result<(), InkError> {
         // check if there are any edits
         if edits.is_empty() {
             return Ok(());
         }
 
-        // open up the original file and the temp file which we are writing to
-        let file = BufReader::new(File::open(file_path)?);
+        // an edit whose content doesn't fit its operation would be applied
+        // as some other change, so the file is left alone
+        for edit in edits {
+            edit.validate()?;
+        }
 
-        // TODO: use NamedTempFile here
-        let tmp_path = file_path.with_extension(".tmp");
-        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        // open up the original file and the temp file which we are writing
+        // to, which is deleted if anything goes wrong before it's renamed
+        let mut file = BufReader::new(File::open(file_path)?);
+        let tmp = atomic::temp_file_beside(file_path)?;
+        fs::set_permissions(tmp.path(), file.get_ref().metadata()?.permissions())?;
+        let mut tmp = BufWriter::new(tmp);
+
+        // the lines before the first edit are copied as they are, which the
+        // system can do without reading them in when it supports it
+        let (offset, mut line_number) = line_offset(&mut file, edits[0].original.line)?;
+        file.seek(SeekFrom::Start(offset))?;
+        io::copy(&mut File::open(file_path)?.take(offset), &mut tmp)?;
 
         let mut edit_index = 0;
         let mut skipped_lines_left = 0;
+        let mut line = String::new();
+
+        loop {
+            // once every edit is applied, the rest of the file is unchanged
+            // and copied as it is
+            if edit_index == edits.len() && skipped_lines_left == 0 {
+                io::copy(&mut file, &mut tmp)?;
+                break;
+            }
 
-        for (line_number, line) in file.lines().enumerate() {
-            let line = line?;
-            let edit = &edits[edit_index];
+            line.clear();
+            if file.read_line(&mut line)? == 0 {
+                break;
+            }
+            // lines are written back with a newline, as `BufRead::lines`
+            // would read them
+            let content = line.strip_suffix('\n').unwrap_or(&line);
+            let content = content.strip_suffix('\r').unwrap_or(content);
+            let current = line_number;
+            line_number += 1;
 
             // if previous edits had us delete this line, don't write it
             // and move to the next line
@@ -78,30 +252,37 @@ impl Diff {
                 continue;
             }
 
+            // lines are inserted before the line they are at, which may
+            // then have another edit of its own
+            while let Some(edit) = edits.get(edit_index) {
+                if edit.original.line != current || edit.op != Operation::Insert {
+                    break;
+                }
+                tmp.write_all((edit.modified.content.join("\n") + "\n").as_bytes())?;
+                edit_index += 1;
+            }
+
             // check if there is an edit operating on this line.
-            if edit.original.line == line_number {
-                match edit.op {
-                    Operation::Insert => {
-                        // nothing to delete, only add the original line + inserted lines
-                        tmp.write_all((line + "\n").as_bytes())?;
-                        tmp.write_all((edit.modified.content.join("\n") + "\n").as_bytes())?;
-                    }
-                    Operation::Delete => {
-                        // skip adding both this line and future lines.
-                        // Subtract one because we are also not writing this line.
-                        skipped_lines_left = edit.original.content.len() - 1;
-                    }
-                    Operation::Replace => {
-                        // skip adding both this line and future lines, instead add inserted lines.
-                        // Subtract one because we are also not writing this line.
-                        skipped_lines_left = edit.original.content.len() - 1;
-                        tmp.write_all((edit.modified.content.join("\n") + "\n").as_bytes())?;
+            match edits.get(edit_index) {
+                Some(edit) if edit.original.line == current => {
+                    match edit.op {
+                        Operation::Insert => unreachable!("inserts were written above"),
+                        Operation::Delete => {
+                            // skip adding both this line and future lines.
+                            // Subtract one because we are also not writing this line.
+                            skipped_lines_left = edit.original.content.len() - 1;
+                        }
+                        Operation::Replace => {
+                            // skip adding both this line and future lines, instead add inserted lines.
+                            // Subtract one because we are also not writing this line.
+                            skipped_lines_left = edit.original.content.len() - 1;
+                            tmp.write_all((edit.modified.content.join("\n") + "\n").as_bytes())?;
+                        }
                     }
+                    edit_index += 1;
                 }
-                edit_index += 1;
-            } else {
                 // write line to file
-                tmp.write_all((line + "\n").as_bytes())?;
+                _ => tmp.write_all((content.to_string() + "\n").as_bytes())?,
             }
         }
 
@@ -114,50 +295,243 @@ impl Diff {
             return Err("Wrong edit type left over".into());
         }
 
-        // drop the writer to the tmp file
-        std::mem::drop(tmp);
-
         // overwrite the main file with the tmp file
-        fs::rename(tmp_path, file_path)?;
+        let tmp = tmp.into_inner().map_err(|err| err.into_error())?;
+        atomic::persist(tmp.into_temp_path(), file_path)?;
 
         Ok(())
     }
 
     /// Apply a diff to a file
-    pub fn apply(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
+    pub fn apply(&self, file_path: &Path) -> Result<(), InkError> {
         Diff::apply_edits(&self.edits, file_path)
     }
 
     /// Rollback a diff on a file by applying the reverse diff
-    pub fn rollback(&self, file_path: &Path) -> Result<(), Box<dyn Error>> {
-        let mut rollback_edits = Vec::with_capacity(self.edits.len());
-
-        // reverse the op and content for each edit.
-        for edit in &self.edits {
-            let new_op = match &edit.op {
-                Operation::Insert => Operation::Delete,
-                Operation::Delete => Operation::Insert,
-                Operation::Replace => Operation::Replace,
+    pub fn rollback(&self, file_path: &Path) -> Result<(), InkError> {
+        Diff::apply_edits(&self.invert().edits, file_path)
+    }
+
+    /// The diff that undoes this one, taking the modified file back to the
+    /// original
+    pub fn invert(&self) -> Diff {
+        Diff {
+            edits: self.edits.iter().map(Edit::invert).collect(),
+            approximate: self.approximate,
+        }
+    }
+
+    /// Whether `later`, a diff of the file this one makes, could have been
+    /// made first, so the two can be reordered, as rebasing or cherry-picking
+    /// would, without a conflict. They can if no edit of `later` changes or
+    /// is next to lines this diff changed, since context on either side of
+    /// an edit is what places it.
+    pub fn commutes_with(&self, later: &Diff) -> bool {
+        // both are compared in the file between them, where this diff's
+        // edits end up and `later`'s start from
+        let mut made = self.edits.iter().map(|edit| &edit.modified).peekable();
+        for changed in later.edits.iter().map(|edit| &edit.original) {
+            let end = changed.line + changed.content.len();
+            while made
+                .peek()
+                .is_some_and(|half| half.line + half.content.len() < changed.line)
+            {
+                made.next();
+            }
+            if made.peek().is_some_and(|half| half.line <= end) {
+                return false;
+            }
+        }
+
+        true
+    }
+
+    /// Combine this diff with `later`, a diff of the file this one makes,
+    /// into one diff from this one's original to `later`'s modified file,
+    /// which applies in a single pass. Edits that undo each other cancel out.
+    /// Fails if `later` changes lines this diff didn't leave there.
+    pub fn compose(&self, later: &Diff) -> Result<Diff, InkError> {
+        let (first, second) = (&self.edits, &later.edits);
+        let mut edits = Vec::new();
+        let (mut i, mut j) = (0, 0);
+        // how many lines each diff has added, less those it deleted, before
+        // its next edit
+        let (mut first_shift, mut second_shift) = (0isize, 0isize);
+
+        // edits of both are gathered into runs that overlap or touch in the
+        // file between them, each becoming one edit
+        loop {
+            let lo = match (first.get(i), second.get(j)) {
+                (Some(a), Some(b)) => a.modified.line.min(b.original.line),
+                (Some(a), None) => a.modified.line,
+                (None, Some(b)) => b.original.line,
+                (None, None) => break,
             };
+            let (first_start, second_start) = (i, j);
+            let mut hi = lo;
+            loop {
+                if let Some(edit) = first.get(i).filter(|edit| edit.modified.line <= hi) {
+                    hi = hi.max(edit.modified.line + edit.modified.content.len());
+                    i += 1;
+                } else if let Some(edit) = second.get(j).filter(|edit| edit.original.line <= hi) {
+                    hi = hi.max(edit.original.line + edit.original.content.len());
+                    j += 1;
+                } else {
+                    break;
+                }
+            }
+            let (first_run, second_run) = (&first[first_start..i], &second[second_start..j]);
+
+            // every line of the run in the middle file is made by this diff
+            // or changed by `later`, and both must agree on it
+            let mut middle: Vec<Option<&str>> = vec![None; hi - lo];
+            for edit in first_run {
+                for (k, line) in edit.modified.content.iter().enumerate() {
+                    middle[edit.modified.line - lo + k] = Some(line);
+                }
+            }
+            for edit in second_run {
+                for (k, line) in edit.original.content.iter().enumerate() {
+                    let known = &mut middle[edit.original.line - lo + k];
+                    if known.is_some_and(|known| known != line) {
+                        return Err("Later diff changes lines the first diff didn't leave".into());
+                    }
+                    *known = Some(line);
+                }
+            }
 
-            rollback_edits.push(Edit {
-                op: new_op,
-                original: edit.modified.clone(),
-                modified: edit.original.clone(),
-            });
+            let original = unfold(
+                lo,
+                &middle,
+                first_run
+                    .iter()
+                    .map(|edit| (&edit.modified, &edit.original)),
+            );
+            let modified = unfold(
+                lo,
+                &middle,
+                second_run
+                    .iter()
+                    .map(|edit| (&edit.original, &edit.modified)),
+            );
+            let original_line = (lo as isize - first_shift) as usize;
+            let modified_line = (lo as isize + second_shift) as usize;
+            first_shift += first_run.iter().map(Edit::growth).sum::<isize>();
+            second_shift += second_run.iter().map(Edit::growth).sum::<isize>();
+
+            if original == modified {
+                continue;
+            }
+            edits.extend(Edit::between(
+                original_line,
+                modified_line,
+                original,
+                modified,
+            ));
         }
 
-        Diff::apply_edits(&rollback_edits, file_path)?;
+        Ok(Diff {
+            edits,
+            approximate: self.approximate || later.approximate,
+        })
+    }
+}
 
-        Ok(())
+/// Find where line `line` starts in `reader`, counting from 0. Lines aren't
+/// decoded, only counted. If the reader has fewer whole lines, this is the
+/// start of the last, which may have no newline. Returns the byte offset and
+/// the line there.
+fn line_offset<R: BufRead>(reader: &mut R, line: usize) -> io::Result<(u64, usize)> {
+    let (mut read, mut offset, mut lines) = (0, 0, 0);
+    while lines < line {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            break;
+        }
+
+        let newlines = buf.iter().enumerate().filter(|(_, byte)| **byte == b'\n');
+        for (i, _) in newlines {
+            lines += 1;
+            offset = read + i as u64 + 1;
+            if lines == line {
+                break;
+            }
+        }
+        let used = buf.len();
+        read += used as u64;
+        reader.consume(used);
+    }
+
+    Ok((offset, lines))
+}
+
+/// One side of a run of composed edits, starting at line `lo` of the middle
+/// file: the lines of `middle` that edits leave alone, and the content edits
+/// put in place of the lines they cover in it
+fn unfold<'a>(
+    lo: usize,
+    middle: &[Option<&str>],
+    edits: impl Iterator<Item = (&'a HalfEdit, &'a HalfEdit)>,
+) -> Vec<String> {
+    let kept = |from: usize, to: usize| {
+        middle[from - lo..to - lo].iter().map(|line| {
+            line.expect("lines left alone by one diff are in the other")
+                .to_string()
+        })
+    };
+
+    let mut lines = Vec::new();
+    let mut at = lo;
+    for (covered, content) in edits {
+        lines.extend(kept(at, covered.line));
+        lines.extend(content.content.iter().cloned());
+        at = covered.line + covered.content.len();
     }
+    lines.extend(kept(at, lo + middle.len()));
+    lines
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::diff::edit::HalfEdit;
-    use tempfile::NamedTempFile;
+
+    /// Replaces the whole file whenever anything differs
+    struct Rewrite;
+
+    impl DiffEngine for Rewrite {
+        fn diff(&self, a: &[&str], b: &[&str]) -> Vec<Edit> {
+            if a == b {
+                return vec![];
+            }
+
+            let to_strings = |lines: &[&str]| lines.iter().map(|l| l.to_string()).collect();
+            vec![Edit::new(Operation::Replace, 0, 0, to_strings(a), to_strings(b)).unwrap()]
+        }
+    }
+
+    #[test]
+    fn custom_engine() {
+        let a = ["one", "two", "three"];
+        let b = ["one", "2", "three"];
+
+        let myers = Diff::with_engine(&Myers, &a, &b);
+        assert_eq!(myers.edits(), Diff::from(&a, &b).edits());
+
+        let diff = Diff::with_engine(&Rewrite, &a, &b);
+        assert_eq!(
+            diff.edit_script(),
+            "0,2r0,2\n< one\n< two\n< three\n---\n> one\n> 2\n> three"
+        );
+
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "{}", a.join("\n")).unwrap();
+        let f_path = f.into_temp_path();
+
+        diff.apply(&f_path).unwrap();
+        assert_eq!(fs::read_to_string(&f_path).unwrap(), "one\n2\nthree\n");
+        diff.rollback(&f_path).unwrap();
+        assert_eq!(fs::read_to_string(&f_path).unwrap(), "one\ntwo\nthree\n");
+    }
 
     #[test]
     fn test_diff_apply() {
@@ -186,7 +560,7 @@ mod tests {
 
         let diff = Diff::from(&A, &B);
 
-        let mut f = NamedTempFile::new_in("./test_tmp_files").unwrap();
+        let mut f = tempfile::NamedTempFile::new().unwrap();
         write!(f, "{}", A.join("\n")).unwrap();
 
         let f_path = f.into_temp_path();
@@ -231,7 +605,7 @@ mod tests {
 
         let diff = Diff::from(&A, &B);
 
-        let mut f = NamedTempFile::new_in("./test_tmp_files").unwrap();
+        let mut f = tempfile::NamedTempFile::new().unwrap();
         write!(f, "{}", B.join("\n")).unwrap();
 
         let f_path = f.into_temp_path();
@@ -249,6 +623,127 @@ mod tests {
         assert_eq!(file_len, A.len())
     }
 
+    #[test]
+    fn commuting_diffs() {
+        let a = ["one", "two", "three", "four", "five"];
+        let b = ["one", "2", "three", "four", "five"];
+        let diff = Diff::from(&a, &b);
+        assert_eq!(diff.invert().invert().edits(), diff.edits());
+        assert_eq!(
+            Diff::from(&b, &a).edits(),
+            diff.invert().edits(),
+            "inverting is diffing the other way"
+        );
+
+        // far enough away from the changed line to be reordered
+        let far = Diff::from(&b, &["one", "2", "three", "four", "5"]);
+        assert!(diff.commutes_with(&far));
+        assert!(diff.commutes_with(&Diff::from(&b, &b)));
+
+        // next to it, or changing it again
+        let next_to = Diff::from(&b, &["one", "2", "3", "four", "five"]);
+        assert!(!diff.commutes_with(&next_to));
+        let again = Diff::from(&b, &["one", "II", "three", "four", "five"]);
+        assert!(!diff.commutes_with(&again));
+        let inserted = Diff::from(&b, &["one", "1.5", "2", "three", "four", "five"]);
+        assert!(!diff.commutes_with(&inserted));
+    }
+
+    #[test]
+    fn composed_diffs() {
+        let a = ["one", "two", "three", "four", "five", "six"];
+        let b = ["one", "2", "three", "four", "4.5", "five", "six"];
+        let c = ["zero", "one", "II", "three", "five", "six", "seven"];
+        let first = Diff::from(&a, &b);
+        let second = Diff::from(&b, &c);
+
+        let composed = first.compose(&second).unwrap();
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        writeln!(f, "{}", a.join("\n")).unwrap();
+        let f_path = f.into_temp_path();
+        composed.apply(&f_path).unwrap();
+        assert_eq!(fs::read_to_string(&f_path).unwrap(), c.join("\n") + "\n");
+
+        // undoing a diff leaves nothing
+        assert!(first.compose(&first.invert()).unwrap().edits().is_empty());
+        assert_eq!(
+            Diff::from(&a, &a).compose(&first).unwrap().edits(),
+            first.edits()
+        );
+
+        // a diff of `a` changes `two`, which `first` already changed
+        assert!(first.compose(&Diff::from(&a, &c)).is_err());
+    }
+
+    #[test]
+    fn apply_copies_unchanged_lines() {
+        let a: Vec<String> = (0..1000).map(|i| format!("line {}", i)).collect();
+        let mut b = a.clone();
+        b[500] = "changed".to_string();
+        let diff = Diff::from(&a, &b);
+
+        // lines before and after the edit are copied byte for byte
+        let mut f = tempfile::NamedTempFile::new().unwrap();
+        write!(f, "{}\r\n", a.join("\r\n")).unwrap();
+        let f_path = f.into_temp_path();
+        diff.apply(&f_path).unwrap();
+        let applied = fs::read_to_string(&f_path).unwrap();
+        assert!(applied.starts_with("line 0\r\nline 1\r\n"));
+        assert!(applied.contains("line 499\r\nchanged\nline 501\r\n"));
+        assert!(applied.ends_with("line 999\r\n"));
+
+        for (line, offset) in &[(0, (0, 0)), (2, (4, 2)), (3, (6, 3)), (9, (6, 3))] {
+            let mut reader = BufReader::with_capacity(3, &b"ab\n\nc\nd"[..]);
+            assert_eq!(line_offset(&mut reader, *line).unwrap(), *offset);
+        }
+    }
+
+    #[test]
+    fn failed_apply_leaves_no_temp_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        }
+
+        let diff = Diff::from(
+            &["one", "two", "three", "four"],
+            &["one", "2", "three", "4"],
+        );
+        assert!(diff.apply(&path).is_err());
+        let names: Vec<_> = fs::read_dir(dir.path()).unwrap().collect();
+        assert_eq!(names.len(), 1);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+
+        let diff = Diff::from(&["one", "two"], &["one", "2"]);
+        diff.apply(&path).unwrap();
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o751, "the file keeps its permissions");
+        }
+    }
+
+    #[test]
+    fn malformed_edits_are_not_applied() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        fs::write(&path, "one\ntwo\n").unwrap();
+
+        let mut diff = Diff::from(&["one", "two"], &["one", "2", "two"]);
+        diff.edits[0].original.content = vec!["two".to_string()];
+        assert!(matches!(
+            diff.apply(&path),
+            Err(InkError::Edit(EditError::InsertRemovesLines))
+        ));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    }
+
     #[test]
     fn to_edit_script() {
         const A: [&str; 8] = [
@@ -319,7 +814,7 @@ mod tests {
             "> of water and sunshine",
         ];
 
-        let diff = Diff::from_edit_script(&es.join("\n")).unwrap();
+        let diff = Diff::from_edit_script(es.join("\n")).unwrap();
 
         assert_eq!(
             diff.edits,
@@ -370,6 +865,80 @@ mod tests {
         assert_eq!(diff.edits.len(), 3);
     }
 
+    #[test]
+    fn edit_script_errors() {
+        let es = [
+            "0,0r0,0", "< one", "---", "> 1", "2,2r2,2", "< three", "> 3",
+        ]
+        .join("\n");
+        let err = Diff::read_edit_script(&es).unwrap_err();
+        assert_eq!((err.line(), err.column()), (7, 1));
+        assert_eq!(
+            err.to_string(),
+            "line 7, column 1: expected `---` between old and new lines, found \"> 3\""
+        );
+
+        let err = Diff::read_edit_script("0,0x0,0\n< a\n---\n> b").unwrap_err();
+        assert_eq!((err.line(), err.column()), (1, 4));
+        match Diff::from_edit_script("0,0x0,0\n< a\n---\n> b") {
+            Err(InkError::Parse(parsed)) => assert_eq!(parsed, err),
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+
+        // overlapping edits only fail strictly
+        let es = [
+            "1,1r1,1", "< two", "---", "> 2", "0,1r0,1", "< one", "< two", "---", "> 1", "> 2",
+        ];
+        let es = es.join("\n");
+        assert!(Diff::from_edit_script(&es).is_ok());
+        let err = Diff::from_edit_script_strict(&es).unwrap_err();
+        assert_eq!((err.line(), err.column()), (5, 1));
+        assert_eq!(err.expected(), "edits in order, without overlapping");
+
+        let err = Diff::from_edit_script_strict("0,0a0,0\n< a\n---\n> b").unwrap_err();
+        assert_eq!(err.line(), 1);
+    }
+
+    #[test]
+    fn applies_patches_from_other_tools() {
+        let a = ["one", "two", "three", "four", "five", "six", "seven"];
+        let b = ["zero", "one", "2", "three", "five", "six", "seven", "eight"];
+        let diff = Diff::from(&a, &b);
+
+        // as written by `diff -u a b` and `diff -e a b`
+        let unified = [
+            "--- a\t2026-10-15 02:06:17.495449042 +0000",
+            "+++ b\t2026-10-15 02:06:17.495449042 +0000",
+            "@@ -1,7 +1,8 @@",
+            "+zero",
+            " one",
+            "-two",
+            "+2",
+            " three",
+            "-four",
+            " five",
+            " six",
+            " seven",
+            "+eight",
+            "",
+        ]
+        .join("\n");
+        let ed = "7a\neight\n.\n4d\n2c\n2\n.\n0a\nzero\n.\n";
+
+        for patch in &[
+            Diff::from_unified(&unified).unwrap(),
+            Diff::from_ed_script(ed, &a).unwrap(),
+        ] {
+            assert_eq!(patch.edits(), diff.edits());
+
+            let mut f = tempfile::NamedTempFile::new().unwrap();
+            writeln!(f, "{}", a.join("\n")).unwrap();
+            let f_path = f.into_temp_path();
+            patch.apply(&f_path).unwrap();
+            assert_eq!(fs::read_to_string(&f_path).unwrap(), b.join("\n") + "\n");
+        }
+    }
+
     #[test]
     fn to_and_from_edit_script() {
         const A: [&str; 8] = [
@@ -420,7 +989,7 @@ mod tests {
 
         let diff = Diff::from(&a, &b);
 
-        let mut f = NamedTempFile::new_in("./test_tmp_files").unwrap();
+        let mut f = tempfile::NamedTempFile::new().unwrap();
         write!(f, "{}", a.join("\n")).unwrap();
 
         let f_path = f.into_temp_path();
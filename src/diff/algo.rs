@@ -1,8 +1,30 @@
-use crate::diff::edit::{Edit, Operation};
+use crate::diff::edit::{Edit, EditChunkBuilder, Operation};
+use crate::diff::DiffEngine;
+
+/// The Myers' Diff Algorithm, used by `Diff::from`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Myers;
+
+impl DiffEngine for Myers {
+    fn diff(&self, a: &[&str], b: &[&str]) -> Vec<Edit> {
+        myers::from(a, b)
+    }
+}
+
+/// Diffs code token by token, so reformatting shows up as small changes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tokens;
+
+impl DiffEngine for Tokens {
+    fn diff(&self, a: &[&str], b: &[&str]) -> Vec<Edit> {
+        tokens::from(a, b).0
+    }
+}
 
 pub mod myers {
     use super::create_edits;
     use crate::diff::edit::Edit;
+    use std::collections::HashMap;
 
     /// Find the path traversed by a Shortest Edit Script
     /// Second part of the Myers' Diff Algorithm
@@ -77,17 +99,25 @@ pub mod myers {
     /// Filling in the trace along the way
     /// First part of the Myers' Diff Algorithm
     /// Single char names because it matches the paper
+    /// Gives up, returning `None`, once the trace would hold more than
+    /// `max_cost` entries
     #[allow(clippy::many_single_char_names)]
-    fn explore_paths<S: AsRef<str>>(a: &[S], b: &[S]) -> Vec<Vec<usize>> {
+    fn explore_paths<T: Eq>(a: &[T], b: &[T], max_cost: usize) -> Option<Vec<Vec<usize>>> {
         let (n, m) = (a.len(), b.len());
         let max = n + m;
         let mut v = vec![0; 2 * max + 1];
         let mut t: Vec<Vec<usize>> = vec![];
+        let mut cost = 0;
 
         // for d = 0, we need a starting point at k = 1, (x, y) = (0, -1)
         v[max + 1] = 0;
 
         for d in 0..=max {
+            cost += v.len();
+            if cost > max_cost {
+                return None;
+            }
+
             // usually k would be iterating from -d <-> d. But isize is a pain here,
             // so it maps to 0 <-> 2d
             for k in (0..=(2 * d)).step_by(2) {
@@ -105,7 +135,7 @@ pub mod myers {
                 let mut y = x + d - k;
 
                 // going along a diagonal
-                while x < n && y < m && a[x].as_ref() == b[y].as_ref() {
+                while x < n && y < m && a[x] == b[y] {
                     x += 1;
                     y += 1;
                 }
@@ -116,22 +146,95 @@ pub mod myers {
                 // we have reached the end point!
                 if x >= n && y >= m {
                     t.push(v);
-                    return t;
+                    return Some(t);
                 }
             }
 
             t.push(v.clone());
         }
 
-        t
+        Some(t)
     }
 
+    /// How much work `from` does before falling back to a coarse diff, counted
+    /// in entries of the trace. Each entry is a `usize`, so this also bounds
+    /// memory use to around 128MB.
+    pub const DEFAULT_MAX_COST: usize = 1 << 24;
+
     /// A function to be used by the diff module to create a diff with the Myers
     /// Diff Algorithm
     pub fn from<S: AsRef<str>>(a: &[S], b: &[S]) -> Vec<Edit> {
-        let trace = explore_paths(a, b);
-        let path = find_path(&trace, a.len(), b.len());
-        create_edits(&path, a, b)
+        from_with_max_cost(a, b, DEFAULT_MAX_COST).0
+    }
+
+    /// Create a diff, giving up on finding the shortest one after `max_cost`
+    /// work. Then everything between the common start and end of the files
+    /// is replaced, and the returned flag is set.
+    pub fn from_with_max_cost<S: AsRef<str>>(
+        a: &[S],
+        b: &[S],
+        max_cost: usize,
+    ) -> (Vec<Edit>, bool) {
+        // lines at the start and end that are the same in both can't be part
+        // of an edit, so only search the graph between them
+        let (prefix, suffix) = common_ends(a, b);
+        let a = &a[prefix..a.len() - suffix];
+        let b = &b[prefix..b.len() - suffix];
+        if a.is_empty() && b.is_empty() {
+            return (vec![], false);
+        }
+
+        let (a_ids, b_ids) = intern(a, b);
+        let (mut edits, approximate) = match explore_paths(&a_ids, &b_ids, max_cost) {
+            Some(trace) => {
+                let path = find_path(&trace, a.len(), b.len());
+                (create_edits(&path, a, b), false)
+            }
+            None => (replace_all(a, b).into_iter().collect(), true),
+        };
+
+        for edit in &mut edits {
+            edit.original.line += prefix;
+            edit.modified.line += prefix;
+        }
+        (edits, approximate)
+    }
+
+    /// A single edit from one file to the other
+    fn replace_all<S: AsRef<str>>(a: &[S], b: &[S]) -> Option<Edit> {
+        let to_strings = |lines: &[S]| lines.iter().map(|l| l.as_ref().to_string()).collect();
+
+        Edit::between(0, 0, to_strings(a), to_strings(b))
+    }
+
+    /// Give every distinct line an id, so the graph search compares
+    /// integers instead of whole lines
+    fn intern<'a, S: AsRef<str>>(a: &'a [S], b: &'a [S]) -> (Vec<u32>, Vec<u32>) {
+        let mut ids: HashMap<&'a str, u32> = HashMap::new();
+        let mut id = |line: &'a S| {
+            let next = ids.len() as u32;
+            *ids.entry(line.as_ref()).or_insert(next)
+        };
+
+        let a = a.iter().map(&mut id).collect();
+        let b = b.iter().map(&mut id).collect();
+        (a, b)
+    }
+
+    /// How many lines the two files share at the start, and then at the end
+    fn common_ends<S: AsRef<str>>(a: &[S], b: &[S]) -> (usize, usize) {
+        let prefix = a
+            .iter()
+            .zip(b)
+            .take_while(|(x, y)| x.as_ref() == y.as_ref())
+            .count();
+        let suffix = a[prefix..]
+            .iter()
+            .rev()
+            .zip(b[prefix..].iter().rev())
+            .take_while(|(x, y)| x.as_ref() == y.as_ref())
+            .count();
+        (prefix, suffix)
     }
 
     #[cfg(test)]
@@ -273,6 +376,75 @@ pub mod myers {
             );
         }
 
+        #[test]
+        fn myers_trims_common_ends() {
+            let a = ["same", "same", "old", "end", "end"];
+            let b = ["same", "same", "new", "newer", "end", "end"];
+
+            assert_eq!(
+                myers::from(&a, &b),
+                vec![Edit {
+                    op: Operation::Replace,
+                    original: HalfEdit {
+                        line: 2,
+                        content: vec!["old".to_string()]
+                    },
+                    modified: HalfEdit {
+                        line: 2,
+                        content: vec!["new".to_string(), "newer".to_string()]
+                    }
+                }]
+            );
+
+            assert_eq!(myers::from(&a, &a), vec![]);
+            assert_eq!(
+                myers::from(&a[..2], &a),
+                vec![Edit {
+                    op: Operation::Insert,
+                    original: HalfEdit {
+                        line: 2,
+                        content: vec![]
+                    },
+                    modified: HalfEdit {
+                        line: 2,
+                        content: vec!["old".to_string(), "end".to_string(), "end".to_string()]
+                    }
+                }]
+            );
+            assert_eq!(myers::common_ends(&["x", "x"], &["x"]), (1, 0));
+        }
+
+        #[test]
+        fn myers_approximates_past_max_cost() {
+            let a = ["same", "same", "old", "end", "end"];
+            let b = ["same", "same", "new", "newer", "end", "end"];
+
+            let (edits, approximate) = myers::from_with_max_cost(&a, &b, 1);
+            assert!(approximate);
+            assert_eq!(edits, myers::from(&a, &b));
+            let (edits, approximate) = myers::from_with_max_cost(&a[1..], &a[..4], 1);
+            assert!(approximate);
+            assert_eq!(
+                edits,
+                vec![Edit::new(
+                    Operation::Replace,
+                    1,
+                    1,
+                    vec!["old".to_string(), "end".to_string()],
+                    vec!["same".to_string(), "old".to_string()],
+                )
+                .unwrap()]
+            );
+        }
+
+        #[test]
+        fn myers_interns_lines() {
+            assert_eq!(
+                myers::intern(&["x", "y", "x"], &["y", "z"]),
+                (vec![0, 1, 0], vec![1, 2])
+            );
+        }
+
         #[test]
         fn myers_creating_edit_delete_line() {
             const A: [&str; 3] = ["this is a line", "new line!", "another line"];
@@ -337,12 +509,255 @@ pub mod myers {
     }
 }
 
+/// Diffs over code tokens, projected back onto lines
+pub mod tokens {
+    use super::myers;
+    use crate::diff::edit::Edit;
+    use std::ops::Range;
+
+    /// The changed parts of the lines in an edit, as byte ranges into each line
+    #[derive(Debug, Clone, PartialEq, Eq, Default)]
+    pub struct IntraLine {
+        /// One entry per line of the edit's original content
+        pub original: Vec<Vec<Range<usize>>>,
+        /// One entry per line of the edit's modified content
+        pub modified: Vec<Vec<Range<usize>>>,
+    }
+
+    /// Split a line into identifiers, runs of whitespace, and single
+    /// punctuation characters, returning each token's byte range
+    pub fn tokenize(line: &str) -> Vec<Range<usize>> {
+        let class = |c: char| {
+            if c.is_alphanumeric() || c == '_' {
+                0
+            } else if c.is_whitespace() {
+                1
+            } else {
+                2
+            }
+        };
+
+        let mut tokens: Vec<Range<usize>> = Vec::new();
+        let mut previous = None;
+        for (index, c) in line.char_indices() {
+            let current = class(c);
+            match tokens.last_mut() {
+                Some(token) if previous == Some(current) && current != 2 => {
+                    token.end = index + c.len_utf8()
+                }
+                _ => tokens.push(index..index + c.len_utf8()),
+            }
+            previous = Some(current);
+        }
+        tokens
+    }
+
+    /// The tokens of every line, each line ending with a newline token
+    struct Tokenized<'a> {
+        tokens: Vec<&'a str>,
+        /// line and byte range of each token; newlines have an empty range
+        positions: Vec<(usize, Range<usize>)>,
+        /// the index of each line's first token, then the number of tokens
+        line_starts: Vec<usize>,
+    }
+
+    impl<'a> Tokenized<'a> {
+        fn new<S: AsRef<str>>(lines: &'a [S]) -> Tokenized<'a> {
+            let mut tokens = Vec::new();
+            let mut positions = Vec::new();
+            let mut line_starts = Vec::with_capacity(lines.len() + 1);
+            for (number, line) in lines.iter().enumerate() {
+                line_starts.push(tokens.len());
+                let line = line.as_ref();
+                for range in tokenize(line) {
+                    tokens.push(&line[range.clone()]);
+                    positions.push((number, range));
+                }
+                tokens.push("\n");
+                positions.push((number, line.len()..line.len()));
+            }
+            line_starts.push(tokens.len());
+
+            Tokenized {
+                tokens,
+                positions,
+                line_starts,
+            }
+        }
+
+        /// For each line, whether all of its tokens are unchanged, and the
+        /// only line on the other side its tokens are matched with
+        fn line_partners(&self, changed: &[bool], partner: &[Option<usize>]) -> Vec<Option<usize>> {
+            let mut partners: Vec<Option<Option<usize>>> = vec![None; self.line_starts.len() - 1];
+            for (token, (line, _)) in self.positions.iter().enumerate() {
+                let other = if changed[token] { None } else { partner[token] };
+                partners[*line] = match partners[*line] {
+                    None => Some(other),
+                    Some(previous) if previous == other => Some(previous),
+                    Some(_) => Some(None),
+                };
+            }
+            partners.into_iter().map(Option::flatten).collect()
+        }
+
+        /// Byte ranges of the changed tokens in a line, joining adjacent ones
+        fn changed_ranges(&self, line: usize, changed: &[bool]) -> Vec<Range<usize>> {
+            let mut ranges: Vec<Range<usize>> = Vec::new();
+            let tokens = self.line_starts[line]..self.line_starts[line + 1];
+            let positions = &self.positions[tokens.clone()];
+            for ((_, range), changed) in positions.iter().zip(&changed[tokens]) {
+                if !changed || range.is_empty() {
+                    continue;
+                }
+
+                match ranges.last_mut() {
+                    Some(last) if last.end == range.start => last.end = range.end,
+                    _ => ranges.push(range.clone()),
+                }
+            }
+            ranges
+        }
+    }
+
+    /// Diff two files token by token, returning line edits and the changed
+    /// ranges within the lines of each edit
+    pub fn from<S: AsRef<str>>(a: &[S], b: &[S]) -> (Vec<Edit>, Vec<IntraLine>) {
+        let a_tokens = Tokenized::new(a);
+        let b_tokens = Tokenized::new(b);
+        let token_edits = myers::from(&a_tokens.tokens, &b_tokens.tokens);
+
+        // pair up the tokens between edits, which are the same in both files
+        let mut changed_a = vec![false; a_tokens.tokens.len()];
+        let mut changed_b = vec![false; b_tokens.tokens.len()];
+        let mut partner_a = vec![None; a_tokens.tokens.len()];
+        let mut partner_b = vec![None; b_tokens.tokens.len()];
+        let (mut x, mut y) = (0, 0);
+        for edit in token_edits.iter().map(Some).chain(std::iter::once(None)) {
+            let (end_x, end_y) = match edit {
+                Some(edit) => (edit.original.line, edit.modified.line),
+                None => (changed_a.len(), changed_b.len()),
+            };
+
+            while x < end_x && y < end_y {
+                partner_a[x] = Some(b_tokens.positions[y].0);
+                partner_b[y] = Some(a_tokens.positions[x].0);
+                x += 1;
+                y += 1;
+            }
+
+            if let Some(edit) = edit {
+                for changed in &mut changed_a[x..x + edit.original.content.len()] {
+                    *changed = true;
+                }
+                for changed in &mut changed_b[y..y + edit.modified.content.len()] {
+                    *changed = true;
+                }
+                x += edit.original.content.len();
+                y += edit.modified.content.len();
+            }
+        }
+
+        // lines that are unchanged and only matched with each other
+        let a_partners = a_tokens.line_partners(&changed_a, &partner_a);
+        let b_partners = b_tokens.line_partners(&changed_b, &partner_b);
+        let mut anchors: Vec<(usize, usize)> = a_partners
+            .iter()
+            .enumerate()
+            .filter_map(|(line, partner)| {
+                partner
+                    .filter(|&other| b_partners[other] == Some(line))
+                    .map(|other| (line, other))
+            })
+            .collect();
+        anchors.push((a.len(), b.len()));
+
+        // everything between anchors is an edit
+        let mut edits = Vec::new();
+        let mut ranges = Vec::new();
+        let (mut x, mut y) = (0, 0);
+        for (anchor_x, anchor_y) in anchors {
+            if anchor_x > x || anchor_y > y {
+                let original = a[x..anchor_x]
+                    .iter()
+                    .map(|l| l.as_ref().to_string())
+                    .collect();
+                let modified = b[y..anchor_y]
+                    .iter()
+                    .map(|l| l.as_ref().to_string())
+                    .collect();
+
+                edits.extend(Edit::between(x, y, original, modified));
+                ranges.push(IntraLine {
+                    original: (x..anchor_x)
+                        .map(|line| a_tokens.changed_ranges(line, &changed_a))
+                        .collect(),
+                    modified: (y..anchor_y)
+                        .map(|line| b_tokens.changed_ranges(line, &changed_b))
+                        .collect(),
+                });
+            }
+
+            x = anchor_x + 1;
+            y = anchor_y + 1;
+        }
+
+        (edits, ranges)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::diff::edit::Operation;
+
+        #[test]
+        fn tokenize_code() {
+            let line = "let x_1 = f(a, b);";
+            let tokens: Vec<&str> = tokenize(line).into_iter().map(|r| &line[r]).collect();
+            assert_eq!(
+                tokens,
+                ["let", " ", "x_1", " ", "=", " ", "f", "(", "a", ",", " ", "b", ")", ";"]
+            );
+        }
+
+        #[test]
+        fn token_diff_reformatted() {
+            let a = ["fn f() {", "    call(a, b);", "}"];
+            let b = ["fn f() {", "    call(a,", "         c);", "}"];
+            let (edits, ranges) = from(&a, &b);
+
+            assert_eq!(
+                edits,
+                vec![Edit::new(
+                    Operation::Replace,
+                    1,
+                    1,
+                    vec!["    call(a, b);".to_string()],
+                    vec!["    call(a,".to_string(), "         c);".to_string()],
+                )
+                .unwrap()]
+            );
+            assert_eq!(
+                ranges,
+                vec![IntraLine {
+                    original: vec![vec![11..13]],
+                    modified: vec![vec![], vec![0..10]],
+                }]
+            );
+
+            assert_eq!(from(&a, &a).0, vec![]);
+            let (edits, _) = from(&a[..2], &a);
+            assert_eq!(
+                edits,
+                vec![Edit::new(Operation::Insert, 2, 2, vec![], vec!["}".to_string()]).unwrap()]
+            );
+        }
+    }
+}
+
 /// Creates a vector of `Edit`s given a path through the edit graph
 /// Final part of the Myers' Diff Algorithm
-#[allow(clippy::collapsible_if)]
 fn create_edits<S: AsRef<str>>(path: &[(usize, usize)], a: &[S], b: &[S]) -> Vec<Edit> {
-    let mut diff: Vec<Edit> = Vec::new();
-    let mut chunk: Option<Edit> = None;
+    let mut chunks = EditChunkBuilder::new();
     let mut x = 0;
     let mut y = 0;
 
@@ -360,46 +775,28 @@ fn create_edits<S: AsRef<str>>(path: &[(usize, usize)], a: &[S], b: &[S]) -> Vec
             None
         };
 
-        match &edit_type {
+        match edit_type {
             Some(edit_type) => {
-                // constuct edit
-                let original_content = if x != a.len() {
-                    vec![a[x].as_ref().to_string()]
-                } else {
-                    vec![]
-                };
-                let modified_content = if y != b.len() {
-                    vec![b[y].as_ref().to_string()]
-                } else {
-                    vec![]
+                // an insert takes the line it moves past in the modified
+                // file, and a delete the one in the original
+                let line = |lines: &[S], i: usize| vec![lines[i].as_ref().to_string()];
+                let (original_content, modified_content) = match edit_type {
+                    Operation::Insert => (vec![], line(b, y)),
+                    _ => (line(a, x), vec![]),
                 };
 
-                let edit = Edit::new(edit_type.clone(), x, y, original_content, modified_content);
-
-                match &mut chunk {
-                    // add edit to chunk. The way the path is created, this should never fail.
-                    Some(chunk) => chunk.join(edit).unwrap(),
-                    // first edit of chunk, so set chunk
-                    None => chunk = Some(edit),
-                }
-            }
-            None => {
-                if let Some(inner_chunk) = &chunk {
-                    // add chunk to diff and reset chunk
-                    diff.push(inner_chunk.clone());
-                    chunk = None;
-                }
+                // the way the path is made, consecutive edits always join
+                chunks.push(
+                    Edit::new(edit_type, x, y, original_content, modified_content)
+                        .expect("a step of the path changes one line"),
+                );
             }
+            None => chunks.finish_chunk(),
         }
 
         x = *prev_x;
         y = *prev_y;
     }
 
-    if let Some(inner_chunk) = &chunk {
-        // add chunk to diff and reset chunk
-        diff.push(inner_chunk.clone());
-    }
-
-    diff
+    chunks.build()
 }
@@ -1,5 +1,6 @@
-use crate::diff::parser;
+use crate::diff::parser::{self, ParseError};
 use std::error::Error;
+use std::fmt;
 
 /// The type of edit - Insertion, Deletion, or Replacement
 #[derive(PartialEq, Eq, Debug, Clone)]
@@ -9,6 +10,29 @@ pub enum Operation {
     Replace,
 }
 
+/// Why an edit can't be made: its content doesn't fit its operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EditError {
+    /// An insert with lines from the original file
+    InsertRemovesLines,
+    /// A delete with lines for the modified file
+    DeleteAddsLines,
+    /// An edit without lines on either side
+    Empty,
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EditError::InsertRemovesLines => "an insert can't remove lines",
+            EditError::DeleteAddsLines => "a delete can't add lines",
+            EditError::Empty => "an edit must change at least one line",
+        })
+    }
+}
+
+impl Error for EditError {}
+
 /// Half of an edit, that can refer to the original file
 /// or the modified file. Should only be constructed with an Edit.
 /// The first line is line 0, and the last line is line len - 1
@@ -26,6 +50,55 @@ impl HalfEdit {
     }
 }
 
+/// Joins edits of single lines into chunks of adjacent lines, as a diff's
+/// edits are. An edit that can't join the chunk being built is handed back,
+/// or starts a new chunk.
+#[derive(Debug, Default)]
+pub struct EditChunkBuilder {
+    chunks: Vec<Edit>,
+    chunk: Option<Edit>,
+}
+
+impl EditChunkBuilder {
+    pub fn new() -> EditChunkBuilder {
+        EditChunkBuilder::default()
+    }
+
+    /// Join an edit to the chunk being built, or start the chunk with it if
+    /// there is none. An edit that can't be joined is handed back, and the
+    /// chunk is left as it was.
+    pub fn join(&mut self, edit: Edit) -> Result<(), Edit> {
+        match &mut self.chunk {
+            Some(chunk) => chunk.join(edit),
+            None => {
+                self.chunk = Some(edit);
+                Ok(())
+            }
+        }
+    }
+
+    /// Join an edit to the chunk being built, or finish that chunk and start
+    /// a new one with the edit if it can't be joined
+    pub fn push(&mut self, edit: Edit) {
+        if let Err(edit) = self.join(edit) {
+            self.finish_chunk();
+            self.chunk = Some(edit);
+        }
+    }
+
+    /// Finish the chunk being built, so the next edit starts a new one even
+    /// if it could have been joined
+    pub fn finish_chunk(&mut self) {
+        self.chunks.extend(self.chunk.take());
+    }
+
+    /// Every chunk, the one being built last
+    pub fn build(mut self) -> Vec<Edit> {
+        self.finish_chunk();
+        self.chunks
+    }
+}
+
 /// One section of a diff which involves adding or removing, or replacing
 /// or more lines.
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -36,50 +109,63 @@ pub struct Edit {
 }
 
 impl Edit {
-    /// create an edit given an op, line numbers, and content
-    // TODO: error check that for insert and delete edit, modified/original is exclusive, just for
-    // error keeping sake.
+    /// Create an edit given an op, line numbers, and content. An insert can
+    /// only add lines and a delete only remove them, and every edit changes
+    /// at least one line.
     pub fn new(
         op: Operation,
         x: usize,
         y: usize,
         original_content: Vec<String>,
         modified_content: Vec<String>,
-    ) -> Edit {
-        match op {
-            Operation::Insert => Edit {
-                op,
-                original: HalfEdit {
-                    line: x,
-                    content: vec![],
-                },
-                modified: HalfEdit {
-                    line: y,
-                    content: modified_content,
-                },
-            },
-            Operation::Delete => Edit {
-                op,
-                original: HalfEdit {
-                    line: x,
-                    content: original_content,
-                },
-                modified: HalfEdit {
-                    line: y,
-                    content: vec![],
-                },
+    ) -> Result<Edit, EditError> {
+        let edit = Edit {
+            op,
+            original: HalfEdit {
+                line: x,
+                content: original_content,
             },
-            Operation::Replace => Edit {
-                op,
-                original: HalfEdit {
-                    line: x,
-                    content: original_content,
-                },
-                modified: HalfEdit {
-                    line: y,
-                    content: modified_content,
-                },
+            modified: HalfEdit {
+                line: y,
+                content: modified_content,
             },
+        };
+        edit.validate()?;
+        Ok(edit)
+    }
+
+    /// The edit changing `original` at line `x` to `modified` at line `y`,
+    /// with the operation that fits which of them has lines, or `None` if
+    /// neither does
+    pub(crate) fn between(
+        x: usize,
+        y: usize,
+        original: Vec<String>,
+        modified: Vec<String>,
+    ) -> Option<Edit> {
+        let op = match (original.is_empty(), modified.is_empty()) {
+            (true, true) => return None,
+            (true, false) => Operation::Insert,
+            (false, true) => Operation::Delete,
+            (false, false) => Operation::Replace,
+        };
+        Edit::new(op, x, y, original, modified).ok()
+    }
+
+    /// Check that the edit's content fits its operation, as `new` does. The
+    /// fields are public, so an edit can be made without it.
+    pub fn validate(&self) -> Result<(), EditError> {
+        match self.op {
+            Operation::Insert if !self.original.content.is_empty() => {
+                Err(EditError::InsertRemovesLines)
+            }
+            Operation::Delete if !self.modified.content.is_empty() => {
+                Err(EditError::DeleteAddsLines)
+            }
+            _ if self.original.content.is_empty() && self.modified.content.is_empty() => {
+                Err(EditError::Empty)
+            }
+            _ => Ok(()),
         }
     }
 
@@ -88,12 +174,11 @@ impl Edit {
         self.original.joinable(&edit.original) && self.modified.joinable(&edit.modified)
     }
 
-    /// Joins two edits, consuming the given edit
-    // TODO: any way to consume the edit only if it's joinable, but leave it as a
-    // ref otherwise so it's still usable?
-    pub fn join(&mut self, edit: Edit) -> Result<(), &'static str> {
+    /// Joins two edits, consuming the given edit if they can be joined and
+    /// handing it back untouched if they can't
+    pub fn join(&mut self, edit: Edit) -> Result<(), Edit> {
         if !self.joinable(&edit) {
-            return Err("edits are not joinable");
+            return Err(edit);
         }
 
         // need to find the smaller line to add which should go first
@@ -112,6 +197,27 @@ impl Edit {
         Ok(())
     }
 
+    /// The edit that undoes this one, taking the modified file back to the
+    /// original
+    pub fn invert(&self) -> Edit {
+        let op = match self.op {
+            Operation::Insert => Operation::Delete,
+            Operation::Delete => Operation::Insert,
+            Operation::Replace => Operation::Replace,
+        };
+
+        Edit {
+            op,
+            original: self.modified.clone(),
+            modified: self.original.clone(),
+        }
+    }
+
+    /// How many more lines the modified side has than the original
+    pub(crate) fn growth(&self) -> isize {
+        self.modified.content.len() as isize - self.original.content.len() as isize
+    }
+
     /// Creating an 'edit script' from a single edit,
     /// based on the UNIX diff utility's edit script,
     /// though this is not an 'ed' compatible edit script
@@ -148,50 +254,30 @@ impl Edit {
     /// Parse an edit script into an Edit
     /// Takes an entire edit script as input, and if sucessful returns the remaining portion of
     /// the edit script along with the serialized edit
-    pub fn parse_edit_script(script: &str) -> Result<(&str, Edit), Box<dyn Error>> {
+    pub fn parse_edit_script(script: &str) -> Result<(&str, Edit), ParseError> {
         // parse out the line numbers from the original file
         let (r, og_line_start) = parser::read_usize(script)?;
-        let r = parser::skip_sequence(r, ",")?;
+        let r = parser::skip_sequence(r, ",", "`,` between line numbers")?;
         let (r, og_line_end) = parser::read_usize(r)?;
 
         // parse out the edit's operation
-        let (r, op) = match r.chars().next().ok_or("No operation")? {
-            'r' => Ok((&r[1..], Operation::Replace)),
-            'a' => Ok((&r[1..], Operation::Insert)),
-            'd' => Ok((&r[1..], Operation::Delete)),
-            _ => Err("Invalid Operation"),
-        }?;
+        let (r, op) = match r.chars().next() {
+            Some('r') => (&r[1..], Operation::Replace),
+            Some('a') => (&r[1..], Operation::Insert),
+            Some('d') => (&r[1..], Operation::Delete),
+            _ => return Err(ParseError::new(r, "an operation, `a`, `d` or `r`")),
+        };
 
         // parse out the line number from the modified file
         let (r, mod_line_start) = parser::read_usize(r)?;
-        let r = parser::skip_sequence(r, ",")?;
+        let r = parser::skip_sequence(r, ",", "`,` between line numbers")?;
         let (r, mod_line_end) = parser::read_usize(r)?;
 
         // parse out the content for each half of the edit
-        let r = parser::skip_sequence(r, "\n")?;
-        let (r, og_content_ref) = parser::read_lines(r, og_line_end - og_line_start + 1)?;
-        let r = parser::skip_sequence(r, "---\n")?;
-        let (r, mod_content_ref) = parser::read_lines(r, mod_line_end - mod_line_start + 1)?;
-
-        // I couldn't find a better way to make a Vec<&str> -> Vec<String> while also stripping the '> '/'< ',
-        // so here we are
-        let mut og_content = Vec::with_capacity(og_content_ref.len());
-        for line in og_content_ref {
-            og_content.push(
-                line.strip_prefix("< ")
-                    .ok_or("Content formatted incorrectly")?
-                    .to_string(),
-            );
-        }
-
-        let mut mod_content = Vec::with_capacity(mod_content_ref.len());
-        for line in mod_content_ref {
-            mod_content.push(
-                line.strip_prefix("> ")
-                    .ok_or("Content formatted incorrectly")?
-                    .to_string(),
-            );
-        }
+        let r = parser::skip_sequence(r, "\n", "the end of the line")?;
+        let (r, og_content) = read_content(r, og_line_start, og_line_end, "< ")?;
+        let r = parser::skip_sequence(r, "---\n", "`---` between old and new lines")?;
+        let (r, mod_content) = read_content(r, mod_line_start, mod_line_end, "> ")?;
 
         let edit = Edit {
             op,
@@ -207,11 +293,87 @@ impl Edit {
 
         Ok((r, edit))
     }
+
+    /// Check, for strict parsing, that the edit changes the kind of lines its
+    /// operation says and comes after `previous` in both files. Errors are
+    /// at the start of `script`, where the edit was read from.
+    pub(crate) fn check(&self, previous: Option<&Edit>, script: &str) -> Result<(), ParseError> {
+        let fits = match self.op {
+            Operation::Insert => self.original.content.is_empty(),
+            Operation::Delete => self.modified.content.is_empty(),
+            Operation::Replace => {
+                !self.original.content.is_empty() && !self.modified.content.is_empty()
+            }
+        };
+        if !fits {
+            return Err(ParseError::new(
+                script,
+                "lines removed only by `d` and `r`, and added only by `a` and `r`",
+            ));
+        }
+
+        if let Some(previous) = previous {
+            let after = |half: &HalfEdit, previous: &HalfEdit| {
+                half.line >= previous.line + previous.content.len()
+            };
+            if !after(&self.original, &previous.original)
+                || !after(&self.modified, &previous.modified)
+            {
+                return Err(ParseError::new(
+                    script,
+                    "edits in order, without overlapping",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read the lines from `start` to `end` of one side of a version 1 edit,
+/// each starting with `prefix`. An empty side has an end one before its
+/// start.
+fn read_content<'a>(
+    input: &'a str,
+    start: usize,
+    end: usize,
+    prefix: &str,
+) -> Result<(&'a str, Vec<String>), ParseError> {
+    let count = end.wrapping_sub(start).wrapping_add(1);
+    let (mut r, mut content) = (input, Vec::new());
+    while content.len() < count {
+        let (rest, lines) = parser::read_lines(r, 1)?;
+        let line = lines[0].strip_prefix(prefix).ok_or_else(|| {
+            ParseError::new(
+                r,
+                if prefix == "< " {
+                    "an old line starting with `< `"
+                } else {
+                    "a new line starting with `> `"
+                },
+            )
+        })?;
+        content.push(line.to_string());
+        r = rest;
+    }
+
+    Ok((r, content))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+
+    #[test]
+    fn invert_swaps_sides() {
+        let delete = Edit::new(Operation::Delete, 3, 2, vec!["gone".to_string()], vec![]).unwrap();
+        let insert = delete.invert();
+        assert_eq!(insert.op, Operation::Insert);
+        assert_eq!((insert.original.line, insert.modified.line), (2, 3));
+        assert_eq!(insert.modified.content, ["gone"]);
+        assert_eq!(insert.invert(), delete);
+    }
+
     #[test]
     fn edit_join_insert_and_delete() {
         let mut insert = Edit {
@@ -299,4 +461,57 @@ mod tests {
             }
         )
     }
+
+    #[test]
+    fn chunk_builder_hands_back_unjoinable_edits() {
+        let line = |line: &str| vec![line.to_string()];
+        let mut chunks = EditChunkBuilder::new();
+        chunks.push(Edit::new(Operation::Delete, 0, 0, line("a"), vec![]).unwrap());
+        chunks.push(Edit::new(Operation::Insert, 1, 0, vec![], line("b")).unwrap());
+
+        let apart = Edit::new(Operation::Delete, 5, 4, line("f"), vec![]).unwrap();
+        assert_eq!(chunks.join(apart.clone()), Err(apart.clone()));
+        chunks.push(apart.clone());
+        chunks.push(Edit::new(Operation::Delete, 6, 4, line("g"), vec![]).unwrap());
+        chunks.finish_chunk();
+        chunks.push(Edit::new(Operation::Delete, 7, 4, line("h"), vec![]).unwrap());
+
+        assert_eq!(
+            chunks.build(),
+            vec![
+                Edit::new(Operation::Replace, 0, 0, line("a"), line("b")).unwrap(),
+                Edit::new(
+                    Operation::Delete,
+                    5,
+                    4,
+                    vec!["f".to_string(), "g".to_string()],
+                    vec![]
+                )
+                .unwrap(),
+                Edit::new(Operation::Delete, 7, 4, line("h"), vec![]).unwrap(),
+            ]
+        );
+    }
+
+    #[test]
+    fn content_must_fit_the_operation() {
+        let line = |line: &str| vec![line.to_string()];
+        assert_eq!(
+            Edit::new(Operation::Insert, 0, 0, line("a"), line("b")),
+            Err(EditError::InsertRemovesLines)
+        );
+        assert_eq!(
+            Edit::new(Operation::Delete, 0, 0, line("a"), line("b")),
+            Err(EditError::DeleteAddsLines)
+        );
+        for op in [Operation::Insert, Operation::Delete, Operation::Replace] {
+            assert_eq!(Edit::new(op, 0, 0, vec![], vec![]), Err(EditError::Empty));
+        }
+
+        assert_eq!(Edit::between(0, 0, vec![], vec![]), None);
+        assert_eq!(
+            Edit::between(2, 1, line("a"), vec![]),
+            Edit::new(Operation::Delete, 2, 1, line("a"), vec![]).ok()
+        );
+    }
 }
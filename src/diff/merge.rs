@@ -0,0 +1,248 @@
+//! Three-way merges of lines, as done by `diff3`
+use crate::diff::edit::Operation;
+use crate::diff::Diff;
+
+/// A stretch of a three-way merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeRegion {
+    /// Lines both sides agree on, whether either changed them or not
+    Resolved(Vec<String>),
+    /// Lines the two sides changed in different ways, with what the base had
+    Conflict {
+        base: Vec<String>,
+        ours: Vec<String>,
+        theirs: Vec<String>,
+    },
+}
+
+/// The outcome of a three-way merge
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MergeResult {
+    /// Every change merged without overlapping another
+    Clean(Vec<String>),
+    /// Some changes overlapped, so the merge is given in regions
+    Conflicted(Vec<MergeRegion>),
+}
+
+impl MergeResult {
+    pub fn is_clean(&self) -> bool {
+        matches!(self, MergeResult::Clean(_))
+    }
+
+    /// The merged lines, with each conflict written out between
+    /// `<<<<<<<`, `=======` and `>>>>>>>` markers naming the sides
+    pub fn lines_with_markers(&self, ours_name: &str, theirs_name: &str) -> Vec<String> {
+        let regions = match self {
+            MergeResult::Clean(lines) => return lines.clone(),
+            MergeResult::Conflicted(regions) => regions,
+        };
+
+        let mut lines = Vec::new();
+        for region in regions {
+            match region {
+                MergeRegion::Resolved(resolved) => lines.extend(resolved.iter().cloned()),
+                MergeRegion::Conflict { ours, theirs, .. } => {
+                    lines.push(format!("<<<<<<< {}", ours_name));
+                    lines.extend(ours.iter().cloned());
+                    lines.push("=======".to_string());
+                    lines.extend(theirs.iter().cloned());
+                    lines.push(format!(">>>>>>> {}", theirs_name));
+                }
+            }
+        }
+        lines
+    }
+}
+
+/// A change to the base: the lines `start..end` replaced by `lines`
+struct Hunk {
+    start: usize,
+    end: usize,
+    lines: Vec<String>,
+}
+
+fn hunks<S: AsRef<str>>(base: &[S], side: &[S]) -> Vec<Hunk> {
+    Diff::from(base, side)
+        .edits()
+        .iter()
+        .map(|edit| {
+            let removed = match edit.op {
+                Operation::Insert => 0,
+                _ => edit.original.content.len(),
+            };
+            Hunk {
+                start: edit.original.line,
+                end: edit.original.line + removed,
+                lines: edit.modified.content.clone(),
+            }
+        })
+        .collect()
+}
+
+/// The lines `start..end` of the base with the given hunks, all inside that
+/// range, applied
+fn apply<S: AsRef<str>>(base: &[S], start: usize, end: usize, hunks: &[&Hunk]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut at = start;
+    for hunk in hunks {
+        lines.extend(base[at..hunk.start].iter().map(|l| l.as_ref().to_string()));
+        lines.extend(hunk.lines.iter().cloned());
+        at = hunk.end;
+    }
+    lines.extend(base[at..end].iter().map(|l| l.as_ref().to_string()));
+    lines
+}
+
+/// Merge the changes two sides made to a common base, line by line.
+///
+/// Changes from one side are taken as they are. Where both sides changed
+/// the same or neighbouring lines, the change is taken if they made the
+/// same one, and is otherwise a conflict.
+pub fn merge3<S: AsRef<str>>(base: &[S], ours: &[S], theirs: &[S]) -> MergeResult {
+    let ours = hunks(base, ours);
+    let theirs = hunks(base, theirs);
+
+    let mut regions: Vec<MergeRegion> = Vec::new();
+
+    let (mut i, mut j, mut at) = (0, 0, 0);
+    while i < ours.len() || j < theirs.len() {
+        // start a chunk at the first hunk from either side, then take in
+        // every hunk overlapping or touching it
+        let first = match (ours.get(i), theirs.get(j)) {
+            (Some(a), Some(b)) => a.start.min(b.start),
+            (Some(a), None) => a.start,
+            (None, Some(b)) => b.start,
+            (None, None) => unreachable!(),
+        };
+        let (from_i, from_j) = (i, j);
+        let mut end = first;
+        loop {
+            if let Some(hunk) = ours.get(i).filter(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                i += 1;
+            } else if let Some(hunk) = theirs.get(j).filter(|h| h.start <= end) {
+                end = end.max(hunk.end);
+                j += 1;
+            } else {
+                break;
+            }
+        }
+
+        let unchanged = base[at..first].iter().map(|l| l.as_ref().to_string());
+        push_resolved(&mut regions, unchanged.collect());
+
+        let ours_hunks: Vec<_> = ours[from_i..i].iter().collect();
+        let theirs_hunks: Vec<_> = theirs[from_j..j].iter().collect();
+        let ours_lines = apply(base, first, end, &ours_hunks);
+        let theirs_lines = apply(base, first, end, &theirs_hunks);
+
+        if theirs_hunks.is_empty() || ours_lines == theirs_lines {
+            push_resolved(&mut regions, ours_lines);
+        } else if ours_hunks.is_empty() {
+            push_resolved(&mut regions, theirs_lines);
+        } else {
+            regions.push(MergeRegion::Conflict {
+                base: base[first..end]
+                    .iter()
+                    .map(|l| l.as_ref().to_string())
+                    .collect(),
+                ours: ours_lines,
+                theirs: theirs_lines,
+            });
+        }
+        at = end;
+    }
+    let rest = base[at..].iter().map(|l| l.as_ref().to_string());
+    push_resolved(&mut regions, rest.collect());
+
+    if regions
+        .iter()
+        .any(|region| matches!(region, MergeRegion::Conflict { .. }))
+    {
+        return MergeResult::Conflicted(regions);
+    }
+
+    // with no conflicts, everything was joined into one resolved region
+    match regions.pop() {
+        Some(MergeRegion::Resolved(lines)) => MergeResult::Clean(lines),
+        _ => MergeResult::Clean(vec![]),
+    }
+}
+
+/// Add resolved lines to the regions, joining them to resolved lines before
+fn push_resolved(regions: &mut Vec<MergeRegion>, lines: Vec<String>) {
+    if let Some(MergeRegion::Resolved(last)) = regions.last_mut() {
+        last.extend(lines);
+    } else if !lines.is_empty() {
+        regions.push(MergeRegion::Resolved(lines));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(text: &str) -> Vec<&str> {
+        text.split_terminator('\n').collect()
+    }
+
+    fn owned(text: &str) -> Vec<String> {
+        lines(text).into_iter().map(String::from).collect()
+    }
+
+    #[test]
+    fn clean_merges() {
+        let base = lines("a\nb\nc\nd\ne\n");
+        let ours = lines("A\nb\nc\nd\ne\n");
+        let theirs = lines("a\nb\nc\nd\nE\nf\n");
+        assert_eq!(
+            merge3(&base, &ours, &theirs),
+            MergeResult::Clean(owned("A\nb\nc\nd\nE\nf\n"))
+        );
+
+        // the same change on both sides, and changes to one side only
+        assert_eq!(
+            merge3(&base, &ours, &ours),
+            MergeResult::Clean(owned("A\nb\nc\nd\ne\n"))
+        );
+        assert_eq!(
+            merge3(&base, &base, &theirs),
+            MergeResult::Clean(owned("a\nb\nc\nd\nE\nf\n"))
+        );
+        let empty: Vec<&str> = vec![];
+        assert_eq!(
+            merge3(&empty, &empty, &lines("new\n")),
+            MergeResult::Clean(owned("new\n"))
+        );
+    }
+
+    #[test]
+    fn conflicting_merges() {
+        let base = lines("a\nb\nc\n");
+        let ours = lines("a\nours\nc\n");
+        let theirs = lines("a\ntheirs\nc\nd\n");
+
+        let result = merge3(&base, &ours, &theirs);
+        assert_eq!(
+            result,
+            MergeResult::Conflicted(vec![
+                MergeRegion::Resolved(owned("a\n")),
+                MergeRegion::Conflict {
+                    base: owned("b\n"),
+                    ours: owned("ours\n"),
+                    theirs: owned("theirs\n"),
+                },
+                MergeRegion::Resolved(owned("c\nd\n")),
+            ])
+        );
+        assert_eq!(
+            result.lines_with_markers("ours", "theirs"),
+            owned("a\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nc\nd\n")
+        );
+
+        // insertions at the same place conflict unless they're the same
+        let base = lines("a\n");
+        let result = merge3(&base, &lines("a\nx\n"), &lines("a\ny\n"));
+        assert!(!result.is_clean());
+    }
+}
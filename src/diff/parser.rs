@@ -1,73 +1,408 @@
-/// Holds functions for parsing simple types from a string
-// TODO: use InkErrors!
+/// Holds functions for parsing simple types from a string, the error they
+/// fail with, and readers for the patches other tools write. A `ParseError`
+/// reaches the rest of the crate as `InkError::Parse`.
+use crate::diff::edit::{Edit, Operation};
 use std::error::Error;
+use std::fmt;
 
-/// Parse a usize from a string, returning the reamaining string and the usize
-pub fn read_usize<'a>(input: &'a str) -> Result<(&'a str, usize), Box<dyn Error>> {
-    let mut boundary = 0;
+/// Where an edit script stops making sense, and what was expected there
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    // bytes from the error to the end of the script, which stays the same
+    // however much of the script before it the error is located in
+    rest: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+    expected: &'static str,
+    found: String,
+}
+
+impl ParseError {
+    /// An error at the start of `input`, which runs to the end of the script
+    pub(crate) fn new(input: &str, expected: &'static str) -> ParseError {
+        let found = input.split('\n').next().unwrap_or("");
+        let found = match found.char_indices().nth(FOUND_CHARS) {
+            Some((end, _)) => format!("{}...", &found[..end]),
+            None => found.to_string(),
+        };
+
+        ParseError {
+            rest: input.len(),
+            offset: 0,
+            line: 1,
+            column: 1,
+            expected,
+            found,
+        }
+        .locate(input)
+    }
+
+    /// Find the error in `script`, which ends where the input it was found
+    /// in did
+    pub(crate) fn locate(mut self, script: &str) -> ParseError {
+        self.offset = script.len().saturating_sub(self.rest);
+        let before = &script[..self.offset];
+        self.line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        self.column = before[line_start..].chars().count() + 1;
+        self
+    }
+
+    /// Byte offset of the error in the script
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Line of the error, counting from 1
+    pub fn line(&self) -> usize {
+        self.line
+    }
 
-    for (index, c) in input.char_indices() {
-        if !c.is_ascii_digit() {
-            boundary = index;
-            break;
+    /// Column of the error in characters, counting from 1
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    /// What should have been at the error
+    pub fn expected(&self) -> &'static str {
+        self.expected
+    }
+}
+
+// how much of what was found is shown
+const FOUND_CHARS: usize = 30;
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: expected {}, ",
+            self.line, self.column, self.expected
+        )?;
+        if self.rest == 0 {
+            write!(f, "but the script ended")
+        } else {
+            write!(f, "found {:?}", self.found)
         }
     }
+}
+
+impl Error for ParseError {}
+
+/// Parse a usize from a string, returning the reamaining string and the usize
+pub fn read_usize(input: &str) -> Result<(&str, usize), ParseError> {
+    let boundary = input
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(input.len());
 
-    let number = input[..boundary].parse::<usize>()?;
+    let number = input[..boundary]
+        .parse::<usize>()
+        .map_err(|_| ParseError::new(input, "a line number"))?;
     let remainder = &input[boundary..];
 
     Ok((remainder, number))
 }
 
-/// Parse lines from a string, returning the remaining string and a vec of lines
-/// If there are no newlines in the string, it will assume the entire string is one line
+/// Parse lines from a string, returning the remaining string and a vec of lines.
+/// The last line of the string counts as a line without a newline after it,
 /// as a workaround for how lines are dealt with in the diff module
-pub fn read_lines<'a>(
-    input: &'a str,
-    num_lines: usize,
-) -> Result<(&'a str, Vec<&'a str>), Box<dyn Error>> {
-    let mut counted_newlines = 0;
+pub fn read_lines(input: &str, num_lines: usize) -> Result<(&str, Vec<&str>), ParseError> {
     let mut lines = Vec::with_capacity(num_lines);
-    let mut prev_newline_index = 0;
-
-    // count newline characters until we reach the amount of lines specified or we're through the whole string.
-    for (index, c) in input.char_indices() {
-        if c == '\n' {
-            counted_newlines += 1;
-            lines.push(&input[prev_newline_index..index]);
-            prev_newline_index = index + 1;
+    let mut remainder = input;
 
-            if counted_newlines == num_lines {
-                break;
+    while lines.len() < num_lines {
+        if remainder.is_empty() {
+            return Err(ParseError::new(remainder, "another line of content"));
+        }
+        match remainder.find('\n') {
+            Some(end) => {
+                lines.push(&remainder[..end]);
+                remainder = &remainder[end + 1..];
+            }
+            None => {
+                lines.push(remainder);
+                remainder = "";
             }
         }
     }
 
-    // if we had no newlines, treat the entire string as one big line. Otherwise, return the lines found.
-    if prev_newline_index != 0 {
-        let remainder = &input[prev_newline_index..];
-        Ok((remainder, lines))
-    } else {
-        lines.push(input);
-        Ok(("", lines))
-    }
+    Ok((remainder, lines))
 }
 
 /// Skips the given string in the input, returning the remaining string.
-pub fn skip_sequence<'a>(input: &'a str, sequence: &str) -> Result<&'a str, Box<dyn Error>> {
-    let val = input
+pub fn skip_sequence<'a>(
+    input: &'a str,
+    sequence: &str,
+    expected: &'static str,
+) -> Result<&'a str, ParseError> {
+    input
         .strip_prefix(sequence)
-        .ok_or("Sequence not found at beginning of input string")?;
+        .ok_or_else(|| ParseError::new(input, expected))
+}
+
+/// Split the first line off the input, as `read_lines` does
+fn read_line(input: &str) -> Result<(&str, &str), ParseError> {
+    let (rest, lines) = read_lines(input, 1)?;
+    Ok((rest, lines[0]))
+}
+
+/// Read a unified diff of one file, as `diff -u` and `git diff` write it.
+/// Anything before the first hunk, like the `---` and `+++` lines, is
+/// skipped, as are `\ No newline at end of file` markers. Context lines are
+/// not checked against the file.
+pub fn read_unified(patch: &str) -> Result<Vec<Edit>, ParseError> {
+    let mut edits = Vec::new();
+    let mut remainder = patch;
+    let mut hunks = 0;
+    let mut in_file = false;
+    // where the last hunk ended in the original file
+    let mut original_end = 0;
+
+    while !remainder.is_empty() {
+        let (rest, line) = read_line(remainder)?;
+        if line.starts_with("@@ ") {
+            let (rest, start, end) = read_hunk(remainder, &mut edits)?;
+            if start < original_end {
+                return Err(ParseError::new(
+                    remainder,
+                    "hunks in order, without overlapping",
+                ));
+            }
+            original_end = end;
+            hunks += 1;
+            remainder = rest;
+        } else {
+            // a second file starts with another header
+            if line.starts_with("--- ") {
+                if in_file {
+                    return Err(ParseError::new(remainder, "a patch of only one file"));
+                }
+                in_file = true;
+            }
+            remainder = rest;
+        }
+    }
+
+    if hunks == 0 && !patch.is_empty() {
+        return Err(ParseError::new(patch, "a hunk starting with `@@ `"));
+    }
+    Ok(edits)
+}
+
+/// Read one hunk of a unified diff, adding its edits. Returns the remaining
+/// string, and the lines of the original file the hunk covers.
+fn read_hunk<'a>(
+    input: &'a str,
+    edits: &mut Vec<Edit>,
+) -> Result<(&'a str, usize, usize), ParseError> {
+    let header = input;
+    let bad_header = || ParseError::new(header, "a hunk header like `@@ -3,2 +3,1 @@`");
+    let input = skip_sequence(input, "@@ -", "a hunk header starting with `@@ -`")?;
+    let (input, mut original_line, mut original_left) =
+        read_range(input).map_err(|_| bad_header())?;
+    let input =
+        skip_sequence(input, " +", "` +` before the new lines").map_err(|_| bad_header())?;
+    let (input, mut modified_line, mut modified_left) =
+        read_range(input).map_err(|_| bad_header())?;
+    if !input.starts_with(" @@") {
+        return Err(bad_header());
+    }
+    // the rest of the header may name the function the hunk is in
+    let (mut input, _) = read_line(input)?;
+    let start = original_line;
+
+    // the lines removed and added since the last context line, and where
+    let mut original = Vec::new();
+    let mut modified = Vec::new();
+    let mut at = (original_line, modified_line);
+    let mut flush = |original: &mut Vec<String>, modified: &mut Vec<String>, at: (usize, usize)| {
+        edits.extend(Edit::between(
+            at.0,
+            at.1,
+            std::mem::take(original),
+            std::mem::take(modified),
+        ));
+    };
+
+    while original_left > 0 || modified_left > 0 {
+        if input.is_empty() {
+            return Err(ParseError::new(
+                input,
+                "as many lines as the hunk header gives",
+            ));
+        }
+        let (rest, line) = read_line(input)?;
+        // some tools trim the space off empty context lines
+        let (marker, content) = match line.chars().next() {
+            Some(marker) => (marker, &line[marker.len_utf8()..]),
+            None => (' ', ""),
+        };
+        match marker {
+            ' ' if original_left > 0 && modified_left > 0 => {
+                flush(&mut original, &mut modified, at);
+                original_left -= 1;
+                modified_left -= 1;
+                original_line += 1;
+                modified_line += 1;
+                at = (original_line, modified_line);
+            }
+            '-' if original_left > 0 => {
+                original.push(content.to_string());
+                original_left -= 1;
+                original_line += 1;
+            }
+            '+' if modified_left > 0 => {
+                modified.push(content.to_string());
+                modified_left -= 1;
+                modified_line += 1;
+            }
+            '\\' => {}
+            ' ' | '-' | '+' => {
+                return Err(ParseError::new(
+                    input,
+                    "as many lines as the hunk header gives",
+                ))
+            }
+            _ => {
+                return Err(ParseError::new(
+                    input,
+                    "a line of the hunk starting with ` `, `-` or `+`",
+                ))
+            }
+        }
+        input = rest;
+    }
+    flush(&mut original, &mut modified, at);
 
-    Ok(val)
+    // the last line of either file may have no newline
+    while input.starts_with('\\') {
+        input = read_line(input)?.0;
+    }
+
+    Ok((input, start, original_line))
+}
+
+/// Read the start and length of one side of a hunk header, like `3,2`,
+/// returning the line the side starts at counting from 0
+fn read_range(input: &str) -> Result<(&str, usize, usize), ParseError> {
+    let (input, start) = read_usize(input)?;
+    let (input, len) = match input.strip_prefix(',') {
+        Some(input) => read_usize(input)?,
+        None => (input, 1),
+    };
+
+    // with no lines, the start is the line before the hunk counting from 1,
+    // which is the line after it counting from 0
+    let line = match (start, len) {
+        (start, 0) => start,
+        (0, _) => return Err(ParseError::new(input, "a line number from 1")),
+        (start, _) => start - 1,
+    };
+    Ok((input, line, len))
+}
+
+/// Read an ed script as `diff -e` writes it: `a`, `c` and `d` commands from
+/// the end of the file to its start. The script doesn't hold the lines it
+/// deletes or changes, so they are taken from `original`, the file it is for.
+pub fn read_ed<S: AsRef<str>>(script: &str, original: &[S]) -> Result<Vec<Edit>, ParseError> {
+    let mut edits: Vec<Edit> = Vec::new();
+    let mut remainder = script;
+
+    while !remainder.is_empty() {
+        let command = remainder;
+        let (rest, line) = read_line(remainder)?;
+        // a script written to run in ed ends by writing the file
+        if matches!(line, "w" | "q" | "wq") {
+            remainder = rest;
+            continue;
+        }
+
+        let (input, first) = read_usize(command)?;
+        let (input, last) = match input.strip_prefix(',') {
+            Some(input) => read_usize(input)?,
+            None => (input, first),
+        };
+        let op = match input.chars().next() {
+            Some('a') => Operation::Insert,
+            Some('c') => Operation::Replace,
+            Some('d') => Operation::Delete,
+            _ => return Err(ParseError::new(input, "a command, `a`, `c` or `d`")),
+        };
+        if !matches!(input[1..].chars().next(), None | Some('\n')) {
+            return Err(ParseError::new(&input[1..], "the end of the command"));
+        }
+
+        // lines are appended after the line given, and changed or deleted
+        // from the first line given to the last
+        let (start, end) = match op {
+            Operation::Insert => (first, first),
+            _ => (first.wrapping_sub(1), last),
+        };
+        if start > end || end > original.len() || (op == Operation::Insert && first != last) {
+            return Err(ParseError::new(command, "lines that are in the file"));
+        }
+        if let Some(later) = edits.last() {
+            let after = later.original.line;
+            if end > after || (end == after && op == Operation::Insert && later.op == op) {
+                return Err(ParseError::new(
+                    command,
+                    "commands from the end of the file to its start",
+                ));
+            }
+        }
+        remainder = rest;
+
+        let mut added = Vec::new();
+        if op != Operation::Delete {
+            loop {
+                if remainder.is_empty() {
+                    return Err(ParseError::new(
+                        remainder,
+                        "a line of just `.` ending the text",
+                    ));
+                }
+                let (rest, line) = read_line(remainder)?;
+                remainder = rest;
+                if line == "." {
+                    break;
+                }
+                added.push(line.to_string());
+            }
+        }
+
+        let removed = original[start..end]
+            .iter()
+            .map(|line| line.as_ref().to_string())
+            .collect();
+        let edit = Edit::new(op, start, 0, removed, added)
+            .map_err(|_| ParseError::new(command, "a command that changes at least one line"))?;
+        edits.push(edit);
+    }
+
+    // the commands were from the end, and each edit moves the lines after it
+    // in the modified file
+    edits.reverse();
+    let mut shift = 0isize;
+    for edit in &mut edits {
+        edit.modified.line = (edit.original.line as isize + shift) as usize;
+        shift += edit.modified.content.len() as isize - edit.original.content.len() as isize;
+    }
+
+    Ok(edits)
 }
 
 #[cfg(test)]
 mod tests {
+    use super::ParseError;
+    use crate::diff::Operation;
+
     #[test]
     fn read_usize() {
         let hello = "123456hi";
-        assert_eq!(super::read_usize(hello).unwrap(), ("hi", 123456))
+        assert_eq!(super::read_usize(hello).unwrap(), ("hi", 123456));
+        assert_eq!(super::read_usize("42").unwrap(), ("", 42));
+        assert!(super::read_usize("x1").is_err());
     }
 
     #[test]
@@ -76,12 +411,82 @@ mod tests {
         assert_eq!(
             super::read_lines(ex, 3).unwrap(),
             ("so cool", vec!["hello", "I am ", ""])
-        )
+        );
+        assert_eq!(
+            super::read_lines("one\ntwo", 2).unwrap(),
+            ("", vec!["one", "two"])
+        );
+        assert!(super::read_lines("one\n", 2).is_err());
     }
 
     #[test]
     fn skip_sequence() {
         let ex = ",.123";
-        assert_eq!(super::skip_sequence(ex, ",.").unwrap(), "123")
+        assert_eq!(super::skip_sequence(ex, ",.", "a comma").unwrap(), "123")
+    }
+
+    #[test]
+    fn errors_know_where_they_are() {
+        let script = "first line\nsecond ñ line\nthird";
+        let err = ParseError::new(&script[21..], "a thing").locate(script);
+        assert_eq!((err.offset(), err.line(), err.column()), (21, 2, 10));
+        assert_eq!(
+            err.to_string(),
+            "line 2, column 10: expected a thing, found \"line\""
+        );
+
+        let err = ParseError::new("", "more").locate(script);
+        assert_eq!((err.line(), err.column()), (3, 6));
+        assert_eq!(
+            err.to_string(),
+            "line 3, column 6: expected more, but the script ended"
+        );
+    }
+
+    #[test]
+    fn unified_diffs() {
+        let patch = "diff --git a/f b/f\n--- a/f\n+++ b/f\n@@ -2,2 +2,0 @@ fn main\n-a\n-b\n\\ No newline at end of file\n@@ -9 +7,2 @@\n \n+c\n";
+        let edits = super::read_unified(patch).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].op, Operation::Delete);
+        assert_eq!((edits[0].original.line, edits[0].modified.line), (1, 2));
+        assert_eq!(edits[1].op, Operation::Insert);
+        assert_eq!((edits[1].original.line, edits[1].modified.line), (9, 7));
+        assert_eq!(edits[1].modified.content, ["c"]);
+        assert!(super::read_unified("").unwrap().is_empty());
+
+        for patch in &[
+            "not a patch\n",
+            "@@ -1,2 +1,1 @@\n-a\n",
+            "@@ -1,1 +1,1 @@\n-a\n-b\n",
+            "@@ -1 +1 x\n-a\n+b\n",
+            "@@ -0,1 +1 @@\n-a\n+b\n",
+            "@@ -1 +1 @@\n*a\n",
+            "@@ -5 +5 @@\n-a\n+b\n@@ -1 +1 @@\n-a\n+b\n",
+            "--- a\n+++ a\n@@ -1 +1 @@\n-a\n+b\n--- b\n+++ b\n@@ -1 +1 @@\n-a\n+b\n",
+        ] {
+            assert!(super::read_unified(patch).is_err(), "{:?}", patch);
+        }
+    }
+
+    #[test]
+    fn ed_scripts() {
+        let original = ["a", "b", "c"];
+        let edits = super::read_ed("3d\n1,2c\nx\n.\nw\nq\n", &original).unwrap();
+        assert_eq!(edits.len(), 2);
+        assert_eq!(edits[0].original.content, ["a", "b"]);
+        assert_eq!(edits[1].op, Operation::Delete);
+        assert_eq!((edits[1].original.line, edits[1].modified.line), (2, 1));
+
+        for script in &[
+            "4d\n",
+            "0c\nx\n.\n",
+            "1a\nx\n",
+            "1d\n3d\n",
+            "1s/a/b/\n",
+            "1dd\n",
+        ] {
+            assert!(super::read_ed(script, &original).is_err(), "{:?}", script);
+        }
     }
 }
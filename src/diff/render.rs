@@ -0,0 +1,594 @@
+//! Turning diffs into text meant to be read by people
+use crate::diff::edit::Edit;
+use crate::diff::Diff;
+
+/// Lines of unchanged content shown around each change
+const CONTEXT: usize = 3;
+
+/// Render a diff in the unified format used by `diff -u`.
+/// Needs the original file's lines to show context around each change.
+pub fn unified<S: AsRef<str>>(
+    diff: &Diff,
+    original: &[S],
+    original_name: &str,
+    modified_name: &str,
+) -> String {
+    if diff.edits.is_empty() {
+        return String::new();
+    }
+
+    let mut out = format!("--- {}\n+++ {}\n", original_name, modified_name);
+
+    for hunk in hunks(&diff.edits, original.len(), CONTEXT) {
+        out += &format!("{}\n", hunk.header());
+
+        let mut line = hunk.og_start;
+        for edit in hunk.edits {
+            for context in &original[line..edit.original.line] {
+                out += &format!(" {}\n", context.as_ref());
+            }
+
+            for removed in &edit.original.content {
+                out += &format!("-{}\n", removed);
+            }
+
+            for added in &edit.modified.content {
+                out += &format!("+{}\n", added);
+            }
+
+            line = original_end(edit);
+        }
+
+        for context in &original[line..hunk.og_end] {
+            out += &format!(" {}\n", context.as_ref());
+        }
+    }
+
+    out
+}
+
+/// Render a diff as two columns, the original on the left and the modified file
+/// on the right, fitted to a terminal `width` columns wide.
+/// Needs the original file's lines to show context around each change.
+///
+/// Like `diff -y`, the gutter between the columns marks changed lines with `|`,
+/// removed lines with `<` and added lines with `>`. Lines too long for their
+/// column are cut off.
+pub fn side_by_side<S: AsRef<str>>(
+    diff: &Diff,
+    original: &[S],
+    original_name: &str,
+    modified_name: &str,
+    width: usize,
+) -> String {
+    if diff.edits.is_empty() {
+        return String::new();
+    }
+
+    // each column gets half of what is left after the gutter
+    let column = width.saturating_sub(3) / 2;
+    let row = |left: &str, mark: char, right: &str| {
+        let line = format!("{} {} {}", fit(left, column), mark, fit(right, column));
+        line.trim_end().to_string() + "\n"
+    };
+
+    let mut out = row(original_name, ' ', modified_name);
+    out += &format!("{}\n", "=".repeat(column * 2 + 3));
+
+    for hunk in hunks(&diff.edits, original.len(), CONTEXT) {
+        out += &format!("{}\n", hunk.header());
+
+        let mut line = hunk.og_start;
+        for edit in hunk.edits {
+            for context in &original[line..edit.original.line] {
+                out += &row(context.as_ref(), ' ', context.as_ref());
+            }
+
+            let removed = &edit.original.content;
+            let added = &edit.modified.content;
+            for index in 0..std::cmp::max(removed.len(), added.len()) {
+                out += &match (removed.get(index), added.get(index)) {
+                    (Some(old), Some(new)) => row(old, '|', new),
+                    (Some(old), None) => row(old, '<', ""),
+                    (None, new) => row("", '>', new.map_or("", |s| s.as_str())),
+                };
+            }
+
+            line = original_end(edit);
+        }
+
+        for context in &original[line..hunk.og_end] {
+            out += &row(context.as_ref(), ' ', context.as_ref());
+        }
+    }
+
+    out
+}
+
+/// Pad or cut text to exactly `width` terminal columns, expanding tabs to
+/// every eighth column as a terminal would
+fn fit(text: &str, width: usize) -> String {
+    let mut fitted = String::with_capacity(width);
+    let mut len = 0;
+
+    for c in text.chars() {
+        if c == '\t' {
+            let stop = std::cmp::min(width, (len / 8 + 1) * 8);
+            while len < stop {
+                fitted.push(' ');
+                len += 1;
+            }
+        } else if len + display_width(c) <= width {
+            fitted.push(c);
+            len += display_width(c);
+        } else {
+            // a wide character cut in half is left out
+            break;
+        }
+
+        if len >= width {
+            break;
+        }
+    }
+
+    fitted + &" ".repeat(width - len)
+}
+
+/// How many columns a terminal shows a character in: none for combining
+/// marks and control characters, two for East Asian wide characters and
+/// emoji, and one for everything else
+fn display_width(c: char) -> usize {
+    match c as u32 {
+        0..=0x1f | 0x7f..=0x9f => 0,
+        0x0300..=0x036f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+/// How `to_html` lays out a diff
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HtmlLayout {
+    /// One column, with removed lines above the lines that replace them
+    Inline,
+    /// The original on the left and the modified file on the right
+    SideBySide,
+}
+
+/// Options for `to_html`
+pub struct HtmlOptions<'a> {
+    pub layout: HtmlLayout,
+    /// Lines of unchanged content shown around each change
+    pub context: usize,
+    /// Turns the text of a line into HTML, e.g. with syntax highlighting.
+    /// Its output is inserted as is, so it must escape the text itself.
+    /// Lines are only escaped when this is not set.
+    pub highlight: Option<&'a dyn Fn(&str) -> String>,
+}
+
+impl Default for HtmlOptions<'_> {
+    fn default() -> Self {
+        HtmlOptions {
+            layout: HtmlLayout::Inline,
+            context: CONTEXT,
+            highlight: None,
+        }
+    }
+}
+
+/// Render a diff as an HTML table, one `tbody` per hunk.
+/// Needs the original file's lines to show context around each change.
+///
+/// Rows have the class `context`, `removed`, `added` or `changed` (side by
+/// side only), and the cells holding line numbers have the class `number`,
+/// so the output can be styled with CSS.
+pub fn to_html<S: AsRef<str>>(diff: &Diff, original: &[S], options: &HtmlOptions) -> String {
+    let columns = match options.layout {
+        HtmlLayout::Inline => "inline",
+        HtmlLayout::SideBySide => "side-by-side",
+    };
+    let mut out = format!("<table class=\"ink-diff {}\">\n", columns);
+    let html = HtmlRows {
+        options,
+        out: &mut out,
+    };
+    html.hunks(diff, original);
+    out += "</table>\n";
+    out
+}
+
+struct HtmlRows<'a, 'o> {
+    options: &'a HtmlOptions<'o>,
+    out: &'a mut String,
+}
+
+impl HtmlRows<'_, '_> {
+    fn line(&self, text: &str) -> String {
+        match self.options.highlight {
+            Some(highlight) => highlight(text),
+            None => escape(text),
+        }
+    }
+
+    fn hunks<S: AsRef<str>>(mut self, diff: &Diff, original: &[S]) {
+        let span = match self.options.layout {
+            HtmlLayout::Inline => 3,
+            HtmlLayout::SideBySide => 4,
+        };
+
+        for hunk in hunks(&diff.edits, original.len(), self.options.context) {
+            *self.out += &format!(
+                "<tbody>\n<tr class=\"hunk\"><td colspan=\"{}\">{}</td></tr>\n",
+                span,
+                escape(&hunk.header())
+            );
+
+            let mut line = hunk.og_start;
+            let mut offset = hunk.mod_start as isize - hunk.og_start as isize;
+            for edit in hunk.edits {
+                self.context(original, line, edit.original.line, offset);
+                self.edit(edit);
+
+                line = original_end(edit);
+                offset = modified_end(edit) as isize - line as isize;
+            }
+            self.context(original, line, hunk.og_end, offset);
+
+            *self.out += "</tbody>\n";
+        }
+    }
+
+    fn context<S: AsRef<str>>(&mut self, original: &[S], from: usize, to: usize, offset: isize) {
+        for (line, text) in original.iter().enumerate().take(to).skip(from) {
+            let text = self.line(text.as_ref());
+            let modified_line = (line as isize + offset) as usize;
+            *self.out += &match self.options.layout {
+                HtmlLayout::Inline => format!(
+                    "<tr class=\"context\"><td class=\"number\">{}</td><td class=\"number\">{}</td><td>{}</td></tr>\n",
+                    line + 1,
+                    modified_line + 1,
+                    text
+                ),
+                HtmlLayout::SideBySide => format!(
+                    "<tr class=\"context\"><td class=\"number\">{}</td><td>{}</td><td class=\"number\">{}</td><td>{}</td></tr>\n",
+                    line + 1,
+                    text,
+                    modified_line + 1,
+                    text
+                ),
+            };
+        }
+    }
+
+    fn edit(&mut self, edit: &Edit) {
+        let removed = &edit.original.content;
+        let added = &edit.modified.content;
+
+        match self.options.layout {
+            HtmlLayout::Inline => {
+                for (index, text) in removed.iter().enumerate() {
+                    *self.out += &format!(
+                        "<tr class=\"removed\"><td class=\"number\">{}</td><td class=\"number\"></td><td>{}</td></tr>\n",
+                        edit.original.line + index + 1,
+                        self.line(text)
+                    );
+                }
+                for (index, text) in added.iter().enumerate() {
+                    *self.out += &format!(
+                        "<tr class=\"added\"><td class=\"number\"></td><td class=\"number\">{}</td><td>{}</td></tr>\n",
+                        edit.modified.line + index + 1,
+                        self.line(text)
+                    );
+                }
+            }
+            HtmlLayout::SideBySide => {
+                for index in 0..std::cmp::max(removed.len(), added.len()) {
+                    let (class, left, right) = match (removed.get(index), added.get(index)) {
+                        (Some(old), Some(new)) => ("changed", Some(old), Some(new)),
+                        (Some(old), None) => ("removed", Some(old), None),
+                        (None, new) => ("added", None, new),
+                    };
+
+                    let left = match left {
+                        Some(text) => (
+                            (edit.original.line + index + 1).to_string(),
+                            self.line(text),
+                        ),
+                        None => (String::new(), String::new()),
+                    };
+                    let right = match right {
+                        Some(text) => (
+                            (edit.modified.line + index + 1).to_string(),
+                            self.line(text),
+                        ),
+                        None => (String::new(), String::new()),
+                    };
+
+                    *self.out += &format!(
+                        "<tr class=\"{}\"><td class=\"number\">{}</td><td>{}</td><td class=\"number\">{}</td><td>{}</td></tr>\n",
+                        class, left.0, left.1, right.0, right.1
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Escape text for use in HTML
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped += "&amp;",
+            '<' => escaped += "&lt;",
+            '>' => escaped += "&gt;",
+            '"' => escaped += "&quot;",
+            '\'' => escaped += "&#39;",
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A group of edits shown together, with the range of lines it covers in
+/// each file including context
+struct Hunk<'a> {
+    edits: &'a [Edit],
+    og_start: usize,
+    og_end: usize,
+    mod_start: usize,
+    mod_end: usize,
+}
+
+impl Hunk<'_> {
+    fn header(&self) -> String {
+        format!(
+            "@@ -{} +{} @@",
+            range(self.og_start, self.og_end - self.og_start),
+            range(self.mod_start, self.mod_end - self.mod_start)
+        )
+    }
+}
+
+fn original_end(edit: &Edit) -> usize {
+    edit.original.line + edit.original.content.len()
+}
+
+fn modified_end(edit: &Edit) -> usize {
+    edit.modified.line + edit.modified.content.len()
+}
+
+/// Format a hunk range, which counts from 1 and points at the line before
+/// the change when it covers no lines
+fn range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+/// Group edits whose context would overlap into the same hunk
+fn hunks(edits: &[Edit], original_len: usize, context: usize) -> Vec<Hunk<'_>> {
+    let mut groups = Vec::new();
+    let mut start = 0;
+
+    for index in 1..edits.len() {
+        let gap = edits[index].original.line - original_end(&edits[index - 1]);
+        if gap > 2 * context {
+            groups.push(&edits[start..index]);
+            start = index;
+        }
+    }
+
+    if start < edits.len() {
+        groups.push(&edits[start..]);
+    }
+
+    groups
+        .into_iter()
+        .map(|edits| {
+            let first = &edits[0];
+            let last = &edits[edits.len() - 1];
+
+            // unchanged lines line up on both sides, so the context before the
+            // first edit is the same length in both files
+            let lead = std::cmp::min(context, first.original.line);
+            let og_end = std::cmp::min(original_len, original_end(last) + context);
+            let trail = og_end - original_end(last);
+
+            Hunk {
+                edits,
+                og_start: first.original.line - lead,
+                og_end,
+                mod_start: first.modified.line - lead,
+                mod_end: modified_end(last) + trail,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unified_diff() {
+        const A: [&str; 8] = [
+            "The small cactus sat in a",
+            "pot full of sand and dirt",
+            "",
+            "Next to it was a small basil",
+            "plant in a similar pot",
+            "",
+            "Everyday, the plants got plenty",
+            "of sunshine and water",
+        ];
+
+        const B: [&str; 9] = [
+            "The small green cactus sat in a",
+            "pot full of sand and dirt",
+            "",
+            "In another part of the house,",
+            "another house plant grew in a",
+            "much bigger pot",
+            "",
+            "Everyday, the plants got plenty",
+            "of water and sunshine",
+        ];
+
+        let diff = Diff::from(&A, &B);
+
+        let expected = [
+            "--- a",
+            "+++ b",
+            "@@ -1,8 +1,9 @@",
+            "-The small cactus sat in a",
+            "+The small green cactus sat in a",
+            " pot full of sand and dirt",
+            " ",
+            "-Next to it was a small basil",
+            "-plant in a similar pot",
+            "+In another part of the house,",
+            "+another house plant grew in a",
+            "+much bigger pot",
+            " ",
+            " Everyday, the plants got plenty",
+            "-of sunshine and water",
+            "+of water and sunshine",
+            "",
+        ];
+
+        assert_eq!(unified(&diff, &A, "a", "b"), expected.join("\n"));
+    }
+
+    #[test]
+    fn unified_diff_separate_hunks() {
+        let a: Vec<String> = (0..20).map(|n| n.to_string()).collect();
+        let mut b = a.clone();
+        b.remove(1);
+        b.insert(15, "new".to_string());
+
+        let diff = Diff::from(&a, &b);
+
+        let expected = [
+            "--- a",
+            "+++ b",
+            "@@ -1,5 +1,4 @@",
+            " 0",
+            "-1",
+            " 2",
+            " 3",
+            " 4",
+            "@@ -14,6 +13,7 @@",
+            " 13",
+            " 14",
+            " 15",
+            "+new",
+            " 16",
+            " 17",
+            " 18",
+            "",
+        ];
+
+        assert_eq!(unified(&diff, &a, "a", "b"), expected.join("\n"));
+    }
+
+    #[test]
+    fn fit_measures_columns() {
+        assert_eq!(fit("日本語", 5), "日本 ");
+        assert_eq!(fit("日本語", 6), "日本語");
+        assert_eq!(fit("e\u{301}tude", 4), "e\u{301}tud");
+        assert_eq!(fit("a\tb", 10), "a       b ");
+    }
+
+    #[test]
+    fn side_by_side_columns() {
+        let a = ["one", "two", "a long line that will not fit", "four"];
+        let b = ["one", "2", "a long line that will not fit", "\tfive"];
+        let diff = Diff::from(&a, &b);
+
+        let expected = [
+            "a           b",
+            "=====================",
+            "@@ -1,4 +1,4 @@",
+            "one         one",
+            "two       | 2",
+            "a long li   a long li",
+            "four      |         f",
+            "",
+        ];
+
+        assert_eq!(side_by_side(&diff, &a, "a", "b", 21), expected.join("\n"));
+    }
+
+    #[test]
+    fn html_inline() {
+        let a = ["one", "<two>", "three"];
+        let b = ["one", "2", "three"];
+        let diff = Diff::from(&a, &b);
+
+        let expected = [
+            "<table class=\"ink-diff inline\">",
+            "<tbody>",
+            "<tr class=\"hunk\"><td colspan=\"3\">@@ -1,3 +1,3 @@</td></tr>",
+            "<tr class=\"context\"><td class=\"number\">1</td><td class=\"number\">1</td><td>one</td></tr>",
+            "<tr class=\"removed\"><td class=\"number\">2</td><td class=\"number\"></td><td>&lt;two&gt;</td></tr>",
+            "<tr class=\"added\"><td class=\"number\"></td><td class=\"number\">2</td><td>2</td></tr>",
+            "<tr class=\"context\"><td class=\"number\">3</td><td class=\"number\">3</td><td>three</td></tr>",
+            "</tbody>",
+            "</table>",
+            "",
+        ];
+
+        assert_eq!(
+            to_html(&diff, &a, &HtmlOptions::default()),
+            expected.join("\n")
+        );
+    }
+
+    #[test]
+    fn html_side_by_side_with_highlighter() {
+        let a = ["one", "two", "three", "four"];
+        let b = ["zero", "one", "2", "three"];
+        let diff = Diff::from(&a, &b);
+
+        let highlight = |line: &str| format!("<b>{}</b>", escape(line));
+        let options = HtmlOptions {
+            layout: HtmlLayout::SideBySide,
+            context: 0,
+            highlight: Some(&highlight),
+        };
+        let html = to_html(&diff, &a, &options);
+
+        assert_eq!(html.matches("<tbody>").count(), 3);
+        assert!(html.contains(
+            "<tr class=\"added\"><td class=\"number\"></td><td></td><td class=\"number\">1</td><td><b>zero</b></td></tr>"
+        ));
+        assert!(html.contains(
+            "<tr class=\"changed\"><td class=\"number\">2</td><td><b>two</b></td><td class=\"number\">3</td><td><b>2</b></td></tr>"
+        ));
+        assert!(html.contains(
+            "<tr class=\"removed\"><td class=\"number\">4</td><td><b>four</b></td><td class=\"number\"></td><td></td></tr>"
+        ));
+    }
+}
@@ -0,0 +1,343 @@
+//! Version 2 of the edit script format.
+//!
+//! A script starts with a version line, then optional header lines naming
+//! the file and the SHA-256 hashes of its content before and after the
+//! edits, then one hunk per edit:
+//!
+//! ```text
+//! ink edit script 2
+//! path src/main.rs
+//! original 5d41402abc4b2a76b9719d911017c592...
+//! modified 7d793037a0760186574b0282f2f435e7...
+//! @ 3 2 r 3 1
+//! -an old line
+//! -another old line
+//! +the new line
+//! ```
+//!
+//! A hunk header gives the first line and number of lines of each side, and
+//! the operation as in version 1. Every content line starts with `-` or `+`
+//! and has `\`, newlines and carriage returns escaped as `\\`, `\n` and
+//! `\r`, so any content reads back as it was written, whatever it starts
+//! with. Every line of the script ends with a newline.
+use crate::diff::edit::{Edit, HalfEdit, Operation};
+use crate::diff::parser::ParseError;
+
+use std::fmt::Write;
+
+/// The first line of a version 2 script
+pub(crate) const VERSION_LINE: &str = "ink edit script 2";
+
+/// What a version 2 script says about the file its edits are to. Every field
+/// is optional; scripts in version 1 have none.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ScriptHeader {
+    pub path: Option<String>,
+    /// SHA-256 of the content before the edits
+    pub original_hash: Option<[u8; 32]>,
+    /// SHA-256 of the content after the edits
+    pub modified_hash: Option<[u8; 32]>,
+}
+
+impl ScriptHeader {
+    pub fn new() -> ScriptHeader {
+        ScriptHeader::default()
+    }
+
+    pub fn path<S: Into<String>>(mut self, path: S) -> ScriptHeader {
+        self.path = Some(path.into());
+        self
+    }
+
+    pub fn original_hash(mut self, hash: [u8; 32]) -> ScriptHeader {
+        self.original_hash = Some(hash);
+        self
+    }
+
+    pub fn modified_hash(mut self, hash: [u8; 32]) -> ScriptHeader {
+        self.modified_hash = Some(hash);
+        self
+    }
+}
+
+/// Write edits as a version 2 script
+pub(crate) fn write(edits: &[Edit], header: &ScriptHeader) -> String {
+    let mut script = format!("{}\n", VERSION_LINE);
+    if let Some(path) = &header.path {
+        let _ = writeln!(script, "path {}", escape(path));
+    }
+    if let Some(hash) = &header.original_hash {
+        let _ = writeln!(script, "original {}", to_hex(hash));
+    }
+    if let Some(hash) = &header.modified_hash {
+        let _ = writeln!(script, "modified {}", to_hex(hash));
+    }
+
+    for edit in edits {
+        let op = match edit.op {
+            Operation::Insert => 'a',
+            Operation::Delete => 'd',
+            Operation::Replace => 'r',
+        };
+        let _ = writeln!(
+            script,
+            "@ {} {} {} {} {}",
+            edit.original.line,
+            edit.original.content.len(),
+            op,
+            edit.modified.line,
+            edit.modified.content.len()
+        );
+        for line in &edit.original.content {
+            let _ = writeln!(script, "-{}", escape(line));
+        }
+        for line in &edit.modified.content {
+            let _ = writeln!(script, "+{}", escape(line));
+        }
+    }
+
+    script
+}
+
+/// Read a version 2 script, starting with its version line. Strictly, the
+/// edits are checked as by `Edit::check`.
+pub(crate) fn read(script: &str, strict: bool) -> Result<(Vec<Edit>, ScriptHeader), ParseError> {
+    let mut lines = Lines { rest: script };
+    lines.expect(VERSION_LINE, "the version line")?;
+
+    let mut header = ScriptHeader::default();
+    loop {
+        let at = lines.rest;
+        let (key, value) = match lines.peek().and_then(|line| line.split_once(' ')) {
+            Some((key, value)) if key != "@" => (key, value),
+            _ => break,
+        };
+        match key {
+            "path" => header.path = Some(unescape(value, &at[key.len() + 1..])?),
+            "original" => header.original_hash = Some(from_hex(value, at)?),
+            "modified" => header.modified_hash = Some(from_hex(value, at)?),
+            _ => return Err(ParseError::new(at, "`path`, `original` or `modified`")),
+        }
+        lines.next()?;
+    }
+
+    let mut edits: Vec<Edit> = Vec::new();
+    while !lines.rest.is_empty() {
+        let at = lines.rest;
+        let edit = read_hunk(&mut lines)?;
+        if strict {
+            edit.check(edits.last(), at)?;
+        }
+        edits.push(edit);
+    }
+
+    Ok((edits, header))
+}
+
+fn read_hunk(lines: &mut Lines) -> Result<Edit, ParseError> {
+    let at = lines.rest;
+    let header = lines.next()?;
+    let fields: Vec<&str> = header
+        .strip_prefix("@ ")
+        .ok_or_else(|| ParseError::new(at, "a hunk header starting with `@ `"))?
+        .split(' ')
+        .collect();
+    let number = |field: &str| {
+        field
+            .parse::<usize>()
+            .map_err(|_| ParseError::new(at, "a hunk header like `@ 3 2 r 3 1`"))
+    };
+    let (original_line, original_len, op, modified_line, modified_len) = match fields[..] {
+        [a, b, op, c, d] => (number(a)?, number(b)?, op, number(c)?, number(d)?),
+        _ => return Err(ParseError::new(at, "a hunk header like `@ 3 2 r 3 1`")),
+    };
+    let op = match op {
+        "a" => Operation::Insert,
+        "d" => Operation::Delete,
+        "r" => Operation::Replace,
+        _ => return Err(ParseError::new(at, "an operation, `a`, `d` or `r`")),
+    };
+
+    let mut content = |count: usize, marker: char, expected| {
+        let mut content = Vec::with_capacity(count);
+        for _ in 0..count {
+            let at = lines.rest;
+            let line = lines.next()?;
+            let line = line
+                .strip_prefix(marker)
+                .ok_or_else(|| ParseError::new(at, expected))?;
+            content.push(unescape(line, &at[1..])?);
+        }
+        Ok(content)
+    };
+    let original = content(original_len, '-', "an old line starting with `-`")?;
+    let modified = content(modified_len, '+', "a new line starting with `+`")?;
+
+    Ok(Edit {
+        op,
+        original: HalfEdit {
+            line: original_line,
+            content: original,
+        },
+        modified: HalfEdit {
+            line: modified_line,
+            content: modified,
+        },
+    })
+}
+
+/// The lines of a script, each of which must end with a newline
+struct Lines<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Lines<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.rest.find('\n').map(|end| &self.rest[..end])
+    }
+
+    fn next(&mut self) -> Result<&'a str, ParseError> {
+        let end = self
+            .rest
+            .find('\n')
+            .ok_or_else(|| ParseError::new(self.rest, "a line ending with a newline"))?;
+        let line = &self.rest[..end];
+        self.rest = &self.rest[end + 1..];
+        Ok(line)
+    }
+
+    fn expect(&mut self, expected_line: &str, expected: &'static str) -> Result<(), ParseError> {
+        let at = self.rest;
+        if self.next().ok() != Some(expected_line) {
+            return Err(ParseError::new(at, expected));
+        }
+        Ok(())
+    }
+}
+
+fn escape(line: &str) -> String {
+    let mut escaped = String::with_capacity(line.len());
+    for c in line.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Unescape part of a line of the script, which starts `at`
+fn unescape(part: &str, at: &str) -> Result<String, ParseError> {
+    let mut unescaped = String::with_capacity(part.len());
+    let mut chars = part.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some((_, '\\')) => unescaped.push('\\'),
+            Some((_, 'n')) => unescaped.push('\n'),
+            Some((_, 'r')) => unescaped.push('\r'),
+            _ => {
+                return Err(ParseError::new(
+                    &at[i..],
+                    "an escape, `\\\\`, `\\n` or `\\r`",
+                ))
+            }
+        }
+    }
+    Ok(unescaped)
+}
+
+fn to_hex(hash: &[u8; 32]) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str, at: &str) -> Result<[u8; 32], ParseError> {
+    let invalid = || ParseError::new(at, "a hash of 64 hex digits");
+    if hex.len() != 64 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+    let mut hash = [0; 32];
+    for (i, byte) in hash.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).map_err(|_| invalid())?;
+    }
+    Ok(hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{Diff, ScriptHeader};
+
+    #[test]
+    fn awkward_lines_round_trip() {
+        let a = ["< looks like v1", "---", "plain", "back\\slash"];
+        let b = ["> also v1", "---", "plain", "carriage\r", "", "new\nline"];
+        let diff = Diff::from(&a, &b);
+
+        let header = ScriptHeader::new()
+            .path("dir/odd name\n")
+            .original_hash([0xab; 32])
+            .modified_hash([7; 32]);
+        let script = diff.edit_script_v2(&header);
+        assert!(script.starts_with("ink edit script 2\npath dir/odd name\\n\noriginal abababab"));
+        assert!(script.contains("\n-< looks like v1\n"));
+        assert!(script.contains("\n+new\\nline\n"));
+
+        let (read, read_header) = Diff::read_edit_script(&script).unwrap();
+        assert_eq!(read.edits(), diff.edits());
+        assert_eq!(read_header, header);
+        assert_eq!(
+            Diff::from_edit_script(&script).unwrap().edits(),
+            diff.edits()
+        );
+
+        let empty = Diff::from(&a, &a).edit_script_v2(&ScriptHeader::new());
+        assert_eq!(empty, "ink edit script 2\n");
+        assert!(Diff::from_edit_script(&empty).unwrap().edits().is_empty());
+    }
+
+    #[test]
+    fn still_reads_v1() {
+        let a = ["one", "two", "three"];
+        let b = ["one", "2", "three"];
+        let diff = Diff::from(&a, &b);
+
+        let (read, header) = Diff::read_edit_script(diff.edit_script()).unwrap();
+        assert_eq!(read.edits(), diff.edits());
+        assert_eq!(header, ScriptHeader::default());
+    }
+
+    #[test]
+    fn malformed_scripts() {
+        for script in &[
+            "ink edit script 2\n@ 0 1 r 0 1\n-a\n",
+            "ink edit script 2\n@ 0 1 r 0 1\n+a\n-b\n",
+            "ink edit script 2\n@ 0 1 x 0 1\n-a\n+b\n",
+            "ink edit script 2\ncolour blue\n",
+            "ink edit script 2\noriginal abc\n",
+            "ink edit script 2\n@ 0 1 r 0 1\n-\\q\n+b\n",
+            "ink edit script 2\n@ 0 1 r 0 1\n-a\n+b",
+        ] {
+            assert!(Diff::from_edit_script(script).is_err(), "{:?}", script);
+        }
+
+        let script = "ink edit script 2\npath a\n@ 0 1 r 0 1\n-one\n+t\\wo\n";
+        let err = Diff::read_edit_script(script).unwrap_err();
+        assert_eq!((err.line(), err.column()), (5, 3));
+        let err = Diff::read_edit_script("ink edit script 2\n@ 0 1 r 0 1\n-a\n-b\n").unwrap_err();
+        assert_eq!((err.line(), err.column(), err.offset()), (4, 1, 33));
+        assert_eq!(err.expected(), "a new line starting with `+`");
+
+        let script = "ink edit script 2\n@ 0 0 a 0 1\n+a\n@ 0 1 d 1 0\n-b\n";
+        assert!(Diff::read_edit_script(script).is_ok());
+        assert!(Diff::from_edit_script_strict(script).is_ok());
+        let script = "ink edit script 2\n@ 1 1 d 1 0\n-a\n@ 0 1 d 0 0\n-b\n";
+        let err = Diff::from_edit_script_strict(script).unwrap_err();
+        assert_eq!((err.line(), err.column()), (4, 1));
+    }
+}
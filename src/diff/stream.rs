@@ -0,0 +1,260 @@
+//! Diffing files too big to hold in memory, a block of lines at a time.
+//!
+//! Up to `block_lines` lines of each file are read into a window. Lines that
+//! appear exactly once in both windows are matched up, keeping the longest
+//! run of matches that are in the same order in both, as patience diff does,
+//! and the last match is taken as an anchor. Everything before the anchor is
+//! diffed with Myers, both windows move past it, and more lines are read.
+//! Windows with no anchor in them are diffed as a whole, which may miss
+//! lines that moved between blocks, so the diff is marked approximate. Only
+//! the two windows and the edits of one block are held at a time.
+use crate::diff::algo::myers;
+use crate::diff::edit::Edit;
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, Lines};
+
+/// How many lines of each file are read at once by default
+pub const DEFAULT_BLOCK_LINES: usize = 1 << 14;
+
+/// The edits between two readers, found a block at a time. Created by
+/// `Diff::stream`.
+pub struct EditStream<A, B> {
+    a: Side<A>,
+    b: Side<B>,
+    block_lines: usize,
+    // edits found but not yet returned
+    pending: VecDeque<Edit>,
+    approximate: bool,
+    finished: bool,
+}
+
+/// The lines of one file still to be diffed
+struct Side<R> {
+    lines: Lines<R>,
+    window: Vec<String>,
+    // line number of the first line in the window
+    start: usize,
+    done: bool,
+}
+
+impl<R: BufRead> Side<R> {
+    fn new(reader: R) -> Side<R> {
+        Side {
+            lines: reader.lines(),
+            window: Vec::new(),
+            start: 0,
+            done: false,
+        }
+    }
+
+    fn fill(&mut self, block_lines: usize) -> io::Result<()> {
+        while !self.done && self.window.len() < block_lines {
+            match self.lines.next() {
+                Some(line) => self.window.push(line?),
+                None => self.done = true,
+            }
+        }
+        Ok(())
+    }
+
+    /// Drop the first `count` lines of the window
+    fn advance(&mut self, count: usize) {
+        self.window.drain(..count);
+        self.start += count;
+    }
+}
+
+impl<A: BufRead, B: BufRead> EditStream<A, B> {
+    pub(crate) fn new(a: A, b: B) -> EditStream<A, B> {
+        EditStream {
+            a: Side::new(a),
+            b: Side::new(b),
+            block_lines: DEFAULT_BLOCK_LINES,
+            pending: VecDeque::new(),
+            approximate: false,
+            finished: false,
+        }
+    }
+
+    /// Read up to this many lines of each file at once. Bigger blocks use
+    /// more memory, but find anchors more often.
+    pub fn block_lines(mut self, lines: usize) -> EditStream<A, B> {
+        self.block_lines = lines.max(1);
+        self
+    }
+
+    /// Whether any block was diffed without an anchor, or gave up on
+    /// finding its smallest set of edits. Only final once every edit has
+    /// been returned.
+    pub fn is_approximate(&self) -> bool {
+        self.approximate
+    }
+
+    /// Diff the next block, adding its edits to `pending`
+    fn next_block(&mut self) -> io::Result<()> {
+        self.a.fill(self.block_lines)?;
+        self.b.fill(self.block_lines)?;
+
+        let at_end = self.a.done && self.b.done;
+        let anchor = if at_end {
+            None
+        } else {
+            anchor(&self.a.window, &self.b.window)
+        };
+        let (a_len, b_len) = match anchor {
+            Some((a, b)) => (a, b),
+            None => (self.a.window.len(), self.b.window.len()),
+        };
+
+        let (edits, approximate) = myers::from_with_max_cost(
+            &self.a.window[..a_len],
+            &self.b.window[..b_len],
+            myers::DEFAULT_MAX_COST,
+        );
+        // with nothing left of one side, what's left of the other is all
+        // inserted or deleted, anchored or not
+        let unanchored =
+            anchor.is_none() && !at_end && !self.a.window.is_empty() && !self.b.window.is_empty();
+        self.approximate |= approximate || (unanchored && !edits.is_empty());
+        for mut edit in edits {
+            edit.original.line += self.a.start;
+            edit.modified.line += self.b.start;
+            self.pending.push_back(edit);
+        }
+
+        // the anchor itself is the same in both, so it is passed over too
+        let skip = usize::from(anchor.is_some());
+        self.a.advance(a_len + skip);
+        self.b.advance(b_len + skip);
+        self.finished = self.a.done && self.b.done && self.a.window.is_empty();
+        Ok(())
+    }
+}
+
+impl<A: BufRead, B: BufRead> Iterator for EditStream<A, B> {
+    type Item = io::Result<Edit>;
+
+    fn next(&mut self) -> Option<io::Result<Edit>> {
+        loop {
+            if let Some(edit) = self.pending.pop_front() {
+                return Some(Ok(edit));
+            }
+            if self.finished {
+                return None;
+            }
+            if let Err(err) = self.next_block() {
+                self.finished = true;
+                return Some(Err(err));
+            }
+        }
+    }
+}
+
+/// The last of the longest run of lines unique to both windows that are in
+/// the same order in both, as indexes into each
+fn anchor<S: AsRef<str>>(a: &[S], b: &[S]) -> Option<(usize, usize)> {
+    // for each line, how often it is in each window, and where it last was
+    let mut counts: HashMap<&str, (usize, usize, usize, usize)> = HashMap::new();
+    for (i, line) in a.iter().enumerate() {
+        let entry = counts.entry(line.as_ref()).or_default();
+        entry.0 += 1;
+        entry.1 = i;
+    }
+    for (j, line) in b.iter().enumerate() {
+        let entry = counts.entry(line.as_ref()).or_default();
+        entry.2 += 1;
+        entry.3 = j;
+    }
+
+    let mut matches: Vec<(usize, usize)> = counts
+        .values()
+        .filter(|(in_a, _, in_b, _)| *in_a == 1 && *in_b == 1)
+        .map(|&(_, i, _, j)| (i, j))
+        .collect();
+    matches.sort_unstable();
+
+    // patience sorting: the top of each pile is the smallest `j` ending an
+    // increasing run of that length, so the last pile ends a longest run
+    let mut piles: Vec<(usize, usize)> = Vec::new();
+    for (i, j) in matches {
+        match piles.binary_search_by(|&(_, top)| top.cmp(&j)) {
+            Ok(_) => unreachable!("lines are unique in each window"),
+            Err(pile) if pile == piles.len() => piles.push((i, j)),
+            Err(pile) => piles[pile] = (i, j),
+        }
+    }
+
+    piles.last().copied()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::diff::{Diff, Edit};
+    use std::io::BufReader;
+
+    fn lines(count: usize, every: usize, changed: &str) -> String {
+        (0..count)
+            .map(|i| {
+                if i % every == 0 {
+                    format!("{} {}\n", changed, i)
+                } else {
+                    format!("line {}\n", i)
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn streams_match_whole_diffs() {
+        let a = lines(1000, 7, "old");
+        let b = lines(1100, 11, "new");
+        let whole = Diff::from(
+            &a.lines().collect::<Vec<_>>(),
+            &b.lines().collect::<Vec<_>>(),
+        );
+
+        let stream = Diff::stream(a.as_bytes(), b.as_bytes()).block_lines(2000);
+        let edits: Vec<_> = stream.map(Result::unwrap).collect();
+        assert_eq!(edits, whole.edits());
+    }
+
+    /// Make the edits to `a`
+    fn patch(a: &[&str], edits: &[Edit]) -> Vec<String> {
+        let mut patched = Vec::new();
+        let mut next = 0;
+        for edit in edits {
+            patched.extend(a[next..edit.original.line].iter().map(|l| l.to_string()));
+            patched.extend(edit.modified.content.iter().cloned());
+            next = edit.original.line + edit.original.content.len();
+        }
+        patched.extend(a[next..].iter().map(|l| l.to_string()));
+        patched
+    }
+
+    #[test]
+    fn small_blocks_still_diff() {
+        let a = lines(1000, 7, "old");
+        let b = lines(1100, 11, "new");
+        let a_lines: Vec<&str> = a.lines().collect();
+
+        for block_lines in &[1, 2, 10, 64, 500] {
+            let mut stream = Diff::stream(a.as_bytes(), b.as_bytes()).block_lines(*block_lines);
+            let edits: Vec<Edit> = stream.by_ref().map(Result::unwrap).collect();
+            assert_eq!(patch(&a_lines, &edits), b.lines().collect::<Vec<_>>());
+            assert_eq!(
+                stream.is_approximate(),
+                *block_lines < 10,
+                "{}",
+                block_lines
+            );
+        }
+    }
+
+    #[test]
+    fn read_errors_end_the_stream() {
+        let mut stream = Diff::stream(BufReader::new(&b"a\n\xff\n"[..]), &b"a\n"[..]);
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+}
@@ -0,0 +1,469 @@
+//! Checking the health of a repository, as `ink doctor` does.
+//!
+//! Each check looks at one part of `.ink`, or at the working directory, and
+//! says whether it is fine, worth a look, or broken, with what to do about
+//! anything that isn't fine. A check that can't finish is reported as broken
+//! rather than ending the others.
+//! ink takes no lock files and its files carry no format version, so the
+//! nearest checks are for a merge left in progress and for a cursor, graph or
+//! index that this version of ink can't read.
+use crate::commit::Commit;
+use crate::graph::CommitGraph;
+use crate::index::ReverseIndex;
+use crate::merge::MergeState;
+use crate::stats::StatsCache;
+use crate::utils::WalkDir;
+use crate::version_info::json_string;
+use crate::{atomic, cursor, objects, InkError, Repository};
+use crate::{DATA_EXT, MERGE_STATE_FILE, POINTERS_DIR};
+
+use std::fmt::Write;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Prefixes of the temporary files ink writes before moving them into place
+const TEMP_PREFIXES: [&str; 2] = [".ink-", ".tmp"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Ok,
+    /// Nothing is broken, but something needs a look
+    Warning,
+    Error,
+}
+
+impl Status {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Error => "error",
+        }
+    }
+}
+
+/// The outcome of one check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub summary: String,
+    /// What to do about it, for anything but `Ok`
+    pub remedy: Option<&'static str>,
+}
+
+impl Check {
+    fn ok(summary: String) -> Check {
+        Check {
+            name: "",
+            status: Status::Ok,
+            summary,
+            remedy: None,
+        }
+    }
+
+    fn warning(summary: String, remedy: &'static str) -> Check {
+        Check {
+            status: Status::Warning,
+            remedy: Some(remedy),
+            ..Check::ok(summary)
+        }
+    }
+
+    fn error(summary: String, remedy: &'static str) -> Check {
+        Check {
+            status: Status::Error,
+            remedy: Some(remedy),
+            ..Check::ok(summary)
+        }
+    }
+}
+
+/// The outcome of every check, in the order they ran
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DoctorReport {
+    pub checks: Vec<Check>,
+}
+
+impl DoctorReport {
+    /// Whether no check found an error. Warnings still count as healthy.
+    pub fn is_healthy(&self) -> bool {
+        self.checks
+            .iter()
+            .all(|check| check.status != Status::Error)
+    }
+
+    /// A JSON object with `healthy` and a `checks` array, with `null` for
+    /// checks without a remedy
+    pub fn to_json(&self) -> String {
+        let mut json = format!("{{\"healthy\": {}, \"checks\": [", self.is_healthy());
+        for (index, check) in self.checks.iter().enumerate() {
+            if index > 0 {
+                json.push_str(", ");
+            }
+            let _ = write!(
+                json,
+                "{{\"name\": {}, \"status\": {}, \"summary\": {}, \"remedy\": {}}}",
+                json_string(check.name),
+                json_string(check.status.as_str()),
+                json_string(&check.summary),
+                check.remedy.map_or("null".to_string(), json_string)
+            );
+        }
+        json.push_str("]}");
+        json
+    }
+}
+
+type CheckFn = fn(&Path) -> Result<Check, InkError>;
+
+const CHECKS: [(&str, CheckFn); 7] = [
+    ("objects", check_objects),
+    ("format", check_format),
+    ("merge", check_merge),
+    ("cache", check_cache),
+    ("disk", check_disk),
+    ("temp-files", check_temp_files),
+    ("permissions", check_permissions),
+];
+
+/// Run every check on the repository, and on its working directory unless
+/// it is bare
+pub fn doctor(repo: &Repository) -> DoctorReport {
+    let mut checks: Vec<Check> = CHECKS
+        .iter()
+        .map(|(name, check)| finish(name, check(repo.ink_root())))
+        .collect();
+    if !repo.is_bare() {
+        checks.push(finish(
+            "checkout-temp-files",
+            check_checkout_temp_files(repo),
+        ));
+    }
+
+    DoctorReport { checks }
+}
+
+fn finish(name: &'static str, check: Result<Check, InkError>) -> Check {
+    let check = check.unwrap_or_else(|err| {
+        Check::error(
+            format!("The check couldn't finish: {}", err),
+            "Fix the error, then run `ink doctor` again",
+        )
+    });
+    Check { name, ..check }
+}
+
+fn check_objects(ink_root: &Path) -> Result<Check, InkError> {
+    let corrupt = objects::fsck(ink_root)?;
+    if corrupt.is_empty() {
+        return Ok(Check::ok("Every content object matches its hash".into()));
+    }
+
+    Ok(Check::error(
+        format!("{} content objects don't match their hashes", corrupt.len()),
+        "Run `ink fsck` to list them and `ink salvage` to find the commits that use them, \
+         then restore their files from another copy",
+    ))
+}
+
+fn check_format(ink_root: &Path) -> Result<Check, InkError> {
+    let mut unreadable = Vec::new();
+    if let Err(err) = cursor::get(ink_root) {
+        unreadable.push(format!("the cursor ({})", err));
+    }
+    if let Err(err) = CommitGraph::get(ink_root) {
+        unreadable.push(format!("the graph ({})", err));
+    }
+    if let Err(err) = ReverseIndex::load(ink_root) {
+        unreadable.push(format!("the index ({})", err));
+    }
+
+    if unreadable.is_empty() {
+        return Ok(Check::ok(
+            "The cursor, graph and index can be read by this version of ink".into(),
+        ));
+    }
+
+    Ok(Check::error(
+        format!("This version of ink can't read {}", unreadable.join(", ")),
+        "Run `ink salvage` to rebuild them from the commits, or use the version of ink \
+         that wrote them",
+    ))
+}
+
+fn check_merge(ink_root: &Path) -> Result<Check, InkError> {
+    let state = match MergeState::load(ink_root) {
+        Ok(Some(state)) => state,
+        Ok(None) => return Ok(Check::ok("No merge is in progress".into())),
+        Err(err) => {
+            return Ok(Check::error(
+                format!("The merge in progress can't be read: {}", err),
+                "Remove .ink/merge-state, then check out the current commit again",
+            ))
+        }
+    };
+
+    let head = hex::encode(state.head);
+    // a merge of a commit that is gone can never be finished
+    if Commit::from(&state.head, ink_root).is_err() {
+        return Ok(Check::error(
+            format!("The merge in progress is of {}, which can't be read", head),
+            "Remove .ink/merge-state, then check out the current commit again",
+        ));
+    }
+
+    let age = fs::metadata(ink_root.join(MERGE_STATE_FILE))?
+        .modified()?
+        .elapsed()
+        .map_or(0, |age| age.as_secs() / (60 * 60 * 24));
+    Ok(Check::warning(
+        format!(
+            "A merge of {} with {} conflicted files has been in progress for {} days",
+            &head[..10],
+            state.conflicts.len(),
+            age
+        ),
+        "Resolve the conflicts and commit, or give up with `ink merge --abort`",
+    ))
+}
+
+fn check_cache(ink_root: &Path) -> Result<Check, InkError> {
+    let check = StatsCache::open(ink_root)?.check()?;
+    if !check.inconsistent.is_empty() {
+        return Ok(Check::warning(
+            format!(
+                "{} of {} cache entries can't be read or are filed under the wrong commits",
+                check.inconsistent.len(),
+                check.entries
+            ),
+            "Remove .ink/cache; it is rebuilt as it is needed",
+        ));
+    }
+    if check.size > check.max_size {
+        return Ok(Check::warning(
+            format!(
+                "The cache takes {} bytes, over its max-size of {}",
+                check.size, check.max_size
+            ),
+            "Remove .ink/cache, or raise max-size in the [cache] config section",
+        ));
+    }
+
+    Ok(Check::ok(format!(
+        "{} cache entries take {} of {} bytes",
+        check.entries, check.size, check.max_size
+    )))
+}
+
+fn check_disk(ink_root: &Path) -> Result<Check, InkError> {
+    let tree = Tree::read(ink_root)?;
+    let total: u64 = tree.files.iter().map(|(_, len)| len).sum();
+    let content: u64 = tree
+        .files
+        .iter()
+        .filter(|(path, _)| {
+            path.starts_with(ink_root.join(DATA_EXT))
+                || path.starts_with(ink_root.join(POINTERS_DIR))
+        })
+        .map(|(_, len)| len)
+        .sum();
+
+    Ok(Check::ok(format!(
+        "{} bytes in {} files, {} bytes of them content objects",
+        total,
+        tree.files.len(),
+        content
+    )))
+}
+
+fn check_temp_files(ink_root: &Path) -> Result<Check, InkError> {
+    let tree = Tree::read(ink_root)?;
+    let temp: Vec<&(PathBuf, u64)> = tree
+        .files
+        .iter()
+        .filter(|(path, _)| {
+            let name = path.file_name().unwrap_or_default().to_string_lossy();
+            TEMP_PREFIXES.iter().any(|prefix| name.starts_with(prefix))
+        })
+        .collect();
+    if temp.is_empty() {
+        return Ok(Check::ok("No temporary files were left behind".into()));
+    }
+
+    Ok(Check::warning(
+        format!(
+            "{} temporary files taking {} bytes were left by interrupted writes",
+            temp.len(),
+            temp.iter().map(|(_, len)| len).sum::<u64>()
+        ),
+        "Remove the files under .ink whose names start with .ink- or .tmp \
+         while ink isn't running; nothing refers to them",
+    ))
+}
+
+/// Temporary files an interrupted checkout left beside the files it was
+/// replacing. Snapshots skip them, so they are never committed.
+fn check_checkout_temp_files(repo: &Repository) -> Result<Check, InkError> {
+    let dir_name = repo.dir_name();
+    let walk = WalkDir::new(repo.work_tree()).links(true).exclude(|path| {
+        path.starts_with(repo.ink_root())
+            || path.file_name() == Some(dir_name.as_ref())
+            || path.join(dir_name).is_dir()
+    });
+
+    let mut temp = Vec::new();
+    for path in walk {
+        let path = path?;
+        if atomic::is_temp(&path) {
+            temp.push(path);
+        }
+    }
+    if temp.is_empty() {
+        return Ok(Check::ok("No checkout left temporary files behind".into()));
+    }
+
+    Ok(Check::warning(
+        format!(
+            "{} temporary files were left by interrupted checkouts, the first {}",
+            temp.len(),
+            temp[0].display()
+        ),
+        "Remove the files in the working directory named .ink- and six letters or \
+         digits while ink isn't running; nothing refers to them",
+    ))
+}
+
+fn check_permissions(ink_root: &Path) -> Result<Check, InkError> {
+    let tree = Tree::read(ink_root)?;
+    let mut problems = tree.unreadable;
+    for dir in &tree.dirs {
+        if fs::metadata(dir)?.permissions().readonly() {
+            problems.push(dir.clone());
+        }
+    }
+    for (path, _) in &tree.files {
+        if let Err(err) = File::open(path) {
+            if err.kind() == io::ErrorKind::PermissionDenied {
+                problems.push(path.clone());
+            }
+        }
+    }
+    problems.sort();
+
+    if problems.is_empty() {
+        return Ok(Check::ok(
+            "Every file under .ink can be read and every directory written".into(),
+        ));
+    }
+
+    Ok(Check::error(
+        format!(
+            "{} paths under .ink can't be read or written, the first {}",
+            problems.len(),
+            problems[0].display()
+        ),
+        "Give your user read access to the files under .ink and write access to its \
+         directories, for example with `chmod -R u+rwX .ink`",
+    ))
+}
+
+/// Every file under `.ink` with its size, and every directory
+struct Tree {
+    files: Vec<(PathBuf, u64)>,
+    dirs: Vec<PathBuf>,
+    // directories that couldn't be listed
+    unreadable: Vec<PathBuf>,
+}
+
+impl Tree {
+    fn read(ink_root: &Path) -> io::Result<Tree> {
+        let mut tree = Tree {
+            files: Vec::new(),
+            dirs: Vec::new(),
+            unreadable: Vec::new(),
+        };
+        tree.visit(ink_root)?;
+        Ok(tree)
+    }
+
+    fn visit(&mut self, dir: &Path) -> io::Result<()> {
+        self.dirs.push(dir.to_path_buf());
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                self.unreadable.push(dir.to_path_buf());
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        };
+
+        for entry in entries {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                self.visit(&entry.path())?;
+            } else {
+                self.files.push((entry.path(), metadata.len()));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+    use crate::{CACHE_DIR, GRAPH_FILE};
+
+    fn status(report: &DoctorReport, name: &str) -> Status {
+        report
+            .checks
+            .iter()
+            .find(|check| check.name == name)
+            .unwrap()
+            .status
+    }
+
+    #[test]
+    fn healthy_repository() {
+        let repo = TestRepo::new().file("a", "first").commit("first");
+        let report = doctor(repo.repo());
+
+        assert!(report.is_healthy());
+        assert_eq!(report.checks.len(), CHECKS.len() + 1);
+        for check in &report.checks {
+            assert_eq!(check.status, Status::Ok, "{:?}", check);
+            assert_eq!(check.remedy, None);
+        }
+        assert!(report.to_json().starts_with(
+            "{\"healthy\": true, \"checks\": [{\"name\": \"objects\", \"status\": \"ok\""
+        ));
+    }
+
+    #[test]
+    fn problems_come_with_remedies() {
+        let repo = TestRepo::new().file("a", "first").commit("first");
+        let ink_root = repo.ink_root();
+        fs::write(ink_root.join(GRAPH_FILE), b"garbage").unwrap();
+        fs::write(ink_root.join(DATA_EXT).join(".ink-abc"), b"half").unwrap();
+        fs::write(repo.path().join(".ink-d3f456"), b"half").unwrap();
+        let stats = ink_root.join(CACHE_DIR).join("stats");
+        fs::create_dir_all(&stats).unwrap();
+        fs::write(stats.join("entry"), b"garbage").unwrap();
+
+        let report = doctor(repo.repo());
+        assert!(!report.is_healthy());
+        assert_eq!(status(&report, "objects"), Status::Ok);
+        assert_eq!(status(&report, "format"), Status::Error);
+        assert_eq!(status(&report, "cache"), Status::Warning);
+        assert_eq!(status(&report, "temp-files"), Status::Warning);
+        assert_eq!(status(&report, "checkout-temp-files"), Status::Warning);
+        for check in &report.checks {
+            assert_eq!(check.remedy.is_some(), check.status != Status::Ok);
+        }
+        assert!(report.to_json().starts_with("{\"healthy\": false, "));
+    }
+}
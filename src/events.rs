@@ -0,0 +1,25 @@
+//! Callbacks for programs embedding ink, so they can react to repository
+//! operations without polling the filesystem.
+use crate::commit::Commit;
+use std::path::Path;
+
+/// Receives events from `Repository` operations.
+/// Every method has an empty default, so implementors only pick what they need.
+pub trait EventSink {
+    /// A new commit was written and the cursor moved to it
+    fn commit_created(&self, _commit: &Commit) {}
+
+    /// The working directory is about to be switched between two commits
+    fn checkout_started(&self, _from: &Commit, _to: &Commit) {}
+
+    /// The working directory now matches the given commit
+    fn checkout_finished(&self, _commit: &Commit) {}
+
+    /// A change in the working directory at the given path stopped an operation
+    fn conflict_detected(&self, _path: &Path) {}
+}
+
+/// Sink used when no one is listening
+pub(crate) struct NoEvents;
+
+impl EventSink for NoEvents {}
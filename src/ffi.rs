@@ -0,0 +1,367 @@
+//! C ABI for editor plugins and other native programs, enabled with the `ffi`
+//! feature. The matching declarations are in `include/ink.h`.
+//!
+//! Ownership rules:
+//! - `ink_repo` and `ink_log` handles belong to the caller, and are released
+//!   with `ink_repo_free` and `ink_log_free`.
+//! - Every `char *` returned by ink belongs to the caller, and is released
+//!   with `ink_string_free`.
+//! - Strings passed in are only borrowed for the length of the call.
+//! - Failures return NULL or -1, and `ink_last_error` describes them. That
+//!   message belongs to ink and lives until the next failure on the same thread.
+use crate::diff::{render, Diff};
+use crate::history::History;
+use crate::paths;
+use crate::{CommitResult, Repository};
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::os::raw::{c_char, c_int};
+use std::path::Path;
+use std::ptr;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+/// Iterator over the history of a repository, handed out as `ink_log *`
+pub struct InkLog(History);
+
+fn set_error<E: Display>(err: E) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(err.to_string()).ok());
+}
+
+/// Borrow a C string, recording an error if it is null or not UTF-8
+unsafe fn borrow_str<'a>(s: *const c_char) -> Option<&'a str> {
+    if s.is_null() {
+        set_error("Unexpected null pointer");
+        return None;
+    }
+
+    match CStr::from_ptr(s).to_str() {
+        Ok(s) => Some(s),
+        Err(_) => {
+            set_error("String is not valid UTF-8");
+            None
+        }
+    }
+}
+
+/// Hand a string over to the caller
+fn give_string(s: String) -> *mut c_char {
+    match CString::new(s) {
+        Ok(s) => s.into_raw(),
+        Err(_) => {
+            set_error("String contains a nul byte");
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Describes the last failure on this thread, or NULL if nothing has failed.
+#[no_mangle]
+pub extern "C" fn ink_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| match &*last.borrow() {
+        Some(err) => err.as_ptr(),
+        None => ptr::null(),
+    })
+}
+
+/// Release a string returned by ink.
+///
+/// # Safety
+/// `s` must be NULL or a string returned by ink that was not already released.
+#[no_mangle]
+pub unsafe extern "C" fn ink_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Open the repository containing `path`. Returns NULL on failure.
+///
+/// # Safety
+/// `path` must be a valid nul terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn ink_repo_open(path: *const c_char) -> *mut Repository {
+    let path = match borrow_str(path) {
+        Some(path) => path,
+        None => return ptr::null_mut(),
+    };
+
+    match Repository::discover_from(Path::new(path)) {
+        Ok(repo) => Box::into_raw(Box::new(repo)),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Release a repository handle.
+///
+/// # Safety
+/// `repo` must be NULL or a handle from `ink_repo_open` that was not already
+/// released.
+#[no_mangle]
+pub unsafe extern "C" fn ink_repo_free(repo: *mut Repository) {
+    if !repo.is_null() {
+        drop(Box::from_raw(repo));
+    }
+}
+
+/// Commit the working directory. Returns 0 and writes the new commit's hash to
+/// `hash_out` (if not NULL) when a commit is made, 1 when there was nothing to
+/// commit, and -1 on failure.
+///
+/// # Safety
+/// `repo` must be a live repository handle, `message` a valid nul terminated
+/// string, and `hash_out` NULL or valid to write a pointer to.
+#[no_mangle]
+pub unsafe extern "C" fn ink_commit(
+    repo: *const Repository,
+    message: *const c_char,
+    hash_out: *mut *mut c_char,
+) -> c_int {
+    let (repo, message) = match (repo.as_ref(), borrow_str(message)) {
+        (Some(repo), Some(message)) => (repo, message),
+        _ => {
+            set_error("Unexpected null pointer");
+            return -1;
+        }
+    };
+
+    match repo.commit(message, Default::default()) {
+        Ok(CommitResult::Created(outcome)) => {
+            if !hash_out.is_null() {
+                *hash_out = give_string(hex::encode(outcome.commit.hash()));
+            }
+            0
+        }
+        Ok(CommitResult::NothingToCommit) => 1,
+        Err(e) => {
+            set_error(e);
+            -1
+        }
+    }
+}
+
+/// Changes in the working directory since the current commit, one per line as
+/// `<status letter> <path>`. Returns NULL on failure.
+///
+/// # Safety
+/// `repo` must be a live repository handle.
+#[no_mangle]
+pub unsafe extern "C" fn ink_status(repo: *const Repository) -> *mut c_char {
+    let repo = match repo.as_ref() {
+        Some(repo) => repo,
+        None => {
+            set_error("Unexpected null pointer");
+            return ptr::null_mut();
+        }
+    };
+
+    match repo.status() {
+        Ok(diff) => give_string(
+            diff.edits
+                .iter()
+                .map(|edit| format!("{} {}\n", edit.status(), paths::escape(edit.path())))
+                .collect(),
+        ),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Start walking the history of the current commit, newest first.
+/// Returns NULL on failure.
+///
+/// # Safety
+/// `repo` must be a live repository handle. The log does not borrow it.
+#[no_mangle]
+pub unsafe extern "C" fn ink_log_open(repo: *const Repository) -> *mut InkLog {
+    let repo = match repo.as_ref() {
+        Some(repo) => repo,
+        None => {
+            set_error("Unexpected null pointer");
+            return ptr::null_mut();
+        }
+    };
+
+    match repo.log() {
+        Ok(history) => Box::into_raw(Box::new(InkLog(history))),
+        Err(e) => {
+            set_error(e);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Move to the next commit in the log. Returns 1 and writes the commit's hash
+/// and message to the out pointers (each may be NULL), 0 when the log is
+/// finished, and -1 on failure.
+///
+/// # Safety
+/// `log` must be a live log handle, and the out pointers NULL or valid to
+/// write a pointer to.
+#[no_mangle]
+pub unsafe extern "C" fn ink_log_next(
+    log: *mut InkLog,
+    hash_out: *mut *mut c_char,
+    message_out: *mut *mut c_char,
+) -> c_int {
+    let log = match log.as_mut() {
+        Some(log) => log,
+        None => {
+            set_error("Unexpected null pointer");
+            return -1;
+        }
+    };
+
+    match log.0.next() {
+        Some(Ok(commit)) => {
+            if !hash_out.is_null() {
+                *hash_out = give_string(hex::encode(commit.hash()));
+            }
+            if !message_out.is_null() {
+                *message_out = give_string(commit.message().to_string());
+            }
+            1
+        }
+        Some(Err(e)) => {
+            set_error(e);
+            -1
+        }
+        None => 0,
+    }
+}
+
+/// Release a log handle.
+///
+/// # Safety
+/// `log` must be NULL or a handle from `ink_log_open` that was not already
+/// released.
+#[no_mangle]
+pub unsafe extern "C" fn ink_log_free(log: *mut InkLog) {
+    if !log.is_null() {
+        drop(Box::from_raw(log));
+    }
+}
+
+/// Diff two texts line by line and render the result in the unified format.
+/// Returns an empty string when they are the same, and NULL on failure.
+///
+/// # Safety
+/// All arguments must be valid nul terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn ink_diff_unified(
+    original: *const c_char,
+    modified: *const c_char,
+    original_name: *const c_char,
+    modified_name: *const c_char,
+) -> *mut c_char {
+    let args = (
+        borrow_str(original),
+        borrow_str(modified),
+        borrow_str(original_name),
+        borrow_str(modified_name),
+    );
+
+    match args {
+        (Some(original), Some(modified), Some(original_name), Some(modified_name)) => {
+            let original: Vec<&str> = original.lines().collect();
+            let modified: Vec<&str> = modified.lines().collect();
+            let diff = Diff::from(&original, &modified);
+
+            give_string(render::unified(
+                &diff,
+                &original,
+                original_name,
+                modified_name,
+            ))
+        }
+        _ => ptr::null_mut(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        ink_string_free(s);
+        owned
+    }
+
+    #[test]
+    fn ffi_commit_status_and_log() {
+        let tmpdir = crate::testkit::temp_dir();
+        crate::init(tmpdir.path()).unwrap();
+        fs::write(tmpdir.path().join("example"), b"this is a test!").unwrap();
+
+        let path = CString::new(tmpdir.path().to_str().unwrap()).unwrap();
+        let message = CString::new("from C").unwrap();
+
+        unsafe {
+            let repo = ink_repo_open(path.as_ptr());
+            assert!(!repo.is_null());
+
+            assert_eq!(take_string(ink_status(repo)), "A example\n");
+
+            let mut hash = ptr::null_mut();
+            assert_eq!(ink_commit(repo, message.as_ptr(), &mut hash), 0);
+            let hash = take_string(hash);
+            assert_eq!(ink_commit(repo, message.as_ptr(), ptr::null_mut()), 1);
+
+            let log = ink_log_open(repo);
+            let mut logged_hash = ptr::null_mut();
+            let mut logged_message = ptr::null_mut();
+            assert_eq!(ink_log_next(log, &mut logged_hash, &mut logged_message), 1);
+            assert_eq!(take_string(logged_hash), hash);
+            assert_eq!(take_string(logged_message), "from C");
+            assert_eq!(ink_log_next(log, ptr::null_mut(), ptr::null_mut()), 1);
+            assert_eq!(ink_log_next(log, ptr::null_mut(), ptr::null_mut()), 0);
+
+            ink_log_free(log);
+            ink_repo_free(repo);
+        }
+    }
+
+    #[test]
+    fn ffi_reports_errors() {
+        let tmpdir = crate::testkit::temp_dir();
+        let path = CString::new(tmpdir.path().to_str().unwrap()).unwrap();
+
+        unsafe {
+            assert!(ink_repo_open(path.as_ptr()).is_null());
+            let err = CStr::from_ptr(ink_last_error()).to_str().unwrap();
+            assert_eq!(err, "Ink Uninitialized");
+        }
+    }
+
+    #[test]
+    fn ffi_unified_diff() {
+        let a = CString::new("one\ntwo\n").unwrap();
+        let b = CString::new("one\nthree\n").unwrap();
+        let name = CString::new("file").unwrap();
+
+        let diff = unsafe {
+            take_string(ink_diff_unified(
+                a.as_ptr(),
+                b.as_ptr(),
+                name.as_ptr(),
+                name.as_ptr(),
+            ))
+        };
+
+        assert_eq!(
+            diff,
+            "--- file\n+++ file\n@@ -1,2 +1,2 @@\n one\n-two\n+three\n"
+        );
+    }
+}
@@ -1,25 +1,35 @@
 use std::cmp::{Eq, Ordering};
-use std::fs::{self, File, Permissions};
-use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::os::unix::{ffi::OsStrExt, fs::PermissionsExt};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
 use std::path::{Path, PathBuf};
 
 use custom_debug_derive::Debug;
-use sha2::{Digest, Sha256};
 
+use crate::atomic;
+use crate::filter::ContentFilters;
+use crate::identity::{self, IdHasher, InkObject, ObjectId};
+use crate::large_files::{self, LargeFiles};
+use crate::metrics::Timings;
+use crate::mmap;
+use crate::objects;
+use crate::paths;
+use crate::storage::{ObjectWriter, StoragePolicy};
 use crate::utils;
-use crate::{InkError, DATA_EXT};
-use libflate::deflate::{Decoder, Encoder};
+use crate::{InkError, DATA_EXT, RAW_EXT};
+use libflate::deflate::Decoder;
 use serde::{Deserialize, Serialize};
-use tempfile;
 
 /// A struct holding the file data nessecary
 /// to commit changes. Includes unix file permissions,
-/// as such it only works on unix systems.
+/// which are left at a default on other systems.
+///
+/// Symbolic links are stored as files whose mode says they are links, and
+/// whose content is the path they point to.
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct FileData {
     #[debug(with = "utils::hex_fmt")]
     hash: [u8; 32],
+    #[serde(with = "crate::paths::escaped")]
     path: PathBuf,
     // rust sets/gets unix file perms as a u32
     permissions: u32,
@@ -29,52 +39,158 @@ pub struct FileData {
 impl FileData {
     /// Creates a FileData struct given a filepath.
     /// Can fail on IO errors.
-    pub(crate) fn new(filepath: &Path, ink_root: &Path) -> Result<FileData, InkError> {
-        let content = Content::new(filepath)?;
-        let permissions = fs::metadata(filepath)?.permissions().mode();
-
-        // make filepath relative to project directory
-        // find the absolute path of the project directory
-        let project_dir = ink_root.parent().ok_or("ink root dir is invalid.")?;
-
-        // root the filepath to the project dir.
-        let absolute_filepath = filepath.canonicalize()?;
+    /// Content is hashed after it is cleaned by the path's filters.
+    pub(crate) fn new(
+        filepath: &Path,
+        dir_name: &str,
+        filters: &ContentFilters,
+    ) -> Result<FileData, InkError> {
+        // make filepath relative to project directory, the one the filters
+        // were loaded for
+        let project_dir = filters.project_dir();
+
+        // root the filepath to the project dir. a link is where it is, not
+        // where it points
+        let metadata = fs::symlink_metadata(filepath)?;
+        let absolute_filepath = if metadata.file_type().is_symlink() {
+            let parent = filepath.parent().ok_or("Path has no parent directory")?;
+            let name = filepath.file_name().ok_or("Path has no file name")?;
+            parent.canonicalize()?.join(name)
+        } else {
+            filepath.canonicalize()?
+        };
         let rooted_filepath = absolute_filepath
             .strip_prefix(project_dir)
             .map_err(|_| "Could not root filepaths relative to project dir")?;
+        paths::validate(rooted_filepath, dir_name)?;
 
-        let mut hasher = Sha256::new();
-        hasher.update(rooted_filepath.as_os_str().as_bytes());
-        hasher.update(permissions.to_be_bytes());
-        hasher.update(content.hash);
-        let hash = hasher.finalize();
+        let content = Content::new(filepath, rooted_filepath, filters)?;
+        let permissions = utils::file_mode(&metadata);
 
-        Ok(FileData {
-            hash: hash.into(),
-            path: rooted_filepath.to_path_buf(),
+        Ok(FileData::from_content(
+            rooted_filepath,
             permissions,
-            content,
-        })
+            content.hash,
+        ))
     }
 
-    pub(crate) fn write(&self, ink_root: &Path) -> Result<(), InkError> {
-        self.content.write(&self.path, ink_root)?;
-        Ok(())
+    /// Creates a FileData struct for content that is already hashed, at a
+    /// path relative to the project directory
+    pub(crate) fn from_content(path: &Path, permissions: u32, content_hash: [u8; 32]) -> FileData {
+        let mut hasher = IdHasher::new();
+        hasher
+            .bytes(&utils::path_bytes(path))
+            .u32(permissions)
+            .id(&content_hash);
+
+        FileData {
+            hash: hasher.finish(),
+            path: path.to_path_buf(),
+            permissions,
+            content: Content { hash: content_hash },
+        }
+    }
+
+    /// Store the content of the file, returning the bytes it took up, or
+    /// `None` if the content was already stored
+    pub(crate) fn write(
+        &self,
+        ink_root: &Path,
+        filters: &ContentFilters,
+        large_files: &LargeFiles,
+        policy: &StoragePolicy,
+        timings: &Timings,
+    ) -> Result<Option<u64>, InkError> {
+        self.content
+            .write(&self.path, ink_root, filters, large_files, policy, timings)
     }
 
-    pub(crate) fn write_to(&self, ink_root: &Path, filepath: &Path) -> Result<(), InkError> {
-        let _f = File::create(&filepath);
-        fs::set_permissions(&filepath, Permissions::from_mode(self.permissions))?;
-        let mut writer = BufWriter::new(File::create(filepath)?);
-        let mut reader = self.content.get_reader(ink_root)?;
-        let _ = io::copy(&mut reader, &mut writer)?;
+    /// Write the file's content to `filepath` with its permissions. The
+    /// content is written to a temporary file beside it which is renamed into
+    /// place, so `filepath` is never seen half written or with other
+    /// permissions.
+    pub(crate) fn write_to(
+        &self,
+        ink_root: &Path,
+        filepath: &Path,
+        filters: &ContentFilters,
+        policy: &StoragePolicy,
+    ) -> Result<(), InkError> {
+        let tmp = atomic::temp_file_beside(filepath)?;
+
+        if self.kind() == FileKind::Symlink {
+            let mut target = Vec::new();
+            self.content
+                .get_reader(ink_root, policy.verify())?
+                .read_to_end(&mut target)?;
+
+            // the link is made where the temporary file was
+            let tmp = tmp.into_temp_path();
+            fs::remove_file(&tmp)?;
+            utils::symlink(&target, &tmp)?;
+            atomic::persist(tmp, filepath)?;
+            return Ok(());
+        }
+
+        // copying can't check the content, so isn't done when verifying
+        let raw_path = if filters.is_identity(&self.path) && !policy.verify() {
+            self.content.raw_path(ink_root)
+        } else {
+            None
+        };
+        if let Some(raw_path) = raw_path {
+            // fs::copy lets the filesystem share the blob's blocks where
+            // it can (copy_file_range on Linux, clonefile on macOS), and
+            // copies bytes otherwise
+            fs::copy(raw_path, tmp.path())?;
+        } else {
+            let mut writer = BufWriter::new(tmp.as_file());
+            let mut reader = self.content.get_reader(ink_root, policy.verify())?;
+
+            if filters.is_identity(&self.path) {
+                let _ = io::copy(&mut reader, &mut writer)?;
+            } else {
+                let mut content = Vec::new();
+                reader.read_to_end(&mut content)?;
+                writer.write_all(&filters.smudge(&self.path, content)?)?;
+            }
+            writer.flush()?;
+        }
+
+        utils::set_file_mode(tmp.path(), self.permissions)?;
+        atomic::persist(tmp.into_temp_path(), filepath)?;
         Ok(())
     }
 
+    /// Read the stored content of the file, checking it against its hash if
+    /// the storage policy says to verify reads
+    pub fn read_content(&self, ink_root: &Path) -> Result<Vec<u8>, InkError> {
+        self.read_content_with(ink_root, &StoragePolicy::load(ink_root)?)
+    }
+
+    /// Like `read_content`, with the storage policy already loaded, for
+    /// reading many files
+    pub fn read_content_with(
+        &self,
+        ink_root: &Path,
+        policy: &StoragePolicy,
+    ) -> Result<Vec<u8>, InkError> {
+        let mut content = Vec::new();
+        self.content
+            .get_reader(ink_root, policy.verify())?
+            .read_to_end(&mut content)?;
+        Ok(content)
+    }
+
     pub fn hash(&self) -> [u8; 32] {
         self.hash
     }
 
+    /// The hash of the stored content, which names it in the object store
+    pub fn content_hash(&self) -> [u8; 32] {
+        self.content.hash
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
@@ -82,6 +198,27 @@ impl FileData {
     pub fn permissions(&self) -> u32 {
         self.permissions
     }
+
+    /// Whether the file is a regular file or a symbolic link, from its mode
+    pub fn kind(&self) -> FileKind {
+        if self.permissions & TYPE_MASK == SYMLINK_TYPE {
+            FileKind::Symlink
+        } else {
+            FileKind::File
+        }
+    }
+}
+
+/// The bits of a unix mode giving the type of file
+const TYPE_MASK: u32 = 0o170000;
+const SYMLINK_TYPE: u32 = 0o120000;
+
+/// The kinds of file in a tree. Directories are implied by the paths of the
+/// files in them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    File,
+    Symlink,
 }
 
 impl Ord for FileData {
@@ -104,103 +241,198 @@ impl PartialEq for FileData {
 
 impl Eq for FileData {}
 
+impl InkObject for FileData {
+    /// The hash of the path, permissions and content's id
+    fn object_id(&self) -> ObjectId {
+        self.hash
+    }
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize, Clone)]
 pub struct Content {
     #[debug(with = "utils::hex_fmt")]
     hash: [u8; 32],
 }
 
+impl InkObject for Content {
+    /// The hash of the content's bytes
+    fn object_id(&self) -> ObjectId {
+        self.hash
+    }
+}
+
 impl Content {
     /// Create a Content struct from a tracked file,
     /// and add it to the data directory.
     /// Only created by FileData
-    fn new(filepath: &Path) -> Result<Content, InkError> {
-        let mut file = File::open(filepath)?;
-        let mut hasher = Sha256::new();
+    fn new(filepath: &Path, path: &Path, filters: &ContentFilters) -> Result<Content, InkError> {
+        if let Some(target) = link_target(filepath)? {
+            return Ok(Content {
+                hash: identity::content_id(&target),
+            });
+        }
 
-        // create buffer for holding chunks of file
-        const BUF_SIZE: usize = 1024 * 128;
-        let mut buffer = [0; BUF_SIZE];
+        let mut hasher = IdHasher::new();
+        io::copy(&mut open_clean(filepath, path, filters)?, &mut hasher)?;
 
-        // read chunks of the file and update the hash.
-        loop {
-            let bytes_read = file.read(&mut buffer)?;
-            hasher.update(&buffer[..bytes_read]);
+        Ok(Content {
+            hash: hasher.finish(),
+        })
+    }
 
-            if bytes_read < BUF_SIZE {
-                break;
-            }
+    fn write(
+        &self,
+        path: &Path,
+        ink_root: &Path,
+        filters: &ContentFilters,
+        large_files: &LargeFiles,
+        policy: &StoragePolicy,
+        timings: &Timings,
+    ) -> Result<Option<u64>, InkError> {
+        // the hash from reading the file before is trusted, so content that
+        // is already stored in any form isn't read again
+        if objects::object_location(ink_root, &self.hash).is_ok() {
+            return Ok(None);
         }
 
+        let filepath = filters.project_dir().join(path);
+        let mut file = open_clean(&filepath, path, filters)?;
+        let mut writer = ObjectWriter::new(ink_root, policy)?
+            .large_files(large_files)
+            .timings(timings);
+        io::copy(&mut file, &mut writer)?;
         drop(file);
 
-        // get the hash of the file
-        let hash = hasher.finalize();
-
-        Ok(Content { hash: hash.into() })
+        Ok(writer.finish(path, Some(&self.hash))?.1)
     }
 
-    fn write(&self, filepath: &Path, ink_root: &Path) -> Result<(), InkError> {
-        let filepath = ink_root
-            .parent()
-            .ok_or("ink_root has no parent")?
-            .join(filepath);
+    /// The stored copy of the content, if it is kept uncompressed
+    fn raw_path(&self, ink_root: &Path) -> Option<PathBuf> {
+        let path = ink_root
+            .join(DATA_EXT)
+            .join(hex::encode(self.hash))
+            .with_extension(RAW_EXT);
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
 
-        let mut file = File::open(filepath)?;
-        let mut hasher = Sha256::new();
+    /// A reader of the stored content. With `verify`, reading fails at the end
+    /// of the content if it doesn't match the hash.
+    fn get_reader(&self, ink_root: &Path, verify: bool) -> Result<Box<dyn Read>, InkError> {
+        let reader = self.get_unverified_reader(ink_root)?;
+        if !verify {
+            return Ok(reader);
+        }
 
-        // create buffer for holding chunks of file
-        const BUF_SIZE: usize = 1024 * 128;
-        let mut buffer = [0; BUF_SIZE];
-        let mut tmp_file = tempfile::tempfile()?;
-        let mut tmp = Encoder::new(BufWriter::new(&tmp_file));
+        Ok(Box::new(VerifyingReader {
+            inner: reader,
+            hasher: Some(IdHasher::new()),
+            expected: self.hash,
+        }))
+    }
 
-        // read chunks of the file and update the hash.
-        loop {
-            let bytes_read = file.read(&mut buffer)?;
-            hasher.update(&buffer[..bytes_read]);
-            tmp.write(&buffer[..bytes_read])?;
+    fn get_unverified_reader(&self, ink_root: &Path) -> Result<Box<dyn Read>, InkError> {
+        // raw content is read as is: mapping it is slower than reading it
+        if let Some(raw_path) = self.raw_path(ink_root) {
+            return Ok(Box::new(BufReader::new(File::open(raw_path)?)));
+        }
 
-            if bytes_read < BUF_SIZE {
-                break;
+        // only the data directory is mapped: its objects are never changed
+        // once written, while a large file store is outside of ink's control
+        let content_file_path = ink_root.join(DATA_EXT).join(hex::encode(self.hash));
+        if content_file_path.exists() {
+            if let Some(map) = mmap::map(&content_file_path)? {
+                return Ok(Box::new(Decoder::new(io::Cursor::new(map))));
             }
+            return Ok(Box::new(Decoder::new(BufReader::new(File::open(
+                content_file_path,
+            )?))));
         }
 
-        drop(file);
-
-        // finish writing
-        tmp.finish().into_result()?;
+        let content_file_path = large_files::object_path(ink_root, &self.hash)?;
+        Ok(Box::new(Decoder::new(BufReader::new(File::open(
+            content_file_path,
+        )?))))
+    }
+}
 
-        // get the hash of the file
-        let hash: [u8; 32] = hasher.finalize().into();
+/// Hashes content as it is read, and fails once it has all been read if the
+/// hash isn't the expected one
+struct VerifyingReader<R> {
+    inner: R,
+    // taken once the end is reached and checked
+    hasher: Option<IdHasher>,
+    expected: [u8; 32],
+}
 
-        if hash != self.hash {
-            return Err(InkError::Err(
-                "File has changed between reading and writing",
-            ));
+impl<R: Read> Read for VerifyingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if bytes_read > 0 {
+            if let Some(hasher) = &mut self.hasher {
+                hasher.bytes(&buf[..bytes_read]);
+            }
+        } else if !buf.is_empty() {
+            if let Some(hasher) = self.hasher.take() {
+                if hasher.finish() != self.expected {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Stored content doesn't match its hash",
+                    ));
+                }
+            }
         }
 
-        // add it to the data directory.
-        let content_file_path = ink_root.join(DATA_EXT).join(hex::encode(hash));
+        Ok(bytes_read)
+    }
+}
 
-        if !content_file_path.exists() {
-            tmp_file.seek(SeekFrom::Start(0))?;
-            let mut file_writer = File::create(content_file_path)?;
-            io::copy(&mut tmp_file, &mut file_writer)?;
-        }
+/// The path a symbolic link points to, which is its content, or `None` if
+/// the file isn't a link
+fn link_target(filepath: &Path) -> Result<Option<Vec<u8>>, InkError> {
+    if !fs::symlink_metadata(filepath)?.file_type().is_symlink() {
+        return Ok(None);
+    }
+    Ok(Some(
+        utils::path_bytes(&fs::read_link(filepath)?).into_owned(),
+    ))
+}
 
-        Ok(())
+/// Read a file in the working directory: its content, or the path it points
+/// to if it is a symbolic link
+pub(crate) fn read_worktree(filepath: &Path) -> Result<Vec<u8>, InkError> {
+    match link_target(filepath)? {
+        Some(target) => Ok(target),
+        None => Ok(fs::read(filepath)?),
     }
+}
 
-    fn get_reader(&self, ink_root: &Path) -> Result<Decoder<BufReader<File>>, InkError> {
-        let content_file_path = ink_root.join(DATA_EXT).join(hex::encode(self.hash));
-        Ok(Decoder::new(BufReader::new(File::open(content_file_path)?)))
+/// Open a file in the working directory, reading it in the form it is stored in.
+/// `path` is the file's path relative to the project directory. Links are
+/// never filtered.
+fn open_clean(
+    filepath: &Path,
+    path: &Path,
+    filters: &ContentFilters,
+) -> Result<Box<dyn Read>, InkError> {
+    if let Some(target) = link_target(filepath)? {
+        return Ok(Box::new(io::Cursor::new(target)));
     }
+    if filters.is_identity(path) {
+        return Ok(Box::new(BufReader::new(File::open(filepath)?)));
+    }
+
+    let content = filters.clean(path, fs::read(filepath)?)?;
+    Ok(Box::new(io::Cursor::new(content)))
 }
 
 #[cfg(test)]
 pub mod tests {
     use super::*;
+    use crate::config::Config;
     use libflate::deflate::Decoder;
     use std::convert::TryInto;
     use std::io::Write;
@@ -224,7 +456,7 @@ pub mod tests {
 
     #[test]
     fn new_content_test() {
-        let tmpdir = tempfile::tempdir_in("./test_tmp_files").unwrap();
+        let tmpdir = crate::testkit::temp_dir();
         let tmpdir_path = tmpdir.path();
         let ex_file_path = tmpdir_path.join("example");
 
@@ -234,9 +466,17 @@ pub mod tests {
             .write_all(b"this is a test!")
             .unwrap();
 
-        let content = Content::new(&ex_file_path).unwrap();
+        let filters = ContentFilters::from_config(&Config::default(), tmpdir_path).unwrap();
+        let content = Content::new(&ex_file_path, Path::new("example"), &filters).unwrap();
         content
-            .write(&ex_file_path, &tmpdir_path.join(".ink"))
+            .write(
+                Path::new("example"),
+                &tmpdir_path.join(".ink"),
+                &filters,
+                &LargeFiles::default(),
+                &StoragePolicy::default(),
+                &Timings::default(),
+            )
             .unwrap();
 
         assert_eq!(
@@ -272,7 +512,7 @@ pub mod tests {
 
     #[test]
     fn new_filedata_test() {
-        let tmpdir = tempfile::tempdir_in("./test_tmp_files").unwrap();
+        let tmpdir = crate::testkit::temp_dir();
         let tmpdir_path = tmpdir.path();
         let ex_file_path = tmpdir_path.join("example");
 
@@ -282,7 +522,8 @@ pub mod tests {
             .write_all(b"this is a test!")
             .unwrap();
 
-        let filedata = FileData::new(&ex_file_path, &tmpdir_path.join(".ink")).unwrap();
+        let filters = ContentFilters::from_config(&Config::default(), tmpdir_path).unwrap();
+        let filedata = FileData::new(&ex_file_path, ".ink", &filters).unwrap();
 
         assert_eq!(
             filedata,
@@ -306,4 +547,97 @@ pub mod tests {
             }
         );
     }
+
+    #[test]
+    fn checkout_uncompressed_content() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        let ex_file_path = tmpdir_path.join("example");
+        crate::init(tmpdir_path).unwrap();
+        fs::write(&ex_file_path, b"this is a test!").unwrap();
+
+        let filters = ContentFilters::from_config(&Config::default(), tmpdir_path).unwrap();
+        let filedata = FileData::new(&ex_file_path, ".ink", &filters).unwrap();
+        let data_path = ink_dir
+            .join(DATA_EXT)
+            .join(hex::encode(filedata.content.hash));
+        fs::write(data_path.with_extension(RAW_EXT), b"this is a test!").unwrap();
+
+        let checkout_path = tmpdir_path.join("checkout");
+        filedata
+            .write_to(
+                &ink_dir,
+                &checkout_path,
+                &filters,
+                &StoragePolicy::default(),
+            )
+            .unwrap();
+        assert_eq!(fs::read(&checkout_path).unwrap(), b"this is a test!");
+        assert_eq!(
+            utils::file_mode(&fs::metadata(&checkout_path).unwrap()),
+            filedata.permissions
+        );
+        assert_eq!(filedata.read_content(&ink_dir).unwrap(), b"this is a test!");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn checkout_keeps_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let repo = crate::testkit::TestRepo::new()
+            .file("script", "#!/bin/sh\n")
+            .file("data", "compressed");
+        let script = repo.path().join("script");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        // an existing file's mode must not carry over to the new one
+        let repo = repo
+            .commit("add files")
+            .file("script", "old")
+            .file("data", "old");
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let (filters, policy) = (ContentFilters::default(), StoragePolicy::default());
+        for filedata in repo.head().files() {
+            let path = repo.path().join(filedata.path());
+            filedata
+                .write_to(repo.ink_root(), &path, &filters, &policy)
+                .unwrap();
+
+            let mode = utils::file_mode(&fs::metadata(&path).unwrap());
+            assert_eq!(mode, filedata.permissions);
+            assert_eq!(
+                fs::read(&path).unwrap(),
+                filedata.read_content(repo.ink_root()).unwrap()
+            );
+        }
+
+        let mode = fs::metadata(&script).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        // nothing is left behind in the directory
+        assert_eq!(fs::read_dir(repo.path()).unwrap().count(), 3);
+    }
+
+    #[test]
+    fn stored_content_is_not_read_again() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "shared")
+            .commit("first")
+            .file("b", "shared");
+        let ink_dir = repo.ink_root();
+        let filters = ContentFilters::load(ink_dir, repo.path()).unwrap();
+        let filedata = FileData::new(&repo.path().join("b"), ".ink", &filters).unwrap();
+
+        // writing would fail if it opened the file
+        fs::remove_file(repo.path().join("b")).unwrap();
+        let written = filedata.write(
+            ink_dir,
+            &filters,
+            &LargeFiles::load(ink_dir).unwrap(),
+            &StoragePolicy::default(),
+            &Timings::default(),
+        );
+        assert_eq!(written.unwrap(), None);
+    }
 }
@@ -0,0 +1,285 @@
+//! Content filters, which transform files as they are committed (clean) and
+//! checked out (smudge).
+//!
+//! Paths choose a filter with `filter` in a `[path "<pattern>"]` section of the
+//! config, where the last matching pattern wins. A filter is either built in,
+//! or defined by a `[filter "<name>"]` section with `clean` and `smudge`
+//! commands, e.g.
+//!
+//! ```text
+//! [filter "strip-outputs"]
+//! clean = jq '.cells[].outputs = []'
+//!
+//! [path "*.ipynb"]
+//! filter = strip-outputs
+//! ```
+//!
+//! Commands are run by the shell in the project directory, read the content on
+//! stdin and write the result to stdout. `%f` is replaced with the file's path.
+//! A missing command leaves content as it is.
+//!
+//! Built in filters:
+//! - `trailing-whitespace` removes spaces and tabs from the end of lines on clean
+//!
+//! Line ending normalization (see `normalize`) applies to every path, after
+//! cleaning and before smudging.
+use crate::config::Config;
+use crate::normalize::{Normalization, TextMode};
+use crate::{utils, InkError};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    TrailingWhitespace,
+    Command {
+        clean: Option<String>,
+        smudge: Option<String>,
+    },
+}
+
+impl Filter {
+    fn clean(
+        &self,
+        path: &Path,
+        project_dir: &Path,
+        content: Vec<u8>,
+    ) -> Result<Vec<u8>, InkError> {
+        match self {
+            Filter::TrailingWhitespace => Ok(strip_trailing_whitespace(&content)),
+            Filter::Command {
+                clean: Some(command),
+                ..
+            } => run_command(command, path, project_dir, content),
+            Filter::Command { clean: None, .. } => Ok(content),
+        }
+    }
+
+    fn smudge(
+        &self,
+        path: &Path,
+        project_dir: &Path,
+        content: Vec<u8>,
+    ) -> Result<Vec<u8>, InkError> {
+        match self {
+            Filter::Command {
+                smudge: Some(command),
+                ..
+            } => run_command(command, path, project_dir, content),
+            _ => Ok(content),
+        }
+    }
+}
+
+/// The filters chosen for each path in a repository
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ContentFilters {
+    project_dir: PathBuf,
+    normalization: Normalization,
+    rules: Vec<(String, Filter)>,
+}
+
+impl ContentFilters {
+    /// The filters in the config, whose commands run in the project directory
+    pub fn load(ink_root: &Path, project_dir: &Path) -> Result<ContentFilters, InkError> {
+        ContentFilters::from_config(&Config::load(ink_root)?, project_dir)
+    }
+
+    pub fn from_config(config: &Config, project_dir: &Path) -> Result<ContentFilters, InkError> {
+        let mut named = HashMap::new();
+        named.insert("trailing-whitespace", Filter::TrailingWhitespace);
+        for section in config.sections("filter") {
+            let name = section.label().ok_or("Filter sections need a name")?;
+            named.insert(
+                name,
+                Filter::Command {
+                    clean: section.get("clean").map(String::from),
+                    smudge: section.get("smudge").map(String::from),
+                },
+            );
+        }
+
+        let mut rules = Vec::new();
+        for section in config.sections("path") {
+            if let (Some(pattern), Some(name)) = (section.label(), section.get("filter")) {
+                let filter = named.get(name).ok_or("Path uses an unknown filter")?;
+                rules.push((pattern.to_string(), filter.clone()));
+            }
+        }
+
+        Ok(ContentFilters {
+            project_dir: project_dir.to_path_buf(),
+            normalization: Normalization::from_config(config)?,
+            rules,
+        })
+    }
+
+    /// The working directory the filters were loaded for, which paths are
+    /// relative to
+    pub(crate) fn project_dir(&self) -> &Path {
+        &self.project_dir
+    }
+
+    fn filter(&self, path: &Path) -> Option<&Filter> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| utils::glob_match(pattern, path))
+            .map(|(_, filter)| filter)
+    }
+
+    /// Whether content at a path is stored exactly as it is in the working directory
+    pub fn is_identity(&self, path: &Path) -> bool {
+        self.filter(path).is_none() && self.normalization.mode(path) == TextMode::Off
+    }
+
+    /// Convert content from the working directory to the form it is stored in.
+    /// `path` is relative to the project directory.
+    pub fn clean(&self, path: &Path, content: Vec<u8>) -> Result<Vec<u8>, InkError> {
+        let content = match self.filter(path) {
+            Some(filter) => filter.clean(path, &self.project_dir, content)?,
+            None => content,
+        };
+        Ok(self.normalization.mode(path).clean(content))
+    }
+
+    /// Convert stored content to the form it is checked out in.
+    /// `path` is relative to the project directory.
+    pub fn smudge(&self, path: &Path, content: Vec<u8>) -> Result<Vec<u8>, InkError> {
+        let content = self.normalization.mode(path).smudge(content);
+        match self.filter(path) {
+            Some(filter) => filter.smudge(path, &self.project_dir, content),
+            None => Ok(content),
+        }
+    }
+}
+
+fn strip_trailing_whitespace(content: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(content.len());
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        let (text, ending) = match line {
+            [text @ .., b'\r', b'\n'] => (text, &b"\r\n"[..]),
+            [text @ .., b'\n'] => (text, &b"\n"[..]),
+            text => (text, &b""[..]),
+        };
+        let end = text
+            .iter()
+            .rposition(|&b| b != b' ' && b != b'\t')
+            .map_or(0, |i| i + 1);
+        stripped.extend_from_slice(&text[..end]);
+        stripped.extend_from_slice(ending);
+    }
+    stripped
+}
+
+/// Run a filter command with the content on stdin, returning its stdout
+fn run_command(
+    command: &str,
+    path: &Path,
+    project_dir: &Path,
+    content: Vec<u8>,
+) -> Result<Vec<u8>, InkError> {
+    let command = command.replace("%f", &shell_quote(&path.to_string_lossy()));
+
+    let mut child = shell(&command, project_dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()?;
+
+    // write from another thread so a filter that streams its output can't
+    // fill the pipe and wait on us forever
+    let mut stdin = child.stdin.take().ok_or("Could not open filter stdin")?;
+    let writer = thread::spawn(move || stdin.write_all(&content));
+
+    let mut output = Vec::new();
+    child
+        .stdout
+        .take()
+        .ok_or("Could not open filter stdout")?
+        .read_to_end(&mut output)?;
+
+    let status = child.wait()?;
+    if !status.success() {
+        return Err("Content filter command failed".into());
+    }
+
+    // a filter may finish without reading all of its input
+    match writer.join() {
+        Ok(Err(e)) if e.kind() != io::ErrorKind::BrokenPipe => Err(e.into()),
+        Ok(_) => Ok(output),
+        Err(_) => Err("Filter input thread panicked".into()),
+    }
+}
+
+/// A command run by the shell in the project directory
+pub(crate) fn shell(command: &str, project_dir: &Path) -> Command {
+    #[cfg(windows)]
+    let mut shell = Command::new("cmd");
+    #[cfg(windows)]
+    shell.arg("/C");
+    #[cfg(not(windows))]
+    let mut shell = Command::new("sh");
+    #[cfg(not(windows))]
+    shell.arg("-c");
+
+    shell.arg(command).current_dir(project_dir);
+    shell
+}
+
+pub(crate) fn shell_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filters(config: &str) -> ContentFilters {
+        ContentFilters::from_config(&Config::parse(config).unwrap(), Path::new(".")).unwrap()
+    }
+
+    #[test]
+    fn builtin_filter() {
+        let filters = filters("[path \"*.md\"]\nfilter = trailing-whitespace\n");
+        let path = Path::new("docs/readme.md");
+
+        assert!(!filters.is_identity(path));
+        assert!(filters.is_identity(Path::new("main.rs")));
+        assert_eq!(
+            filters
+                .clean(path, b"one  \r\ntwo\t\nthree ".to_vec())
+                .unwrap(),
+            b"one\r\ntwo\nthree"
+        );
+        assert_eq!(filters.smudge(path, b"one ".to_vec()).unwrap(), b"one ");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_filter() {
+        let filters = filters(
+            "[core]\ntext = lf\n[filter \"upper\"]\nclean = tr a-z A-Z\nsmudge = tr A-Z a-z; echo %f\n[path \"*.txt\"]\nfilter = upper\n",
+        );
+        let path = Path::new("it's.txt");
+
+        assert_eq!(filters.clean(path, b"one\r\n".to_vec()).unwrap(), b"ONE\n");
+        assert_eq!(
+            filters.smudge(path, b"ONE\n".to_vec()).unwrap(),
+            b"one\nit's.txt\n"
+        );
+
+        let failing =
+            self::filters("[filter \"fail\"]\nclean = exit 1\n[path \"*\"]\nfilter = fail\n");
+        assert!(failing.clean(path, vec![]).is_err());
+    }
+
+    #[test]
+    fn unknown_filter() {
+        let config = Config::parse("[path \"*\"]\nfilter = nothing\n").unwrap();
+        assert!(ContentFilters::from_config(&config, Path::new(".")).is_err());
+    }
+}
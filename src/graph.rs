@@ -1,3 +1,4 @@
 mod commit_graph;
 mod id_graph;
-pub use commit_graph::CommitGraph;
+pub mod render;
+pub use commit_graph::{CommitGraph, MissingParent};
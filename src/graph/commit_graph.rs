@@ -1,9 +1,37 @@
 use super::id_graph::IDGraph;
-use crate::commit::Commit;
-use crate::{InkError, GRAPH_FILE};
-use std::fs;
+use super::render::{self, Node};
+use crate::commit::{self, Commit};
+use crate::history::CommitsTouching;
+use crate::storage::Durability;
+use crate::vfs::{OsFs, VirtualFs};
+use crate::{InkError, GRAPH_FILE, GRAPH_LOG_FILE};
+use bincode::Options;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
+/// A parent hash followed by its child's
+const LOG_RECORD: usize = 64;
+/// How many commits the log holds before it is folded into the graph file
+const COMPACT_AFTER: u64 = 1024;
+const DAMAGED: &str = "`ink debug rebuild-graph` can rebuild the graph from the commits";
+
+/// A commit, and a parent of it that isn't in the graph
+pub type MissingParent = ([u8; 32], [u8; 32]);
+
+/// The graph of every commit and its parents.
+///
+/// The graph is stored whole in `.ink/graph`. Only writes are incremental:
+/// making a commit doesn't need the rest of the graph, so new commits are
+/// appended to `.ink/graph-log` instead, and the log is folded into the
+/// graph file when it grows long or the graph is next written. Reads are
+/// not; `get` still deserializes the whole graph and replays the log.
+/// The graph file ends with a sha256 of the rest of it, so a truncated or
+/// damaged file is found when it is read rather than misread.
 #[derive(Debug)]
 pub struct CommitGraph {
     graph_path: PathBuf,
@@ -18,29 +46,473 @@ impl CommitGraph {
         // maybe ensure this is the empty commit by checking it's hash is the same thing the empty
         // commit's hash always is?
         graph.add_node(empty_commit.hash())?;
-        fs::write(&graph_path, bincode::serialize(&graph)?)?;
+        Durability::load(ink_dir)?.write(graph_path, &encode(&graph)?)?;
 
         Ok(())
     }
 
+    /// Load the whole graph, including commits still in the log
     pub fn get(ink_dir: &Path) -> Result<CommitGraph, InkError> {
+        CommitGraph::read(&OsFs, ink_dir)
+    }
+
+    /// Like `get`, but reads the graph through the given filesystem
+    pub fn read(fs: &dyn VirtualFs, ink_dir: &Path) -> Result<CommitGraph, InkError> {
         let graph_path = ink_dir.join(GRAPH_FILE);
-        let graph: IDGraph = bincode::deserialize(&fs::read(&graph_path)?)?;
+        let mut graph = decode(&fs.read(&graph_path)?).ok_or_else(|| damaged(&graph_path))?;
+
+        let log_path = ink_dir.join(GRAPH_LOG_FILE);
+        if fs.is_file(&log_path) {
+            // a record cut short by a crash was never finished, so is left out
+            for record in fs.read(&log_path)?.chunks_exact(LOG_RECORD) {
+                let (parent, child) = record.split_at(32);
+                let (parent, child) = (parent.try_into().unwrap(), child.try_into().unwrap());
+                // a commit made again, e.g. at a fixed time, is logged again
+                if !graph.contains(&child) {
+                    graph.add_node(child)?;
+                }
+                if !graph.parents(&child).is_some_and(|p| p.contains(&parent)) {
+                    graph
+                        .add_edge(parent, child)
+                        .map_err(|_| damaged(&log_path))?;
+                }
+            }
+        }
+
         Ok(CommitGraph { graph_path, graph })
     }
 
+    /// Add a commit as a child of each of its parents without reading the
+    /// graph, by appending it to the graph log. The log is folded into the
+    /// graph file once it holds enough records.
+    pub fn append_commit(ink_dir: &Path, commit: &Commit) -> Result<(), InkError> {
+        let log_path = ink_dir.join(GRAPH_LOG_FILE);
+        let mut log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)?;
+
+        // written at once, so a crash can only cut the last record short
+        let mut records = Vec::with_capacity(commit.parents().len() * LOG_RECORD);
+        for parent in commit.parents() {
+            records.extend_from_slice(parent);
+            records.extend_from_slice(&commit.hash());
+        }
+        log.write_all(&records)?;
+        let durability = Durability::load(ink_dir)?;
+        durability.sync(&log)?;
+        durability.sync_dir(&log_path)?;
+
+        if log.metadata()?.len() >= COMPACT_AFTER * LOG_RECORD as u64 {
+            CommitGraph::get(ink_dir)?.write()?;
+        }
+
+        Ok(())
+    }
+
+    /// Reconstruct the graph from the parent hashes stored in every commit object,
+    /// for when the graph file is lost or corrupt. Does not write the new graph.
+    pub fn rebuild(ink_dir: &Path) -> Result<CommitGraph, InkError> {
+        let commits = commit::stored_hashes(ink_dir)?
+            .iter()
+            .map(|hash| Commit::from(hash, ink_dir))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let (graph, missing) = CommitGraph::from_commits(ink_dir, &commits)?;
+        if !missing.is_empty() {
+            return Err("A commit's parent is missing from the commit directory".into());
+        }
+
+        Ok(graph)
+    }
+
+    /// Build a graph of the given commits from their parent hashes. Parents
+    /// that aren't among the commits are left out, and returned as pairs of
+    /// the commit and the missing parent. Does not write the new graph.
+    pub fn from_commits(
+        ink_dir: &Path,
+        commits: &[Commit],
+    ) -> Result<(CommitGraph, Vec<MissingParent>), InkError> {
+        let mut graph = IDGraph::new();
+        for commit in commits {
+            graph.add_node(commit.hash())?;
+        }
+
+        let mut missing = Vec::new();
+        for commit in commits {
+            for parent in commit.parents() {
+                if graph.contains(parent) {
+                    graph.add_edge(*parent, commit.hash())?;
+                } else {
+                    missing.push((commit.hash(), *parent));
+                }
+            }
+        }
+
+        let graph = CommitGraph {
+            graph_path: ink_dir.join(GRAPH_FILE),
+            graph,
+        };
+        Ok((graph, missing))
+    }
+
     pub fn add_commit(&mut self, from: &Commit, to: &Commit) -> Result<(), InkError> {
         self.graph.add_node(to.hash())?;
         self.graph.add_edge(from.hash(), to.hash())?;
         Ok(())
     }
 
+    /// Whether the commit with the given hash is in the graph
+    pub fn contains(&self, hash: &[u8; 32]) -> bool {
+        self.graph.contains(hash)
+    }
+
     pub fn write(self) -> Result<(), InkError> {
-        fs::write(&self.graph_path, bincode::serialize(&self.graph)?)?;
+        // synced as the config says before the log it replaces is removed
+        let ink_dir = self
+            .graph_path
+            .parent()
+            .ok_or("Path has no parent directory")?;
+        Durability::load(ink_dir)?.write(&self.graph_path, &encode(&self.graph)?)?;
+        self.remove_log(&OsFs)
+    }
+
+    /// Like `write`, but writes the graph through the given filesystem
+    pub fn write_with(self, fs: &dyn VirtualFs) -> Result<(), InkError> {
+        fs.write(&self.graph_path, &encode(&self.graph)?)?;
+        self.remove_log(fs)
+    }
+
+    /// Remove the log, once the graph holds everything that was logged in it
+    fn remove_log(&self, fs: &dyn VirtualFs) -> Result<(), InkError> {
+        let log_path = self.graph_path.with_file_name(GRAPH_LOG_FILE);
+        if fs.is_file(&log_path) {
+            fs.remove_file(&log_path)?;
+        }
         Ok(())
     }
 
+    /// The parents of a commit, none if it isn't in the graph
+    pub fn parents(&self, hash: &[u8; 32]) -> &[[u8; 32]] {
+        self.graph.parents(hash).unwrap_or(&[])
+    }
+
     pub fn commit_hashes(&self) -> Vec<&[u8; 32]> {
         self.graph.keys()
     }
+
+    /// The commits with no children
+    pub fn heads(&self) -> Vec<[u8; 32]> {
+        self.graph.heads()
+    }
+
+    /// The commits in the graph that aren't any of the given heads or an
+    /// ancestor of one
+    pub fn unreachable_from(&self, heads: &[[u8; 32]]) -> Vec<[u8; 32]> {
+        let reachable = self.ancestors(heads);
+        let mut unreachable: Vec<[u8; 32]> = self
+            .graph
+            .keys()
+            .into_iter()
+            .filter(|hash| !reachable.contains(*hash))
+            .copied()
+            .collect();
+        unreachable.sort();
+        unreachable
+    }
+
+    /// The given commits and every ancestor of theirs in the graph
+    pub fn ancestors(&self, heads: &[[u8; 32]]) -> HashSet<[u8; 32]> {
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<[u8; 32]> = heads.to_vec();
+        while let Some(hash) = stack.pop() {
+            if !reachable.insert(hash) {
+                continue;
+            }
+
+            if let Some(parents) = self.graph.parents(&hash) {
+                stack.extend_from_slice(parents);
+            }
+        }
+
+        reachable
+    }
+
+    /// Whether `ancestor` is in the history of `descendant`, or is it, so
+    /// moving from one to the other is a fast-forward
+    pub fn is_ancestor(&self, ancestor: &[u8; 32], descendant: &[u8; 32]) -> bool {
+        self.ancestors(&[*descendant]).contains(ancestor)
+    }
+
+    /// The best common ancestor of the commits in `ours` and `theirs`: one
+    /// that no other common ancestor has in its history. If there are
+    /// several, as after criss-cross merges, the lowest hash is taken so the
+    /// choice is the same every time.
+    pub fn merge_base(&self, ours: &[[u8; 32]], theirs: &[u8; 32]) -> Option<[u8; 32]> {
+        let theirs = self.ancestors(&[*theirs]);
+        let common: Vec<[u8; 32]> = self
+            .ancestors(ours)
+            .into_iter()
+            .filter(|hash| theirs.contains(hash))
+            .collect();
+
+        common
+            .iter()
+            .filter(|&hash| {
+                !common
+                    .iter()
+                    .any(|other| other != hash && self.is_ancestor(hash, other))
+            })
+            .min()
+            .copied()
+    }
+
+    /// How many commits `a` has that `b` doesn't, and how many `b` has that
+    /// `a` doesn't: the commits each has made since their merge base
+    pub fn ahead_behind(&self, a: &[u8; 32], b: &[u8; 32]) -> (usize, usize) {
+        let a = self.ancestors(&[*a]);
+        let b = self.ancestors(&[*b]);
+        (a.difference(&b).count(), b.difference(&a).count())
+    }
+
+    /// Walk the history of a commit, newest first, keeping only the commits
+    /// that change the given path
+    pub fn commits_touching(&self, from: Commit, path: &Path) -> Result<CommitsTouching, InkError> {
+        let ink_dir = self.graph_path.parent().ok_or("Graph has no directory")?;
+        CommitsTouching::new(ink_dir, from, path)
+    }
+
+    /// Every commit in the graph, each before its parents. Commits that
+    /// could come in either order are ordered by hash, since the graph
+    /// doesn't know when they were made.
+    pub fn topological(&self) -> Vec<[u8; 32]> {
+        let mut waiting: HashMap<[u8; 32], usize> = HashMap::new();
+        let mut ready: BTreeSet<[u8; 32]> = self.graph.heads().into_iter().collect();
+        let mut order = Vec::new();
+
+        while let Some(hash) = ready.pop_first() {
+            order.push(hash);
+            for parent in self.graph.parents(&hash).unwrap_or(&[]) {
+                // a parent is ready once all of its children are out
+                let children = self.graph.children(parent).map_or(0, <[_]>::len);
+                let done = waiting.entry(*parent).or_insert(0);
+                *done += 1;
+                if *done == children {
+                    ready.insert(*parent);
+                }
+            }
+        }
+
+        order
+    }
+
+    /// Remove a commit and its edges from the graph
+    pub fn remove_commit(&mut self, hash: &[u8; 32]) -> Result<(), InkError> {
+        self.graph.remove_node(*hash)?;
+        Ok(())
+    }
+}
+
+impl fmt::Display for CommitGraph {
+    /// The commits as a graph, by short hash
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let nodes = self.topological().into_iter().map(|hash| Node {
+            hash,
+            parents: self.graph.parents(&hash).unwrap_or(&[]).to_vec(),
+            label: render::short_hash(&hash),
+        });
+        f.write_str(&render::graph(nodes))
+    }
+}
+
+/// Serialize a graph, followed by a checksum of it
+fn encode(graph: &IDGraph) -> Result<Vec<u8>, InkError> {
+    let mut bytes = bincode::serialize(graph)?;
+    let checksum = Sha256::digest(&bytes);
+    bytes.extend_from_slice(&checksum);
+    Ok(bytes)
+}
+
+/// Deserialize a graph written by `encode`, checking it first, or one
+/// written before graphs had a checksum, which `encode` adds when it is next
+/// written. A graph that doesn't check out is `None`.
+fn decode(bytes: &[u8]) -> Option<IDGraph> {
+    if bytes.len() >= 32 {
+        let (graph, checksum) = bytes.split_at(bytes.len() - 32);
+        if Sha256::digest(graph).as_slice() == checksum {
+            return bincode::deserialize(graph).ok();
+        }
+    }
+
+    // a legacy graph is the whole file, so a damaged graph with a checksum
+    // doesn't pass for one by leaving bytes over
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .reject_trailing_bytes()
+        .deserialize(bytes)
+        .ok()
+}
+
+/// The error for a damaged graph or graph log
+fn damaged(path: &Path) -> InkError {
+    InkError::Malformed {
+        path: path.to_path_buf(),
+        problem: DAMAGED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn rebuild_from_commits() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = crate::Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir_path.join("example"), b"this is a test!").unwrap();
+        repo.commit("first", Default::default()).unwrap();
+        fs::write(tmpdir_path.join("example"), b"this is another test!").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+
+        let graph = CommitGraph::get(&ink_dir).unwrap();
+        fs::remove_file(ink_dir.join(GRAPH_FILE)).unwrap();
+
+        let rebuilt = CommitGraph::rebuild(&ink_dir).unwrap();
+        assert_eq!(graph.graph, rebuilt.graph);
+    }
+
+    #[test]
+    fn commits_are_logged_then_compacted() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "first")
+            .commit("first")
+            .file("a", "second")
+            .commit("second");
+        let ink_dir = repo.ink_root();
+        let log_path = ink_dir.join(GRAPH_LOG_FILE);
+
+        // both commits are only in the log, but read as part of the graph
+        assert_eq!(
+            fs::metadata(&log_path).unwrap().len(),
+            2 * LOG_RECORD as u64
+        );
+        let graph = CommitGraph::get(ink_dir).unwrap();
+        assert_eq!(graph.commit_hashes().len(), 3);
+        assert!(graph.contains(&repo.head().hash()));
+
+        // a record cut short is ignored, and writing folds the log in
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&log_path)
+            .unwrap()
+            .write_all(&[1; 10])
+            .unwrap();
+        let graph = CommitGraph::get(ink_dir).unwrap();
+        graph.write().unwrap();
+        assert!(!log_path.exists());
+        assert_eq!(CommitGraph::get(ink_dir).unwrap().commit_hashes().len(), 3);
+    }
+
+    #[test]
+    fn damaged_graph_is_detected() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "a")
+            .commit("first");
+        let ink_dir = repo.ink_root();
+        let graph_path = ink_dir.join(GRAPH_FILE);
+        CommitGraph::get(ink_dir).unwrap().write().unwrap();
+
+        let bytes = fs::read(&graph_path).unwrap();
+        let mut flipped = bytes.clone();
+        flipped[0] ^= 1;
+        for damaged in &[&bytes[..bytes.len() - 1], &bytes[..10], &flipped[..]] {
+            fs::write(&graph_path, damaged).unwrap();
+            let err = CommitGraph::get(ink_dir).unwrap_err();
+            assert!(err.to_string().contains("rebuild-graph"));
+            assert!(matches!(err, InkError::Malformed { path, .. } if path == graph_path));
+        }
+    }
+
+    #[test]
+    fn legacy_graph_is_read() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "a")
+            .commit("first");
+        let ink_dir = repo.ink_root();
+        let graph_path = ink_dir.join(GRAPH_FILE);
+        let graph = CommitGraph::get(ink_dir).unwrap();
+        let hashes = graph.commit_hashes().len();
+
+        // written without a checksum, and given one when next written
+        fs::write(&graph_path, bincode::serialize(&graph.graph).unwrap()).unwrap();
+        let graph = CommitGraph::get(ink_dir).unwrap();
+        assert_eq!(graph.commit_hashes().len(), hashes);
+        graph.write().unwrap();
+        let bytes = fs::read(&graph_path).unwrap();
+        assert_eq!(
+            decode(&bytes[..bytes.len() - 32]).unwrap().keys().len(),
+            hashes
+        );
+    }
+
+    #[test]
+    fn topological_order() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "first")
+            .commit("first")
+            .file("a", "second")
+            .commit("second");
+        let graph = CommitGraph::get(repo.ink_root()).unwrap();
+        let order = graph.topological();
+
+        let second = repo.head();
+        assert_eq!(order.len(), 3);
+        assert_eq!(order[0], second.hash());
+        assert_eq!(order[1], second.parents()[0]);
+        assert_eq!(graph.to_string().lines().count(), 3);
+    }
+
+    #[test]
+    fn ahead_and_behind() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "first")
+            .commit("first")
+            .file("a", "second")
+            .commit("second")
+            .file("a", "third")
+            .commit("third");
+        let third = repo.head();
+        let second = Commit::from(&third.parents()[0], repo.ink_root()).unwrap();
+        let first = Commit::from(&second.parents()[0], repo.ink_root()).unwrap();
+
+        // branch off the first commit, which going back to isn't a fast-forward
+        let ff_only = crate::CheckoutOptions::new().ff_only(true);
+        let again = Commit::from(&first.hash(), repo.ink_root()).unwrap();
+        assert!(repo.repo().go(again, ff_only).is_err());
+        repo.repo().go(first, Default::default()).unwrap();
+        let repo = repo.file("b", "other").commit("other");
+        let other = repo.head();
+
+        let graph = CommitGraph::get(repo.ink_root()).unwrap();
+        assert_eq!(graph.ahead_behind(&third.hash(), &other.hash()), (2, 1));
+        assert_eq!(graph.ahead_behind(&other.hash(), &third.hash()), (1, 2));
+        assert_eq!(graph.ahead_behind(&third.hash(), &second.hash()), (1, 0));
+        assert_eq!(graph.ahead_behind(&third.hash(), &third.hash()), (0, 0));
+        assert!(graph.is_ancestor(&second.hash(), &third.hash()));
+        assert!(!graph.is_ancestor(&third.hash(), &second.hash()));
+        assert!(!graph.is_ancestor(&other.hash(), &third.hash()));
+        assert_eq!(
+            graph.merge_base(&[third.hash()], &other.hash()),
+            Some(other.parents()[0])
+        );
+        assert_eq!(
+            graph.merge_base(&[third.hash(), other.hash()], &second.hash()),
+            Some(second.hash())
+        );
+    }
 }
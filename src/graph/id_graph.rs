@@ -50,13 +50,13 @@ impl IDGraph {
         // remove all edges for this node
         for other_id in children.iter() {
             if let Some(node) = self.nodes.get_mut(other_id) {
-                (*node).parents.retain(|elem| elem != &id)
+                node.parents.retain(|elem| elem != &id)
             }
         }
 
         for other_id in parents.iter() {
             if let Some(node) = self.nodes.get_mut(other_id) {
-                (*node).children.retain(|elem| elem != &id)
+                node.children.retain(|elem| elem != &id)
             }
         }
 
@@ -96,6 +96,7 @@ impl IDGraph {
     }
 
     /// Remove an edge between two IDs. Fails if the node IDs are not found.
+    #[allow(dead_code)]
     pub(crate) fn remove_edge(&mut self, from: InkID, to: InkID) -> Result<(), &'static str> {
         if !self.nodes.contains_key(&from) {
             return Err("Invalid ID for 'from' node");
@@ -132,6 +133,20 @@ impl IDGraph {
             .collect::<Vec<InkID>>()
     }
 
+    /// The parents of an ID, if it is in the graph
+    pub fn parents(&self, id: &InkID) -> Option<&[InkID]> {
+        self.nodes.get(id).map(|node| node.parents.as_slice())
+    }
+
+    /// The children of an ID, if it is in the graph
+    pub fn children(&self, id: &InkID) -> Option<&[InkID]> {
+        self.nodes.get(id).map(|node| node.children.as_slice())
+    }
+
+    pub fn contains(&self, id: &InkID) -> bool {
+        self.nodes.contains_key(id)
+    }
+
     pub fn keys(&self) -> Vec<&InkID> {
         self.nodes.keys().collect()
     }
@@ -0,0 +1,334 @@
+//! Drawing commits and the graph of them as text meant to be read by people
+use crate::commit::Commit;
+use crate::{cursor, tags, time, InkError};
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Hex digits of a hash shown to people
+pub const SHORT_HASH_LEN: usize = 10;
+
+/// A commit to draw in a graph: its hash, its parents' hashes, and the text
+/// shown next to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Node {
+    pub hash: [u8; 32],
+    pub parents: Vec<[u8; 32]>,
+    pub label: String,
+}
+
+impl Node {
+    pub fn new(commit: &Commit, label: String) -> Node {
+        Node {
+            hash: commit.hash(),
+            parents: commit.parents().to_vec(),
+            label,
+        }
+    }
+}
+
+/// The start of a hash, enough to tell commits apart by eye
+pub fn short_hash(hash: &[u8; 32]) -> String {
+    hex::encode(&hash[..SHORT_HASH_LEN / 2])
+}
+
+/// The names shown next to commits: `HEAD` for the current commit, then
+/// its tags by name
+pub fn decorations(ink_root: &Path) -> Result<HashMap<[u8; 32], Vec<String>>, InkError> {
+    let mut decorations: HashMap<_, Vec<_>> = HashMap::new();
+    decorations
+        .entry(cursor::get(ink_root)?.hash())
+        .or_default()
+        .push("HEAD".to_string());
+    for (name, hash) in tags::list(ink_root)? {
+        decorations
+            .entry(hash)
+            .or_default()
+            .push(format!("tag: {}", name));
+    }
+
+    Ok(decorations)
+}
+
+/// A commit on one line: its short hash, date, first line of its message
+/// and any decorations, like `1a2b3c4d5e 2020-09-13 14:26:40 +0200 fix (HEAD)`
+pub fn one_line(
+    commit: &Commit,
+    decorations: &HashMap<[u8; 32], Vec<String>>,
+    utc: bool,
+) -> String {
+    let offset = if utc { 0 } else { commit.offset() };
+    let mut line = format!(
+        "{} {}",
+        short_hash(&commit.hash()),
+        time::format(commit.time(), offset)
+    );
+    if let Some(summary) = commit.message().lines().next() {
+        line.push(' ');
+        line.push_str(summary);
+    }
+    if let Some(names) = decorations.get(&commit.hash()) {
+        line.push_str(&format!(" ({})", names.join(", ")));
+    }
+
+    line
+}
+
+/// Draw commits as a graph, one line each with `|`, `/` and `\\` lines
+/// between them, given children before parents.
+///
+/// Each line of history being drawn has a column, holding the commit it
+/// is waiting for. A commit goes in the first column waiting for it, and
+/// that column then waits for its first parent. Other parents of a merge
+/// get new columns to its right, and when several columns wait for the
+/// same commit, the others join the first before it is drawn. A merge of
+/// more than two parents is drawn like `*-.`, with a line to each.
+pub fn graph<I: IntoIterator<Item = Node>>(nodes: I) -> String {
+    let mut columns: Vec<Option<[u8; 32]>> = Vec::new();
+    let mut out = String::new();
+
+    for node in nodes {
+        // branches that split from this commit join up, rightmost first
+        loop {
+            let waiting: Vec<_> = (0..columns.len())
+                .filter(|&i| columns[i] == Some(node.hash))
+                .collect();
+            let (first, other) = match waiting[..] {
+                [first, .., other] => (first, other),
+                _ => break,
+            };
+
+            let mut row = Row::new(&columns);
+            for i in first + 1..other {
+                row.set(2 * i - 1, '_');
+            }
+            row.set(2 * other, ' ');
+            row.set(2 * other - 1, '/');
+            row.shift(&columns, other + 1, '/');
+            out.push_str(&row.finish());
+            columns.remove(other);
+        }
+
+        let column = match columns.iter().position(|c| *c == Some(node.hash)) {
+            Some(column) => column,
+            None => match columns.iter().position(Option::is_none) {
+                Some(free) => free,
+                None => {
+                    columns.push(None);
+                    columns.len() - 1
+                }
+            },
+        };
+
+        // an octopus merge spreads out to the right on its own line, so
+        // the columns there move over first
+        let spread = node.parents.len().saturating_sub(2);
+        for _ in 0..spread {
+            if columns[column + 1..].iter().any(Option::is_some) {
+                let mut row = Row::new(&columns);
+                row.shift(&columns, column + 1, '\\');
+                out.push_str(&row.finish());
+            }
+            columns.insert(column + 1, None);
+        }
+
+        let mut row = Row::new(&columns);
+        row.set(2 * column, '*');
+        for i in 1..=spread {
+            row.set(2 * (column + i) - 1, '-');
+            row.set(2 * (column + i), '.');
+        }
+        let line = row.finish();
+        out.push_str(line.trim_end());
+        out.push(' ');
+        out.push_str(&node.label);
+        out.push('\n');
+
+        columns[column] = node.parents.first().copied();
+        if node.parents.len() > 1 {
+            let last = column + node.parents.len() - 1;
+            let mut row = Row::new(&columns);
+            for at in column + 1..=last {
+                row.set(2 * at - 1, '\\');
+            }
+            row.shift(&columns, last, '\\');
+            out.push_str(&row.finish());
+
+            for (i, parent) in node.parents.iter().enumerate().skip(1) {
+                match i {
+                    i if i <= spread => columns[column + i] = Some(*parent),
+                    _ => columns.insert(last, Some(*parent)),
+                }
+            }
+        }
+
+        while columns.last() == Some(&None) {
+            columns.pop();
+        }
+    }
+
+    out
+}
+
+/// A line of connectors between commits, two characters per column
+struct Row {
+    chars: Vec<char>,
+}
+
+impl Row {
+    /// A row with a `|` for each column waiting for a commit
+    fn new(columns: &[Option<[u8; 32]>]) -> Row {
+        let mut chars = vec![' '; 2 * columns.len() + 2];
+        for (i, column) in columns.iter().enumerate() {
+            if column.is_some() {
+                chars[2 * i] = '|';
+            }
+        }
+        Row { chars }
+    }
+
+    fn set(&mut self, at: usize, c: char) {
+        if self.chars.len() <= at {
+            self.chars.resize(at + 1, ' ');
+        }
+        self.chars[at] = c;
+    }
+
+    /// Draw the columns from `from` on as moving one place, left for `/`
+    /// and right for `\\`
+    fn shift(&mut self, columns: &[Option<[u8; 32]>], from: usize, c: char) {
+        for (i, column) in columns.iter().enumerate().skip(from) {
+            if column.is_some() {
+                self.set(2 * i, ' ');
+                let to = if c == '/' { 2 * i - 1 } else { 2 * i + 1 };
+                self.set(to, c);
+            }
+        }
+    }
+
+    fn finish(self) -> String {
+        let line: String = self.chars.into_iter().collect();
+        format!("{}\n", line.trim_end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+
+    #[test]
+    fn commit_lines() {
+        let repo = TestRepo::new().file("a", "a").commit("first\nmore");
+        let commit = repo.head();
+        tags::create(repo.ink_root(), "v1", &commit).unwrap();
+
+        let decorations = decorations(repo.ink_root()).unwrap();
+        let line = one_line(&commit, &decorations, true);
+        assert!(line.starts_with(&hex::encode(commit.hash())[..SHORT_HASH_LEN]));
+        assert!(line.ends_with(" +0000 first (HEAD, tag: v1)"));
+
+        let node = Node::new(&commit, "first".to_string());
+        assert_eq!(graph(vec![node]), "* first\n");
+    }
+
+    fn node(label: &str, parents: &[&str]) -> Node {
+        let hash = |name: &str| {
+            let mut hash = [0; 32];
+            hash[..name.len()].copy_from_slice(name.as_bytes());
+            hash
+        };
+        Node {
+            hash: hash(label),
+            parents: parents.iter().map(|p| hash(p)).collect(),
+            label: label.to_string(),
+        }
+    }
+
+    #[test]
+    fn branches_and_merges() {
+        let nodes = vec![
+            node("merge", &["a2", "b"]),
+            node("a2", &["a1"]),
+            node("b", &["base"]),
+            node("a1", &["base"]),
+            node("base", &[]),
+        ];
+        assert_eq!(
+            graph(nodes),
+            "* merge\n\
+             |\\\n\
+             * | a2\n\
+             | * b\n\
+             * | a1\n\
+             |/\n\
+             * base\n"
+        );
+
+        // a third line of history joins across the one in the middle
+        let nodes = vec![
+            node("x", &["base"]),
+            node("y", &["base"]),
+            node("z", &["base"]),
+            node("base", &[]),
+        ];
+        assert_eq!(
+            graph(nodes),
+            "* x\n\
+             | * y\n\
+             | | * z\n\
+             |_|/\n\
+             |/\n\
+             * base\n"
+        );
+    }
+
+    #[test]
+    fn octopus_merges() {
+        let nodes = vec![
+            node("merge", &["a", "b", "c"]),
+            node("a", &["base"]),
+            node("b", &["base"]),
+            node("c", &["base"]),
+            node("base", &[]),
+        ];
+        assert_eq!(
+            graph(nodes),
+            "*-. merge\n\
+             |\\ \\\n\
+             * | | a\n\
+             | * | b\n\
+             | | * c\n\
+             |_|/\n\
+             |/\n\
+             * base\n"
+        );
+
+        // lines to the right move over to make room
+        let nodes = vec![
+            node("top", &["merge", "x"]),
+            node("merge", &["a", "b", "c"]),
+            node("x", &["a"]),
+            node("a", &["base"]),
+            node("b", &["base"]),
+            node("c", &["base"]),
+            node("base", &[]),
+        ];
+        assert_eq!(
+            graph(nodes),
+            "* top\n\
+             |\\\n\
+             |  \\\n\
+             *-. | merge\n\
+             |\\ \\ \\\n\
+             | | | * x\n\
+             |_|_|/\n\
+             * | | a\n\
+             | * | b\n\
+             | | * c\n\
+             |_|/\n\
+             |/\n\
+             * base\n"
+        );
+    }
+}
@@ -0,0 +1,559 @@
+//! Walking the history of commits
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::commit::Commit;
+use crate::index::ReverseIndex;
+use crate::vfs::{OsFs, VirtualFs};
+use crate::InkError;
+
+/// Iterator over a commit and all of its ancestors, newest first.
+/// Follows every parent, so each commit in a merged history is visited once.
+pub struct History {
+    fs: Rc<dyn VirtualFs>,
+    ink_root: PathBuf,
+    // ordered by time, then by when the commit was found, so children made
+    // in the same second as their parents still come first
+    queue: BinaryHeap<(u64, Reverse<usize>, [u8; 32])>,
+    pending: HashMap<[u8; 32], Commit>,
+    seen: HashSet<[u8; 32]>,
+    // how many found children of a commit are still to come, since after
+    // a merge a parent can be found before its other children
+    children: HashMap<[u8; 32], usize>,
+}
+
+impl History {
+    pub fn new(ink_root: &Path, from: Commit) -> History {
+        History::new_in(Rc::new(OsFs), ink_root, from)
+    }
+
+    /// Walk history, reading commits through the given filesystem
+    pub fn new_in(fs: Rc<dyn VirtualFs>, ink_root: &Path, from: Commit) -> History {
+        let mut history = History {
+            fs,
+            ink_root: ink_root.to_path_buf(),
+            queue: BinaryHeap::new(),
+            pending: HashMap::new(),
+            seen: HashSet::new(),
+            children: HashMap::new(),
+        };
+        history.push(from);
+        history
+    }
+
+    fn push(&mut self, commit: Commit) {
+        if self.seen.insert(commit.hash()) {
+            let order = Reverse(self.seen.len());
+            self.queue.push((commit.time(), order, commit.hash()));
+            for parent in commit.parents() {
+                *self.children.entry(*parent).or_default() += 1;
+            }
+            self.pending.insert(commit.hash(), commit);
+        }
+    }
+}
+
+impl Iterator for History {
+    type Item = Result<Commit, InkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a commit waits for its children, which are always in the queue
+        let mut waiting = Vec::new();
+        let hash = loop {
+            let entry = self.queue.pop()?;
+            match self.children.get(&entry.2) {
+                Some(&children) if children > 0 => waiting.push(entry),
+                _ => break entry.2,
+            }
+        };
+        self.queue.extend(waiting);
+
+        let commit = self.pending.remove(&hash)?;
+        for parent in commit.parents() {
+            if let Some(children) = self.children.get_mut(parent) {
+                *children -= 1;
+            }
+        }
+
+        for parent in commit.parents() {
+            if self.seen.contains(parent) {
+                continue;
+            }
+
+            match Commit::read(&*self.fs, parent, &self.ink_root) {
+                Ok(parent) => self.push(parent),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+
+        Some(Ok(commit))
+    }
+}
+
+/// A test a commit has to pass to stay in a filtered log
+pub type CommitFilter = Box<dyn Fn(&Commit) -> bool>;
+
+/// A log of commits keeping only those that pass every filter added to it,
+/// wrapping a `History` or `CommitsTouching` so the filters combine with a
+/// path. Commits are read as they are needed, so the whole history is only
+/// walked if the log is.
+pub struct Filtered<I> {
+    commits: I,
+    filters: Vec<CommitFilter>,
+}
+
+impl<I: Iterator<Item = Result<Commit, InkError>>> Filtered<I> {
+    pub fn new(commits: I) -> Filtered<I> {
+        Filtered {
+            commits,
+            filters: Vec::new(),
+        }
+    }
+
+    /// Keep only the commits the predicate is true of
+    pub fn filter<F: Fn(&Commit) -> bool + 'static>(mut self, predicate: F) -> Filtered<I> {
+        self.filters.push(Box::new(predicate));
+        self
+    }
+
+    /// Keep commits made at or after a time
+    pub fn since(self, time: SystemTime) -> Filtered<I> {
+        let time = epoch_seconds(time);
+        self.filter(move |commit| commit.time() >= time)
+    }
+
+    /// Keep commits made at or before a time
+    pub fn until(self, time: SystemTime) -> Filtered<I> {
+        let time = epoch_seconds(time);
+        self.filter(move |commit| commit.time() <= time)
+    }
+
+    /// Keep commits whose author contains the pattern
+    pub fn author(self, pattern: &str) -> Filtered<I> {
+        let pattern = pattern.to_string();
+        self.filter(move |commit| {
+            commit
+                .author()
+                .is_some_and(|author| author.contains(&pattern))
+        })
+    }
+
+    /// Keep commits whose message contains the pattern
+    pub fn grep(self, pattern: &str) -> Filtered<I> {
+        let pattern = pattern.to_string();
+        self.filter(move |commit| commit.message().contains(&pattern))
+    }
+}
+
+impl<I: Iterator<Item = Result<Commit, InkError>>> Iterator for Filtered<I> {
+    type Item = Result<Commit, InkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.commits.next()? {
+                Ok(commit) if !self.filters.iter().all(|keep| keep(&commit)) => continue,
+                result => return Some(result),
+            }
+        }
+    }
+}
+
+/// How many commits each author made, most first, then by name. Commits
+/// with no author recorded are counted under `None`, after named authors
+/// with as many.
+pub fn shortlog<I: Iterator<Item = Result<Commit, InkError>>>(
+    commits: I,
+) -> Result<Vec<(Option<String>, usize)>, InkError> {
+    let mut counts: HashMap<Option<String>, usize> = HashMap::new();
+    for commit in commits {
+        *counts
+            .entry(commit?.author().map(String::from))
+            .or_default() += 1;
+    }
+
+    let mut counts: Vec<_> = counts.into_iter().collect();
+    counts.sort_by_key(|(author, count)| (Reverse(*count), author.is_none(), author.clone()));
+    Ok(counts)
+}
+
+fn epoch_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+/// The files under a path in a commit, with their content hashes, by path
+type Entries = Vec<(PathBuf, [u8; 32])>;
+
+/// Iterator over the commits in a history that change a path, newest first.
+/// The path is relative to the project directory, and can name a file or a
+/// directory, standing for every file in it.
+///
+/// A commit changes the path if the files under it differ from those in each
+/// of its parents, so a merge that took them from one side is left out. With
+/// a reverse index, the files in each commit are looked up in it rather
+/// than read from parent commits.
+///
+/// When following renames, a file that appears in a commit with the content
+/// of a file its first parent had and it doesn't is taken to be that file
+/// renamed, and older commits are walked for the old path. Like `git log
+/// --follow`, only one path is followed at a time, across all lines of
+/// history.
+pub struct CommitsTouching {
+    history: History,
+    ink_root: PathBuf,
+    path: PathBuf,
+    follow: bool,
+    index: Option<ReverseIndex>,
+    // the entries of every commit under the path, from the index
+    indexed: Option<HashMap<[u8; 32], Entries>>,
+}
+
+impl CommitsTouching {
+    pub fn new(ink_root: &Path, from: Commit, path: &Path) -> Result<CommitsTouching, InkError> {
+        let index = ReverseIndex::load(ink_root)?;
+        let indexed = index.as_ref().map(|index| index_entries(index, path));
+
+        Ok(CommitsTouching {
+            history: History::new(ink_root, from),
+            ink_root: ink_root.to_path_buf(),
+            path: path.to_path_buf(),
+            follow: false,
+            index,
+            indexed,
+        })
+    }
+
+    /// Follow the file through renames
+    pub fn follow(mut self, follow: bool) -> CommitsTouching {
+        self.follow = follow;
+        self
+    }
+
+    /// The path being looked at, which changes as renames are followed
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn entries_of(&self, commit: &Commit) -> Entries {
+        if let Some(indexed) = &self.indexed {
+            return indexed.get(&commit.hash()).cloned().unwrap_or_default();
+        }
+
+        let mut entries: Entries = commit
+            .files()
+            .iter()
+            .filter(|file| file.path().starts_with(&self.path))
+            .map(|file| (file.path().to_path_buf(), file.content_hash()))
+            .collect();
+        entries.sort();
+        entries
+    }
+
+    fn entries(&self, hash: &[u8; 32]) -> Result<Entries, InkError> {
+        if let Some(indexed) = &self.indexed {
+            return Ok(indexed.get(hash).cloned().unwrap_or_default());
+        }
+
+        Ok(self.entries_of(&Commit::from(hash, &self.ink_root)?))
+    }
+
+    fn touches(&self, commit: &Commit, entries: &Entries) -> Result<bool, InkError> {
+        if commit.parents().is_empty() {
+            return Ok(!entries.is_empty());
+        }
+
+        for parent in commit.parents() {
+            if &self.entries(parent)? == entries {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// The path the followed file had before the commit, if the commit
+    /// renamed it
+    fn renamed_from(
+        &self,
+        commit: &Commit,
+        entries: &Entries,
+    ) -> Result<Option<PathBuf>, InkError> {
+        let content_hash = match (entries.as_slice(), commit.parents().first()) {
+            ([(path, content_hash)], Some(parent))
+                if path == &self.path && self.entries(parent)?.is_empty() =>
+            {
+                content_hash
+            }
+            _ => return Ok(None),
+        };
+
+        // paths in the parent with the same content
+        let parent = &commit.parents()[0];
+        let candidates: Vec<PathBuf> = match &self.index {
+            Some(index) => index
+                .references(content_hash)
+                .iter()
+                .filter(|reference| &reference.commit == parent)
+                .map(|reference| reference.path.clone())
+                .collect(),
+            None => Commit::from(parent, &self.ink_root)?
+                .files()
+                .iter()
+                .filter(|file| &file.content_hash() == content_hash)
+                .map(|file| file.path().to_path_buf())
+                .collect(),
+        };
+
+        Ok(candidates
+            .into_iter()
+            .find(|old| commit.file(old).is_none()))
+    }
+
+    fn next_touching(&mut self, commit: &Commit) -> Result<bool, InkError> {
+        let entries = self.entries_of(commit);
+        if !self.touches(commit, &entries)? {
+            return Ok(false);
+        }
+
+        if self.follow {
+            if let Some(old) = self.renamed_from(commit, &entries)? {
+                self.indexed = self.index.as_ref().map(|index| index_entries(index, &old));
+                self.path = old;
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+/// The entries of every commit under a path, from the index
+fn index_entries(index: &ReverseIndex, path: &Path) -> HashMap<[u8; 32], Entries> {
+    let mut indexed: HashMap<[u8; 32], Entries> = HashMap::new();
+    for (content_hash, references) in index.iter() {
+        for reference in references {
+            if reference.path.starts_with(path) {
+                indexed
+                    .entry(reference.commit)
+                    .or_default()
+                    .push((reference.path.clone(), *content_hash));
+            }
+        }
+    }
+
+    for entries in indexed.values_mut() {
+        entries.sort();
+    }
+    indexed
+}
+
+impl Iterator for CommitsTouching {
+    type Item = Result<Commit, InkError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let commit = match self.history.next()? {
+                Ok(commit) => commit,
+                Err(e) => return Some(Err(e)),
+            };
+
+            match self.next_touching(&commit) {
+                Ok(true) => return Some(Ok(commit)),
+                Ok(false) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cursor;
+    use crate::options::CommitOptions;
+    use crate::vfs::MemoryFs;
+    use crate::COMMIT_EXT;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn history_newest_first() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = crate::Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir_path.join("example"), b"this is a test!").unwrap();
+        repo.commit("first", Default::default()).unwrap();
+        fs::write(tmpdir_path.join("example"), b"this is another test!").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+
+        let messages = History::new(&ink_dir, cursor::get(&ink_dir).unwrap())
+            .map(|c| c.unwrap().message().to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(messages, vec!["second", "first", ""]);
+    }
+
+    #[test]
+    fn history_from_memory() {
+        let fs = MemoryFs::new();
+        let ink_dir = Path::new("/project/.ink");
+        fs.create_dir_all(&ink_dir.join(COMMIT_EXT)).unwrap();
+
+        let mut parents = vec![];
+        for (time, message) in [(1, "first"), (2, "second"), (2, "third")] {
+            let commit = Commit::from_files(
+                vec![],
+                parents,
+                UNIX_EPOCH + Duration::from_secs(time),
+                message,
+            )
+            .unwrap();
+            commit.write_object(&fs, ink_dir).unwrap();
+            parents = vec![commit.hash()];
+        }
+
+        let head = Commit::read(&fs, &parents[0], ink_dir).unwrap();
+        let messages = History::new_in(Rc::new(fs), ink_dir, head)
+            .map(|c| c.unwrap().message().to_string())
+            .collect::<Vec<String>>();
+
+        assert_eq!(messages, vec!["third", "second", "first"]);
+    }
+
+    #[test]
+    fn commits_touching_path() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("src/a", "a")
+            .file("b", "b")
+            .commit("add both")
+            .file("b", "changed")
+            .commit("change b")
+            .file("src/a", "changed")
+            .commit("change a")
+            .remove("src/a")
+            .commit("remove a");
+        let ink_dir = repo.ink_root();
+
+        let touching = |path: &str| -> Vec<String> {
+            let graph = crate::graph::CommitGraph::get(ink_dir).unwrap();
+            graph
+                .commits_touching(repo.head(), Path::new(path))
+                .unwrap()
+                .map(|c| c.unwrap().message().to_string())
+                .collect()
+        };
+        let expected = ["remove a", "change a", "add both"];
+        assert_eq!(touching("src/a"), expected);
+        assert_eq!(touching("src"), expected);
+        assert_eq!(touching("b"), ["change b", "add both"]);
+        assert!(touching("sr").is_empty());
+
+        // the same without the index
+        fs::remove_file(ink_dir.join(crate::INDEX_FILE)).unwrap();
+        assert_eq!(touching("src/a"), expected);
+        assert_eq!(touching("b"), ["change b", "add both"]);
+    }
+
+    #[test]
+    fn commits_touching_follows_renames() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("old", "content")
+            .commit("add old")
+            .file("old", "changed")
+            .commit("change old")
+            .remove("old")
+            .file("new", "changed")
+            .commit("rename")
+            .file("new", "changed again")
+            .commit("change new");
+        let ink_dir = repo.ink_root();
+
+        let touching = |follow: bool| -> Vec<String> {
+            let graph = crate::graph::CommitGraph::get(ink_dir).unwrap();
+            graph
+                .commits_touching(repo.head(), Path::new("new"))
+                .unwrap()
+                .follow(follow)
+                .map(|c| c.unwrap().message().to_string())
+                .collect()
+        };
+        let followed = ["change new", "rename", "change old", "add old"];
+        assert_eq!(touching(true), followed);
+        assert_eq!(touching(false), ["change new", "rename"]);
+
+        fs::remove_file(ink_dir.join(crate::INDEX_FILE)).unwrap();
+        assert_eq!(touching(true), followed);
+    }
+
+    #[test]
+    fn filtered_log() {
+        let repo = crate::testkit::TestRepo::new();
+        for (time, message, author) in [
+            (100, "fix a bug", "ann"),
+            (200, "add a feature", "bob"),
+            (300, "fix another bug", "bob"),
+        ] {
+            let options = CommitOptions::new()
+                .allow_empty(true)
+                .time(UNIX_EPOCH + Duration::from_secs(time))
+                .field("author", author);
+            repo.repo().commit(message, options).unwrap();
+        }
+
+        let log = || Filtered::new(History::new(repo.ink_root(), repo.head()));
+        let messages = |log: Filtered<History>| -> Vec<String> {
+            log.map(|c| c.unwrap().message().to_string()).collect()
+        };
+        assert_eq!(
+            messages(log().grep("fix")),
+            ["fix another bug", "fix a bug"]
+        );
+        assert_eq!(
+            messages(log().author("bob").grep("fix")),
+            ["fix another bug"]
+        );
+        let at = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(
+            messages(log().since(at(150)).until(at(250))),
+            ["add a feature"]
+        );
+        assert!(messages(log().author("carol")).is_empty());
+    }
+
+    #[test]
+    fn attribution_and_shortlog() {
+        let repo = crate::testkit::TestRepo::new();
+        fs::write(
+            repo.ink_root().join(crate::CONFIG_FILE),
+            "[user]\nname = Ann\nemail = ann@example.com\n",
+        )
+        .unwrap();
+        let ann = "Ann <ann@example.com>";
+
+        let repo = repo.commit("first");
+        assert_eq!(repo.head().author(), Some(ann));
+        assert_eq!(repo.head().committer(), Some(ann));
+
+        // ann commits a change bob wrote
+        let options = CommitOptions::new().allow_empty(true).author("Bob");
+        repo.repo().commit("second", options).unwrap();
+        assert_eq!(repo.head().author(), Some("Bob"));
+        assert_eq!(repo.head().committer(), Some(ann));
+        let repo = repo.commit("third");
+
+        let counts = shortlog(History::new(repo.ink_root(), repo.head())).unwrap();
+        assert_eq!(
+            counts,
+            vec![
+                (Some(ann.to_string()), 2),
+                (Some("Bob".to_string()), 1),
+                (None, 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! How ink names its objects: by a SHA-256 hash of what makes them up.
+//!
+//! Every object's id is computed with an `IdHasher`, which fixes how each
+//! kind of part becomes bytes: numbers big-endian, ids as their 32 bytes,
+//! and text either as it is or, where it could run into what follows, after
+//! its length as a big-endian `u64`. An object's parts are always hashed in
+//! the same order, so equal objects get equal ids. Content is named by the
+//! hash of its bytes alone.
+use crate::filedata::FileData;
+
+use sha2::{Digest, Sha256};
+use std::io::{self, Write};
+
+/// The SHA-256 hash naming an object
+pub type ObjectId = [u8; 32];
+
+/// Something ink names by a hash: commits, files, their content, trees and
+/// safety snapshots
+pub trait InkObject {
+    fn object_id(&self) -> ObjectId;
+}
+
+/// Hashes the parts of an object into its id. Content streamed into it with
+/// `Write` is hashed as it is.
+#[derive(Default, Clone)]
+pub(crate) struct IdHasher {
+    hasher: Sha256,
+}
+
+impl IdHasher {
+    pub(crate) fn new() -> IdHasher {
+        IdHasher::default()
+    }
+
+    /// Bytes as they are, for content or a part nothing follows
+    pub(crate) fn bytes(&mut self, bytes: &[u8]) -> &mut IdHasher {
+        self.hasher.update(bytes);
+        self
+    }
+
+    /// Bytes after their length, so they can't run into what follows
+    pub(crate) fn field(&mut self, bytes: &[u8]) -> &mut IdHasher {
+        self.u64(bytes.len() as u64).bytes(bytes)
+    }
+
+    pub(crate) fn id(&mut self, id: &ObjectId) -> &mut IdHasher {
+        self.bytes(id)
+    }
+
+    pub(crate) fn u32(&mut self, n: u32) -> &mut IdHasher {
+        self.bytes(&n.to_be_bytes())
+    }
+
+    pub(crate) fn i32(&mut self, n: i32) -> &mut IdHasher {
+        self.bytes(&n.to_be_bytes())
+    }
+
+    pub(crate) fn u64(&mut self, n: u64) -> &mut IdHasher {
+        self.bytes(&n.to_be_bytes())
+    }
+
+    pub(crate) fn u128(&mut self, n: u128) -> &mut IdHasher {
+        self.bytes(&n.to_be_bytes())
+    }
+
+    pub(crate) fn finish(self) -> ObjectId {
+        self.hasher.finalize().into()
+    }
+}
+
+impl Write for IdHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.hasher.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The id of content, the hash of its bytes
+pub fn content_id(content: &[u8]) -> ObjectId {
+    let mut hasher = IdHasher::new();
+    hasher.bytes(content);
+    hasher.finish()
+}
+
+/// The files of a commit, in the order of their hashes as a commit keeps
+/// them, named apart from the commit's parents, time and message so
+/// identical trees can be compared
+#[derive(Debug, Clone, Copy)]
+pub struct Tree<'a> {
+    files: &'a [FileData],
+}
+
+impl<'a> Tree<'a> {
+    /// A tree of files, which must be ordered by their hashes
+    pub(crate) fn new(files: &'a [FileData]) -> Tree<'a> {
+        Tree { files }
+    }
+}
+
+impl InkObject for Tree<'_> {
+    fn object_id(&self) -> ObjectId {
+        let mut hasher = IdHasher::new();
+        for file in self.files {
+            hasher.id(&file.object_id());
+        }
+        hasher.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn parts_are_encoded_as_before() {
+        let mut hasher = IdHasher::new();
+        hasher.field(b"key").i32(-1).id(&[7; 32]).bytes(b"rest");
+        let mut expected = Sha256::new();
+        expected.update(3u64.to_be_bytes());
+        expected.update(b"key");
+        expected.update((-1i32).to_be_bytes());
+        expected.update([7; 32]);
+        expected.update(b"rest");
+        assert_eq!(hasher.finish(), <[u8; 32]>::from(expected.finalize()));
+
+        assert_eq!(content_id(b"abc"), <[u8; 32]>::from(Sha256::digest(b"abc")));
+    }
+}
@@ -0,0 +1,57 @@
+//! Paths left out of commits.
+//!
+//! Set `paths` in the `[ignore]` config section to glob patterns separated by
+//! spaces or commas, e.g. `target, *.o, docs/build/**`. A directory that
+//! matches is skipped along with everything in it. Besides these, the ink
+//! directory and any nested repository are always left out.
+use crate::config::Config;
+use crate::{utils, InkError};
+
+use std::path::Path;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct IgnoreRules {
+    patterns: Vec<String>,
+}
+
+impl IgnoreRules {
+    pub fn load(ink_root: &Path) -> Result<IgnoreRules, InkError> {
+        Ok(IgnoreRules::from_config(&Config::load(ink_root)?))
+    }
+
+    pub fn from_config(config: &Config) -> IgnoreRules {
+        let patterns = config
+            .get("ignore", "paths")
+            .unwrap_or("")
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|pattern| !pattern.is_empty())
+            .map(String::from)
+            .collect();
+
+        IgnoreRules { patterns }
+    }
+
+    /// Whether a path relative to the project directory is ignored
+    pub fn is_ignored(&self, path: &Path) -> bool {
+        self.patterns
+            .iter()
+            .any(|pattern| utils::glob_match(pattern, path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignore_patterns() {
+        let config = Config::parse("[ignore]\npaths = target, *.o docs/build/**\n").unwrap();
+        let rules = IgnoreRules::from_config(&config);
+
+        assert!(rules.is_ignored(Path::new("target")));
+        assert!(rules.is_ignored(Path::new("src/main.o")));
+        assert!(rules.is_ignored(Path::new("docs/build/index.html")));
+        assert!(!rules.is_ignored(Path::new("src/main.rs")));
+        assert!(!IgnoreRules::default().is_ignored(Path::new("target")));
+    }
+}
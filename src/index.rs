@@ -0,0 +1,140 @@
+//! The reverse index, from content hashes to the commits and paths using them.
+//!
+//! It is kept in `.ink/index`, created by `init` and updated on every commit.
+//! Repositories made before the index existed have none until it is rebuilt
+//! with `ink index --rebuild`; until then lookups scan every commit instead.
+use crate::commit::{self, Commit};
+use crate::{InkError, INDEX_FILE};
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A commit that uses a content object, and the path it uses it for
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct Reference {
+    pub commit: [u8; 32],
+    #[serde(with = "crate::paths::escaped")]
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReverseIndex {
+    references: BTreeMap<[u8; 32], Vec<Reference>>,
+}
+
+impl ReverseIndex {
+    /// Read the index, if the repository has one
+    pub fn load(ink_root: &Path) -> Result<Option<ReverseIndex>, InkError> {
+        let path = ink_root.join(INDEX_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Ok(Some(bincode::deserialize(&fs::read(path)?)?))
+    }
+
+    /// Read the index, or build it from the stored commits if there is none
+    pub fn get(ink_root: &Path) -> Result<ReverseIndex, InkError> {
+        match ReverseIndex::load(ink_root)? {
+            Some(index) => Ok(index),
+            None => ReverseIndex::rebuild(ink_root),
+        }
+    }
+
+    /// Build the index by reading every stored commit
+    pub fn rebuild(ink_root: &Path) -> Result<ReverseIndex, InkError> {
+        let mut index = ReverseIndex::default();
+        for hash in commit::stored_hashes(ink_root)? {
+            index.add_commit(&Commit::from(&hash, ink_root)?);
+        }
+
+        Ok(index)
+    }
+
+    pub fn write(&self, ink_root: &Path) -> Result<(), InkError> {
+        fs::write(ink_root.join(INDEX_FILE), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Record the content used by a commit
+    pub fn add_commit(&mut self, commit: &Commit) {
+        for file in commit.files() {
+            let reference = Reference {
+                commit: commit.hash(),
+                path: file.path().to_path_buf(),
+            };
+
+            let references = self.references.entry(file.content_hash()).or_default();
+            if let Err(position) = references.binary_search(&reference) {
+                references.insert(position, reference);
+            }
+        }
+    }
+
+    /// The commits and paths using a content object, in order of commit hash
+    pub fn references(&self, content_hash: &[u8; 32]) -> &[Reference] {
+        self.references
+            .get(content_hash)
+            .map_or(&[], |references| references.as_slice())
+    }
+
+    /// Every indexed content hash with the commits and paths using it, in
+    /// order of content hash
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 32], &[Reference])> {
+        self.references
+            .iter()
+            .map(|(hash, references)| (hash, references.as_slice()))
+    }
+
+    /// Every indexed content hash with the commits and paths using it
+    pub fn into_map(self) -> BTreeMap<[u8; 32], Vec<Reference>> {
+        self.references
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repository;
+
+    #[test]
+    fn index_is_kept_up_to_date() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+        assert_eq!(
+            ReverseIndex::load(&ink_dir).unwrap(),
+            Some(Default::default())
+        );
+
+        fs::write(tmpdir_path.join("example"), b"this is a test!").unwrap();
+        let commit = crate::testkit::created(repo.commit("first", Default::default())).commit;
+
+        let index = ReverseIndex::load(&ink_dir).unwrap().unwrap();
+        let content_hash = commit.files()[0].content_hash();
+        assert_eq!(
+            index.references(&content_hash),
+            &[Reference {
+                commit: commit.hash(),
+                path: PathBuf::from("example"),
+            }]
+        );
+        assert_eq!(index, ReverseIndex::rebuild(&ink_dir).unwrap());
+
+        fs::remove_file(ink_dir.join(INDEX_FILE)).unwrap();
+        repo.commit("second", crate::CommitOptions::new().allow_empty(true))
+            .unwrap();
+        assert!(ReverseIndex::load(&ink_dir).unwrap().is_none());
+        assert_eq!(
+            ReverseIndex::get(&ink_dir)
+                .unwrap()
+                .references(&content_hash)
+                .len(),
+            2
+        );
+    }
+}
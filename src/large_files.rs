@@ -0,0 +1,206 @@
+//! Keeping large file content outside of `.ink`.
+//!
+//! Set `threshold` (in bytes) and `store` (a directory, relative to the
+//! directory containing `.ink` unless absolute) in the `[large-files]` config
+//! section.
+//! Content bigger than the threshold is written to the store instead of the
+//! data directory, and a small pointer in `.ink/pointers` records where it went.
+//! The content is only read from the store when it is needed, e.g. on checkout.
+//! A store inside the working directory is left out of commits.
+//!
+//! Hashes are the same wherever content is kept, so changing the threshold
+//! does not change any commits.
+use crate::config::Config;
+use crate::{InkError, POINTERS_DIR};
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where large content should be stored
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LargeFiles {
+    threshold: Option<u64>,
+    store: PathBuf,
+}
+
+/// The record of content kept in an external store
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+pub struct Pointer {
+    /// As configured when the content was stored
+    pub store: PathBuf,
+    /// Size of the content before compression
+    pub size: u64,
+}
+
+impl LargeFiles {
+    pub fn load(ink_root: &Path) -> Result<LargeFiles, InkError> {
+        LargeFiles::from_config(&Config::load(ink_root)?)
+    }
+
+    pub fn from_config(config: &Config) -> Result<LargeFiles, InkError> {
+        let threshold = match config.get("large-files", "threshold") {
+            Some(threshold) => Some(
+                threshold
+                    .parse()
+                    .map_err(|_| "large-files threshold must be a number of bytes")?,
+            ),
+            None => None,
+        };
+
+        let store = match (threshold, config.get("large-files", "store")) {
+            (Some(_), Some(store)) => PathBuf::from(store),
+            (Some(_), None) => return Err("large-files needs a store directory".into()),
+            (None, _) => PathBuf::new(),
+        };
+
+        Ok(LargeFiles { threshold, store })
+    }
+
+    /// Whether content of the given size goes to the external store
+    pub fn is_large(&self, size: u64) -> bool {
+        self.threshold.is_some_and(|threshold| size > threshold)
+    }
+
+    /// The store's directory, if large files are kept in one
+    pub(crate) fn store_dir(&self, ink_root: &Path) -> Result<Option<PathBuf>, InkError> {
+        match self.threshold {
+            Some(_) => Ok(Some(store_dir(ink_root, &self.store)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Record that content is kept in the store, and return the path to
+    /// write it to
+    pub(crate) fn prepare(
+        &self,
+        ink_root: &Path,
+        hash: &[u8; 32],
+        size: u64,
+    ) -> Result<PathBuf, InkError> {
+        let pointer = Pointer {
+            store: self.store.clone(),
+            size,
+        };
+
+        let pointers = ink_root.join(POINTERS_DIR);
+        fs::create_dir_all(&pointers)?;
+        fs::write(
+            pointers.join(hex::encode(hash)),
+            bincode::serialize(&pointer)?,
+        )?;
+
+        let store = store_dir(ink_root, &self.store)?;
+        fs::create_dir_all(&store)?;
+        Ok(store.join(hex::encode(hash)))
+    }
+}
+
+/// Read the pointer for content kept in an external store, if it is
+pub fn pointer(ink_root: &Path, hash: &[u8; 32]) -> Result<Option<Pointer>, InkError> {
+    let path = ink_root.join(POINTERS_DIR).join(hex::encode(hash));
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    Ok(Some(bincode::deserialize(&fs::read(path)?)?))
+}
+
+/// Find content that is kept in an external store
+pub(crate) fn object_path(ink_root: &Path, hash: &[u8; 32]) -> Result<PathBuf, InkError> {
+    let pointer = pointer(ink_root, hash)?.ok_or("Content is missing from the data directory")?;
+    let path = store_dir(ink_root, &pointer.store)?.join(hex::encode(hash));
+
+    if !path.is_file() {
+        return Err("Content is missing from the large file store".into());
+    }
+
+    Ok(path)
+}
+
+// relative to the repository rather than its working directory, so content
+// is found without knowing which working directory it was committed from
+fn store_dir(ink_root: &Path, store: &Path) -> Result<PathBuf, InkError> {
+    Ok(crate::default_work_tree(ink_root)?.join(store))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Repository, DATA_EXT};
+
+    #[test]
+    fn large_content_is_stored_outside() {
+        let tmpdir = crate::testkit::temp_dir();
+        let project_dir = tmpdir.path().join("project");
+        let store = tmpdir.path().join("store");
+        fs::create_dir(&project_dir).unwrap();
+        crate::init(&project_dir).unwrap();
+
+        let ink_dir = project_dir.join(".ink");
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[large-files]\nthreshold = 10\nstore = ../store\n",
+        )
+        .unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let big = project_dir.join("big");
+        fs::write(&big, b"this is more than ten bytes").unwrap();
+        fs::write(project_dir.join("small"), b"tiny").unwrap();
+        let first = crate::testkit::created(repo.commit("first", Default::default())).commit;
+
+        assert_eq!(fs::read_dir(ink_dir.join(DATA_EXT)).unwrap().count(), 1);
+        assert_eq!(fs::read_dir(&store).unwrap().count(), 1);
+
+        let big_file = first
+            .files()
+            .iter()
+            .find(|f| f.path() == Path::new("big"))
+            .unwrap()
+            .clone();
+        assert_eq!(
+            big_file.read_content(&ink_dir).unwrap(),
+            b"this is more than ten bytes"
+        );
+
+        fs::write(&big, b"changed").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+        repo.go(first, Default::default()).unwrap();
+        assert_eq!(fs::read(&big).unwrap(), b"this is more than ten bytes");
+
+        fs::remove_dir_all(&store).unwrap();
+        assert!(big_file.read_content(&ink_dir).is_err());
+    }
+
+    #[test]
+    fn store_inside_project_is_left_out() {
+        let tmpdir = crate::testkit::temp_dir();
+        let ink_dir = crate::init(tmpdir.path()).unwrap();
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[large-files]\nthreshold = 10\nstore = ./store\n",
+        )
+        .unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir.path().join("big"), b"this is more than ten bytes").unwrap();
+        repo.commit("first", Default::default()).unwrap();
+        assert_eq!(
+            fs::read_dir(tmpdir.path().join("store")).unwrap().count(),
+            1
+        );
+
+        let head = repo.current_commit().unwrap();
+        let paths: Vec<&Path> = head.files().iter().map(|f| f.path()).collect();
+        assert_eq!(paths, [Path::new("big")]);
+        assert!(repo.status().unwrap().edits.is_empty());
+    }
+
+    #[test]
+    fn threshold_needs_store() {
+        let config = Config::parse("[large-files]\nthreshold = 10\n").unwrap();
+        assert!(LargeFiles::from_config(&config).is_err());
+        assert!(!LargeFiles::default().is_large(u64::MAX));
+    }
+}
@@ -1,122 +1,132 @@
+// custom_debug_derive 0.5 expands its impls inside an anonymous const
+#![allow(non_local_definitions)]
+
+#[cfg(feature = "repo")]
+mod archive;
+#[cfg(feature = "async")]
+mod async_repository;
+mod atomic;
+#[cfg(feature = "repo")]
+mod checkout;
+#[cfg(feature = "repo")]
 pub mod commit;
+#[cfg(feature = "repo")]
+pub mod config;
+#[cfg(feature = "repo")]
 mod cursor;
 pub mod diff;
+#[cfg(feature = "repo")]
+pub mod doctor;
+#[cfg(feature = "repo")]
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "repo")]
 pub mod filedata;
+#[cfg(feature = "repo")]
+pub mod filter;
+#[cfg(feature = "repo")]
 pub mod graph;
+#[cfg(feature = "repo")]
+pub mod history;
+#[cfg(feature = "repo")]
+pub mod identity;
+#[cfg(feature = "repo")]
+pub mod ignore;
+#[cfg(feature = "repo")]
+pub mod index;
+#[cfg(feature = "repo")]
+pub mod large_files;
+#[cfg(feature = "repo")]
+pub mod merge;
+#[cfg(feature = "repo")]
+pub mod metrics;
+#[cfg(feature = "repo")]
+mod mmap;
+#[cfg(feature = "repo")]
+pub mod normalize;
+#[cfg(feature = "repo")]
+pub mod notes;
+#[cfg(feature = "repo")]
+pub mod objects;
+#[cfg(feature = "repo")]
+pub mod options;
+#[cfg(feature = "repo")]
+pub mod output;
+#[cfg(feature = "repo")]
+pub mod paths;
+#[cfg(feature = "repo")]
+pub mod prune;
+#[cfg(feature = "repo")]
+mod repo;
+#[cfg(feature = "repo")]
+mod repository;
+#[cfg(feature = "repo")]
+pub mod rerere;
+#[cfg(feature = "repo")]
+pub mod safety;
+#[cfg(feature = "repo")]
+pub mod salvage;
+#[cfg(feature = "repo")]
+pub mod serve;
+#[cfg(feature = "repo")]
+pub mod snapshot;
+#[cfg(feature = "repo")]
+pub mod stats;
+#[cfg(feature = "repo")]
+pub mod storage;
+#[cfg(feature = "repo")]
+pub mod tags;
+#[cfg(all(feature = "repo", any(test, feature = "testkit")))]
+pub mod testkit;
+#[cfg(feature = "repo")]
+pub mod time;
+#[cfg(feature = "repo")]
 mod utils;
+#[cfg(feature = "repo")]
+pub mod version_info;
+#[cfg(feature = "repo")]
+pub mod vfs;
+
+#[cfg(feature = "async")]
+pub use crate::async_repository::{AsyncRepository, Task};
+#[cfg(feature = "repo")]
+pub use crate::options::{CheckoutOptions, CommitOptions, InitOptions, MergeOptions, OpenOptions};
+#[cfg(feature = "repo")]
+pub use crate::repo::*;
+#[cfg(feature = "repo")]
+pub use crate::repository::{
+    CheckoutOutcome, CommitOutcome, CommitResult, MergeOutcome, Repository,
+};
+#[cfg(feature = "repo")]
+pub use crate::utils::WalkDir;
 
-use crate::commit::{Commit, Edit};
-use crate::graph::CommitGraph;
-
-use std::env;
 use std::error::Error;
 use std::fmt::Display;
-use std::fs;
 use std::io;
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-
-const DATA_EXT: &str = "data";
-const COMMIT_EXT: &str = "commit";
-const GRAPH_FILE: &str = "graph";
-const CURSOR_FILE: &str = "cursor";
-
-fn root_dir() -> Result<Option<PathBuf>, InkError> {
-    let curr_dir = env::current_dir()?.canonicalize()?;
-
-    for path in curr_dir.ancestors() {
-        let ink_dir = path.join(".ink");
-        if ink_dir.exists() && ink_dir.is_dir() {
-            return Ok(Some(ink_dir));
-        }
-    }
-
-    Ok(None)
-}
-
-// functions called by cli
-pub fn init(in_dir: &Path) -> Result<(), InkError> {
-    // create ./.ink dir
-    let ink_dir = in_dir.join(".ink");
-
-    fs::create_dir(&ink_dir)?;
-    fs::create_dir(&ink_dir.join(COMMIT_EXT))?;
-    fs::create_dir(&ink_dir.join(DATA_EXT))?;
-    let empty_commit = Commit::new::<PathBuf>(vec![], SystemTime::now(), &ink_dir)?;
-    empty_commit.write(&ink_dir)?;
-    cursor::init(&ink_dir)?;
-    cursor::set(&ink_dir, &empty_commit)?;
-    CommitGraph::init(&ink_dir, &empty_commit)?;
-
-    Ok(())
-}
-
-fn create_commit_from_wd(root_dir: &Path) -> Result<Commit, InkError> {
-    let mut paths = Vec::new();
-    utils::find_paths(
-        root_dir
-            .parent()
-            .ok_or("Could not find project directory")?,
-        &mut paths,
-    )?;
-    paths = paths
-        .into_iter()
-        .filter(|p| !p.starts_with(&root_dir))
-        .collect();
-
-    Commit::new(paths, SystemTime::now(), &root_dir)
-}
-
-pub fn commit() -> Result<Commit, InkError> {
-    let root_dir = root_dir()?.ok_or("Ink Uninitialized")?;
-    let commit = create_commit_from_wd(&root_dir)?;
-    commit.write(&root_dir)?;
-
-    let mut graph = CommitGraph::get(&root_dir)?;
-
-    let current_commit = cursor::get(&root_dir)?;
-    graph.add_commit(&current_commit, &commit)?;
-
-    cursor::set(&root_dir, &commit)?;
-    graph.write()?;
-
-    Ok(commit)
-}
-
-pub fn go(to: Commit) -> Result<(), InkError> {
-    let root_dir = root_dir()?.ok_or("Ink Uninitialized")?;
-    let from = cursor::get(&root_dir)?;
-
-    // perform check to see if pwd is dirty
-    if !(create_commit_from_wd(&root_dir)?.diff(&from).edits).is_empty() {
-        return Err(
-            "The working directory is dirty, please commit all changes before proceeding".into(),
-        );
-    }
-
-    // diff current commit and target commit
-    let diff = from.diff(&to);
-    // apply diff by removing removed files, applying diffs to changed files, and add new files
-    for edit in diff.edits {
-        match edit {
-            Edit::Insert(f) => f.write_to(&root_dir, f.path()),
-            Edit::Delete(f) => fs::remove_file(f.path()).map_err(|e| e.into()),
-            Edit::Modify { original, modified } => {
-                fs::remove_file(original.path())?;
-                modified.write_to(&root_dir, modified.path())
-            }
-        }?;
-    }
-
-    // set cursor to new commit
-    cursor::set(&root_dir, &to)
-}
+#[cfg(feature = "repo")]
+use std::path::PathBuf;
 
 #[derive(Debug)]
 pub enum InkError {
     Err(&'static str),
     IO(io::Error),
+    /// An edit script or patch that couldn't be read, and where
+    Parse(diff::ParseError),
+    /// A diff with an edit that can't be applied
+    Edit(diff::EditError),
+    #[cfg(feature = "repo")]
     Serialization(bincode::ErrorKind),
+    /// `init` found a repository already there, in the given directory
+    #[cfg(feature = "repo")]
+    AlreadyInitialized(PathBuf),
+    /// A file of the repository that doesn't hold what it should, and what
+    /// it should hold or how to fix it
+    #[cfg(feature = "repo")]
+    Malformed {
+        path: PathBuf,
+        problem: &'static str,
+    },
 }
 
 impl Error for InkError {}
@@ -133,6 +143,19 @@ impl From<&'static str> for InkError {
     }
 }
 
+impl From<diff::ParseError> for InkError {
+    fn from(err: diff::ParseError) -> InkError {
+        InkError::Parse(err)
+    }
+}
+
+impl From<diff::EditError> for InkError {
+    fn from(err: diff::EditError) -> InkError {
+        InkError::Edit(err)
+    }
+}
+
+#[cfg(feature = "repo")]
 impl From<Box<bincode::ErrorKind>> for InkError {
     fn from(err: Box<bincode::ErrorKind>) -> InkError {
         InkError::Serialization(*err)
@@ -144,7 +167,18 @@ impl Display for InkError {
         match &self {
             InkError::Err(e) => write!(f, "{}", e),
             InkError::IO(e) => write!(f, "{}", e),
+            InkError::Parse(e) => write!(f, "{}", e),
+            InkError::Edit(e) => write!(f, "{}", e),
+            #[cfg(feature = "repo")]
             InkError::Serialization(e) => write!(f, "{}", e),
+            #[cfg(feature = "repo")]
+            InkError::AlreadyInitialized(dir) => {
+                write!(f, "Repository is already initialized in {}", dir.display())
+            }
+            #[cfg(feature = "repo")]
+            InkError::Malformed { path, problem } => {
+                write!(f, "{} is malformed: {}", path.display(), problem)
+            }
         }
     }
 }
@@ -1,41 +1,953 @@
-use ink::commit::commit_hash_from_prefix;
+use ink::commit::{commit_hash_from_prefix, Commit, LineDiff};
+use ink::config::Config;
+use ink::diff::{render, Diff};
 use ink::graph::CommitGraph;
-use ink::InkError;
+use ink::output::Output;
+use ink::paths::escape;
+use ink::stats::{DiffStat, StatsCache};
+use ink::{CheckoutOptions, CommitOptions, InitOptions, InkError, OpenOptions, Repository};
+use std::collections::BTreeMap;
 use std::convert::TryInto;
 use std::env;
 use std::error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::time::{Duration, SystemTime};
 
-fn main() -> Result<(), Box<dyn error::Error>> {
-    debugging_cli(std::env::args().collect())
+/// Exit codes, following diff(1): `status` and `diff` exit with `CHANGED`
+/// when there are changes, and every command exits with `FAILED` on an error
+const CHANGED: i32 = 1;
+const FAILED: i32 = 2;
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let mut out = Output::stdio();
+
+    let mut location = Location::from_env();
+    let result = match take_global_flags(&mut args, &mut location) {
+        Ok(quiet) => {
+            out = out.quiet(quiet);
+            debugging_cli(&mut out, args, &location)
+        }
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(0) => {}
+        Ok(code) => process::exit(code),
+        Err(err) => {
+            let _ = out.error(err);
+            process::exit(FAILED);
+        }
+    }
+}
+
+/// The flags of each command that take a value. Every flag read with
+/// `flag_value` is listed under its command, so that its value is never
+/// taken for a global flag, e.g. in `commit -m -q`.
+const VALUE_FLAGS: &[(&str, &[&str])] = &[
+    (
+        "commit",
+        &["-m", "--meta", "--author", "--date", "--from-archive"],
+    ),
+    ("status", &["--against"]),
+    ("diff", &["--width"]),
+    (
+        "log",
+        &["--meta", "--since", "--until", "--author", "--grep"],
+    ),
+    ("prune", &["--expire"]),
+    ("merge", &["-X", "-m"]),
+    ("tag", &["--delete"]),
+    ("serve", &["--addr"]),
+];
+
+/// The flags of a command that take a value
+fn value_flags(command: &str) -> &'static [&'static str] {
+    VALUE_FLAGS
+        .iter()
+        .find(|(name, _)| *name == command)
+        .map_or(&[], |(_, flags)| flags)
+}
+
+/// Where the repository to work on is. Each part is taken from a global flag,
+/// or else an environment variable: `--repo <path>` or `INK_DIR` names the
+/// ink directory or the project directory containing it, `--work-tree
+/// <path>` or `INK_WORK_TREE` the working directory, and `INK_DIR_NAME` what
+/// ink directories are called. Without a path, the repository is the one
+/// containing the current directory.
+#[derive(Debug, Default, PartialEq)]
+struct Location {
+    dir: Option<PathBuf>,
+    work_tree: Option<PathBuf>,
+    dir_name: Option<String>,
 }
 
-fn debugging_cli(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
+impl Location {
+    fn from_env() -> Location {
+        let var = |name| env::var_os(name).filter(|value| !value.is_empty());
+        Location {
+            dir: var("INK_DIR").map(PathBuf::from),
+            work_tree: var("INK_WORK_TREE").map(PathBuf::from),
+            dir_name: var("INK_DIR_NAME").map(|name| name.to_string_lossy().into_owned()),
+        }
+    }
+
+    fn open(&self) -> Result<Repository, InkError> {
+        let mut options = OpenOptions::new();
+        if let Some(dir_name) = &self.dir_name {
+            options = options.dir_name(dir_name);
+        }
+        if let Some(work_tree) = &self.work_tree {
+            options = options.work_tree(work_tree);
+        }
+
+        match &self.dir {
+            Some(dir) => Repository::open_with(dir, options),
+            None => Repository::discover_with(&env::current_dir()?, options),
+        }
+    }
+}
+
+/// Remove the flags that apply to every command from the arguments, wherever
+/// they are, and return whether `--quiet` was given. `--repo <path>` and
+/// `--work-tree <path>` are kept in `location`. Values of other flags, like a
+/// commit message of `-q`, are left alone.
+fn take_global_flags(
+    args: &mut Vec<String>,
+    location: &mut Location,
+) -> Result<bool, Box<dyn error::Error>> {
+    let mut quiet = false;
+    let mut command: Option<String> = None;
+    let mut index = 1;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--quiet" | "-q" => {
+                args.remove(index);
+                quiet = true;
+            }
+            "--repo" | "--work-tree" => {
+                if index + 1 >= args.len() {
+                    return Err("Flag is missing its value".into());
+                }
+                let value = PathBuf::from(args.remove(index + 1));
+                match args.remove(index).as_str() {
+                    "--repo" => location.dir = Some(value),
+                    _ => location.work_tree = Some(value),
+                }
+            }
+            arg => {
+                match &command {
+                    None => command = Some(arg.to_string()),
+                    // its value is left alone, whatever it looks like
+                    Some(command) if value_flags(command).contains(&arg) => index += 1,
+                    Some(_) => {}
+                }
+                index += 1;
+            }
+        }
+    }
+
+    Ok(quiet)
+}
+
+/// Run a command, returning the code to exit with
+fn debugging_cli(
+    out: &mut Output,
+    args: Vec<String>,
+    location: &Location,
+) -> Result<i32, Box<dyn error::Error>> {
     if args.len() < 2 {
         return Err("No args provided".into());
     }
 
     match args[1].as_str() {
-        "init" => ink::init(&env::current_dir()?.canonicalize()?)?,
+        "init" => {
+            // `ink init --bare [dir]` makes a bare repository in the given
+            // directory, or the current one
+            let current_dir = env::current_dir()?.canonicalize()?;
+            let mut options = InitOptions::new();
+            if let Some(dir_name) = &location.dir_name {
+                options = options.dir_name(dir_name);
+            }
+            let bare = options.clone().bare(true);
+            let result = match args.get(2).map(String::as_str) {
+                Some("--bare") => match args.get(3) {
+                    Some(dir) => ink::init_with(Path::new(dir), bare),
+                    None => ink::init_with(&current_dir, bare),
+                },
+                Some(_) => return Err("Usage: ink init [--bare [dir]]".into()),
+                None => ink::init_with(&current_dir, options),
+            };
+
+            match result {
+                Ok(ink_dir) => {
+                    out.info(format!("Initialized repository in {}", ink_dir.display()))?
+                }
+                Err(err @ InkError::AlreadyInitialized(_)) => out.info(err)?,
+                Err(err) => return Err(err.into()),
+            }
+        }
         "commit" => {
-            let _ = ink::commit()?;
+            let allow_empty = args[2..].iter().any(|arg| arg == "--allow-empty");
+            let message = flag_value(&args, "-m")?.unwrap_or("");
+
+            let mut metadata = BTreeMap::new();
+            for field in flag_values(&args, "--meta")? {
+                let (key, value) = field
+                    .split_once('=')
+                    .ok_or("Metadata must be given as key=value")?;
+                metadata.insert(key.to_string(), value.to_string());
+            }
+
+            let mut options = CommitOptions::new()
+                .metadata(metadata)
+                .allow_empty(allow_empty);
+            if let Some(author) = flag_value(&args, "--author")? {
+                options = options.author(author);
+            }
+            // `--date "<seconds> [+hhmm]"`, seconds since the unix epoch like
+            // SOURCE_DATE_EPOCH, and optionally an offset from UTC
+            if let Some(date) = flag_value(&args, "--date")? {
+                let mut parts = date.split_whitespace();
+                options = options.time(ink::time::parse_epoch(parts.next().unwrap_or(""))?);
+                if let Some(offset) = parts.next() {
+                    options = options.offset(ink::time::parse_offset(offset)?);
+                }
+            }
+
+            // `--from-archive <tar>` commits an archive, or stdin with `-`,
+            // without touching the working directory
+            let repo = location.open()?;
+            let mut checked_out = true;
+            let result = match flag_value(&args, "--from-archive")? {
+                Some(archive) => {
+                    checked_out = repo.is_bare();
+                    match archive {
+                        "-" => repo.import_archive(std::io::stdin().lock(), message, options)?,
+                        path => repo.import_archive(
+                            std::io::BufReader::new(std::fs::File::open(path)?),
+                            message,
+                            options,
+                        )?,
+                    }
+                }
+                None => repo.commit(message, options)?,
+            };
+            match result {
+                ink::CommitResult::Created(outcome) => {
+                    let hash = &hex::encode(outcome.commit.hash())[..10];
+                    out.info(format!(
+                        "Committed {}: {} files stored ({} bytes), {} already stored, {} duplicates",
+                        hash,
+                        outcome.files_written,
+                        outcome.bytes_stored,
+                        outcome.skipped,
+                        outcome.deduplicated
+                    ))?;
+                    if !checked_out {
+                        out.info(format!("Check it out with `ink go {}`", hash))?;
+                    }
+                }
+                ink::CommitResult::NothingToCommit => out.info("Nothing to commit")?,
+            }
+        }
+        "status" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let edits = repo.status()?.edits;
+            if !out.is_quiet() {
+                for edit in &edits {
+                    out.line(format!("{} {}", edit.status(), escape(edit.path())))?;
+                }
+            }
+
+            if let Some(merging) = ink::merge::MergeState::load(root_dir)? {
+                out.info(format!(
+                    "Merging {}: commit to finish, or give up with `ink merge --abort`",
+                    &hex::encode(merging.head)[..10]
+                ))?;
+            }
+
+            // `status --against <commit>` also compares the current commit's
+            // history to another's
+            if let Some(other) = flag_value(&args, "--against")? {
+                let against = resolve_commit(root_dir, other)?;
+                let (ahead, behind) = repo.ahead_behind(&repo.current_commit()?, &against)?;
+                match (ahead, behind) {
+                    (0, 0) => out.info(format!("Up to date with {}", other))?,
+                    (ahead, 0) => out.info(format!("Ahead of {} by {}", other, ahead))?,
+                    (0, behind) => out.info(format!("Behind {} by {}", other, behind))?,
+                    (ahead, behind) => out.info(format!(
+                        "Ahead of {} by {} and behind by {}",
+                        other, ahead, behind
+                    ))?,
+                }
+            }
+
+            if !edits.is_empty() {
+                return Ok(CHANGED);
+            }
+        }
+        "diff" => {
+            // `ink diff` compares the working directory to the current commit,
+            // `ink diff <from> <to>` compares two commits
+            let side_by_side = args[2..].iter().any(|arg| arg == "--side-by-side");
+            let tokens = args[2..].iter().any(|arg| arg == "--tokens");
+            let name_only = args[2..].iter().any(|arg| arg == "--name-only");
+            let name_status = args[2..].iter().any(|arg| arg == "--name-status");
+            let width = match flag_value(&args, "--width")? {
+                Some(width) => width.parse()?,
+                None => terminal_width(),
+            };
+
+            let mut commits = Vec::new();
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "--side-by-side" | "--tokens" | "--name-only" | "--name-status" => {}
+                    "--width" => {
+                        rest.next();
+                    }
+                    commit => commits.push(commit),
+                }
+            }
+
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let max_cost = match Config::load(root_dir)?.get("diff", "max-cost") {
+                Some(max_cost) => max_cost.parse()?,
+                None => ink::diff::DEFAULT_MAX_COST,
+            };
+            let (changes, worktree) = match commits.as_slice() {
+                [] => (repo.status()?, Some(repo.work_tree())),
+                [from, to] => {
+                    let from = resolve_commit(root_dir, from)?;
+                    let to = resolve_commit(root_dir, to)?;
+                    (from.diff(&to), None)
+                }
+                _ => return Err("Expected no commits or two commits to compare".into()),
+            };
+            let code = if changes.edits.is_empty() { 0 } else { CHANGED };
+
+            // with --quiet, only whether anything changed
+            if out.is_quiet() {
+                return Ok(code);
+            }
+
+            // only the changed paths, without reading any content
+            if name_only || name_status {
+                for name in changes.name_status() {
+                    match (&name.from, name_status) {
+                        (_, false) => out.line(escape(&name.path))?,
+                        (Some(from), true) => out.line(format!(
+                            "{}\t{}\t{}",
+                            name.status,
+                            escape(from),
+                            escape(&name.path)
+                        ))?,
+                        (None, true) => {
+                            out.line(format!("{}\t{}", name.status, escape(&name.path)))?
+                        }
+                    }
+                }
+                return Ok(code);
+            }
+
+            let line_diffs =
+                changes.compute_line_diffs_with(root_dir, worktree, true, move |a, b| {
+                    if tokens {
+                        Diff::by_tokens(a, b).0
+                    } else {
+                        Diff::with_max_cost(a, b, max_cost)
+                    }
+                })?;
+
+            for line_diff in line_diffs {
+                let LineDiff {
+                    path,
+                    original,
+                    diff,
+                    ..
+                } = line_diff?;
+                let name = path.display().to_string();
+                if diff.is_approximate() {
+                    out.warn(format!("{} changed too much to diff precisely", name))?;
+                }
+                if side_by_side {
+                    out.print(render::side_by_side(&diff, &original, &name, &name, width))?;
+                } else {
+                    out.print(render::unified(&diff, &original, &name, &name))?;
+                }
+            }
+            return Ok(code);
+        }
+        "log" => {
+            let meta_key = flag_value(&args, "--meta")?;
+            let utc = args[2..].iter().any(|arg| arg == "--utc");
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+
+            // `log --graph` draws the whole history, one line per commit
+            if args[2..].iter().any(|arg| arg == "--graph") {
+                let filters = ["--", "--stat", "--since", "--until", "--author", "--grep"];
+                if meta_key.is_some() || args[2..].iter().any(|arg| filters.contains(&arg.as_str()))
+                {
+                    return Err("--graph can't be used with --meta, --stat or filters".into());
+                }
+
+                let decorations = ink::graph::render::decorations(root_dir)?;
+                let nodes = repo
+                    .log()?
+                    .map(|commit| {
+                        commit.map(|commit| {
+                            let label = ink::graph::render::one_line(&commit, &decorations, utc);
+                            ink::graph::render::Node::new(&commit, label)
+                        })
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                out.print(ink::graph::render::graph(nodes))?;
+                return Ok(0);
+            }
+
+            let stats = match args[2..].iter().any(|arg| arg == "--stat") {
+                true => Some(StatsCache::open(root_dir)?),
+                false => None,
+            };
+
+            // `log -- <path>` shows only the commits changing the path
+            let commits: Box<dyn Iterator<Item = Result<Commit, InkError>>> =
+                match args.iter().position(|arg| arg == "--") {
+                    Some(i) => {
+                        let path = args.get(i + 1).ok_or("Usage: ink log -- <path>")?;
+                        let follow = !args[2..].iter().any(|arg| arg == "--no-follow");
+                        let graph = ink::graph::CommitGraph::get(root_dir)?;
+                        let commits =
+                            graph.commits_touching(repo.current_commit()?, Path::new(path))?;
+                        Box::new(commits.follow(follow))
+                    }
+                    None => Box::new(repo.log()?),
+                };
+
+            // `--since`/`--until` take seconds since the epoch or a date,
+            // `--author` and `--grep` a string to look for
+            let mut commits = ink::history::Filtered::new(commits);
+            if let Some(since) = flag_value(&args, "--since")? {
+                commits = commits.since(ink::time::parse_time(since)?);
+            }
+            if let Some(until) = flag_value(&args, "--until")? {
+                commits = commits.until(ink::time::parse_time(until)?);
+            }
+            if let Some(author) = flag_value(&args, "--author")? {
+                commits = commits.author(author);
+            }
+            if let Some(pattern) = flag_value(&args, "--grep")? {
+                commits = commits.grep(pattern);
+            }
+
+            for commit in commits {
+                let commit = commit?;
+                match meta_key {
+                    Some(key) => {
+                        if let Some(value) = commit.metadata().get(key) {
+                            out.line(format!("{} {}={}", hex::encode(commit.hash()), key, value))?;
+                        }
+                    }
+                    None => out.line(format!(
+                        "{} {} {}",
+                        hex::encode(commit.hash()),
+                        commit_date(&commit, utc),
+                        commit.message()
+                    ))?,
+                }
+
+                // changes are counted from the first parent
+                if let (Some(stats), Some(parent)) = (&stats, commit.parents().first()) {
+                    let parent = Commit::from(parent, root_dir)?;
+                    print_stat(out, &stats.get(&parent, &commit)?)?;
+                }
+            }
+        }
+        "patch-id" => {
+            // `patch-id [<commit>] [--applied]` hashes the changes a commit
+            // made to its first parent, and can look for them in history
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let commit = match args[2..].iter().find(|arg| *arg != "--applied") {
+                Some(prefix) => resolve_commit(root_dir, prefix)?,
+                None => repo.current_commit()?,
+            };
+
+            if args[2..].iter().any(|arg| arg == "--applied") {
+                match repo.find_applied(&commit)? {
+                    Some(applied) => out.line(hex::encode(applied.hash()))?,
+                    None => return Ok(CHANGED),
+                }
+            } else {
+                let patch_id = commit.changes(root_dir)?.patch_id(root_dir)?;
+                out.line(hex::encode(patch_id))?;
+            }
+        }
+        "apply" => {
+            // `ink apply <patch> <file>` changes a file by a unified diff, an
+            // ed script from `diff -e`, or an ink edit script
+            let usage = "Usage: ink apply <patch> <file>";
+            let patch = std::fs::read_to_string(args.get(2).ok_or(usage)?)?;
+            let path = Path::new(args.get(3).ok_or(usage)?);
+
+            let diff = if patch.lines().any(|line| line.starts_with("@@ -")) {
+                Diff::from_unified(&patch)?
+            } else if is_ed_script(&patch) {
+                let original = std::fs::read_to_string(path)?;
+                Diff::from_ed_script(&patch, &original.lines().collect::<Vec<_>>())?
+            } else {
+                Diff::from_edit_script(&patch)?
+            };
+            diff.apply(path)?;
+        }
+        "shortlog" => {
+            // how many commits each author made in the current history
+            for (author, count) in ink::history::shortlog(location.open()?.log()?)? {
+                let author = author.as_deref().unwrap_or("(unknown)");
+                out.line(format!("{:>6}  {}", count, author))?;
+            }
+        }
+        "show" => {
+            // `ink show [<commit>] [--utc]` shows a commit and the paths it
+            // changed from its first parent
+            let utc = args[2..].iter().any(|arg| arg == "--utc");
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let commit = match args[2..].iter().find(|arg| *arg != "--utc") {
+                Some(prefix) => resolve_commit(root_dir, prefix)?,
+                None => repo.current_commit()?,
+            };
+
+            out.line(format!("commit {}", hex::encode(commit.hash())))?;
+            out.line(format!("Date:   {}", commit_date(&commit, utc)))?;
+            if let Some(author) = commit.author() {
+                out.line(format!("Author: {}", author))?;
+            }
+            if let Some(committer) = commit.committer().filter(|c| Some(*c) != commit.author()) {
+                out.line(format!("Commit: {}", committer))?;
+            }
+            for (key, value) in commit.metadata() {
+                if key != ink::commit::AUTHOR_KEY && key != ink::commit::COMMITTER_KEY {
+                    out.line(format!("{}: {}", key, value))?;
+                }
+            }
+            out.line("")?;
+            for line in commit.message().lines() {
+                out.line(format!("    {}", line))?;
+            }
+
+            let names = commit.changes(root_dir)?.name_status();
+            if !names.is_empty() {
+                out.line("")?;
+            }
+            for name in names {
+                match name.from {
+                    Some(from) => out.line(format!(
+                        "{}\t{}\t{}",
+                        name.status,
+                        from.display(),
+                        name.path.display()
+                    ))?,
+                    None => out.line(format!("{}\t{}", name.status, name.path.display()))?,
+                }
+            }
+        }
+        "index" => {
+            if args.get(2).map(String::as_str) != Some("--rebuild") {
+                return Err("Usage: ink index --rebuild".into());
+            }
+
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let index = ink::index::ReverseIndex::rebuild(root_dir)?;
+            index.write(root_dir)?;
+            out.info(format!(
+                "Rebuilt index of {} content objects",
+                index.into_map().len()
+            ))?;
+        }
+        "hash-object" => {
+            // `ink hash-object [-w] <file>` prints the hash content is stored
+            // under, storing it with -w
+            let write = args[2..].iter().any(|arg| arg == "-w");
+            let path = args[2..]
+                .iter()
+                .find(|arg| *arg != "-w")
+                .ok_or("Usage: ink hash-object [-w] <file>")?;
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let content = std::fs::read(path)?;
+            let hash = ink::objects::hash_object(root_dir, &content, Path::new(path), write)?;
+            out.line(hex::encode(hash))?;
+        }
+        "cat-object" => {
+            // a commit is printed decoded, content as it was stored
+            let prefix = hex::decode(args.get(2).ok_or("Usage: ink cat-object <hash>")?)?;
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            match commit_hash_from_prefix(root_dir, &prefix) {
+                Ok(hash) => print_commit(out, &Commit::from(&hash, root_dir)?)?,
+                Err(_) => {
+                    let hash = ink::objects::object_hash_from_prefix(root_dir, &prefix)?;
+                    out.write_bytes(&ink::objects::read_object(root_dir, &hash)?)?;
+                }
+            }
+        }
+        "ls-tree" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let commit = match args.get(2) {
+                Some(prefix) => resolve_commit(root_dir, prefix)?,
+                None => repo.current_commit()?,
+            };
+            for file in commit.files() {
+                out.line(format!(
+                    "{:06o} {}\t{}",
+                    file.permissions(),
+                    hex::encode(file.content_hash()),
+                    file.path().display()
+                ))?;
+            }
+        }
+        "state-id" => {
+            let repo = location.open()?;
+            out.line(hex::encode(repo.state_id()?))?;
+        }
+        "fsck" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let corrupt = ink::objects::fsck(root_dir)?;
+            for hash in &corrupt {
+                out.line(format!("corrupt {}", hex::encode(hash)))?;
+            }
+
+            if !corrupt.is_empty() {
+                return Err(format!("{} content objects are corrupt", corrupt.len()).into());
+            }
+        }
+        "doctor" => {
+            let report = ink::doctor::doctor(&location.open()?);
+            if args[2..].iter().any(|arg| arg == "--json") {
+                out.line(report.to_json())?;
+            } else {
+                for check in &report.checks {
+                    out.line(format!(
+                        "{} {}: {}",
+                        check.status.as_str(),
+                        check.name,
+                        check.summary
+                    ))?;
+                    if let Some(remedy) = check.remedy {
+                        out.line(format!("  {}", remedy))?;
+                    }
+                }
+            }
+
+            if !report.is_healthy() {
+                return Err("The repository has problems".into());
+            }
+        }
+        "metrics" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let records = if args[2..].iter().any(|arg| arg == "--clear") {
+                ink::metrics::clear(root_dir)?;
+                Vec::new()
+            } else {
+                ink::metrics::load(root_dir)?
+            };
+            if records.is_empty() && !ink::metrics::enabled(&Config::load(root_dir)?)? {
+                out.info("Nothing is logged until `enabled = true` is set in [metrics]")?;
+            }
+
+            for summary in ink::metrics::summarize(&records) {
+                out.line(format!(
+                    "{}: {} times, {:.1?} in all, {:.1?} each, {} files, {} bytes",
+                    summary.operation,
+                    summary.count,
+                    summary.duration,
+                    summary.mean(),
+                    summary.files,
+                    summary.bytes
+                ))?;
+                for (phase, duration) in &summary.phases {
+                    let share = duration.as_secs_f64() / summary.duration.as_secs_f64().max(1e-9);
+                    out.line(format!(
+                        "  {:<10} {:>9.1?} {:>5.1}%",
+                        phase,
+                        duration,
+                        share * 100.0
+                    ))?;
+                }
+            }
+        }
+        "salvage" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let report = ink::salvage::salvage(root_dir)?;
+
+            for path in &report.unreadable_commits {
+                out.line(format!("unreadable commit {}", path.display()))?;
+            }
+            for (commit, parent) in &report.missing_parents {
+                out.line(format!(
+                    "missing parent {} of {}",
+                    hex::encode(parent),
+                    hex::encode(commit)
+                ))?;
+            }
+            for (commit, path) in &report.missing_content {
+                out.line(format!(
+                    "missing content of {} in {}",
+                    path.display(),
+                    hex::encode(commit)
+                ))?;
+            }
+            for hash in &report.corrupt_content {
+                out.line(format!("corrupt {}", hex::encode(hash)))?;
+            }
+            if let Some(hash) = report.cursor_reset {
+                out.info(format!("Reset the cursor to {}", hex::encode(hash)))?;
+            }
+            out.info(format!("Rebuilt the graph from {} commits", report.commits))?;
+        }
+        "prune" => {
+            let days: u64 = match flag_value(&args, "--expire")? {
+                Some(days) => days
+                    .parse()
+                    .map_err(|_| "--expire takes a number of days")?,
+                None => 14,
+            };
+
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let expire = SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60);
+            for commit in ink::prune::prune(root_dir, expire)? {
+                out.line(format!(
+                    "pruned {} {}",
+                    &hex::encode(commit.hash())[..10],
+                    commit.message()
+                ))?;
+            }
+        }
+        "reflog" => {
+            // the snapshots taken before operations that can lose changes
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            for snapshot in ink::safety::list(root_dir)? {
+                out.line(format!(
+                    "{} {} {} at {}",
+                    &hex::encode(snapshot.id())[..10],
+                    ink::time::format(snapshot.time.as_secs(), 0),
+                    snapshot.operation,
+                    &hex::encode(snapshot.cursor)[..10]
+                ))?;
+            }
+        }
+        "undo" => {
+            // `undo [<snapshot>]` puts back the working directory from the
+            // given snapshot, or the latest one
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let snapshot = match args.get(2) {
+                Some(id) => ink::safety::find(root_dir, id)?,
+                None => ink::safety::list(root_dir)?
+                    .into_iter()
+                    .next()
+                    .ok_or("No snapshots to undo to")?,
+            };
+            let outcome = repo.undo(&snapshot)?;
+            out.info(format!(
+                "Undid {}: {} files changed, {} deleted, {} directories created",
+                snapshot.operation,
+                outcome.files_changed,
+                outcome.files_deleted,
+                outcome.dirs_created
+            ))?;
         }
         "go" => {
             if args.len() < 2 {
                 return Err("Not enough args (commit hash)".into());
             }
 
-            let root_dir = root_dir()?.ok_or("no root")?;
-            let hash = hex::decode(&args[2])?;
-            let commit = ink::commit::Commit::from(
-                &commit_hash_from_prefix(&root_dir, &hash).unwrap(),
-                &root_dir,
-            )?;
-            ink::go(commit)?;
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let commit = resolve_commit(root_dir, &args[2])?;
+            let force = args[3..].iter().any(|arg| arg == "--force");
+            let ff_only = args[3..].iter().any(|arg| arg == "--ff-only");
+            // `--carry` brings uncommitted changes along
+            let carry = args[3..].iter().any(|arg| arg == "--carry");
+            let options = CheckoutOptions::new()
+                .force(force)
+                .ff_only(ff_only)
+                .carry(carry);
+            let outcome = repo.go(commit, options)?;
+            out.info(format!(
+                "{} files changed, {} deleted, {} directories created",
+                outcome.files_changed, outcome.files_deleted, outcome.dirs_created
+            ))?;
+        }
+        "merge" => {
+            // `merge <commit>... [-m <message>] [-X ours|theirs]` merges one
+            // or more commits into the current one, as a single commit, and
+            // `merge --abort` gives up a merge left with conflicts
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            if args[2..].iter().any(|arg| arg == "--abort") {
+                repo.abort_merge()?;
+                return Ok(0);
+            }
+            let mut names = Vec::new();
+            let mut rest = args[2..].iter();
+            while let Some(arg) = rest.next() {
+                match arg.as_str() {
+                    "-m" | "-X" => {
+                        rest.next();
+                    }
+                    name => names.push(name),
+                }
+            }
+            if names.is_empty() {
+                return Err(
+                    "Usage: ink merge <commit>... [-m <message>] [-X ours|theirs] | --abort".into(),
+                );
+            }
+            let mut options = ink::MergeOptions::new();
+            if let Some(strategy) = flag_value(&args, "-X")? {
+                options = options.strategy(ink::merge::Strategy::parse(strategy)?);
+            }
+
+            let heads = names
+                .iter()
+                .map(|name| resolve_commit(root_dir, name))
+                .collect::<Result<Vec<_>, _>>()?;
+            let message = match flag_value(&args, "-m")? {
+                Some(message) => message.to_string(),
+                None => format!("Merge {}", names.join(", ")),
+            };
+
+            match repo.merge(heads, &message, options)? {
+                ink::MergeOutcome::UpToDate => out.info("Already up to date")?,
+                ink::MergeOutcome::FastForward(commit) => out.info(format!(
+                    "Fast-forwarded to {}",
+                    &hex::encode(commit.hash())[..10]
+                ))?,
+                ink::MergeOutcome::Merged(commit) => out.info(format!(
+                    "Merged {} commits as {}",
+                    commit.parents().len() - 1,
+                    &hex::encode(commit.hash())[..10]
+                ))?,
+                ink::MergeOutcome::Conflicted(paths) => {
+                    for path in &paths {
+                        out.line(format!("conflict {}", escape(path)))?;
+                    }
+                    if ink::merge::MergeState::load(root_dir)?.is_none() {
+                        return Err("Changes conflict, so nothing was merged".into());
+                    }
+                    return Err(
+                        "Changes conflict: resolve them and commit, or give up with `ink merge --abort`"
+                            .into(),
+                    );
+                }
+            }
+        }
+        "note" => {
+            if args.len() < 4 {
+                return Err("Not enough args (add/show, commit hash)".into());
+            }
+
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let commit = resolve_commit(root_dir, &args[3])?;
+
+            match args[2].as_str() {
+                "add" => {
+                    let note = args.get(4).ok_or("Not enough args (note)")?;
+                    ink::notes::add(root_dir, &commit, note)?;
+                }
+                "show" => {
+                    for note in ink::notes::show(root_dir, &commit)? {
+                        out.line(format!("{}\n", note))?;
+                    }
+                }
+                _ => return Err(format!("Unknown note subcommand {}", args[2]).into()),
+            }
+        }
+        "tag" => {
+            // `tag <name> [<commit>] [--force]`, where moving a tag off the
+            // history of its commit needs --force, or `tag --delete <name>`
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            if let Some(name) = flag_value(&args, "--delete")? {
+                ink::tags::delete(root_dir, name)?;
+                return Ok(0);
+            }
+            let force = args[2..].iter().any(|arg| arg == "--force");
+            let mut positional = args[2..].iter().filter(|arg| *arg != "--force");
+            match positional.next() {
+                Some(name) => {
+                    let commit = match positional.next() {
+                        Some(prefix) => resolve_commit(root_dir, prefix)?,
+                        None => repo.current_commit()?,
+                    };
+                    ink::tags::update(root_dir, name, &commit, force)?;
+                }
+                None => {
+                    for (name, hash) in ink::tags::list(root_dir)? {
+                        out.line(format!("{} {}", name, hex::encode(hash)))?;
+                    }
+                }
+            }
+        }
+        "describe" => {
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let always = args[2..].iter().any(|arg| arg == "--always");
+            let commit = match args[2..].iter().find(|arg| !arg.starts_with("--")) {
+                Some(prefix) => resolve_commit(root_dir, prefix)?,
+                None => repo.current_commit()?,
+            };
+
+            let hash = commit.hash();
+            match ink::tags::describe(root_dir, commit)? {
+                Some(description) => out.line(description)?,
+                None if always => out.line(&hex::encode(hash)[..10])?,
+                None => return Err("No tags in the history of the commit".into()),
+            }
+        }
+        "version-info" => {
+            let info = location.open()?.version_info()?;
+            if args[2..].iter().any(|arg| arg == "--json") {
+                out.line(info.to_json())?;
+            } else if args[2..].iter().any(|arg| arg == "--env") {
+                out.print(info.to_env())?;
+            } else {
+                out.line(format!("commit {}", info.hash))?;
+                out.line(format!("dirty {}", info.dirty))?;
+                if let Some(describe) = info.describe {
+                    out.line(format!("describe {}", describe))?;
+                }
+                out.line(format!("timestamp {}", info.timestamp))?;
+            }
+        }
+        "serve" => {
+            if !args[2..].iter().any(|arg| arg == "--web") {
+                return Err("Only the web interface is available (--web)".into());
+            }
+
+            let repo = location.open()?;
+            let root_dir = repo.ink_root();
+            let addr = flag_value(&args, "--addr")?.unwrap_or(ink::serve::DEFAULT_ADDR);
+            out.info(format!("Serving on http://{}", addr))?;
+            ink::serve::web(root_dir, addr)?;
         }
         "debug" => {
             if args.len() < 3 {
-                return Err("Not enough args (commit, graph)".into());
+                return Err("Not enough args (commit, graph, objects)".into());
             }
 
             match args[2].as_str() {
@@ -44,14 +956,33 @@ fn debugging_cli(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
                         return Err("Not enough args - commit hash".into());
                     }
 
-                    let root_dir = root_dir()?.ok_or("no root")?;
+                    let repo = location.open()?;
+                    let root_dir = repo.ink_root();
                     let hash = hex::decode(&args[3])?.try_into().unwrap();
-                    println!("{:?}", ink::commit::Commit::from(&hash, &root_dir));
+                    out.print(ink::commit::Commit::from(&hash, root_dir)?)?;
                 }
                 "graph" => {
-                    let root_dir = root_dir()?.ok_or("no root")?;
-                    let graph = CommitGraph::get(&root_dir);
-                    println!("{:?}", graph);
+                    let repo = location.open()?;
+                    let root_dir = repo.ink_root();
+                    out.print(CommitGraph::get(root_dir)?)?;
+                }
+                "rebuild-graph" => {
+                    let repo = location.open()?;
+                    let root_dir = repo.ink_root();
+                    let graph = CommitGraph::rebuild(root_dir)?;
+                    out.info(format!(
+                        "Rebuilt graph with {} commits",
+                        graph.commit_hashes().len()
+                    ))?;
+                    graph.write()?;
+                }
+                "objects" => {
+                    let repo = location.open()?;
+                    let root_dir = repo.ink_root();
+                    match args.get(3) {
+                        Some(prefix) => print_references(out, root_dir, prefix)?,
+                        None => print_objects(out, root_dir)?,
+                    }
                 }
                 _ => unimplemented!(),
             }
@@ -59,18 +990,206 @@ fn debugging_cli(args: Vec<String>) -> Result<(), Box<dyn error::Error>> {
         _ => unimplemented!(),
     };
 
+    Ok(0)
+}
+
+/// List every content object, its sizes and how many times commits use it
+fn print_objects(out: &mut Output, root_dir: &Path) -> Result<(), Box<dyn error::Error>> {
+    let objects = ink::objects::inspect(root_dir)?;
+    let (mut stored, mut size, mut references, mut saved) = (0, 0, 0, 0);
+
+    for object in &objects {
+        let mut flags = Vec::new();
+        if object.is_orphan() {
+            flags.push("orphan");
+        }
+        if object.large {
+            flags.push("large");
+        }
+
+        out.line(format!(
+            "{} {:>10} {:>10} {:>5} {}",
+            hex::encode(object.hash),
+            object.stored_size,
+            object.size,
+            object.references.len(),
+            flags.join(",")
+        ))?;
+
+        stored += object.stored_size;
+        size += object.size;
+        references += object.references.len();
+        saved += object.dedup_savings();
+    }
+
+    out.line(format!("{} objects, {} bytes stored, {} bytes uncompressed, {} references, {} bytes saved by deduplication",
+        objects.len(),
+        stored,
+        size,
+        references,
+        saved))?;
+    Ok(())
+}
+
+/// List the commits and paths using the content object a hex hash prefix refers to
+fn print_references(
+    out: &mut Output,
+    root_dir: &Path,
+    prefix: &str,
+) -> Result<(), Box<dyn error::Error>> {
+    let prefix = hex::decode(prefix)?;
+    let references = ink::objects::content_references(root_dir)?;
+    let mut candidates = references
+        .iter()
+        .filter(|(hash, _)| hash.starts_with(&prefix));
+
+    let (_, references) = match (candidates.next(), candidates.next()) {
+        (Some(found), None) => found,
+        (None, _) => return Err("No commits use a content object with the given prefix".into()),
+        (Some(_), Some(_)) => return Err("Too many possible objects with the given prefix".into()),
+    };
+
+    for reference in references {
+        out.line(format!(
+            "{} {}",
+            hex::encode(reference.commit),
+            reference.path.display()
+        ))?;
+    }
+    Ok(())
+}
+
+/// Print a commit's fields, one per line, then its message
+fn print_commit(out: &mut Output, commit: &Commit) -> Result<(), Box<dyn error::Error>> {
+    out.line(format!("tree {}", hex::encode(commit.tree_hash())))?;
+    for parent in commit.parents() {
+        out.line(format!("parent {}", hex::encode(parent)))?;
+    }
+    out.line(format!("time {} {}", commit.time(), commit.offset()))?;
+    for (key, value) in commit.metadata() {
+        out.line(format!("meta {}={}", key, value))?;
+    }
+    out.line("")?;
+    out.line(commit.message())?;
     Ok(())
 }
 
-fn root_dir() -> Result<Option<PathBuf>, InkError> {
-    let curr_dir = env::current_dir()?.canonicalize()?;
+/// When a commit was made, in the committer's time zone or in UTC
+fn commit_date(commit: &Commit, utc: bool) -> String {
+    let offset = if utc { 0 } else { commit.offset() };
+    ink::time::format(commit.time(), offset)
+}
+
+/// List the lines changed in each file, then in total
+fn print_stat(out: &mut Output, stat: &DiffStat) -> Result<(), Box<dyn error::Error>> {
+    for file in &stat.files {
+        out.line(format!(
+            "  {} {} +{} -{}",
+            file.status,
+            file.path.display(),
+            file.insertions,
+            file.deletions
+        ))?;
+    }
+    out.line(format!(
+        "  {} files changed, +{} -{}",
+        stat.files.len(),
+        stat.insertions(),
+        stat.deletions()
+    ))?;
+    Ok(())
+}
+
+/// Find the commit a tag or hex hash prefix refers to
+fn resolve_commit(root_dir: &Path, prefix: &str) -> Result<Commit, Box<dyn error::Error>> {
+    // a tag is looked for first, since its name can look like hex
+    if let Ok(Some(hash)) = ink::tags::get(root_dir, prefix) {
+        return Ok(Commit::from(&hash, root_dir)?);
+    }
+
+    let hash = hex::decode(prefix)?;
+    Ok(Commit::from(
+        &commit_hash_from_prefix(root_dir, &hash)?,
+        root_dir,
+    )?)
+}
+
+/// Whether a patch starts with an ed command like `3a` or `1,2c`, which an
+/// ink edit script's `0,0r0,0` doesn't look like
+fn is_ed_script(patch: &str) -> bool {
+    let first = patch.lines().next().unwrap_or("");
+    let command = first.trim_start_matches(|c: char| c.is_ascii_digit() || c == ',');
+    command.len() < first.len() && matches!(command, "a" | "c" | "d")
+}
+
+/// Width of the terminal from `COLUMNS`, which most shells set, or 80
+fn terminal_width() -> usize {
+    env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(80)
+}
+
+/// Find every value given for a flag, e.g. `--meta a=1 --meta b=2`
+fn flag_values<'a>(args: &'a [String], flag: &str) -> Result<Vec<&'a str>, Box<dyn error::Error>> {
+    debug_assert!(
+        value_flags(&args[1]).contains(&flag),
+        "{} {} is missing from VALUE_FLAGS",
+        args[1],
+        flag
+    );
+    let mut values = Vec::new();
 
-    for path in curr_dir.ancestors() {
-        let ink_dir = path.join(".ink");
-        if ink_dir.exists() && ink_dir.is_dir() {
-            return Ok(Some(ink_dir));
+    for (index, arg) in args.iter().enumerate() {
+        if arg == flag {
+            let value = args.get(index + 1).ok_or("Flag is missing its value")?;
+            values.push(value.as_str());
         }
     }
 
-    Ok(None)
+    Ok(values)
+}
+
+/// Find the value following a flag, e.g. `-m <message>`
+fn flag_value<'a>(
+    args: &'a [String],
+    flag: &str,
+) -> Result<Option<&'a str>, Box<dyn error::Error>> {
+    Ok(flag_values(args, flag)?.pop())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn global_flags_anywhere() {
+        let mut location = Location::default();
+        let mut given = args("ink --repo r commit -m -q --author --quiet -q");
+        assert!(take_global_flags(&mut given, &mut location).unwrap());
+        assert_eq!(given, args("ink commit -m -q --author --quiet"));
+        assert_eq!(location.dir, Some(PathBuf::from("r")));
+
+        // every value flag of every command keeps its value
+        for (command, flags) in VALUE_FLAGS {
+            for flag in flags.iter() {
+                let line = format!("ink {} {} --work-tree -q", command, flag);
+                let mut given = args(&line);
+                let mut location = Location::default();
+                assert!(take_global_flags(&mut given, &mut location).unwrap());
+                assert_eq!(
+                    given,
+                    args(&format!("ink {} {} --work-tree", command, flag))
+                );
+                assert_eq!(location, Location::default());
+            }
+        }
+
+        let mut given = args("ink status --repo");
+        assert!(take_global_flags(&mut given, &mut Location::default()).is_err());
+    }
 }
@@ -0,0 +1,653 @@
+//! Merging the files of commits, path by path.
+//!
+//! Changes both sides made to the same lines conflict, unless a strategy
+//! says which side to take. The strategy given to a merge, like `-X ours`,
+//! can be set per path with `merge` in a `[path "<pattern>"]` section of the
+//! config, where the last matching pattern wins, e.g.
+//!
+//! ```text
+//! [path "Cargo.lock"]
+//! merge = theirs
+//! ```
+//!
+//! - `conflict` (the default) leaves the changes as a conflict
+//! - `ours` and `theirs` take that side's lines, permissions, or whole
+//!   content if it isn't text
+//!
+//! `merge` can also name a merge driver, an external command defined by a
+//! `[merge "<name>"]` section, which is run when the lines of a file can't
+//! be merged, before the file is taken to conflict, e.g.
+//!
+//! ```text
+//! [merge "json"]
+//! driver = json-merge %O %A %B
+//!
+//! [path "*.json"]
+//! merge = json
+//! ```
+//!
+//! The command is run by the shell in the project directory. `%O`, `%A` and
+//! `%B` are replaced with the paths of temporary files holding the base, our
+//! and their content as it is stored, and `%P` with the file's path. It
+//! writes the merged content to `%A` and exits with 0, or exits with another
+//! code if it can't merge them. Paths with a driver use the strategy given
+//! to the merge if it fails.
+//!
+//! A file deleted on one side and changed on the other is always a conflict.
+use crate::config::Config;
+use crate::diff::{merge3, MergeRegion, MergeResult};
+use crate::filedata::FileData;
+use crate::filter;
+use crate::objects;
+use crate::storage::StoragePolicy;
+use crate::{atomic, utils, InkError, MERGE_STATE_FILE};
+
+use serde::{Deserialize, Serialize};
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+
+/// How changes both sides made to the same part of a file are merged
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Strategy {
+    #[default]
+    Conflict,
+    Ours,
+    Theirs,
+}
+
+impl Strategy {
+    pub fn parse(value: &str) -> Result<Strategy, InkError> {
+        match value {
+            "conflict" => Ok(Strategy::Conflict),
+            "ours" => Ok(Strategy::Ours),
+            "theirs" => Ok(Strategy::Theirs),
+            _ => Err("merge strategy must be one of conflict, ours or theirs".into()),
+        }
+    }
+
+    /// The side to take, or `None` if the changes conflict
+    fn pick<T>(self, ours: T, theirs: T) -> Option<T> {
+        match self {
+            Strategy::Conflict => None,
+            Strategy::Ours => Some(ours),
+            Strategy::Theirs => Some(theirs),
+        }
+    }
+}
+
+/// An external command that merges a file, see the module docs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Driver {
+    command: String,
+}
+
+impl Driver {
+    /// Run the driver on the content of a file at `path`, returning the
+    /// merged content, or `None` if it couldn't merge it
+    fn run(
+        &self,
+        path: &Path,
+        project_dir: &Path,
+        base: &[u8],
+        ours: &[u8],
+        theirs: &[u8],
+    ) -> Result<Option<Vec<u8>>, InkError> {
+        let mut files = Vec::new();
+        for content in &[base, ours, theirs] {
+            let mut file = tempfile::Builder::new()
+                .prefix(atomic::TEMP_PREFIX)
+                .tempfile()?;
+            file.write_all(content)?;
+            file.flush()?;
+            files.push(file);
+        }
+
+        let quoted =
+            |file: &tempfile::NamedTempFile| filter::shell_quote(&file.path().to_string_lossy());
+        let command = self
+            .command
+            .replace("%O", &quoted(&files[0]))
+            .replace("%A", &quoted(&files[1]))
+            .replace("%B", &quoted(&files[2]))
+            .replace("%P", &filter::shell_quote(&path.to_string_lossy()));
+
+        let status = filter::shell(&command, project_dir)
+            .stdin(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Ok(None);
+        }
+
+        Ok(Some(fs::read(files[1].path())?))
+    }
+}
+
+/// How a path's file is merged, from the config
+#[derive(Debug, Clone, PartialEq)]
+enum Rule {
+    Strategy(Strategy),
+    Driver(Driver),
+}
+
+/// The strategy and driver chosen for each path in a repository
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MergeRules {
+    project_dir: PathBuf,
+    default: Strategy,
+    rules: Vec<(String, Rule)>,
+}
+
+impl MergeRules {
+    /// The rules in the config, with `default` for paths none match.
+    /// Drivers run in the project directory.
+    pub fn load(
+        ink_root: &Path,
+        project_dir: &Path,
+        default: Strategy,
+    ) -> Result<MergeRules, InkError> {
+        MergeRules::from_config(&Config::load(ink_root)?, project_dir, default)
+    }
+
+    pub fn from_config(
+        config: &Config,
+        project_dir: &Path,
+        default: Strategy,
+    ) -> Result<MergeRules, InkError> {
+        let mut drivers = HashMap::new();
+        for section in config.sections("merge") {
+            if let Some(name) = section.label() {
+                let command = section
+                    .get("driver")
+                    .ok_or("Merge drivers need a driver command")?;
+                drivers.insert(
+                    name,
+                    Driver {
+                        command: command.to_string(),
+                    },
+                );
+            }
+        }
+
+        let mut rules = Vec::new();
+        for section in config.sections("path") {
+            if let (Some(pattern), Some(value)) = (section.label(), section.get("merge")) {
+                let rule = match drivers.get(value) {
+                    Some(driver) => Rule::Driver(driver.clone()),
+                    None => Rule::Strategy(
+                        Strategy::parse(value)
+                            .map_err(|_| "Path uses an unknown merge strategy or driver")?,
+                    ),
+                };
+                rules.push((pattern.to_string(), rule));
+            }
+        }
+
+        Ok(MergeRules {
+            project_dir: project_dir.to_path_buf(),
+            default,
+            rules,
+        })
+    }
+
+    fn rule(&self, path: &Path) -> Option<&Rule> {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| utils::glob_match(pattern, path))
+            .map(|(_, rule)| rule)
+    }
+
+    /// The strategy for a path relative to the project directory
+    pub fn strategy(&self, path: &Path) -> Strategy {
+        match self.rule(path) {
+            Some(Rule::Strategy(strategy)) => *strategy,
+            _ => self.default,
+        }
+    }
+
+    /// The driver for a path relative to the project directory, if it has one
+    pub fn driver(&self, path: &Path) -> Option<&Driver> {
+        match self.rule(path) {
+            Some(Rule::Driver(driver)) => Some(driver),
+            _ => None,
+        }
+    }
+}
+
+/// A merge stopped by conflicts, kept in `.ink/merge-state` until it is
+/// committed or given up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MergeState {
+    /// The commit being merged into the current one
+    pub head: [u8; 32],
+    pub message: String,
+    /// The paths whose lines conflicted, with the ids their conflicts are
+    /// recorded under for `rerere`
+    pub conflicts: Vec<(PathBuf, [u8; 32])>,
+}
+
+impl MergeState {
+    /// The merge in progress, if there is one
+    pub fn load(ink_root: &Path) -> Result<Option<MergeState>, InkError> {
+        match fs::read(ink_root.join(MERGE_STATE_FILE)) {
+            Ok(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    pub fn write(&self, ink_root: &Path) -> Result<(), InkError> {
+        fs::write(ink_root.join(MERGE_STATE_FILE), bincode::serialize(self)?)?;
+        Ok(())
+    }
+
+    /// Forget the merge in progress, if there is one
+    pub fn clear(ink_root: &Path) -> Result<(), InkError> {
+        match fs::remove_file(ink_root.join(MERGE_STATE_FILE)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The files of a merge of two trees, and the paths that couldn't be merged
+#[derive(Debug)]
+pub struct TreeMerge {
+    pub files: Vec<FileData>,
+    pub conflicts: Vec<PathBuf>,
+    /// The conflicts between lines of text, with what each side changed
+    pub line_conflicts: Vec<LineConflict>,
+}
+
+impl TreeMerge {
+    pub fn is_clean(&self) -> bool {
+        self.conflicts.is_empty()
+    }
+
+    /// Take a conflict as resolved with the given file
+    pub fn resolve(&mut self, file: FileData) {
+        self.conflicts.retain(|path| path != file.path());
+        self.line_conflicts
+            .retain(|conflict| conflict.path != file.path());
+        self.files.push(file);
+        self.files.sort_by(|a, b| a.path().cmp(b.path()));
+    }
+}
+
+/// A text file both sides changed, where some of the changes conflict
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineConflict {
+    pub path: PathBuf,
+    /// The merged permissions, which didn't conflict
+    pub permissions: u32,
+    pub regions: Vec<MergeRegion>,
+}
+
+impl LineConflict {
+    /// The merged content, with each conflict written out between
+    /// `<<<<<<<`, `=======` and `>>>>>>>` markers naming the sides
+    pub fn with_markers(&self, ours_name: &str, theirs_name: &str) -> Vec<u8> {
+        // markers go on lines of their own, even after a last line without
+        // an ending
+        fn push_lines(text: &mut String, lines: &[String]) {
+            text.push_str(&lines.concat());
+            if !text.is_empty() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+        }
+
+        let mut text = String::new();
+        for region in &self.regions {
+            match region {
+                MergeRegion::Resolved(lines) => text.push_str(&lines.concat()),
+                MergeRegion::Conflict { ours, theirs, .. } => {
+                    push_lines(&mut text, &[]);
+                    text.push_str(&format!("<<<<<<< {}\n", ours_name));
+                    push_lines(&mut text, ours);
+                    text.push_str("=======\n");
+                    push_lines(&mut text, theirs);
+                    text.push_str(&format!(">>>>>>> {}\n", theirs_name));
+                }
+            }
+        }
+        text.into_bytes()
+    }
+}
+
+/// How a file both sides changed merged
+enum FileMerge {
+    Merged(FileData),
+    Lines(LineConflict),
+    Conflict,
+}
+
+/// Merge the changes `ours` and `theirs` made to the files of `base`.
+///
+/// A path changed on one side only takes that side's file. Where both
+/// sides changed a file, their line changes are merged and the merged
+/// content is stored. Files changed in conflicting ways, deleted on one
+/// side and changed on the other, or whose content isn't text, are
+/// conflicts unless the path's driver or strategy resolves them, and
+/// conflicts are left out of the files.
+pub fn merge_trees(
+    ink_root: &Path,
+    base: &[FileData],
+    ours: &[FileData],
+    theirs: &[FileData],
+    rules: &MergeRules,
+) -> Result<TreeMerge, InkError> {
+    let by_path = |files: &[FileData]| -> BTreeMap<PathBuf, FileData> {
+        files
+            .iter()
+            .map(|file| (file.path().to_path_buf(), file.clone()))
+            .collect()
+    };
+    let (base, ours, theirs) = (by_path(base), by_path(ours), by_path(theirs));
+    let paths: BTreeSet<&PathBuf> = base
+        .keys()
+        .chain(ours.keys())
+        .chain(theirs.keys())
+        .collect();
+
+    let policy = StoragePolicy::load(ink_root)?;
+    let mut merged = TreeMerge {
+        files: Vec::new(),
+        conflicts: Vec::new(),
+        line_conflicts: Vec::new(),
+    };
+    for path in paths {
+        let (b, o, t) = (base.get(path), ours.get(path), theirs.get(path));
+        let file = if o == t || b == t {
+            o.cloned()
+        } else if b == o {
+            t.cloned()
+        } else {
+            let file = match (o, t) {
+                (Some(o), Some(t)) => merge_file(ink_root, &policy, b, o, t, rules)?,
+                // deleted on one side, changed on the other
+                _ => FileMerge::Conflict,
+            };
+            match file {
+                FileMerge::Merged(file) => Some(file),
+                FileMerge::Lines(conflict) => {
+                    merged.conflicts.push(path.clone());
+                    merged.line_conflicts.push(conflict);
+                    None
+                }
+                FileMerge::Conflict => {
+                    merged.conflicts.push(path.clone());
+                    None
+                }
+            }
+        };
+
+        merged.files.extend(file);
+    }
+
+    Ok(merged)
+}
+
+/// Merge a file both sides changed
+fn merge_file(
+    ink_root: &Path,
+    policy: &StoragePolicy,
+    base: Option<&FileData>,
+    ours: &FileData,
+    theirs: &FileData,
+    rules: &MergeRules,
+) -> Result<FileMerge, InkError> {
+    let path = ours.path();
+    let strategy = rules.strategy(path);
+    let permissions = match merge_value(
+        base.map(FileData::permissions),
+        ours.permissions(),
+        theirs.permissions(),
+    )
+    .or_else(|| strategy.pick(ours.permissions(), theirs.permissions()))
+    {
+        Some(permissions) => permissions,
+        None => return Ok(FileMerge::Conflict),
+    };
+
+    let content_hash = match merge_value(
+        base.map(FileData::content_hash),
+        ours.content_hash(),
+        theirs.content_hash(),
+    ) {
+        Some(content_hash) => content_hash,
+        None => {
+            // a file added on both sides merges as if it was empty before
+            let base_content = match base {
+                Some(base) => base.read_content_with(ink_root, policy)?,
+                None => Vec::new(),
+            };
+            let ours_content = ours.read_content_with(ink_root, policy)?;
+            let theirs_content = theirs.read_content_with(ink_root, policy)?;
+
+            let lines = merge_lines(&base_content, &ours_content, &theirs_content);
+            let mut merged = lines
+                .as_ref()
+                .and_then(|lines| resolve(lines, Strategy::Conflict));
+            if let (None, Some(driver)) = (&merged, rules.driver(path)) {
+                merged = driver.run(
+                    path,
+                    &rules.project_dir,
+                    &base_content,
+                    &ours_content,
+                    &theirs_content,
+                )?;
+            }
+            if merged.is_none() && strategy != Strategy::Conflict {
+                merged = lines.as_ref().and_then(|lines| resolve(lines, strategy));
+            }
+
+            match merged {
+                Some(content) => objects::store_content(ink_root, &content, path)?.0,
+                None => match strategy.pick(ours.content_hash(), theirs.content_hash()) {
+                    Some(content_hash) => content_hash,
+                    None => {
+                        return Ok(match lines {
+                            Some(MergeResult::Conflicted(regions)) => {
+                                FileMerge::Lines(LineConflict {
+                                    path: path.to_path_buf(),
+                                    permissions,
+                                    regions,
+                                })
+                            }
+                            _ => FileMerge::Conflict,
+                        })
+                    }
+                },
+            }
+        }
+    };
+
+    Ok(FileMerge::Merged(FileData::from_content(
+        path,
+        permissions,
+        content_hash,
+    )))
+}
+
+/// The side that changed a value, or either if they agree, or `None` if
+/// both changed it differently
+fn merge_value<T: PartialEq>(base: Option<T>, ours: T, theirs: T) -> Option<T> {
+    if ours == theirs || base.as_ref() == Some(&theirs) {
+        Some(ours)
+    } else if base.as_ref() == Some(&ours) {
+        Some(theirs)
+    } else {
+        None
+    }
+}
+
+/// Merge text line by line, keeping each line's ending, or `None` if the
+/// content isn't text
+pub(crate) fn merge_lines(base: &[u8], ours: &[u8], theirs: &[u8]) -> Option<MergeResult> {
+    let lines = |content| {
+        std::str::from_utf8(content)
+            .ok()
+            .map(|text| text.split_inclusive('\n').collect::<Vec<_>>())
+    };
+    Some(merge3(&lines(base)?, &lines(ours)?, &lines(theirs)?))
+}
+
+/// The merged content, or `None` if the changes conflict and the strategy
+/// doesn't resolve them
+fn resolve(merged: &MergeResult, strategy: Strategy) -> Option<Vec<u8>> {
+    let lines = match merged {
+        MergeResult::Clean(lines) => lines.clone(),
+        MergeResult::Conflicted(regions) => {
+            let mut lines = Vec::new();
+            for region in regions {
+                match region {
+                    MergeRegion::Resolved(resolved) => lines.extend(resolved.iter().cloned()),
+                    MergeRegion::Conflict { ours, theirs, .. } => {
+                        lines.extend(strategy.pick(ours, theirs)?.iter().cloned())
+                    }
+                }
+            }
+            lines
+        }
+    };
+
+    Some(lines.concat().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+
+    #[test]
+    fn merges_path_by_path() {
+        let repo = TestRepo::new()
+            .file("kept", "a\nb\n")
+            .file("changed", "a\nb\nc\n")
+            .file("deleted", "a\n")
+            .commit("base");
+        let base = repo.head();
+        let repo = repo
+            .file("changed", "A\nb\nc\n")
+            .file("deleted", "changed\n")
+            .file("added", "same\n")
+            .commit("ours");
+        let ours = repo.head();
+        let repo = repo
+            .file("changed", "a\nb\nC\n")
+            .remove("deleted")
+            .file("added", "same\n")
+            .commit("theirs");
+        let theirs = repo.head();
+
+        let merge = |rules| {
+            merge_trees(
+                repo.ink_root(),
+                base.files(),
+                ours.files(),
+                theirs.files(),
+                &rules,
+            )
+            .unwrap()
+        };
+        let merged = merge(MergeRules::default());
+        assert_eq!(merged.conflicts, [Path::new("deleted")]);
+        let paths: Vec<_> = merged.files.iter().map(FileData::path).collect();
+        assert_eq!(paths, ["added", "changed", "kept"].map(Path::new));
+        assert_eq!(
+            merged.files[1].read_content(repo.ink_root()).unwrap(),
+            b"A\nb\nC\n"
+        );
+    }
+
+    #[test]
+    fn strategies_resolve_conflicts() {
+        let repo = TestRepo::new()
+            .file("Cargo.lock", "a\nb\n")
+            .file("src/main.rs", "a\nb\n")
+            .commit("base");
+        let base = repo.head();
+        let repo = repo
+            .file("Cargo.lock", "ours\nb\n")
+            .file("src/main.rs", "ours\nb\n")
+            .commit("ours");
+        let ours = repo.head();
+        let repo = repo
+            .file("Cargo.lock", "theirs\nb\nc\n")
+            .file("src/main.rs", "theirs\nb\nc\n")
+            .commit("theirs");
+        let theirs = repo.head();
+
+        let config = Config::parse("[path \"*.lock\"]\nmerge = theirs\n").unwrap();
+        let merge = |default| {
+            let rules = MergeRules::from_config(&config, repo.path(), default).unwrap();
+            let merged = merge_trees(
+                repo.ink_root(),
+                base.files(),
+                ours.files(),
+                theirs.files(),
+                &rules,
+            )
+            .unwrap();
+            let contents: Vec<_> = merged
+                .files
+                .iter()
+                .map(|file| file.read_content(repo.ink_root()).unwrap())
+                .collect();
+            (merged.conflicts, contents)
+        };
+
+        // only the conflicting lines are taken from the chosen side
+        let (conflicts, contents) = merge(Strategy::Conflict);
+        assert_eq!(conflicts, [Path::new("src/main.rs")]);
+        assert_eq!(contents, [b"theirs\nb\nc\n".to_vec()]);
+        let (conflicts, contents) = merge(Strategy::Ours);
+        assert!(conflicts.is_empty());
+        assert_eq!(contents[1], b"ours\nb\nc\n");
+
+        assert!(Strategy::parse("mine").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn drivers_merge_before_conflicts() {
+        let repo = TestRepo::new().file("list.txt", "a\n").commit("base");
+        let base = repo.head();
+        let repo = repo.file("list.txt", "ours\n").commit("ours");
+        let ours = repo.head();
+        let repo = repo.file("list.txt", "theirs\n").commit("theirs");
+        let theirs = repo.head();
+
+        let merge = |driver: &str| {
+            let config = format!(
+                "[merge \"driver\"]\ndriver = {}\n[path \"*.txt\"]\nmerge = driver\n",
+                driver
+            );
+            let config = Config::parse(&config).unwrap();
+            let rules = MergeRules::from_config(&config, repo.path(), Strategy::Conflict).unwrap();
+            merge_trees(
+                repo.ink_root(),
+                base.files(),
+                ours.files(),
+                theirs.files(),
+                &rules,
+            )
+            .unwrap()
+        };
+
+        let merged = merge("test %P = list.txt && cat %B >> %A");
+        assert!(merged.is_clean());
+        assert_eq!(
+            merged.files[0].read_content(repo.ink_root()).unwrap(),
+            b"ours\ntheirs\n"
+        );
+        assert!(!merge("exit 1").is_clean());
+
+        let unknown = Config::parse("[path \"*\"]\nmerge = nothing\n").unwrap();
+        assert!(MergeRules::from_config(&unknown, repo.path(), Strategy::Ours).is_err());
+    }
+}
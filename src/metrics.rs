@@ -0,0 +1,303 @@
+//! A local log of how long operations take, for `ink metrics`.
+//!
+//! Logging is off unless `enabled = true` is set in the `[metrics]` config
+//! section, and the log never leaves `.ink/metrics`. Each line is one
+//! operation: when it finished in seconds since the unix epoch, its name,
+//! how long it took, the files and bytes it wrote, and the time spent in each
+//! of its phases, all in microseconds:
+//!
+//! ```text
+//! 1700000000 commit 41250 12 80211 scan=9800 hash=3100 compress=21000 io=6900 other=450
+//! ```
+//!
+//! A commit is split into scanning the working directory, which reads and
+//! hashes every file, then hashing, compressing and the rest of the IO of
+//! storing new content, and everything else. Content stored by several jobs
+//! at once has its hashing and compressing added up across them, so the
+//! phases of a commit can add up to more than its duration. Checkouts are
+//! logged whole.
+use crate::config::Config;
+use crate::{InkError, METRICS_FILE};
+
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// One logged operation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// When it finished, in seconds since the unix epoch
+    pub time: u64,
+    pub operation: String,
+    pub duration: Duration,
+    pub files: usize,
+    pub bytes: u64,
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl Record {
+    /// A record of an operation that just finished
+    pub(crate) fn new(operation: &str, duration: Duration) -> Record {
+        Record {
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs()),
+            operation: operation.to_string(),
+            duration,
+            files: 0,
+            bytes: 0,
+            phases: Vec::new(),
+        }
+    }
+
+    pub(crate) fn files(mut self, files: usize) -> Record {
+        self.files = files;
+        self
+    }
+
+    pub(crate) fn bytes(mut self, bytes: u64) -> Record {
+        self.bytes = bytes;
+        self
+    }
+
+    pub(crate) fn phase(mut self, name: &str, duration: Duration) -> Record {
+        self.phases.push((name.to_string(), duration));
+        self
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = format!(
+            "{} {} {} {} {}",
+            self.time,
+            self.operation,
+            self.duration.as_micros(),
+            self.files,
+            self.bytes
+        );
+        for (name, duration) in &self.phases {
+            let _ = write!(line, " {}={}", name, duration.as_micros());
+        }
+        line
+    }
+
+    fn from_line(line: &str) -> Option<Record> {
+        let mut fields = line.split(' ');
+        let mut record = Record {
+            time: fields.next()?.parse().ok()?,
+            operation: fields.next()?.to_string(),
+            duration: micros(fields.next()?)?,
+            files: fields.next()?.parse().ok()?,
+            bytes: fields.next()?.parse().ok()?,
+            phases: Vec::new(),
+        };
+        for phase in fields {
+            let (name, duration) = phase.split_once('=')?;
+            record.phases.push((name.to_string(), micros(duration)?));
+        }
+        Some(record)
+    }
+}
+
+fn micros(field: &str) -> Option<Duration> {
+    field.parse().ok().map(Duration::from_micros)
+}
+
+/// Time spent in phases of work shared between threads
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    hashing: AtomicU64,
+    compressing: AtomicU64,
+}
+
+impl Timings {
+    pub(crate) fn hashed(&self, duration: Duration) {
+        self.hashing
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn compressed(&self, duration: Duration) {
+        self.compressing
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn hashing(&self) -> Duration {
+        Duration::from_nanos(self.hashing.load(Ordering::Relaxed))
+    }
+
+    pub(crate) fn compressing(&self) -> Duration {
+        Duration::from_nanos(self.compressing.load(Ordering::Relaxed))
+    }
+}
+
+/// Whether operations are logged, as the `[metrics]` section says
+pub fn enabled(config: &Config) -> Result<bool, InkError> {
+    match config.get("metrics", "enabled") {
+        None | Some("false") => Ok(false),
+        Some("true") => Ok(true),
+        Some(_) => Err("metrics enabled must be true or false".into()),
+    }
+}
+
+/// Add a record to the log
+pub(crate) fn record(ink_root: &Path, record: &Record) -> Result<(), InkError> {
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(ink_root.join(METRICS_FILE))?;
+    // one write, so records from operations at once don't interleave
+    log.write_all(format!("{}\n", record.to_line()).as_bytes())?;
+    Ok(())
+}
+
+/// Every record in the log, oldest first
+pub fn load(ink_root: &Path) -> Result<Vec<Record>, InkError> {
+    let path = ink_root.join(METRICS_FILE);
+    let log = match fs::read_to_string(&path) {
+        Ok(log) => log,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err.into()),
+    };
+
+    log.lines()
+        .map(|line| {
+            Record::from_line(line).ok_or_else(|| InkError::Malformed {
+                path: path.clone(),
+                problem: "`ink metrics --clear` removes a log with lines that aren't records",
+            })
+        })
+        .collect()
+}
+
+/// Remove the log
+pub fn clear(ink_root: &Path) -> Result<(), InkError> {
+    match fs::remove_file(ink_root.join(METRICS_FILE)) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// The records of one operation added up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperationSummary {
+    pub operation: String,
+    pub count: usize,
+    pub duration: Duration,
+    pub files: usize,
+    pub bytes: u64,
+    /// Phases by the time spent in them, the longest first
+    pub phases: Vec<(String, Duration)>,
+}
+
+impl OperationSummary {
+    pub fn mean(&self) -> Duration {
+        self.duration / self.count.max(1) as u32
+    }
+}
+
+/// Add up the records of each operation, the one that took longest in all
+/// first
+pub fn summarize(records: &[Record]) -> Vec<OperationSummary> {
+    let mut operations: BTreeMap<&str, (OperationSummary, BTreeMap<&str, Duration>)> =
+        BTreeMap::new();
+    for record in records {
+        let (summary, phases) = operations.entry(&record.operation).or_insert_with(|| {
+            let summary = OperationSummary {
+                operation: record.operation.clone(),
+                count: 0,
+                duration: Duration::ZERO,
+                files: 0,
+                bytes: 0,
+                phases: Vec::new(),
+            };
+            (summary, BTreeMap::new())
+        });
+        summary.count += 1;
+        summary.duration += record.duration;
+        summary.files += record.files;
+        summary.bytes += record.bytes;
+        for (name, duration) in &record.phases {
+            *phases.entry(name).or_default() += *duration;
+        }
+    }
+
+    let mut summaries: Vec<OperationSummary> = operations
+        .into_values()
+        .map(|(mut summary, phases)| {
+            summary.phases = phases
+                .into_iter()
+                .map(|(name, duration)| (name.to_string(), duration))
+                .collect();
+            summary
+                .phases
+                .sort_by_key(|(_, duration)| Reverse(*duration));
+            summary
+        })
+        .collect();
+    summaries.sort_by_key(|summary| Reverse(summary.duration));
+    summaries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+    use crate::CONFIG_FILE;
+
+    #[test]
+    fn records_round_trip() {
+        let record = Record::new("commit", Duration::from_micros(41250))
+            .files(12)
+            .bytes(80211)
+            .phase("scan", Duration::from_micros(9800))
+            .phase("hash", Duration::from_micros(3100));
+        assert_eq!(Record::from_line(&record.to_line()), Some(record));
+        assert_eq!(Record::from_line("1 commit 2 3"), None);
+        assert_eq!(Record::from_line("1 commit 2 3 4 scan"), None);
+    }
+
+    #[test]
+    fn logged_only_when_enabled() {
+        let repo = TestRepo::new().file("a", "first").commit("first");
+        assert!(load(repo.ink_root()).unwrap().is_empty());
+
+        fs::write(
+            repo.ink_root().join(CONFIG_FILE),
+            "[metrics]\nenabled = true\n",
+        )
+        .unwrap();
+        let repo = repo
+            .file("a", "second")
+            .file("b", "b")
+            .commit("second")
+            .file("b", "changed")
+            .commit("third");
+
+        let records = load(repo.ink_root()).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].operation, "commit");
+        assert_eq!(records[0].files, 2);
+        let phases: Vec<&str> = records[0]
+            .phases
+            .iter()
+            .map(|(name, _)| &name[..])
+            .collect();
+        assert_eq!(phases, ["scan", "hash", "compress", "io", "other"]);
+
+        let summaries = summarize(&records);
+        assert_eq!(summaries.len(), 1);
+        assert_eq!(summaries[0].count, 2);
+        assert_eq!(summaries[0].files, 3);
+        assert_eq!(
+            summaries[0].duration,
+            records[0].duration + records[1].duration
+        );
+
+        clear(repo.ink_root()).unwrap();
+        assert!(load(repo.ink_root()).unwrap().is_empty());
+    }
+}
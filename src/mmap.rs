@@ -0,0 +1,129 @@
+//! Memory-mapped reads of big files, with the `mmap` feature on unix.
+//!
+//! Mapping a file saves the read calls and the copy into a buffer that reading
+//! it in chunks costs, which adds up for files of many megabytes. Without the
+//! feature, `map` always returns `None` and files are read as usual.
+//!
+//! A mapped file must not be truncated while it is mapped, or reading the
+//! missing part kills the process with SIGBUS. So only objects in the data
+//! directory are mapped, which are never changed once written. Files in the
+//! working directory, or in a large file store, can change at any time and
+//! are always read.
+use std::io;
+use std::path::Path;
+
+/// Smaller files are quicker to read than to map
+#[cfg(all(feature = "mmap", unix))]
+const THRESHOLD: u64 = 1024 * 1024;
+
+#[cfg(all(feature = "mmap", unix))]
+pub(crate) use self::unix::Mmap;
+
+/// Without mapping, nothing is ever mapped, but the type keeps callers the same
+#[cfg(not(all(feature = "mmap", unix)))]
+pub(crate) type Mmap = Vec<u8>;
+
+/// Map a whole file read-only, if it is big enough to be worth it
+#[cfg(all(feature = "mmap", unix))]
+pub(crate) fn map(path: &Path) -> io::Result<Option<Mmap>> {
+    let file = std::fs::File::open(path)?;
+    if file.metadata()?.len() < THRESHOLD {
+        return Ok(None);
+    }
+
+    Mmap::new(&file).map(Some)
+}
+
+#[cfg(not(all(feature = "mmap", unix)))]
+pub(crate) fn map(_path: &Path) -> io::Result<Option<Mmap>> {
+    Ok(None)
+}
+
+#[cfg(all(feature = "mmap", unix))]
+mod unix {
+    use std::convert::TryInto;
+    use std::fs::File;
+    use std::io;
+    use std::ops::Deref;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr;
+    use std::slice;
+
+    /// A read-only mapping of a whole file
+    pub struct Mmap {
+        ptr: *mut libc::c_void,
+        len: usize,
+    }
+
+    // the mapping is read-only, so sharing it between threads is fine
+    unsafe impl Send for Mmap {}
+    unsafe impl Sync for Mmap {}
+
+    impl Mmap {
+        pub fn new(file: &File) -> io::Result<Mmap> {
+            let len: usize = file.metadata()?.len().try_into().map_err(|_| {
+                io::Error::new(io::ErrorKind::InvalidInput, "file is too big to map")
+            })?;
+
+            // SAFETY: mapping a file we have open for reading, read-only and
+            // private, doesn't touch any memory Rust knows about
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ,
+                    libc::MAP_PRIVATE,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error());
+            }
+
+            Ok(Mmap { ptr, len })
+        }
+    }
+
+    impl Deref for Mmap {
+        type Target = [u8];
+
+        fn deref(&self) -> &[u8] {
+            // SAFETY: the mapping is `len` bytes long and lives as long as self
+            unsafe { slice::from_raw_parts(self.ptr as *const u8, self.len) }
+        }
+    }
+
+    impl AsRef<[u8]> for Mmap {
+        fn as_ref(&self) -> &[u8] {
+            self
+        }
+    }
+
+    impl Drop for Mmap {
+        fn drop(&mut self) {
+            // SAFETY: ptr and len are exactly what mmap returned and was given
+            unsafe {
+                libc::munmap(self.ptr, self.len);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "mmap", unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_big_files() {
+        let tmpdir = crate::testkit::temp_dir();
+        let small = tmpdir.path().join("small");
+        let big = tmpdir.path().join("big");
+        std::fs::write(&small, b"small").unwrap();
+        let content: Vec<u8> = (0..THRESHOLD as u32 + 10).map(|i| i as u8).collect();
+        std::fs::write(&big, &content).unwrap();
+
+        assert!(map(&small).unwrap().is_none());
+        assert_eq!(&*map(&big).unwrap().unwrap(), &content[..]);
+    }
+}
@@ -0,0 +1,175 @@
+//! Line ending normalization, so a repository shared between systems doesn't
+//! show every text file as modified.
+//!
+//! Set with `text` in the `[core]` section of the config, and per path in
+//! `[path "<pattern>"]` sections, where the last matching pattern wins:
+//! - `off` (the default) stores and checks out content exactly as it is
+//! - `auto` treats files as text, and checks them out with the platform's
+//!   line endings
+//! - `lf` and `crlf` treat files as text, and check them out with the given
+//!   line endings
+//!
+//! Text is always stored with LF line endings. A file with a NUL byte in its
+//! first 8000 bytes is binary, and is left alone whatever the mode.
+use crate::config::Config;
+use crate::{utils, InkError};
+
+use std::path::Path;
+
+/// How much of a file is looked at to tell if it is binary, as git does
+const BINARY_CHECK_LEN: usize = 8000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextMode {
+    Off,
+    Auto,
+    Lf,
+    Crlf,
+}
+
+impl TextMode {
+    fn parse(value: &str) -> Result<TextMode, InkError> {
+        match value {
+            "off" | "binary" => Ok(TextMode::Off),
+            "auto" => Ok(TextMode::Auto),
+            "lf" => Ok(TextMode::Lf),
+            "crlf" => Ok(TextMode::Crlf),
+            _ => Err("text must be one of off, auto, lf or crlf".into()),
+        }
+    }
+
+    /// Whether content is treated as text
+    fn is_text(self, content: &[u8]) -> bool {
+        let binary = content[..content.len().min(BINARY_CHECK_LEN)].contains(&0);
+        self != TextMode::Off && !binary
+    }
+
+    /// Convert content from the working directory to the form it is stored in
+    pub fn clean(self, content: Vec<u8>) -> Vec<u8> {
+        if !self.is_text(&content) {
+            return content;
+        }
+
+        let mut cleaned = Vec::with_capacity(content.len());
+        for (index, &byte) in content.iter().enumerate() {
+            if byte == b'\r' && content.get(index + 1) == Some(&b'\n') {
+                continue;
+            }
+            cleaned.push(byte);
+        }
+        cleaned
+    }
+
+    /// Convert stored content to the form it is checked out in
+    pub fn smudge(self, content: Vec<u8>) -> Vec<u8> {
+        let crlf = match self {
+            TextMode::Crlf => true,
+            TextMode::Auto => cfg!(windows),
+            TextMode::Off | TextMode::Lf => false,
+        };
+
+        if !crlf || !self.is_text(&content) {
+            return content;
+        }
+
+        let mut smudged = Vec::with_capacity(content.len());
+        for (index, &byte) in content.iter().enumerate() {
+            if byte == b'\n' && (index == 0 || content[index - 1] != b'\r') {
+                smudged.push(b'\r');
+            }
+            smudged.push(byte);
+        }
+        smudged
+    }
+}
+
+/// The text mode chosen for each path in a repository
+#[derive(Debug, Clone, PartialEq)]
+pub struct Normalization {
+    default: TextMode,
+    rules: Vec<(String, TextMode)>,
+}
+
+impl Default for Normalization {
+    fn default() -> Self {
+        Normalization {
+            default: TextMode::Off,
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl Normalization {
+    pub fn load(ink_root: &Path) -> Result<Normalization, InkError> {
+        Normalization::from_config(&Config::load(ink_root)?)
+    }
+
+    pub fn from_config(config: &Config) -> Result<Normalization, InkError> {
+        let default = match config.get("core", "text") {
+            Some(value) => TextMode::parse(value)?,
+            None => TextMode::Off,
+        };
+
+        let mut rules = Vec::new();
+        for section in config.sections("path") {
+            if let (Some(pattern), Some(value)) = (section.label(), section.get("text")) {
+                rules.push((pattern.to_string(), TextMode::parse(value)?));
+            }
+        }
+
+        Ok(Normalization { default, rules })
+    }
+
+    /// The mode for a path relative to the project directory
+    pub fn mode(&self, path: &Path) -> TextMode {
+        self.rules
+            .iter()
+            .rev()
+            .find(|(pattern, _)| utils::glob_match(pattern, path))
+            .map_or(self.default, |(_, mode)| *mode)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clean_and_smudge() {
+        let crlf = b"one\r\ntwo\nthree\r\n".to_vec();
+
+        assert_eq!(TextMode::Off.clean(crlf.clone()), crlf);
+        assert_eq!(TextMode::Lf.clean(crlf.clone()), b"one\ntwo\nthree\n");
+        assert_eq!(TextMode::Lf.smudge(b"one\n".to_vec()), b"one\n");
+        assert_eq!(
+            TextMode::Crlf.smudge(b"one\r\ntwo\n".to_vec()),
+            b"one\r\ntwo\r\n"
+        );
+
+        let binary = b"\0\r\n".to_vec();
+        for mode in &[TextMode::Auto, TextMode::Lf, TextMode::Crlf] {
+            assert_eq!(mode.clean(binary.clone()), binary);
+            assert_eq!(mode.smudge(b"\0\n".to_vec()), b"\0\n");
+        }
+    }
+
+    #[test]
+    fn mode_per_path() {
+        let config =
+            Config::parse("[core]\ntext = auto\n[path \"*.bat\"]\ntext = crlf\n[path \"assets/**\"]\ntext = off\n")
+                .unwrap();
+        let normalization = Normalization::from_config(&config).unwrap();
+
+        assert_eq!(normalization.mode(Path::new("src/main.rs")), TextMode::Auto);
+        assert_eq!(
+            normalization.mode(Path::new("scripts/run.bat")),
+            TextMode::Crlf
+        );
+        assert_eq!(
+            normalization.mode(Path::new("assets/a/run.bat")),
+            TextMode::Off
+        );
+
+        assert!(Normalization::from_config(&Config::parse("[core]\ntext = yes").unwrap()).is_err());
+    }
+}
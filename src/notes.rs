@@ -0,0 +1,57 @@
+//! Notes attached to commits after the fact.
+//! Stored outside of commit objects, so adding one never changes a commit hash.
+use crate::commit::Commit;
+use crate::{InkError, NOTES_DIR};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+fn note_path(ink_root: &Path, commit: &Commit) -> PathBuf {
+    ink_root.join(NOTES_DIR).join(hex::encode(commit.hash()))
+}
+
+/// Add a note to a commit, after any notes it already has
+pub fn add(ink_root: &Path, commit: &Commit, note: &str) -> Result<(), InkError> {
+    let mut notes = show(ink_root, commit)?;
+    notes.push(note.to_string());
+
+    fs::create_dir_all(ink_root.join(NOTES_DIR))?;
+    fs::write(note_path(ink_root, commit), bincode::serialize(&notes)?)?;
+
+    Ok(())
+}
+
+/// Get all the notes on a commit, oldest first
+pub fn show(ink_root: &Path, commit: &Commit) -> Result<Vec<String>, InkError> {
+    let path = note_path(ink_root, commit);
+
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+
+    Ok(bincode::deserialize(&fs::read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cursor;
+
+    #[test]
+    fn add_and_show_notes() {
+        let tmpdir = crate::testkit::temp_dir();
+        let ink_dir = tmpdir.path().join(".ink");
+        crate::init(tmpdir.path()).unwrap();
+
+        let commit = cursor::get(&ink_dir).unwrap();
+        assert!(show(&ink_dir, &commit).unwrap().is_empty());
+
+        add(&ink_dir, &commit, "looks good").unwrap();
+        add(&ink_dir, &commit, "benchmarks: 12ms\nmemory: 3MB").unwrap();
+
+        assert_eq!(
+            show(&ink_dir, &commit).unwrap(),
+            vec!["looks good", "benchmarks: 12ms\nmemory: 3MB"]
+        );
+        assert_eq!(Commit::from(&commit.hash(), &ink_dir).unwrap(), commit);
+    }
+}
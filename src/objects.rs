@@ -0,0 +1,387 @@
+//! Inspecting the content objects in the object store
+use crate::commit::Commit;
+use crate::history::History;
+use crate::identity::{self, IdHasher};
+use crate::index::ReverseIndex;
+use crate::large_files;
+use crate::storage::{ObjectWriter, StoragePolicy};
+use crate::{InkError, DATA_EXT, POINTERS_DIR, RAW_EXT};
+
+use libflate::deflate::Decoder;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub use crate::index::Reference;
+
+/// What is known about one content object
+#[derive(Debug)]
+pub struct ObjectInfo {
+    pub hash: [u8; 32],
+    /// Bytes taken up on disk, compressed
+    pub stored_size: u64,
+    /// Bytes of content once decompressed
+    pub size: u64,
+    /// Whether the content is kept in the large file store
+    pub large: bool,
+    pub references: Vec<Reference>,
+}
+
+impl ObjectInfo {
+    /// Whether no commit uses the object
+    pub fn is_orphan(&self) -> bool {
+        self.references.is_empty()
+    }
+
+    /// Bytes saved by storing the object once for all of its references
+    pub fn dedup_savings(&self) -> u64 {
+        self.stored_size * (self.references.len().max(1) as u64 - 1)
+    }
+}
+
+/// Map every content hash used by a stored commit to the commits and paths using it
+pub fn content_references(ink_root: &Path) -> Result<BTreeMap<[u8; 32], Vec<Reference>>, InkError> {
+    Ok(ReverseIndex::get(ink_root)?.into_map())
+}
+
+/// The commits and paths using a content object
+pub fn references_to(ink_root: &Path, content_hash: &[u8; 32]) -> Result<Vec<Reference>, InkError> {
+    Ok(ReverseIndex::get(ink_root)?
+        .references(content_hash)
+        .to_vec())
+}
+
+/// The earliest commit using a content object, and the path it used it for.
+/// A commit whose ancestors already used the content didn't introduce it.
+pub fn introduced_by(
+    ink_root: &Path,
+    content_hash: &[u8; 32],
+) -> Result<Option<(Commit, Reference)>, InkError> {
+    let references = references_to(ink_root, content_hash)?;
+    let users: HashSet<[u8; 32]> = references.iter().map(|r| r.commit).collect();
+
+    let mut first: Option<(Commit, Reference)> = None;
+    for reference in references {
+        let commit = Commit::from(&reference.commit, ink_root)?;
+        if used_by_ancestor(ink_root, &reference.commit, &users)? {
+            continue;
+        }
+
+        if first
+            .as_ref()
+            .is_none_or(|(earliest, _)| commit.time() < earliest.time())
+        {
+            first = Some((commit, reference));
+        }
+    }
+
+    Ok(first)
+}
+
+fn used_by_ancestor(
+    ink_root: &Path,
+    hash: &[u8; 32],
+    users: &HashSet<[u8; 32]>,
+) -> Result<bool, InkError> {
+    for ancestor in History::new(ink_root, Commit::from(hash, ink_root)?).skip(1) {
+        if users.contains(&ancestor?.hash()) {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+/// The hashes of every content object in the data directory and the large
+/// file store
+fn object_hashes(ink_root: &Path) -> Result<BTreeSet<[u8; 32]>, InkError> {
+    let mut hashes = BTreeSet::new();
+    let raw_suffix = format!(".{}", RAW_EXT);
+    for dir in &[DATA_EXT, POINTERS_DIR] {
+        let dir = ink_root.join(dir);
+        if !dir.is_dir() {
+            continue;
+        }
+
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            // objects still being written
+            if name.starts_with('.') {
+                continue;
+            }
+            let name = name.strip_suffix(&raw_suffix).unwrap_or(&name);
+            let hash: [u8; 32] = hex::decode(name.as_bytes())
+                .map_err(|_| "Content object has an invalid name")?
+                .try_into()
+                .map_err(|_| "Content object has an invalid name")?;
+            hashes.insert(hash);
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Where a content object is stored, whether it is compressed and whether it
+/// is in the large file store
+pub(crate) fn object_location(
+    ink_root: &Path,
+    hash: &[u8; 32],
+) -> Result<(PathBuf, bool, bool), InkError> {
+    let data_path = ink_root.join(DATA_EXT).join(hex::encode(hash));
+    let raw_path = data_path.with_extension(RAW_EXT);
+
+    if raw_path.is_file() {
+        Ok((raw_path, false, false))
+    } else if data_path.is_file() {
+        Ok((data_path, true, false))
+    } else {
+        Ok((large_files::object_path(ink_root, hash)?, true, true))
+    }
+}
+
+/// Read the content of an object, decompressing it
+fn object_reader(path: &Path, compressed: bool) -> Result<Box<dyn Read>, InkError> {
+    let file = BufReader::new(File::open(path)?);
+    if compressed {
+        Ok(Box::new(Decoder::new(file)))
+    } else {
+        Ok(Box::new(file))
+    }
+}
+
+/// Find the content object a hash prefix refers to
+pub fn object_hash_from_prefix(ink_root: &Path, prefix: &[u8]) -> Result<[u8; 32], InkError> {
+    if prefix.len() > 32 {
+        return Err("invalid content hash prefix: too long".into());
+    }
+
+    let hashes = object_hashes(ink_root)?;
+    let mut candidates = hashes.iter().filter(|hash| hash.starts_with(prefix));
+
+    match (candidates.next(), candidates.next()) {
+        (Some(hash), None) => Ok(*hash),
+        (None, _) => Err("No content objects match the given prefix".into()),
+        (Some(_), Some(_)) => Err("Too many possible content objects with the given prefix".into()),
+    }
+}
+
+/// The content of a stored object, decompressed
+pub fn read_object(ink_root: &Path, hash: &[u8; 32]) -> Result<Vec<u8>, InkError> {
+    let (path, compressed, _) = object_location(ink_root, hash)?;
+    let mut content = Vec::new();
+    object_reader(&path, compressed)?.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// The hash content would be stored under, storing it too if `write` is
+/// set. The content is taken as is, without the filters of any path, and
+/// `path` only decides whether it is compressed. Content stored this way is
+/// never put in the large file store.
+pub fn hash_object(
+    ink_root: &Path,
+    content: &[u8],
+    path: &Path,
+    write: bool,
+) -> Result<[u8; 32], InkError> {
+    if write {
+        return Ok(store_content(ink_root, content, path)?.0);
+    }
+
+    Ok(identity::content_id(content))
+}
+
+/// Store content as `hash_object` does, returning its hash and the bytes it
+/// took up, or `None` if it was already stored
+pub(crate) fn store_content(
+    ink_root: &Path,
+    content: &[u8],
+    path: &Path,
+) -> Result<([u8; 32], Option<u64>), InkError> {
+    let hash = identity::content_id(content);
+    if object_location(ink_root, &hash).is_ok() {
+        return Ok((hash, None));
+    }
+
+    let policy = StoragePolicy::load(ink_root)?;
+    let mut writer = ObjectWriter::new(ink_root, &policy)?;
+    writer.write_all(content)?;
+    writer.finish(path, Some(&hash))
+}
+
+/// Describe every content object in the data directory and the large file store
+pub fn inspect(ink_root: &Path) -> Result<Vec<ObjectInfo>, InkError> {
+    let mut references = content_references(ink_root)?;
+    let mut objects = Vec::new();
+
+    for hash in object_hashes(ink_root)? {
+        let (path, compressed, large) = object_location(ink_root, &hash)?;
+        let stored_size = fs::metadata(&path)?.len();
+        let size = if compressed {
+            io::copy(&mut object_reader(&path, compressed)?, &mut io::sink())?
+        } else {
+            stored_size
+        };
+
+        objects.push(ObjectInfo {
+            hash,
+            stored_size,
+            size,
+            large,
+            references: references.remove(&hash).unwrap_or_default(),
+        });
+    }
+
+    Ok(objects)
+}
+
+/// Check every content object against the hash it is named by, returning
+/// the hashes of the ones whose content no longer matches
+pub fn fsck(ink_root: &Path) -> Result<Vec<[u8; 32]>, InkError> {
+    let mut corrupt = Vec::new();
+    for hash in object_hashes(ink_root)? {
+        let (path, compressed, _) = object_location(ink_root, &hash)?;
+
+        let mut hasher = IdHasher::new();
+        // content that can't even be decompressed is corrupt too
+        let matches = match io::copy(&mut object_reader(&path, compressed)?, &mut hasher) {
+            Ok(_) => hasher.finish() == hash,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                false
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        if !matches {
+            corrupt.push(hash);
+        }
+    }
+
+    Ok(corrupt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repository;
+    use std::path::PathBuf;
+
+    #[test]
+    fn inspect_objects() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir_path.join("a"), b"shared content").unwrap();
+        fs::write(tmpdir_path.join("b"), b"shared content").unwrap();
+        let first = crate::testkit::created(repo.commit("first", Default::default())).commit;
+        fs::write(tmpdir_path.join("b"), b"other content").unwrap();
+        let second = crate::testkit::created(repo.commit("second", Default::default())).commit;
+
+        let objects = inspect(&ink_dir).unwrap();
+        assert_eq!(objects.len(), 2);
+
+        let shared = first.files()[0].content_hash();
+        let info = objects.iter().find(|o| o.hash == shared).unwrap();
+        assert_eq!(info.size, 14);
+        assert_eq!(info.references.len(), 3);
+        assert!(!info.is_orphan());
+        assert_eq!(info.dedup_savings(), info.stored_size * 2);
+
+        let refs = references_to(&ink_dir, &shared).unwrap();
+        assert!(refs.contains(&Reference {
+            commit: first.hash(),
+            path: PathBuf::from("b"),
+        }));
+        let (introduced, _) = introduced_by(&ink_dir, &shared).unwrap().unwrap();
+        assert_eq!(introduced.hash(), first.hash());
+
+        // content only the removed commit used
+        fs::remove_file(
+            ink_dir
+                .join(crate::COMMIT_EXT)
+                .join(hex::encode(second.hash())),
+        )
+        .unwrap();
+        ReverseIndex::rebuild(&ink_dir)
+            .unwrap()
+            .write(&ink_dir)
+            .unwrap();
+        let objects = inspect(&ink_dir).unwrap();
+        let orphans: Vec<_> = objects.iter().filter(|o| o.is_orphan()).collect();
+        assert_eq!(orphans.len(), 1);
+        assert_ne!(orphans[0].hash, shared);
+        assert_eq!(references_to(&ink_dir, &shared).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn fsck_finds_changed_objects() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "original")
+            .file("b", "untouched")
+            .commit("first");
+        let ink_dir = repo.ink_root();
+        let head = repo.head();
+        let file = &head.files()[0];
+        let path = ink_dir
+            .join(DATA_EXT)
+            .join(hex::encode(file.content_hash()));
+
+        assert!(fs::metadata(&path).unwrap().permissions().readonly());
+        assert!(fsck(ink_dir).unwrap().is_empty());
+
+        let mut permissions = fs::metadata(&path).unwrap().permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(&path, permissions).unwrap();
+        let mut encoder = libflate::deflate::Encoder::new(Vec::new());
+        io::Write::write_all(&mut encoder, b"tampered").unwrap();
+        fs::write(&path, encoder.finish().into_result().unwrap()).unwrap();
+
+        assert_eq!(fsck(ink_dir).unwrap(), vec![file.content_hash()]);
+        assert_eq!(file.read_content(ink_dir).unwrap(), b"tampered");
+
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[storage]\nverify = true\n",
+        )
+        .unwrap();
+        assert!(file.read_content(ink_dir).is_err());
+        assert_eq!(head.files()[1].read_content(ink_dir).unwrap(), b"untouched");
+    }
+
+    #[test]
+    fn hash_and_read_objects() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "committed")
+            .commit("first");
+        let ink_dir = repo.ink_root();
+        let committed = repo.head().files()[0].content_hash();
+
+        // the same content hashes the same as when it was committed
+        let hash = hash_object(ink_dir, b"committed", Path::new("a"), false).unwrap();
+        assert_eq!(hash, committed);
+        assert_eq!(read_object(ink_dir, &hash).unwrap(), b"committed");
+        assert_eq!(object_hash_from_prefix(ink_dir, &hash[..2]).unwrap(), hash);
+
+        let hash = hash_object(ink_dir, b"new", Path::new("b"), false).unwrap();
+        assert!(read_object(ink_dir, &hash).is_err());
+        assert!(object_hash_from_prefix(ink_dir, &hash).is_err());
+
+        assert_eq!(
+            hash_object(ink_dir, b"new", Path::new("b"), true).unwrap(),
+            hash
+        );
+        assert_eq!(read_object(ink_dir, &hash).unwrap(), b"new");
+        assert!(fsck(ink_dir).unwrap().is_empty());
+    }
+}
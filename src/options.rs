@@ -0,0 +1,179 @@
+//! Options for repository operations.
+//!
+//! Each is built from its `Default` with builder methods, and new options can
+//! be added without changing the signatures of the operations taking them.
+use crate::merge::Strategy;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Options for `init`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct InitOptions {
+    /// Make a bare repository, with no working directory, in the directory
+    /// given to `init_with` itself
+    pub bare: bool,
+    /// Name of the metadata directory, rather than `DEFAULT_DIR_NAME`
+    pub dir_name: Option<String>,
+}
+
+impl InitOptions {
+    pub fn new() -> InitOptions {
+        InitOptions::default()
+    }
+
+    pub fn bare(mut self, bare: bool) -> InitOptions {
+        self.bare = bare;
+        self
+    }
+
+    pub fn dir_name(mut self, dir_name: &str) -> InitOptions {
+        self.dir_name = Some(dir_name.to_string());
+        self
+    }
+}
+
+/// Options for opening a repository with `Repository::open_with`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct OpenOptions {
+    /// Name of the metadata directory to look for, rather than
+    /// `DEFAULT_DIR_NAME`
+    pub dir_name: Option<String>,
+    /// The working directory, rather than the directory containing the ink
+    /// directory
+    pub work_tree: Option<PathBuf>,
+}
+
+impl OpenOptions {
+    pub fn new() -> OpenOptions {
+        OpenOptions::default()
+    }
+
+    pub fn dir_name(mut self, dir_name: &str) -> OpenOptions {
+        self.dir_name = Some(dir_name.to_string());
+        self
+    }
+
+    pub fn work_tree(mut self, work_tree: &Path) -> OpenOptions {
+        self.work_tree = Some(work_tree.to_path_buf());
+        self
+    }
+}
+
+/// Options for `commit`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct CommitOptions {
+    /// Fields stored with the commit, searchable with `log --meta`
+    pub metadata: BTreeMap<String, String>,
+    /// Commit even if nothing changed since the current commit
+    pub allow_empty: bool,
+    /// When the commit was made, rather than now or `SOURCE_DATE_EPOCH`
+    pub time: Option<SystemTime>,
+    /// The committer's offset from UTC in seconds, rather than the local one
+    pub offset: Option<i32>,
+    /// Who wrote the change, as `Name <email>`, when it isn't the committer
+    pub author: Option<String>,
+}
+
+impl CommitOptions {
+    pub fn new() -> CommitOptions {
+        CommitOptions::default()
+    }
+
+    pub fn metadata(mut self, metadata: BTreeMap<String, String>) -> CommitOptions {
+        self.metadata = metadata;
+        self
+    }
+
+    /// Add one metadata field
+    pub fn field(mut self, key: &str, value: &str) -> CommitOptions {
+        self.metadata.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    pub fn allow_empty(mut self, allow_empty: bool) -> CommitOptions {
+        self.allow_empty = allow_empty;
+        self
+    }
+
+    pub fn time(mut self, time: SystemTime) -> CommitOptions {
+        self.time = Some(time);
+        self
+    }
+
+    pub fn offset(mut self, offset: i32) -> CommitOptions {
+        self.offset = Some(offset);
+        self
+    }
+
+    pub fn author(mut self, author: &str) -> CommitOptions {
+        self.author = Some(author.to_string());
+        self
+    }
+}
+
+/// Options for `go`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct CheckoutOptions {
+    /// Check out even if the working directory has uncommitted changes,
+    /// throwing them away
+    pub force: bool,
+    /// Only go to a commit that has the current one in its history
+    pub ff_only: bool,
+    /// Carry uncommitted changes over to the commit checked out, merging
+    /// them with its changes. Nothing is checked out if they conflict.
+    pub carry: bool,
+}
+
+impl CheckoutOptions {
+    pub fn new() -> CheckoutOptions {
+        CheckoutOptions::default()
+    }
+
+    pub fn force(mut self, force: bool) -> CheckoutOptions {
+        self.force = force;
+        self
+    }
+
+    pub fn ff_only(mut self, ff_only: bool) -> CheckoutOptions {
+        self.ff_only = ff_only;
+        self
+    }
+
+    pub fn carry(mut self, carry: bool) -> CheckoutOptions {
+        self.carry = carry;
+        self
+    }
+}
+
+/// Options for `merge`
+#[derive(Debug, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub struct MergeOptions {
+    /// How changes both sides made to the same lines are merged, for paths
+    /// without a `merge` strategy in the config
+    pub strategy: Strategy,
+    /// Options for the merge commit
+    pub commit: CommitOptions,
+}
+
+impl MergeOptions {
+    pub fn new() -> MergeOptions {
+        MergeOptions::default()
+    }
+
+    pub fn strategy(mut self, strategy: Strategy) -> MergeOptions {
+        self.strategy = strategy;
+        self
+    }
+
+    pub fn commit(mut self, commit: CommitOptions) -> MergeOptions {
+        self.commit = commit;
+        self
+    }
+}
@@ -0,0 +1,114 @@
+//! Where the command line writes what it has to say.
+//!
+//! The library never prints. The CLI makes one `Output` and writes everything
+//! through it, so that `--quiet` can leave out messages that aren't the
+//! result of the command, and so output can be captured, e.g. in tests.
+use std::fmt::Display;
+use std::io::{self, Stderr, Stdout, Write};
+
+pub struct Output<O: Write = Stdout, E: Write = Stderr> {
+    out: O,
+    err: E,
+    quiet: bool,
+}
+
+impl Output {
+    /// Output to stdout and stderr
+    pub fn stdio() -> Output {
+        Output::new(io::stdout(), io::stderr())
+    }
+}
+
+impl<O: Write, E: Write> Output<O, E> {
+    pub fn new(out: O, err: E) -> Output<O, E> {
+        Output {
+            out,
+            err,
+            quiet: false,
+        }
+    }
+
+    /// Leave out informational messages and warnings
+    pub fn quiet(mut self, quiet: bool) -> Output<O, E> {
+        self.quiet = quiet;
+        self
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet
+    }
+
+    /// Write the result of a command, like a status line or a diff, as is
+    pub fn print<T: Display>(&mut self, text: T) -> io::Result<()> {
+        write!(self.out, "{}", text)
+    }
+
+    /// Write the result of a command that isn't text, like stored content
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.out.write_all(bytes)
+    }
+
+    /// Write a line of the result of a command
+    pub fn line<T: Display>(&mut self, text: T) -> io::Result<()> {
+        writeln!(self.out, "{}", text)
+    }
+
+    /// Write a message about what a command did, unless quiet
+    pub fn info<T: Display>(&mut self, text: T) -> io::Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+
+        writeln!(self.out, "{}", text)
+    }
+
+    /// Write a warning to stderr, unless quiet
+    pub fn warn<T: Display>(&mut self, text: T) -> io::Result<()> {
+        if self.quiet {
+            return Ok(());
+        }
+
+        writeln!(self.err, "warning: {}", text)
+    }
+
+    /// Write an error to stderr, even when quiet
+    pub fn error<T: Display>(&mut self, text: T) -> io::Result<()> {
+        writeln!(self.err, "error: {}", text)
+    }
+
+    /// The writers for the result and for errors
+    pub fn into_inner(self) -> (O, E) {
+        (self.out, self.err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_all<O: Write, E: Write>(output: &mut Output<O, E>) {
+        output.line("A example").unwrap();
+        output.print("--- diff\n").unwrap();
+        output.info("Nothing to commit").unwrap();
+        output.warn("example changed too much").unwrap();
+        output.error("no root").unwrap();
+    }
+
+    #[test]
+    fn quiet_output() {
+        let mut output = Output::new(Vec::new(), Vec::new());
+        write_all(&mut output);
+        let (out, err) = output.into_inner();
+        assert_eq!(out, b"A example\n--- diff\nNothing to commit\n");
+        assert_eq!(
+            err,
+            b"warning: example changed too much\nerror: no root\n".as_slice()
+        );
+
+        let mut output = Output::new(Vec::new(), Vec::new()).quiet(true);
+        write_all(&mut output);
+        let (out, err) = output.into_inner();
+        assert_eq!(out, b"A example\n--- diff\n");
+        assert_eq!(err, b"error: no root\n");
+    }
+}
@@ -0,0 +1,228 @@
+//! Checking the paths of files in the project, and writing unusual ones
+//! safely.
+//!
+//! A path is stored relative to the project directory, so it can't be
+//! absolute or climb out with `..`, and it can't be in the ink directory,
+//! so checking a commit out never writes over the repository. Its
+//! components can't hold NUL, which no filesystem takes, or be longer than
+//! 255 bytes, which few do. Anything
+//! else is allowed, but a name with a newline would break every format with
+//! a path per line, and one that isn't UTF-8 can't be stored as a string.
+//! Such names are escaped like C strings between double quotes, as git
+//! quotes them: `"a\nb"` or `"caf\xe9"`. Ordinary names are written as they
+//! are, so nothing stored before changes.
+use crate::{utils, InkError};
+
+use std::borrow::Cow;
+use std::path::{Component, Path, PathBuf};
+
+/// Longest name of a file or directory, in bytes
+pub const MAX_COMPONENT_LEN: usize = 255;
+
+/// Check that a path can be stored and checked out, in a repository whose
+/// ink directory is named `dir_name`
+pub fn validate(path: &Path, dir_name: &str) -> Result<(), InkError> {
+    if path.as_os_str().is_empty() {
+        return Err("Path is empty".into());
+    }
+
+    // the repository's own files are never project files
+    let first = path.components().find(|c| *c != Component::CurDir);
+    if first == Some(Component::Normal(dir_name.as_ref())) {
+        return Err("Path is in the ink directory".into());
+    }
+
+    for component in path.components() {
+        let name = match component {
+            Component::Normal(name) => name,
+            Component::CurDir => continue,
+            Component::ParentDir => return Err("Path leaves the project directory".into()),
+            Component::RootDir | Component::Prefix(_) => {
+                return Err("Path is absolute, not relative to the project directory".into())
+            }
+        };
+        let bytes = utils::path_bytes(Path::new(name));
+        if bytes.contains(&0) {
+            return Err("Path has a NUL byte in it".into());
+        }
+        if bytes.len() > MAX_COMPONENT_LEN {
+            return Err("Path has a component longer than 255 bytes".into());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a name needs escaping to be written on a line of its own
+fn is_unusual(name: &str) -> bool {
+    name.starts_with('"') || name.chars().any(char::is_control)
+}
+
+/// The path as a single line: itself if it is ordinary, otherwise escaped
+/// between double quotes
+pub fn escape(path: &Path) -> Cow<'_, str> {
+    if let Some(name) = path.to_str() {
+        if !is_unusual(name) {
+            return Cow::Borrowed(name);
+        }
+    }
+
+    let mut escaped = String::from("\"");
+    // invalid UTF-8 is escaped byte by byte, the rest char by char
+    for chunk in utils::path_bytes(path).utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '"' => escaped.push_str("\\\""),
+                '\\' => escaped.push_str("\\\\"),
+                '\n' => escaped.push_str("\\n"),
+                '\t' => escaped.push_str("\\t"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_control() => {
+                    for byte in c.to_string().bytes() {
+                        escaped.push_str(&format!("\\x{:02x}", byte));
+                    }
+                }
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped.push('"');
+
+    Cow::Owned(escaped)
+}
+
+/// Read a path written by `escape`
+pub fn unescape(name: &str) -> Result<PathBuf, InkError> {
+    let quoted = match name.strip_prefix('"').and_then(|n| n.strip_suffix('"')) {
+        Some(quoted) => quoted,
+        _ => return Ok(PathBuf::from(name)),
+    };
+
+    let mut bytes = Vec::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('"') => bytes.push(b'"'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('n') => bytes.push(b'\n'),
+            Some('t') => bytes.push(b'\t'),
+            Some('r') => bytes.push(b'\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| "Escaped path has a bad \\x escape")?;
+                bytes.push(byte);
+            }
+            _ => return Err("Escaped path has an unknown escape".into()),
+        }
+    }
+
+    path_from_bytes(bytes)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, InkError> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Ok(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Result<PathBuf, InkError> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| "Path isn't UTF-8, which this system can't name files with".into())
+}
+
+/// Serde for paths as strings escaped by `escape`, so any path can be
+/// stored, for use with `#[serde(with = "crate::paths::escaped")]`
+pub(crate) mod escaped {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::path::{Path, PathBuf};
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::escape(path))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        super::unescape(&name).map_err(|err| D::Error::custom(err.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+
+    #[test]
+    fn validates_paths() {
+        assert!(validate(Path::new("src/main.rs"), ".ink").is_ok());
+        assert!(validate(Path::new("odd\nname"), ".ink").is_ok());
+        assert!(validate(Path::new(""), ".ink").is_err());
+        assert!(validate(Path::new("../outside"), ".ink").is_err());
+        assert!(validate(Path::new("/etc/passwd"), ".ink").is_err());
+        assert!(validate(Path::new(".ink/config"), ".ink").is_err());
+        assert!(validate(Path::new("./.ink"), ".ink").is_err());
+        assert!(validate(Path::new("sub/.ink/config"), ".ink").is_ok());
+        assert!(validate(Path::new(".meta/config"), ".meta").is_err());
+        assert!(validate(Path::new(".ink/config"), ".meta").is_ok());
+        assert!(validate(&Path::new("dir").join("a".repeat(256)), ".ink").is_err());
+        assert!(validate(Path::new(&"a".repeat(255)), ".ink").is_ok());
+    }
+
+    #[test]
+    fn escapes_unusual_names() {
+        assert_eq!(escape(Path::new("plain name")), "plain name");
+        assert_eq!(escape(Path::new("a\\b")), "a\\b");
+        assert_eq!(escape(Path::new("a\nb\"c\\")), "\"a\\nb\\\"c\\\\\"");
+        assert_eq!(escape(Path::new("\"quoted\"")), "\"\\\"quoted\\\"\"");
+
+        for name in &[
+            "plain name",
+            "a\nb\"c\\",
+            "\"quoted\"",
+            "tab\there",
+            "\x01",
+            "\u{85}",
+        ] {
+            let escaped = escape(Path::new(name));
+            assert!(!escaped.contains('\n'));
+            assert_eq!(unescape(&escaped).unwrap(), Path::new(name));
+        }
+        assert!(unescape("\"\\q\"").is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn escapes_names_that_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = Path::new(OsStr::from_bytes(b"caf\xe9"));
+        assert_eq!(escape(path), "\"caf\\xe9\"");
+        assert_eq!(unescape(&escape(path)).unwrap(), path);
+
+        // and are stored as they are
+        let repo = TestRepo::new()
+            .file(path, "a")
+            .file("new\nline", "b")
+            .commit("odd");
+        let stored: Vec<_> = repo
+            .head()
+            .files()
+            .iter()
+            .map(|f| f.path().to_path_buf())
+            .collect();
+        assert_eq!(stored, [path, Path::new("new\nline")]);
+    }
+}
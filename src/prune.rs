@@ -0,0 +1,173 @@
+//! Removing commits nothing leads to any more.
+//!
+//! Every commit in the commit graph is kept: ink has no branches, so a
+//! line of work left behind by going back to an older commit is still
+//! wanted, and is found by the graph's heads. A commit is only unreachable
+//! once it is stored but no longer in the graph, like one that was
+//! rewritten, and the cursor, a tag or a safety snapshot isn't on it or on
+//! a descendant of it. Pruning removes unreachable commits made before a
+//! cutoff, so recently made ones survive a grace period in case they are
+//! still wanted. Their content objects are left for `ink debug objects` to
+//! report as orphans.
+use crate::commit::{self, Commit};
+use crate::graph::CommitGraph;
+use crate::index::ReverseIndex;
+use crate::{cursor, safety, tags, InkError, COMMIT_EXT, NOTES_DIR};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Commits the graph's heads, the cursor, a tag or a safety snapshot can
+/// reach
+fn roots(ink_root: &Path, graph: &CommitGraph) -> Result<Vec<[u8; 32]>, InkError> {
+    let mut roots = graph.heads();
+    roots.push(cursor::get(ink_root)?.hash());
+    roots.extend(tags::list(ink_root)?.into_iter().map(|(_, hash)| hash));
+    roots.extend(
+        safety::list(ink_root)?
+            .iter()
+            .map(|snapshot| snapshot.cursor),
+    );
+    Ok(roots)
+}
+
+/// The commits that pruning with the given cutoff would remove
+pub fn prunable(ink_root: &Path, expire_older_than: SystemTime) -> Result<Vec<Commit>, InkError> {
+    let cutoff = expire_older_than
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "Time went backwards")?
+        .as_secs();
+
+    // every stored commit, including ones the graph has left out
+    let graph = CommitGraph::get(ink_root)?;
+    let mut roots = roots(ink_root, &graph)?;
+    let commits = commit::stored_hashes(ink_root)?
+        .iter()
+        .map(|hash| Commit::from(hash, ink_root))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (all, _) = CommitGraph::from_commits(ink_root, &commits)?;
+    let mut stored: HashMap<[u8; 32], Commit> = commits
+        .into_iter()
+        .map(|commit| (commit.hash(), commit))
+        .collect();
+
+    // recent commits keep their ancestors, however old
+    let (recent, old): (Vec<_>, Vec<_>) = all
+        .unreachable_from(&roots)
+        .into_iter()
+        .partition(|hash| stored[hash].time() >= cutoff);
+    roots.extend(recent);
+    let prunable = all.unreachable_from(&roots);
+
+    Ok(old
+        .into_iter()
+        .filter(|hash| prunable.contains(hash))
+        .filter_map(|hash| stored.remove(&hash))
+        .collect())
+}
+
+/// Remove unreachable commits made before `expire_older_than`, along with
+/// their notes, and return them
+pub fn prune(ink_root: &Path, expire_older_than: SystemTime) -> Result<Vec<Commit>, InkError> {
+    let commits = prunable(ink_root, expire_older_than)?;
+    if commits.is_empty() {
+        return Ok(commits);
+    }
+
+    // the graph's heads are roots, so nothing pruned is in the graph
+    for commit in &commits {
+        let name = hex::encode(commit.hash());
+        fs::remove_file(ink_root.join(COMMIT_EXT).join(&name))?;
+
+        let note = ink_root.join(NOTES_DIR).join(&name);
+        if note.exists() {
+            fs::remove_file(note)?;
+        }
+    }
+
+    if ReverseIndex::load(ink_root)?.is_some() {
+        ReverseIndex::rebuild(ink_root)?.write(ink_root)?;
+    }
+
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+    use std::time::Duration;
+
+    #[test]
+    fn prune_unreachable_commits() {
+        let repo = TestRepo::new()
+            .file("a", "kept")
+            .commit("kept")
+            .file("a", "left behind")
+            .commit("left behind")
+            .file("a", "also left behind")
+            .commit("also left behind");
+        let ink_dir = repo.ink_root();
+        let tip = repo.head();
+        let left_behind = Commit::from(&tip.parents()[0], ink_dir).unwrap();
+        let kept = left_behind.parents()[0];
+        let go_to = Commit::from(&kept, ink_dir).unwrap();
+        repo.repo().go(go_to, Default::default()).unwrap();
+
+        // going back leaves newer commits in the graph, so none are pruned
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(60);
+        assert!(prune(ink_dir, later).unwrap().is_empty());
+        assert_eq!(CommitGraph::get(ink_dir).unwrap().commit_hashes().len(), 4);
+
+        // commits left out of the graph, as rewritten ones are, are pruned
+        // once nothing else leads to them and they are old enough
+        let mut graph = CommitGraph::get(ink_dir).unwrap();
+        graph.remove_commit(&tip.hash()).unwrap();
+        graph.remove_commit(&left_behind.hash()).unwrap();
+        graph.write().unwrap();
+        assert!(prune(ink_dir, now - Duration::from_secs(60))
+            .unwrap()
+            .is_empty());
+
+        // a tag keeps a commit and its ancestors
+        tags::create(ink_dir, "v1", &left_behind).unwrap();
+        assert_eq!(prunable(ink_dir, later).unwrap().len(), 1);
+        fs::remove_file(ink_dir.join(crate::TAGS_DIR).join("v1")).unwrap();
+
+        // and so does a safety snapshot
+        safety::record(ink_dir, "go --force", tip.hash(), vec![]).unwrap();
+        assert!(prunable(ink_dir, later).unwrap().is_empty());
+        fs::remove_dir_all(ink_dir.join(crate::SAFETY_DIR)).unwrap();
+
+        let pruned = prune(ink_dir, later).unwrap();
+        let mut messages: Vec<_> = pruned.iter().map(|c| c.message().to_string()).collect();
+        messages.sort();
+        assert_eq!(messages, ["also left behind", "left behind"]);
+        assert!(Commit::from(&left_behind.hash(), ink_dir).is_err());
+        assert_eq!(CommitGraph::get(ink_dir).unwrap().commit_hashes().len(), 2);
+        assert_eq!(repo.repo().log().unwrap().count(), 2);
+    }
+
+    #[test]
+    fn going_back_keeps_newer_commits() {
+        let repo = TestRepo::new()
+            .file("a", "one")
+            .commit("one")
+            .file("a", "two")
+            .commit("two")
+            .file("a", "three")
+            .commit("three");
+        let ink_dir = repo.ink_root();
+        let three = repo.head();
+        let one = repo.repo().log().unwrap().nth(2).unwrap().unwrap();
+        repo.repo().go(one, Default::default()).unwrap();
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(prune(ink_dir, later).unwrap().is_empty());
+        repo.repo().go(three, Default::default()).unwrap();
+        assert_eq!(repo.read("a"), b"three");
+    }
+}
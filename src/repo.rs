@@ -0,0 +1,159 @@
+//! Finding and creating repositories, and shortcuts for working on the one
+//! the current directory is in. Needs the `repo` feature, like everything
+//! but the `diff` module.
+use crate::commit::{Commit, CommitDiff};
+use crate::cursor;
+use crate::graph::CommitGraph;
+use crate::history::History;
+use crate::index::ReverseIndex;
+use crate::options::{CheckoutOptions, CommitOptions, InitOptions};
+use crate::repository::{CheckoutOutcome, CommitResult, Repository};
+use crate::version_info::VersionInfo;
+use crate::{time, InkError};
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub(crate) const DATA_EXT: &str = "data";
+pub(crate) const RAW_EXT: &str = "raw";
+pub(crate) const COMMIT_EXT: &str = "commit";
+pub(crate) const GRAPH_FILE: &str = "graph";
+pub(crate) const GRAPH_LOG_FILE: &str = "graph-log";
+pub(crate) const CURSOR_FILE: &str = "cursor";
+pub(crate) const NOTES_DIR: &str = "notes";
+pub(crate) const CONFIG_FILE: &str = "config";
+pub(crate) const POINTERS_DIR: &str = "pointers";
+pub(crate) const INDEX_FILE: &str = "index";
+pub(crate) const TAGS_DIR: &str = "tags";
+pub(crate) const CACHE_DIR: &str = "cache";
+pub(crate) const RERERE_DIR: &str = "rerere";
+pub(crate) const MERGE_STATE_FILE: &str = "merge-state";
+pub(crate) const SAFETY_DIR: &str = "safety";
+pub(crate) const METRICS_FILE: &str = "metrics";
+
+/// Name of the metadata directory in a project, unless another is given
+/// with `InitOptions::dir_name` or `OpenOptions::dir_name`
+pub const DEFAULT_DIR_NAME: &str = ".ink";
+
+/// Find the ink directory of the repository containing the current directory
+pub fn root_dir() -> Result<Option<PathBuf>, InkError> {
+    root_dir_from(&env::current_dir()?, DEFAULT_DIR_NAME)
+}
+
+/// Find the ink directory of a repository given either the ink directory
+/// itself or the project directory containing it, named `dir_name`
+pub(crate) fn given_root_dir(dir: &Path, dir_name: &str) -> Result<PathBuf, InkError> {
+    let dir = dir.canonicalize()?;
+    let ink_dir = dir.join(dir_name);
+    if ink_dir.is_dir() {
+        Ok(ink_dir)
+    } else if dir.join(COMMIT_EXT).is_dir() {
+        Ok(dir)
+    } else {
+        Err("Not an ink repository".into())
+    }
+}
+
+/// The working directory of a repository opened without one: the directory
+/// containing the ink directory
+pub(crate) fn default_work_tree(ink_root: &Path) -> Result<PathBuf, InkError> {
+    Ok(ink_root
+        .parent()
+        .ok_or("ink root dir is invalid.")?
+        .to_path_buf())
+}
+
+/// Find the ink directory, named `dir_name`, of the repository containing the
+/// given path
+pub(crate) fn root_dir_from(dir: &Path, dir_name: &str) -> Result<Option<PathBuf>, InkError> {
+    let dir = dir.canonicalize()?;
+
+    for path in dir.ancestors() {
+        let ink_dir = path.join(dir_name);
+        if ink_dir.exists() && ink_dir.is_dir() {
+            return Ok(Some(ink_dir));
+        }
+    }
+
+    Ok(None)
+}
+
+// functions called by cli
+/// Initialize a repository in the given directory, and return the path of its
+/// metadata directory, named by `dir_name`. Only the given directory is used,
+/// never the current one.
+pub fn init(in_dir: &Path) -> Result<PathBuf, InkError> {
+    init_with(in_dir, InitOptions::default())
+}
+
+/// Like `init`, with options. A bare repository holds the objects and graph
+/// directly in the given directory, has no working directory, and is e.g.
+/// for copying to. The returned path is the one to open the repository with.
+pub fn init_with(in_dir: &Path, options: InitOptions) -> Result<PathBuf, InkError> {
+    let ink_dir = match (options.bare, options.dir_name) {
+        (true, _) => in_dir.to_path_buf(),
+        (false, Some(dir_name)) => in_dir.join(dir_name),
+        (false, None) => in_dir.join(DEFAULT_DIR_NAME),
+    };
+
+    create_repository(&ink_dir, options.bare)?;
+    Ok(ink_dir)
+}
+
+fn create_repository(ink_dir: &Path, bare: bool) -> Result<(), InkError> {
+    if ink_dir.join(COMMIT_EXT).is_dir() {
+        return Err(InkError::AlreadyInitialized(ink_dir.to_path_buf()));
+    }
+    if ink_dir.exists() && (!ink_dir.is_dir() || fs::read_dir(ink_dir)?.next().is_some()) {
+        return Err("Can't initialize a repository over an existing file or directory".into());
+    }
+
+    fs::create_dir_all(ink_dir)?;
+    fs::create_dir(ink_dir.join(COMMIT_EXT))?;
+    fs::create_dir(ink_dir.join(DATA_EXT))?;
+    fs::create_dir(ink_dir.join(NOTES_DIR))?;
+    fs::create_dir(ink_dir.join(POINTERS_DIR))?;
+    fs::create_dir(ink_dir.join(TAGS_DIR))?;
+    if bare {
+        fs::write(ink_dir.join(CONFIG_FILE), "[core]\nbare = true\n")?;
+    }
+    let empty_commit = Commit::new::<PathBuf>(vec![], vec![], time::commit_time()?, "", ink_dir)?;
+    empty_commit.write(ink_dir)?;
+    cursor::init(ink_dir)?;
+    cursor::set(ink_dir, &empty_commit)?;
+    CommitGraph::init(ink_dir, &empty_commit)?;
+    ReverseIndex::default().write(ink_dir)?;
+
+    Ok(())
+}
+
+/// Commit the working directory with a message and metadata fields. If nothing
+/// changed since the current commit, no commit is made unless `allow_empty` is set.
+pub fn commit(message: &str, options: CommitOptions) -> Result<CommitResult, InkError> {
+    Repository::discover()?.commit(message, options)
+}
+
+/// The commit the working directory is based on
+pub fn current_commit() -> Result<Commit, InkError> {
+    Repository::discover()?.current_commit()
+}
+
+/// Identify the current commit, for stamping builds
+pub fn version_info() -> Result<VersionInfo, InkError> {
+    Repository::discover()?.version_info()
+}
+
+/// Find the changes in the working directory since the current commit
+pub fn status() -> Result<CommitDiff, InkError> {
+    Repository::discover()?.status()
+}
+
+/// Walk the history of the current commit, newest first
+pub fn log() -> Result<History, InkError> {
+    Repository::discover()?.log()
+}
+
+pub fn go(to: Commit, options: CheckoutOptions) -> Result<CheckoutOutcome, InkError> {
+    Repository::discover()?.go(to, options)
+}
@@ -0,0 +1,1560 @@
+//! Operations on an ink repository
+use crate::archive;
+use crate::checkout::Checkout;
+use crate::commit::{self, Commit, CommitDiff, Edit};
+use crate::config::Config;
+use crate::cursor;
+use crate::events::{EventSink, NoEvents};
+use crate::filedata::FileData;
+use crate::filter::ContentFilters;
+use crate::graph::render;
+use crate::graph::CommitGraph;
+use crate::history::History;
+use crate::index::ReverseIndex;
+use crate::large_files::LargeFiles;
+use crate::merge::{self, MergeState};
+use crate::metrics::{self, Record, Timings};
+use crate::objects;
+use crate::options::{CheckoutOptions, CommitOptions, MergeOptions, OpenOptions};
+use crate::rerere;
+use crate::safety::{self, Snapshot};
+use crate::snapshot::TreeSnapshot;
+use crate::storage::StoragePolicy;
+use crate::tags::{self, Description};
+use crate::version_info::VersionInfo;
+use crate::vfs::OsFs;
+use crate::{time, InkError, CURSOR_FILE, GRAPH_FILE, GRAPH_LOG_FILE};
+
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// The outcome of trying to create a commit
+#[derive(Debug)]
+// made once per commit, so boxing the outcome would buy nothing
+#[allow(clippy::large_enum_variant)]
+pub enum CommitResult {
+    Created(CommitOutcome),
+    /// The working directory has the same files as the current commit
+    NothingToCommit,
+}
+
+/// A commit that was made, and what making it stored
+#[derive(Debug)]
+pub struct CommitOutcome {
+    pub commit: Commit,
+    /// Files whose content was newly stored
+    pub files_written: usize,
+    /// Bytes the newly stored content takes up, after compression
+    pub bytes_stored: u64,
+    /// Files whose content was already stored, e.g. because it is unchanged
+    pub skipped: usize,
+    /// Files with the same content as another file in the commit, whose
+    /// content was only read and stored once
+    pub deduplicated: usize,
+}
+
+/// The outcome of merging commits into the current one
+#[derive(Debug)]
+pub enum MergeOutcome {
+    /// Every commit given is already in the current commit's history
+    UpToDate,
+    /// The current commit was in the history of the one commit given, which
+    /// was checked out
+    FastForward(Commit),
+    /// A merge commit was made and checked out
+    Merged(Commit),
+    /// Changes to these paths conflict. A single commit's merge is left in
+    /// progress with the conflicts in the working directory, to be resolved
+    /// and committed; otherwise nothing was merged.
+    Conflicted(Vec<PathBuf>),
+}
+
+/// What checking out a commit changed in the working directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CheckoutOutcome {
+    /// Files written, whether new or modified
+    pub files_changed: usize,
+    pub files_deleted: usize,
+    /// Directories made to hold new files
+    pub dirs_created: usize,
+}
+
+/// An ink repository, found by its `.ink` directory
+pub struct Repository {
+    ink_root: PathBuf,
+    // the directory committed and checked out
+    work_tree: PathBuf,
+    // what the metadata directory is called, never committed or checked out
+    dir_name: String,
+    // with no working directory
+    bare: bool,
+    events: Box<dyn EventSink>,
+    // how many files are read or written at once
+    jobs: usize,
+}
+
+impl Repository {
+    /// Open the repository with the given `.ink` directory
+    pub fn open(ink_root: &Path) -> Result<Repository, InkError> {
+        if !ink_root.is_dir() {
+            return Err("Ink Uninitialized".into());
+        }
+
+        let bare = Config::load(ink_root)?.get("core", "bare") == Some("true");
+        let dir_name = match ink_root.file_name() {
+            Some(name) if !bare => name.to_string_lossy().into_owned(),
+            _ => crate::DEFAULT_DIR_NAME.to_string(),
+        };
+        Ok(Repository {
+            ink_root: ink_root.to_path_buf(),
+            work_tree: crate::default_work_tree(ink_root)?,
+            dir_name,
+            bare,
+            events: Box::new(NoEvents),
+            jobs: 1,
+        })
+    }
+
+    /// Open the repository with the given ink directory, or in the given
+    /// project directory, with a metadata directory name and working
+    /// directory from the options
+    pub fn open_with(dir: &Path, options: OpenOptions) -> Result<Repository, InkError> {
+        let dir_name = options
+            .dir_name
+            .unwrap_or_else(|| crate::DEFAULT_DIR_NAME.to_string());
+        let repo = Repository::open(&crate::given_root_dir(dir, &dir_name)?)?;
+        repo.with_layout(dir_name, options.work_tree)
+    }
+
+    /// Open the repository containing the current directory
+    pub fn discover() -> Result<Repository, InkError> {
+        Repository::discover_from(&env::current_dir()?)
+    }
+
+    /// Open the repository containing the given path
+    pub fn discover_from(path: &Path) -> Result<Repository, InkError> {
+        Repository::discover_with(path, OpenOptions::default())
+    }
+
+    /// Open the repository containing the given path, looking for a metadata
+    /// directory named as in the options
+    pub fn discover_with(path: &Path, options: OpenOptions) -> Result<Repository, InkError> {
+        let dir_name = options
+            .dir_name
+            .unwrap_or_else(|| crate::DEFAULT_DIR_NAME.to_string());
+        let ink_root = crate::root_dir_from(path, &dir_name)?.ok_or("Ink Uninitialized")?;
+        Repository::open(&ink_root)?.with_layout(dir_name, options.work_tree)
+    }
+
+    pub(crate) fn with_layout(
+        mut self,
+        dir_name: String,
+        work_tree: Option<PathBuf>,
+    ) -> Result<Repository, InkError> {
+        self.dir_name = dir_name;
+        if let Some(work_tree) = work_tree {
+            self.work_tree = work_tree.canonicalize()?;
+        }
+        Ok(self)
+    }
+
+    /// Send events from operations on this repository to the given sink
+    pub fn with_events<E: EventSink + 'static>(mut self, events: E) -> Repository {
+        self.events = Box::new(events);
+        self
+    }
+
+    /// Store and check out up to `jobs` files at once
+    #[cfg(feature = "async")]
+    pub(crate) fn with_jobs(mut self, jobs: usize) -> Repository {
+        self.jobs = jobs.max(1);
+        self
+    }
+
+    #[cfg(feature = "async")]
+    pub(crate) fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    pub fn ink_root(&self) -> &Path {
+        &self.ink_root
+    }
+
+    /// The directory that is committed and checked out
+    pub fn work_tree(&self) -> &Path {
+        &self.work_tree
+    }
+
+    /// The name of the metadata directory, which is never committed
+    pub fn dir_name(&self) -> &str {
+        &self.dir_name
+    }
+
+    /// Whether the repository was made bare, and so has no
+    /// working directory to commit or check out
+    pub fn is_bare(&self) -> bool {
+        self.bare
+    }
+
+    /// Snapshot every file in the working directory that would be committed
+    pub(crate) fn snapshot_worktree(&self) -> Result<TreeSnapshot, InkError> {
+        TreeSnapshot::from_worktree(&self.ink_root, &self.work_tree, &self.dir_name)
+    }
+
+    fn check_worktree(&self) -> Result<(), InkError> {
+        if self.bare {
+            return Err("Bare repositories have no working directory".into());
+        }
+
+        Ok(())
+    }
+
+    /// Commit the working directory with a message. If nothing changed since
+    /// the current commit, no commit is made unless `allow_empty` is set.
+    /// What is left out is as `TreeSnapshot::from_worktree` describes,
+    /// including files named like a checkout's temporary files.
+    ///
+    /// A merge in progress is finished by the commit, which has the commit
+    /// being merged as a second parent and the merge's message if none is
+    /// given. Fails if conflict markers are left in its conflicted files,
+    /// and records how their conflicts were resolved.
+    pub fn commit(&self, message: &str, options: CommitOptions) -> Result<CommitResult, InkError> {
+        self.check_worktree()?;
+        let started = Instant::now();
+        let current_commit = cursor::get(&self.ink_root)?;
+        let merging = MergeState::load(&self.ink_root)?;
+        let mut parents = vec![current_commit.hash()];
+        let mut message = message;
+        if let Some(merging) = &merging {
+            for (path, _) in &merging.conflicts {
+                let content = match fs::read(self.work_tree.join(path)) {
+                    Ok(content) => content,
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                    Err(err) => return Err(err.into()),
+                };
+                if content
+                    .split(|&b| b == b'\n')
+                    .any(|line| line.starts_with(b"<<<<<<< "))
+                {
+                    self.events.conflict_detected(path);
+                    return Err("Conflict markers are left in the working directory, \
+                                resolve them before committing"
+                        .into());
+                }
+            }
+
+            parents.push(merging.head);
+            if message.is_empty() {
+                message = &merging.message;
+            }
+        }
+
+        let timestamp = options.time.map_or_else(time::commit_time, Ok)?;
+        let scanning = Instant::now();
+        let snapshot = self.snapshot_worktree()?;
+        let scanned = scanning.elapsed();
+        let commit = snapshot
+            .into_commit(parents, timestamp, message)?
+            .with_offset(options.offset.unwrap_or_else(time::commit_offset))
+            .with_metadata(self.attributed(options.metadata, options.author)?);
+
+        // a merge is a change even when it keeps the current files
+        if !options.allow_empty
+            && merging.is_none()
+            && commit.tree_hash() == current_commit.tree_hash()
+        {
+            return Ok(CommitResult::NothingToCommit);
+        }
+
+        let storing = Instant::now();
+        let stored = commit.write_with_jobs(&self.ink_root, &self.work_tree, self.jobs)?;
+        let store = storing.elapsed();
+
+        CommitGraph::append_commit(&self.ink_root, &commit)?;
+        cursor::set(&self.ink_root, &commit)?;
+
+        if let Some(merging) = merging {
+            let policy = StoragePolicy::load(&self.ink_root)?;
+            for (path, id) in &merging.conflicts {
+                if let Some(file) = commit.file(path) {
+                    rerere::record_resolution(
+                        &self.ink_root,
+                        id,
+                        &file.read_content_with(&self.ink_root, &policy)?,
+                    )?;
+                }
+            }
+            MergeState::clear(&self.ink_root)?;
+        }
+
+        if let Some(mut index) = ReverseIndex::load(&self.ink_root)? {
+            index.add_commit(&commit);
+            index.write(&self.ink_root)?;
+        }
+
+        self.events.commit_created(&commit);
+
+        let duration = started.elapsed();
+        self.log_metrics(
+            Record::new("commit", duration)
+                .files(stored.files_written)
+                .bytes(stored.bytes_stored)
+                .phase("scan", scanned)
+                .phase("hash", stored.hashing)
+                .phase("compress", stored.compressing)
+                .phase(
+                    "io",
+                    store.saturating_sub(stored.hashing + stored.compressing),
+                )
+                .phase("other", duration.saturating_sub(scanned + store)),
+        );
+
+        Ok(CommitResult::Created(CommitOutcome {
+            commit,
+            files_written: stored.files_written,
+            bytes_stored: stored.bytes_stored,
+            skipped: stored.skipped,
+            deduplicated: stored.deduplicated,
+        }))
+    }
+
+    /// Commit the files of a tar archive as a child of the current commit,
+    /// without extracting them. The working directory is left alone, so the
+    /// cursor only moves to the new commit in a bare repository; otherwise
+    /// `go` checks it out.
+    pub fn import_archive<R: Read>(
+        &self,
+        reader: R,
+        message: &str,
+        options: CommitOptions,
+    ) -> Result<CommitResult, InkError> {
+        let current_commit = cursor::get(&self.ink_root)?;
+        let timestamp = options.time.map_or_else(time::commit_time, Ok)?;
+        let (files, stored) = archive::store_files(reader, self)?;
+        let commit = Commit::from_files(files, vec![current_commit.hash()], timestamp, message)?
+            .with_offset(options.offset.unwrap_or_else(time::commit_offset))
+            .with_metadata(self.attributed(options.metadata, options.author)?);
+
+        if !options.allow_empty && commit.tree_hash() == current_commit.tree_hash() {
+            return Ok(CommitResult::NothingToCommit);
+        }
+
+        commit.write_object(&OsFs, &self.ink_root)?;
+
+        CommitGraph::append_commit(&self.ink_root, &commit)?;
+        if self.bare {
+            cursor::set(&self.ink_root, &commit)?;
+        }
+
+        if let Some(mut index) = ReverseIndex::load(&self.ink_root)? {
+            index.add_commit(&commit);
+            index.write(&self.ink_root)?;
+        }
+
+        if self.bare {
+            self.events.commit_created(&commit);
+        }
+
+        Ok(CommitResult::Created(CommitOutcome {
+            commit,
+            files_written: stored.files_written,
+            bytes_stored: stored.bytes_stored,
+            skipped: stored.skipped,
+            deduplicated: stored.deduplicated,
+        }))
+    }
+
+    /// Add who made a commit, and who wrote it, to its metadata. The
+    /// committer is `INK_COMMITTER`, or `name` and `email` from the `[user]`
+    /// config section, and the author is the committer unless given. Fields
+    /// already set are kept, and nothing is added if no one is known.
+    fn attributed(
+        &self,
+        mut metadata: BTreeMap<String, String>,
+        author: Option<String>,
+    ) -> Result<BTreeMap<String, String>, InkError> {
+        let config = Config::load(&self.ink_root)?;
+        let committer = match env::var("INK_COMMITTER") {
+            Ok(committer) if !committer.is_empty() => Some(committer),
+            _ => match (config.get("user", "name"), config.get("user", "email")) {
+                (Some(name), Some(email)) => Some(format!("{} <{}>", name, email)),
+                (Some(name), None) => Some(name.to_string()),
+                _ => None,
+            },
+        };
+
+        if let Some(author) = author.or_else(|| committer.clone()) {
+            metadata
+                .entry(commit::AUTHOR_KEY.to_string())
+                .or_insert(author);
+        }
+        if let Some(committer) = committer {
+            metadata
+                .entry(commit::COMMITTER_KEY.to_string())
+                .or_insert(committer);
+        }
+
+        Ok(metadata)
+    }
+
+    /// The commit the working directory is based on
+    pub fn current_commit(&self) -> Result<Commit, InkError> {
+        cursor::get(&self.ink_root)
+    }
+
+    /// A fingerprint of the repository's state: its graph, tags and cursor.
+    /// It changes whenever a commit is made or removed, a tag moves or the
+    /// cursor does, and only then, so it can be compared to tell whether
+    /// anything changed without walking the repository. The working
+    /// directory isn't part of it.
+    pub fn state_id(&self) -> Result<[u8; 32], InkError> {
+        let mut hasher = Sha256::new();
+
+        // length prefixed, so the parts can't run into each other
+        for file in &[GRAPH_FILE, GRAPH_LOG_FILE, CURSOR_FILE] {
+            let bytes = match fs::read(self.ink_root.join(file)) {
+                Ok(bytes) => bytes,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(err) => return Err(err.into()),
+            };
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        }
+
+        for (name, hash) in tags::list(&self.ink_root)? {
+            hasher.update((name.len() as u64).to_be_bytes());
+            hasher.update(name.as_bytes());
+            hasher.update(hash);
+        }
+
+        Ok(hasher.finalize().into())
+    }
+
+    /// Describe the current commit by the closest tag in its history
+    pub fn describe(&self) -> Result<Option<Description>, InkError> {
+        tags::describe(&self.ink_root, self.current_commit()?)
+    }
+
+    /// Identify the current commit and whether the working directory has
+    /// changed since, for stamping builds
+    pub fn version_info(&self) -> Result<VersionInfo, InkError> {
+        let current_commit = self.current_commit()?;
+        let dirty = !self.bare && !self.status()?.edits.is_empty();
+        let time = current_commit.time();
+        let hash = current_commit.hash();
+        let describe = tags::describe(&self.ink_root, current_commit)?;
+
+        Ok(VersionInfo::new(
+            hash,
+            dirty,
+            describe.map(|d| d.to_string()),
+            time,
+        ))
+    }
+
+    /// Find the changes in the working directory since the current commit
+    pub fn status(&self) -> Result<CommitDiff, InkError> {
+        self.check_worktree()?;
+        let current_commit = cursor::get(&self.ink_root)?;
+        Ok(self.snapshot_worktree()?.diff_from(&current_commit))
+    }
+
+    /// A commit in the current history making the same changes as the
+    /// given one does to its first parent, if any, so a change about to be
+    /// applied again can be skipped. Commits are compared by patch id, and
+    /// only when they change the same paths.
+    pub fn find_applied(&self, commit: &Commit) -> Result<Option<Commit>, InkError> {
+        let wanted = commit.changes(&self.ink_root)?;
+        let paths = |diff: &CommitDiff| {
+            let mut paths: Vec<_> = diff.edits.iter().map(|e| e.path().to_path_buf()).collect();
+            paths.sort();
+            paths
+        };
+        let (wanted_paths, wanted_id) = (paths(&wanted), wanted.patch_id(&self.ink_root)?);
+
+        for candidate in self.log()? {
+            let candidate = candidate?;
+            let diff = candidate.changes(&self.ink_root)?;
+            if paths(&diff) == wanted_paths && diff.patch_id(&self.ink_root)? == wanted_id {
+                return Ok(Some(candidate));
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// How many commits `a` is ahead of `b`, and how many it is behind:
+    /// those in the history of one but not the other
+    pub fn ahead_behind(&self, a: &Commit, b: &Commit) -> Result<(usize, usize), InkError> {
+        Ok(CommitGraph::get(&self.ink_root)?.ahead_behind(&a.hash(), &b.hash()))
+    }
+
+    /// Walk the history of the current commit, newest first
+    pub fn log(&self) -> Result<History, InkError> {
+        let current_commit = cursor::get(&self.ink_root)?;
+        Ok(History::new(&self.ink_root, current_commit))
+    }
+
+    /// Merge the given commits into the current one, as a single commit with
+    /// the current commit and each of them as parents, and check it out.
+    /// Commits already in the current history are left out, and a single
+    /// commit the current one is in the history of is fast-forwarded to.
+    /// Each commit is merged in turn from its merge base with those before
+    /// it. Conflicts resolved before are resolved the same way again, see
+    /// `rerere`. If other changes conflict that the strategy for their path
+    /// doesn't resolve, a single commit's merge is left in progress for
+    /// `commit` to finish or `abort_merge` to give up, and otherwise nothing
+    /// is merged. Fails if the working directory has uncommitted changes or
+    /// a merge is in progress.
+    pub fn merge(
+        &self,
+        heads: Vec<Commit>,
+        message: &str,
+        options: MergeOptions,
+    ) -> Result<MergeOutcome, InkError> {
+        self.check_worktree()?;
+        if MergeState::load(&self.ink_root)?.is_some() {
+            return Err("A merge is in progress, commit or abort it first".into());
+        }
+        let current_commit = cursor::get(&self.ink_root)?;
+        let worktree = self.snapshot_worktree()?;
+        if !worktree.is_clean(&current_commit) {
+            return Err(
+                "The working directory is dirty, please commit all changes before merging".into(),
+            );
+        }
+
+        // commits in the current history, or another given one's, are
+        // already merged or merged with it
+        let graph = CommitGraph::get(&self.ink_root)?;
+        let hashes: Vec<[u8; 32]> = heads.iter().map(Commit::hash).collect();
+        let mut merging: Vec<Commit> = Vec::new();
+        for head in heads {
+            let hash = head.hash();
+            let merged = graph.is_ancestor(&hash, &current_commit.hash())
+                || merging.iter().any(|other| other.hash() == hash)
+                || hashes
+                    .iter()
+                    .any(|other| *other != hash && graph.is_ancestor(&hash, other));
+            if !merged {
+                merging.push(head);
+            }
+        }
+
+        if merging.is_empty() {
+            return Ok(MergeOutcome::UpToDate);
+        }
+        self.take_snapshot("merge", &current_commit, &worktree)?;
+        if merging.len() == 1 && graph.is_ancestor(&current_commit.hash(), &merging[0].hash()) {
+            let head = merging.remove(0);
+            self.check_out(
+                &current_commit,
+                &head,
+                &current_commit.diff(&head),
+                &worktree,
+            )?;
+            return Ok(MergeOutcome::FastForward(head));
+        }
+
+        let rules = merge::MergeRules::load(&self.ink_root, &self.work_tree, options.strategy)?;
+        let mut files = current_commit.files().to_vec();
+        let mut parents = vec![current_commit.hash()];
+        for head in &merging {
+            let base = graph
+                .merge_base(&parents, &head.hash())
+                .map(|base| Commit::from(&base, &self.ink_root))
+                .transpose()?;
+            let base_files = base.as_ref().map_or(&[][..], Commit::files);
+
+            let mut tree =
+                merge::merge_trees(&self.ink_root, base_files, &files, head.files(), &rules)?;
+            rerere::replay(&self.ink_root, &mut tree)?;
+            if !tree.is_clean() {
+                for path in &tree.conflicts {
+                    self.events.conflict_detected(path);
+                }
+                if merging.len() == 1 {
+                    self.stop_merge(&current_commit, head, &tree, message)?;
+                }
+                return Ok(MergeOutcome::Conflicted(tree.conflicts));
+            }
+            files = tree.files;
+            parents.push(head.hash());
+        }
+
+        let options = options.commit;
+        let timestamp = options.time.map_or_else(time::commit_time, Ok)?;
+        let commit = Commit::from_files(files, parents, timestamp, message)?
+            .with_offset(options.offset.unwrap_or_else(time::commit_offset))
+            .with_metadata(self.attributed(options.metadata, options.author)?);
+
+        // the merged content is stored already
+        commit.write_object(&OsFs, &self.ink_root)?;
+        CommitGraph::append_commit(&self.ink_root, &commit)?;
+
+        if let Some(mut index) = ReverseIndex::load(&self.ink_root)? {
+            index.add_commit(&commit);
+            index.write(&self.ink_root)?;
+        }
+
+        self.events.commit_created(&commit);
+        let diff = current_commit.diff(&commit);
+        self.check_out(&current_commit, &commit, &diff, &worktree)?;
+
+        Ok(MergeOutcome::Merged(commit))
+    }
+
+    /// Leave a merge with conflicts in progress: write the merged files to
+    /// the working directory, with conflicting lines between markers and
+    /// other conflicts as our side has them, and record the conflicts
+    fn stop_merge(
+        &self,
+        current_commit: &Commit,
+        head: &Commit,
+        tree: &merge::TreeMerge,
+        message: &str,
+    ) -> Result<(), InkError> {
+        let mut files = tree.files.clone();
+        let mut conflicts = Vec::new();
+        for conflict in &tree.line_conflicts {
+            let content = conflict.with_markers(
+                &render::short_hash(&current_commit.hash()),
+                &render::short_hash(&head.hash()),
+            );
+            let (content_hash, _) =
+                objects::store_content(&self.ink_root, &content, &conflict.path)?;
+            files.push(FileData::from_content(
+                &conflict.path,
+                conflict.permissions,
+                content_hash,
+            ));
+            conflicts.push((
+                conflict.path.clone(),
+                rerere::record_conflict(&self.ink_root, conflict)?,
+            ));
+        }
+        // a file deleted on our side comes back as theirs
+        for path in &tree.conflicts {
+            let side = |commit: &Commit| {
+                commit
+                    .files()
+                    .iter()
+                    .find(|file| file.path() == path)
+                    .cloned()
+            };
+            if !conflicts.iter().any(|(conflicted, _)| conflicted == path) {
+                files.extend(side(current_commit).or_else(|| side(head)));
+            }
+        }
+        files.sort_by(|a, b| a.path().cmp(b.path()));
+
+        let edits = commit::diff_files(current_commit.files(), &files).edits;
+        Checkout::apply(
+            &self.ink_root,
+            &self.work_tree,
+            &self.dir_name,
+            &edits,
+            self.jobs,
+        )?
+        .finish();
+
+        MergeState {
+            head: head.hash(),
+            message: message.to_string(),
+            conflicts,
+        }
+        .write(&self.ink_root)
+    }
+
+    /// Give up the merge in progress, checking out the current commit again
+    /// and throwing away changes made to the working directory since
+    pub fn abort_merge(&self) -> Result<CheckoutOutcome, InkError> {
+        if MergeState::load(&self.ink_root)?.is_none() {
+            return Err("No merge is in progress".into());
+        }
+
+        self.go(
+            cursor::get(&self.ink_root)?,
+            CheckoutOptions::new().force(true),
+        )
+    }
+
+    /// Switch the working directory to the given commit, giving up any merge
+    /// in progress. Fails if the working directory has uncommitted changes,
+    /// unless `force` is set, which keeps them in a snapshot for `undo`, or
+    /// `carry` carries them over.
+    pub fn go(&self, to: Commit, options: CheckoutOptions) -> Result<CheckoutOutcome, InkError> {
+        self.check_worktree()?;
+        let started = Instant::now();
+        let from = cursor::get(&self.ink_root)?;
+
+        // perform check to see if pwd is dirty
+        let worktree = self.snapshot_worktree()?;
+        let dirty = worktree.diff_from(&from);
+        if options.ff_only
+            && !CommitGraph::get(&self.ink_root)?.is_ancestor(&from.hash(), &to.hash())
+        {
+            return Err(
+                "Not a fast-forward: the current commit isn't in that commit's history".into(),
+            );
+        }
+
+        if options.force {
+            self.take_snapshot("go --force", &from, &worktree)?;
+        }
+
+        let diff = if dirty.edits.is_empty() {
+            from.diff(&to)
+        } else if options.force {
+            // go from what is actually there, discarding the changes
+            commit::diff_files(worktree.files(), to.files())
+        } else if options.carry {
+            self.carried(&from, &worktree, &to)?
+        } else {
+            for edit in &dirty.edits {
+                self.events.conflict_detected(edit.path());
+            }
+
+            return Err(
+                "The working directory is dirty, please commit all changes before proceeding"
+                    .into(),
+            );
+        };
+
+        let outcome = self.check_out(&from, &to, &diff, &worktree)?;
+        self.log_metrics(
+            Record::new("go", started.elapsed())
+                .files(outcome.files_changed + outcome.files_deleted),
+        );
+        Ok(outcome)
+    }
+
+    /// The changes taking the working directory to `to` with its
+    /// uncommitted changes merged into `to`'s files, as `merge` merges them.
+    /// The changed content is stored to be merged. Fails before anything is
+    /// checked out if the changes conflict.
+    fn carried(
+        &self,
+        from: &Commit,
+        worktree: &TreeSnapshot,
+        to: &Commit,
+    ) -> Result<CommitDiff, InkError> {
+        self.store_worktree(from, worktree)?;
+        let rules = merge::MergeRules::load(&self.ink_root, &self.work_tree, Default::default())?;
+        let merged = merge::merge_trees(
+            &self.ink_root,
+            from.files(),
+            worktree.files(),
+            to.files(),
+            &rules,
+        )?;
+        if !merged.is_clean() {
+            for path in &merged.conflicts {
+                self.events.conflict_detected(path);
+            }
+
+            return Err(
+                "Uncommitted changes conflict with that commit's, so nothing was checked out"
+                    .into(),
+            );
+        }
+
+        Ok(commit::diff_files(worktree.files(), &merged.files))
+    }
+
+    /// Add to the metrics log if it is enabled. The operation has already
+    /// happened, so failing to log it isn't worth failing it for.
+    fn log_metrics(&self, record: Record) {
+        let enabled = Config::load(&self.ink_root)
+            .and_then(|config| metrics::enabled(&config))
+            .unwrap_or(false);
+        if enabled {
+            let _ = metrics::record(&self.ink_root, &record);
+        }
+    }
+
+    /// Store the content of the files in the working directory that aren't
+    /// in the given commit
+    fn store_worktree(&self, from: &Commit, worktree: &TreeSnapshot) -> Result<(), InkError> {
+        let filters = ContentFilters::load(&self.ink_root, &self.work_tree)?;
+        let large_files = LargeFiles::load(&self.ink_root)?;
+        let policy = StoragePolicy::load(&self.ink_root)?;
+        for file in worktree.files() {
+            if !from.contains(file) {
+                file.write(
+                    &self.ink_root,
+                    &filters,
+                    &large_files,
+                    &policy,
+                    &Timings::default(),
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Keep the working directory as it is before an operation that can
+    /// throw changes away, see `safety`
+    fn take_snapshot(
+        &self,
+        operation: &str,
+        from: &Commit,
+        worktree: &TreeSnapshot,
+    ) -> Result<(), InkError> {
+        self.store_worktree(from, worktree)?;
+        safety::record(
+            &self.ink_root,
+            operation,
+            from.hash(),
+            worktree.files().to_vec(),
+        )?;
+
+        Ok(())
+    }
+
+    /// Put the working directory back as it was when a snapshot was taken,
+    /// with the cursor on the commit it was on then. The working directory
+    /// as it is now is kept in a snapshot first, so this can be undone too.
+    pub fn undo(&self, snapshot: &Snapshot) -> Result<CheckoutOutcome, InkError> {
+        self.check_worktree()?;
+        let from = cursor::get(&self.ink_root)?;
+        let to = Commit::from(&snapshot.cursor, &self.ink_root)?;
+        let worktree = self.snapshot_worktree()?;
+        self.take_snapshot("undo", &from, &worktree)?;
+
+        let diff = commit::diff_files(worktree.files(), &snapshot.files);
+        self.check_out(&from, &to, &diff, &worktree)
+    }
+
+    /// Apply the changes from one commit to another to the working
+    /// directory, and move the cursor to the other. Files the working
+    /// directory already has as they are in the other commit, by the hashes
+    /// in `worktree`, are left alone.
+    fn check_out(
+        &self,
+        from: &Commit,
+        to: &Commit,
+        diff: &CommitDiff,
+        worktree: &TreeSnapshot,
+    ) -> Result<CheckoutOutcome, InkError> {
+        self.events.checkout_started(from, to);
+
+        let on_disk: HashMap<&Path, &FileData> = worktree
+            .files()
+            .iter()
+            .map(|file| (file.path(), file))
+            .collect();
+        let edits: Vec<Edit> = diff
+            .edits
+            .iter()
+            .filter(|edit| match edit {
+                Edit::Insert(file)
+                | Edit::Modify { modified: file, .. }
+                | Edit::TypeChange { modified: file, .. }
+                | Edit::ChmodOnly { modified: file, .. } => on_disk.get(file.path()) != Some(&file),
+                Edit::Delete(_) => true,
+            })
+            .map(|edit| match edit {
+                // only the mode is set, so the content on disk must already
+                // be the content wanted
+                Edit::ChmodOnly { original, modified }
+                    if on_disk
+                        .get(modified.path())
+                        .is_none_or(|file| file.content_hash() != modified.content_hash()) =>
+                {
+                    Edit::Modify {
+                        original: original.clone(),
+                        modified: modified.clone(),
+                    }
+                }
+                edit => edit.clone(),
+            })
+            .collect();
+        let checkout = Checkout::apply(
+            &self.ink_root,
+            &self.work_tree,
+            &self.dir_name,
+            &edits,
+            self.jobs,
+        )?;
+
+        // the working directory only counts as switched once the cursor is
+        if let Err(err) = cursor::set(&self.ink_root, to) {
+            checkout.rollback();
+            return Err(err);
+        }
+
+        let outcome = checkout.finish();
+        // checking out another commit gives up a merge in progress
+        MergeState::clear(&self.ink_root)?;
+        self.events.checkout_finished(to);
+
+        Ok(outcome)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Rc<RefCell<Vec<String>>>,
+    }
+
+    impl EventSink for Recorder {
+        fn commit_created(&self, commit: &Commit) {
+            self.events
+                .borrow_mut()
+                .push(format!("commit {}", commit.message()));
+        }
+
+        fn checkout_started(&self, _from: &Commit, to: &Commit) {
+            self.events
+                .borrow_mut()
+                .push(format!("checkout {}", to.message()));
+        }
+
+        fn checkout_finished(&self, to: &Commit) {
+            self.events
+                .borrow_mut()
+                .push(format!("finished {}", to.message()));
+        }
+
+        fn conflict_detected(&self, path: &Path) {
+            self.events
+                .borrow_mut()
+                .push(format!("conflict {}", path.display()));
+        }
+    }
+
+    #[test]
+    fn commit_skips_unchanged_tree() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        crate::init(tmpdir_path).unwrap();
+        let repo = Repository::open(&tmpdir_path.join(".ink")).unwrap();
+
+        fs::write(tmpdir_path.join("example"), b"this is a test!").unwrap();
+
+        let commit = crate::testkit::created(repo.commit("", Default::default())).commit;
+
+        assert!(matches!(
+            repo.commit("", Default::default()).unwrap(),
+            CommitResult::NothingToCommit
+        ));
+        assert_eq!(cursor::get(repo.ink_root()).unwrap(), commit);
+
+        fs::write(tmpdir_path.join("example"), b"this is another test!").unwrap();
+
+        assert!(matches!(
+            repo.commit("", Default::default()).unwrap(),
+            CommitResult::Created(_)
+        ));
+    }
+
+    #[test]
+    fn operations_send_events() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        crate::init(tmpdir_path).unwrap();
+
+        let recorder = Recorder::default();
+        let events = Rc::clone(&recorder.events);
+        let repo = Repository::open(&tmpdir_path.join(".ink"))
+            .unwrap()
+            .with_events(recorder);
+
+        let example = tmpdir_path.join("example");
+        fs::write(&example, b"this is a test!").unwrap();
+        let first = crate::testkit::created(repo.commit("first", Default::default())).commit;
+
+        fs::write(&example, b"this is another test!").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+
+        fs::write(&example, b"uncommitted").unwrap();
+        let first_again = Commit::from(&first.hash(), repo.ink_root()).unwrap();
+        assert!(repo.go(first_again, Default::default()).is_err());
+
+        fs::write(&example, b"this is another test!").unwrap();
+        repo.go(first, Default::default()).unwrap();
+        assert_eq!(fs::read(&example).unwrap(), b"this is a test!");
+
+        assert_eq!(
+            *events.borrow(),
+            vec![
+                "commit first",
+                "commit second",
+                "conflict example",
+                "checkout first",
+                "finished first"
+            ]
+        );
+    }
+
+    #[test]
+    fn normalizes_line_endings() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        crate::init(tmpdir_path).unwrap();
+        let ink_dir = tmpdir_path.join(".ink");
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[core]\ntext = lf\n[path \"*.bat\"]\ntext = crlf\n",
+        )
+        .unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let script = tmpdir_path.join("run.bat");
+        let notes = tmpdir_path.join("notes.txt");
+        fs::write(&script, b"echo one\r\necho two\r\n").unwrap();
+        fs::write(&notes, b"one\r\ntwo\n").unwrap();
+        let first = crate::testkit::created(repo.commit("first", Default::default())).commit;
+
+        let stored = first
+            .files()
+            .iter()
+            .map(|f| f.read_content(&ink_dir).unwrap())
+            .collect::<Vec<_>>();
+        assert!(stored.contains(&b"echo one\necho two\n".to_vec()));
+        assert!(stored.contains(&b"one\ntwo\n".to_vec()));
+
+        // only the line endings changed, which is not a change at all
+        fs::write(&notes, b"one\ntwo\r\n").unwrap();
+        assert!(repo.status().unwrap().edits.is_empty());
+
+        fs::write(&notes, b"one\ntwo\nthree\n").unwrap();
+        fs::write(&script, b"echo three\n").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+        repo.go(first, Default::default()).unwrap();
+
+        assert_eq!(fs::read(&script).unwrap(), b"echo one\r\necho two\r\n");
+        assert_eq!(fs::read(&notes).unwrap(), b"one\ntwo\n");
+        assert!(repo.status().unwrap().edits.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn filters_content() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        crate::init(tmpdir_path).unwrap();
+        let ink_dir = tmpdir_path.join(".ink");
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[filter \"version\"]\nclean = sed 's/VERSION=.*/VERSION=$/'\nsmudge = sed 's/VERSION=\\$/VERSION=1.0/'\n[path \"*.env\"]\nfilter = version\n",
+        )
+        .unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let env = tmpdir_path.join("app.env");
+        fs::write(&env, b"VERSION=0.9\n").unwrap();
+        let first = crate::testkit::created(repo.commit("first", Default::default())).commit;
+        assert_eq!(
+            first.files()[0].read_content(&ink_dir).unwrap(),
+            b"VERSION=$\n"
+        );
+
+        fs::write(&env, b"VERSION=2.0\n").unwrap();
+        assert!(repo.status().unwrap().edits.is_empty());
+
+        fs::write(&env, b"VERSION=2.0\nDEBUG=1\n").unwrap();
+        repo.commit("second", Default::default()).unwrap();
+        repo.go(first, Default::default()).unwrap();
+        assert_eq!(fs::read(&env).unwrap(), b"VERSION=1.0\n");
+    }
+
+    #[test]
+    fn init_bare_and_named() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+
+        let named = crate::InitOptions::new().dir_name(".meta");
+        let ink_dir = crate::init_with(tmpdir_path, named.clone()).unwrap();
+        assert_eq!(ink_dir, tmpdir_path.join(".meta"));
+        assert!(matches!(
+            crate::init_with(tmpdir_path, named),
+            Err(InkError::AlreadyInitialized(dir)) if dir == ink_dir
+        ));
+        fs::write(tmpdir_path.join("example"), b"this is a test!").unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+        assert!(!repo.is_bare());
+        assert_eq!(repo.status().unwrap().edits.len(), 1);
+
+        fs::write(tmpdir_path.join("file"), b"in the way").unwrap();
+        let in_file = crate::InitOptions::new().dir_name("file");
+        assert!(crate::init_with(tmpdir_path, in_file).is_err());
+
+        let bare = crate::InitOptions::new().bare(true);
+        let bare_dir = crate::init_with(&tmpdir_path.join("bare.ink"), bare).unwrap();
+        let bare = Repository::open(&bare_dir).unwrap();
+        assert!(bare.is_bare());
+        assert!(bare.status().is_err());
+        assert!(bare
+            .commit("", crate::CommitOptions::new().allow_empty(true))
+            .is_err());
+        assert_eq!(bare.log().unwrap().count(), 1);
+        assert!(!bare.version_info().unwrap().dirty);
+    }
+
+    #[test]
+    fn open_with_layout() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path().canonicalize().unwrap();
+        let ink_dir = crate::init(&tmpdir_path).unwrap();
+
+        let open = |dir: &Path| Repository::open_with(dir, OpenOptions::new());
+        assert_eq!(open(&tmpdir_path).unwrap().ink_root(), ink_dir);
+        assert_eq!(open(&ink_dir).unwrap().ink_root(), ink_dir);
+        assert!(open(&ink_dir.join(crate::DATA_EXT)).is_err());
+        assert!(open(&tmpdir_path.join("missing")).is_err());
+
+        // metadata under another name, apart from the files it tracks
+        let meta = tmpdir_path.join("meta");
+        let work = tmpdir_path.join("work");
+        fs::create_dir_all(work.join(".meta")).unwrap();
+        fs::write(work.join("a"), b"a").unwrap();
+        fs::write(work.join(".meta").join("b"), b"b").unwrap();
+        let meta_dir =
+            crate::init_with(&meta, crate::InitOptions::new().dir_name(".meta")).unwrap();
+
+        let options = OpenOptions::new().dir_name(".meta").work_tree(&work);
+        let repo = Repository::discover_with(&meta, options).unwrap();
+        assert_eq!(repo.ink_root(), meta_dir);
+        assert_eq!(
+            (repo.work_tree(), repo.dir_name()),
+            (work.as_path(), ".meta")
+        );
+        let paths: Vec<PathBuf> = repo
+            .status()
+            .unwrap()
+            .edits
+            .iter()
+            .map(|edit| edit.path().to_path_buf())
+            .collect();
+        assert_eq!(paths, [PathBuf::from("a")]);
+        // under the default name, the repository around it is found instead
+        assert_eq!(
+            Repository::discover_from(&meta).unwrap().ink_root(),
+            ink_dir
+        );
+    }
+
+    #[test]
+    fn forced_checkout_discards_changes() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("example", "first")
+            .commit("first")
+            .file("example", "second")
+            .commit("second");
+        let first = repo.head().parents()[0];
+
+        let repo = repo.file("example", "uncommitted").file("untracked", "new");
+        let target = || Commit::from(&first, repo.ink_root()).unwrap();
+        assert!(repo.repo().go(target(), Default::default()).is_err());
+
+        let force = CheckoutOptions::new().force(true);
+        repo.repo().go(target(), force).unwrap();
+        assert_eq!(repo.read("example"), b"first");
+        assert!(!repo.path().join("untracked").exists());
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+
+        // until undone
+        let snapshot = &safety::list(repo.ink_root()).unwrap()[0];
+        assert_eq!(snapshot.operation, "go --force");
+        repo.repo().undo(snapshot).unwrap();
+        assert_eq!(repo.read("example"), b"uncommitted");
+        assert_eq!(repo.read("untracked"), b"new");
+        assert_eq!(repo.head().message(), "second");
+        assert_eq!(safety::list(repo.ink_root()).unwrap()[0].operation, "undo");
+    }
+
+    #[test]
+    fn checkout_skips_files_already_there() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("same", "first")
+            .file("other", "first")
+            .commit("first");
+        let first = repo.head();
+        let repo = repo
+            .file("same", "second")
+            .file("other", "second")
+            .commit("second");
+        let second = repo.head();
+        let again = Commit::from(&first.hash(), repo.ink_root()).unwrap();
+        repo.repo().go(again, Default::default()).unwrap();
+
+        // edited by hand to what the other commit has
+        fs::write(repo.path().join("same"), "second").unwrap();
+        let worktree = repo.repo().snapshot_worktree().unwrap();
+        let outcome = repo
+            .repo()
+            .check_out(&first, &second, &first.diff(&second), &worktree)
+            .unwrap();
+        assert_eq!(outcome.files_changed, 1);
+        assert_eq!(repo.read("other"), b"second");
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+    }
+
+    #[test]
+    fn checkout_carries_changes() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("lines", "one\ntwo\nthree\n")
+            .file("other", "first")
+            .commit("first")
+            .file("lines", "one\ntwo\n3\n")
+            .file("other", "second")
+            .commit("second");
+        let (first, second) = (repo.head().parents()[0], repo.head().hash());
+        let target = |hash| Commit::from(hash, repo.ink_root()).unwrap();
+        let carry = CheckoutOptions::new().carry(true);
+
+        // changes to other lines and files come along
+        fs::write(repo.path().join("lines"), "1\ntwo\n3\n").unwrap();
+        fs::write(repo.path().join("untracked"), "new").unwrap();
+        repo.repo().go(target(&first), carry.clone()).unwrap();
+        assert_eq!(repo.head().hash(), first);
+        assert_eq!(repo.read("lines"), b"1\ntwo\nthree\n");
+        assert_eq!(repo.read("other"), b"first");
+        assert_eq!(repo.read("untracked"), b"new");
+
+        // conflicting ones leave everything as it was
+        fs::write(repo.path().join("other"), "uncommitted").unwrap();
+        let state = repo.repo().state_id().unwrap();
+        assert!(repo.repo().go(target(&second), carry).is_err());
+        assert_eq!(repo.repo().state_id().unwrap(), state);
+        assert_eq!(repo.read("other"), b"uncommitted");
+        assert_eq!(repo.read("lines"), b"1\ntwo\nthree\n");
+    }
+
+    #[test]
+    fn outcomes_count_changes() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "a")
+            .file("b", "b")
+            .file("copy", "b");
+        let first = crate::testkit::created(repo.repo().commit("first", Default::default()));
+        assert_eq!(
+            (first.files_written, first.skipped, first.deduplicated),
+            (2, 0, 1)
+        );
+        assert!(first.bytes_stored > 0);
+
+        let repo = repo
+            .file("a", "changed")
+            .file("dir/nested/c", "c")
+            .remove("b")
+            .remove("copy");
+        let second = crate::testkit::created(repo.repo().commit("second", Default::default()));
+        // only "a" and "c" have new content
+        assert_eq!((second.files_written, second.skipped), (2, 0));
+
+        let outcome = repo.repo().go(first.commit, Default::default()).unwrap();
+        assert_eq!(
+            outcome,
+            CheckoutOutcome {
+                files_changed: 3,
+                files_deleted: 1,
+                dirs_created: 0,
+            }
+        );
+
+        // deleting files leaves their directories behind
+        fs::remove_dir_all(repo.path().join("dir")).unwrap();
+        let outcome = repo.repo().go(second.commit, Default::default()).unwrap();
+        assert_eq!(outcome.dirs_created, 2);
+        assert_eq!(repo.read("dir/nested/c"), b"c");
+    }
+
+    #[test]
+    fn import_archive_commits() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "worktree")
+            .commit("first");
+        let first = repo.head();
+        let archive =
+            crate::archive::tests::tar(&[("a", 0o644, b"imported"), ("dir/b", 0o755, b"b")]);
+
+        let outcome = crate::testkit::created(repo.repo().import_archive(
+            &archive[..],
+            "import",
+            Default::default(),
+        ));
+        assert_eq!((outcome.files_written, outcome.skipped), (2, 0));
+        let imported = outcome.commit;
+        assert_eq!(imported.parents(), [first.hash()]);
+        assert_eq!(
+            Commit::from(&imported.hash(), repo.ink_root()).unwrap(),
+            imported
+        );
+
+        // the working directory is untouched until the import is checked out
+        assert_eq!(repo.head(), first);
+        assert_eq!(repo.read("a"), b"worktree");
+        repo.repo().go(imported, Default::default()).unwrap();
+        assert_eq!(repo.read("a"), b"imported");
+        assert_eq!(repo.read("dir/b"), b"b");
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+
+        // importing the same files again changes nothing
+        assert!(matches!(
+            repo.repo()
+                .import_archive(&archive[..], "again", Default::default())
+                .unwrap(),
+            CommitResult::NothingToCommit
+        ));
+
+        // nothing lands in an ink directory, at any depth
+        for metadata in &[".ink/config", "./.ink/cursor", "sub/.ink/config"] {
+            let archive = crate::archive::tests::tar(&[(metadata, 0o644, b"")]);
+            assert!(repo
+                .repo()
+                .import_archive(&archive[..], "metadata", Default::default())
+                .is_err());
+        }
+
+        // paths a snapshot leaves out are left out of an import too
+        fs::write(
+            repo.ink_root().join(crate::CONFIG_FILE),
+            "[ignore]\npaths = target\n",
+        )
+        .unwrap();
+        let archive = crate::archive::tests::tar(&[
+            ("c", 0o644, b"c"),
+            ("target/debug/out", 0o644, b""),
+            (".ink-a1b2c3", 0o644, b""),
+            ("dir/.ink-d4e5f6", 0o644, b""),
+        ]);
+        let outcome = crate::testkit::created(repo.repo().import_archive(
+            &archive[..],
+            "excluded",
+            Default::default(),
+        ));
+        let paths: Vec<&Path> = outcome.commit.files().iter().map(|f| f.path()).collect();
+        assert_eq!(paths, [Path::new("c")]);
+    }
+
+    #[test]
+    fn state_id_tracks_changes() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "first")
+            .commit("first");
+        let first = repo.head();
+        let state = repo.repo().state_id().unwrap();
+
+        // reading and changing the working directory don't count
+        let repo = repo.file("a", "uncommitted");
+        repo.repo().status().unwrap();
+        repo.repo().log().unwrap().count();
+        assert_eq!(repo.repo().state_id().unwrap(), state);
+
+        let repo = repo.commit("second");
+        let committed = repo.repo().state_id().unwrap();
+        assert_ne!(committed, state);
+
+        tags::create(repo.ink_root(), "v1", &first).unwrap();
+        let tagged = repo.repo().state_id().unwrap();
+        assert_ne!(tagged, committed);
+
+        repo.repo().go(first, Default::default()).unwrap();
+        assert_ne!(repo.repo().state_id().unwrap(), tagged);
+    }
+
+    #[test]
+    fn commits_at_a_given_time_are_reproducible() {
+        let repo = crate::testkit::TestRepo::new().file("a", "content");
+        let base = repo.head();
+        let time =
+            std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000);
+        let options = || CommitOptions::new().time(time);
+
+        let commit = |repo: &crate::testkit::TestRepo| {
+            crate::testkit::created(repo.repo().commit("same", options())).commit
+        };
+        let first = commit(&repo);
+        assert_eq!(first.time(), 1_600_000_000);
+
+        let force = CheckoutOptions::new().force(true);
+        repo.repo().go(base, force).unwrap();
+        let repo = repo.file("a", "content");
+        let second = commit(&repo);
+        assert_eq!(second, first);
+        assert_eq!(
+            fs::read(
+                repo.ink_root()
+                    .join(crate::COMMIT_EXT)
+                    .join(hex::encode(first.hash()))
+            )
+            .unwrap(),
+            second.encode().unwrap()
+        );
+    }
+
+    #[test]
+    fn applied_changes_are_found() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "one\ntwo\nthree\n")
+            .commit("base")
+            .file("a", "one\n2\nthree\n")
+            .commit("change");
+        let change = repo.head();
+        let base = Commit::from(&change.parents()[0], repo.ink_root()).unwrap();
+
+        // the same change made again elsewhere, after another one
+        repo.repo().go(base, Default::default()).unwrap();
+        let repo = repo
+            .file("a", "zero\none\ntwo\nthree\n")
+            .commit("other")
+            .file("a", "zero\none\n  2\nthree\n")
+            .commit("same change");
+        let found = repo.repo().find_applied(&change).unwrap().unwrap();
+        assert_eq!(found.message(), "same change");
+
+        let other = Commit::from(&repo.head().parents()[0], repo.ink_root()).unwrap();
+        repo.repo().go(other, Default::default()).unwrap();
+        assert!(repo.repo().find_applied(&change).unwrap().is_none());
+    }
+
+    #[test]
+    fn octopus_merge() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("shared", "one\ntwo\nthree\n")
+            .commit("base");
+        let base = repo.head().hash();
+        let again = |repo: &crate::testkit::TestRepo| Commit::from(&base, repo.ink_root()).unwrap();
+
+        let mut repo = repo;
+        let mut heads = Vec::new();
+        for (name, shared) in &[
+            ("a", "1\ntwo\nthree\n"),
+            ("b", "one\ntwo\n3\n"),
+            ("c", "one\ntwo\nthree\n"),
+        ] {
+            repo.repo().go(again(&repo), Default::default()).unwrap();
+            repo = repo.file(name, name).file("shared", shared).commit(name);
+            heads.push(repo.head());
+        }
+        repo.repo().go(again(&repo), Default::default()).unwrap();
+        let hashes: Vec<_> = heads.iter().map(Commit::hash).collect();
+
+        // a single commit ahead is fast-forwarded to
+        let a = Commit::from(&hashes[0], repo.ink_root()).unwrap();
+        assert!(matches!(
+            repo.repo().merge(vec![a], "", Default::default()).unwrap(),
+            MergeOutcome::FastForward(commit) if commit.hash() == hashes[0]
+        ));
+
+        let merge = match repo.repo().merge(heads, "octopus", Default::default()) {
+            Ok(MergeOutcome::Merged(commit)) => commit,
+            other => panic!("expected a merge, got {:?}", other),
+        };
+        assert_eq!(merge.parents(), &hashes[..]);
+        assert_eq!(repo.head(), merge);
+        assert_eq!(repo.read("shared"), b"1\ntwo\n3\n");
+        for name in &["a", "b", "c"] {
+            assert_eq!(repo.read(name), name.as_bytes());
+        }
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+
+        let graph = CommitGraph::get(repo.ink_root()).unwrap();
+        assert!(hashes
+            .iter()
+            .all(|hash| graph.is_ancestor(hash, &merge.hash())));
+
+        // the merged lines of history all come before where they split
+        let log: Vec<_> = repo
+            .repo()
+            .log()
+            .unwrap()
+            .map(|commit| commit.unwrap().message().to_string())
+            .collect();
+        assert_eq!(log.len(), 6);
+        assert_eq!(log[4..], ["base", ""]);
+
+        let b = Commit::from(&hashes[1], repo.ink_root()).unwrap();
+        assert!(matches!(
+            repo.repo().merge(vec![b], "", Default::default()).unwrap(),
+            MergeOutcome::UpToDate
+        ));
+    }
+
+    #[test]
+    fn conflicting_merges() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "base\n")
+            .commit("base");
+        let base = repo.head().hash();
+        let again =
+            |repo: &crate::testkit::TestRepo, hash| Commit::from(hash, repo.ink_root()).unwrap();
+        let repo = repo.file("a", "ours\n").commit("ours");
+        let ours = repo.head().hash();
+        repo.repo()
+            .go(again(&repo, &base), Default::default())
+            .unwrap();
+        let repo = repo.file("a", "theirs\n").commit("theirs");
+        let theirs = repo.head().hash();
+        repo.repo()
+            .go(again(&repo, &base), Default::default())
+            .unwrap();
+        let repo = repo.file("b", "other\n").commit("other");
+        let other = repo.head().hash();
+        repo.repo()
+            .go(again(&repo, &ours), Default::default())
+            .unwrap();
+        let state = repo.repo().state_id().unwrap();
+
+        // merging several commits changes nothing
+        let heads = vec![again(&repo, &theirs), again(&repo, &other)];
+        match repo.repo().merge(heads, "", Default::default()) {
+            Ok(MergeOutcome::Conflicted(paths)) => assert_eq!(paths, [Path::new("a")]),
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(repo.read("a"), b"ours\n");
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+
+        // merging one leaves the conflict to resolve
+        let merge = || {
+            repo.repo()
+                .merge(vec![again(&repo, &theirs)], "merge", Default::default())
+                .unwrap()
+        };
+        assert!(matches!(merge(), MergeOutcome::Conflicted(_)));
+        assert_eq!(repo.repo().state_id().unwrap(), state);
+        let markers = format!(
+            "<<<<<<< {}\nours\n=======\ntheirs\n>>>>>>> {}\n",
+            render::short_hash(&ours),
+            render::short_hash(&theirs)
+        );
+        assert_eq!(repo.read("a"), markers.as_bytes());
+        assert!(repo.repo().merge(vec![], "", Default::default()).is_err());
+        assert!(repo.repo().commit("", Default::default()).is_err());
+
+        repo.repo().abort_merge().unwrap();
+        assert_eq!(repo.read("a"), b"ours\n");
+        assert!(repo.repo().abort_merge().is_err());
+
+        assert!(matches!(merge(), MergeOutcome::Conflicted(_)));
+        fs::write(repo.path().join("a"), "both\n").unwrap();
+        let commit = crate::testkit::created(repo.repo().commit("", Default::default())).commit;
+        assert_eq!(commit.parents(), [ours, theirs]);
+        assert_eq!(commit.message(), "merge");
+
+        // the same conflict is resolved the same way
+        repo.repo()
+            .go(again(&repo, &ours), Default::default())
+            .unwrap();
+        match merge() {
+            MergeOutcome::Merged(commit) => assert_ne!(commit.hash(), ours),
+            other => panic!("expected a merge, got {:?}", other),
+        }
+        assert_eq!(repo.read("a"), b"both\n");
+    }
+}
@@ -0,0 +1,207 @@
+//! Reusing recorded resolutions of conflicts.
+//!
+//! When a merge stops with the lines of a file conflicting, the file with
+//! its conflicts written out between markers is recorded as the pre-image
+//! of the conflict, in `.ink/rerere/<id>/preimage`, where the id is the hash
+//! of the conflicting lines. Committing the merge records the file as it was
+//! resolved as the post-image. When a later merge runs into the same
+//! conflict, the change from pre-image to post-image is merged into its
+//! file, and if that merges cleanly the conflict is taken as resolved.
+//!
+//! The two sides of each conflict are put in order before either is used,
+//! so merging the same branches the other way around finds the same
+//! resolution.
+use crate::diff::{MergeRegion, MergeResult};
+use crate::filedata::FileData;
+use crate::merge::{self, LineConflict, TreeMerge};
+use crate::objects;
+use crate::{InkError, RERERE_DIR};
+
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const PREIMAGE_FILE: &str = "preimage";
+const POSTIMAGE_FILE: &str = "postimage";
+
+/// Identify a conflict by the lines each side changed to, so it is the same
+/// wherever the rest of the file has changed, and whichever side is ours
+pub fn conflict_id(conflict: &LineConflict) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+
+    // length prefixed, so lines can't move between sides or conflicts
+    for region in &sides_in_order(conflict).regions {
+        if let MergeRegion::Conflict { ours, theirs, .. } = region {
+            for side in &[ours, theirs] {
+                hasher.update((side.len() as u64).to_be_bytes());
+                for line in side.iter() {
+                    hasher.update((line.len() as u64).to_be_bytes());
+                    hasher.update(line.as_bytes());
+                }
+            }
+        }
+    }
+
+    hasher.finalize().into()
+}
+
+/// Record a conflict to be resolved, returning its id
+pub fn record_conflict(ink_root: &Path, conflict: &LineConflict) -> Result<[u8; 32], InkError> {
+    let id = conflict_id(conflict);
+    let dir = conflict_dir(ink_root, &id);
+    fs::create_dir_all(&dir)?;
+    fs::write(dir.join(PREIMAGE_FILE), preimage(conflict))?;
+
+    Ok(id)
+}
+
+/// Record the content a conflict was resolved with. Nothing is recorded for
+/// a conflict that wasn't.
+pub fn record_resolution(ink_root: &Path, id: &[u8; 32], content: &[u8]) -> Result<(), InkError> {
+    let dir = conflict_dir(ink_root, id);
+    if dir.join(PREIMAGE_FILE).is_file() {
+        fs::write(dir.join(POSTIMAGE_FILE), content)?;
+    }
+
+    Ok(())
+}
+
+/// The content resolving a conflict the way a recorded one was resolved,
+/// if there is one and its resolution applies cleanly
+pub fn resolution(ink_root: &Path, conflict: &LineConflict) -> Result<Option<Vec<u8>>, InkError> {
+    let dir = conflict_dir(ink_root, &conflict_id(conflict));
+    let (recorded, resolved) = match (
+        read_if_exists(&dir.join(PREIMAGE_FILE))?,
+        read_if_exists(&dir.join(POSTIMAGE_FILE))?,
+    ) {
+        (Some(recorded), Some(resolved)) => (recorded, resolved),
+        _ => return Ok(None),
+    };
+
+    match merge::merge_lines(&recorded, &resolved, &preimage(conflict)) {
+        Some(MergeResult::Clean(lines)) => Ok(Some(lines.concat().into_bytes())),
+        _ => Ok(None),
+    }
+}
+
+/// Resolve the conflicts of a merge that were resolved before, returning
+/// their paths
+pub fn replay(ink_root: &Path, merged: &mut TreeMerge) -> Result<Vec<PathBuf>, InkError> {
+    let mut replayed = Vec::new();
+    for conflict in merged.line_conflicts.clone() {
+        if let Some(content) = resolution(ink_root, &conflict)? {
+            let (content_hash, _) = objects::store_content(ink_root, &content, &conflict.path)?;
+            merged.resolve(FileData::from_content(
+                &conflict.path,
+                conflict.permissions,
+                content_hash,
+            ));
+            replayed.push(conflict.path);
+        }
+    }
+
+    Ok(replayed)
+}
+
+// markers with fixed names and sides in order, so pre-images of different
+// merges line up
+fn preimage(conflict: &LineConflict) -> Vec<u8> {
+    sides_in_order(conflict).with_markers("ours", "theirs")
+}
+
+/// The conflict with the sides of each conflicting region swapped where
+/// needed to put the lesser first
+fn sides_in_order(conflict: &LineConflict) -> LineConflict {
+    let mut conflict = conflict.clone();
+    for region in &mut conflict.regions {
+        if let MergeRegion::Conflict { ours, theirs, .. } = region {
+            if theirs < ours {
+                std::mem::swap(ours, theirs);
+            }
+        }
+    }
+    conflict
+}
+
+fn conflict_dir(ink_root: &Path, id: &[u8; 32]) -> PathBuf {
+    ink_root.join(RERERE_DIR).join(hex::encode(id))
+}
+
+fn read_if_exists(path: &Path) -> Result<Option<Vec<u8>>, InkError> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::merge::{merge_trees, MergeRules};
+    use crate::testkit::TestRepo;
+
+    #[test]
+    fn replays_resolutions() {
+        let repo = TestRepo::new()
+            .file("a", "top\nmiddle\nbottom\nend\n")
+            .commit("base");
+        let base = repo.head();
+        let repo = repo.file("a", "top\nours\nbottom\nend\n").commit("ours");
+        let ours = repo.head();
+        let repo = repo
+            .file("a", "top\ntheirs\nbottom\nend\n")
+            .commit("theirs");
+        let theirs = repo.head();
+        // the same conflict, with the rest of the file changed
+        let repo = repo.file("a", "top\nours\nbottom\nEND\n").commit("again");
+        let again = repo.head();
+
+        let merge_both = |ours: &crate::commit::Commit, theirs: &crate::commit::Commit| {
+            merge_trees(
+                repo.ink_root(),
+                base.files(),
+                ours.files(),
+                theirs.files(),
+                &MergeRules::default(),
+            )
+            .unwrap()
+        };
+        let merge = |ours: &crate::commit::Commit| merge_both(ours, &theirs);
+
+        let mut merged = merge(&ours);
+        assert!(replay(repo.ink_root(), &mut merged).unwrap().is_empty());
+        let conflict = &merged.line_conflicts[0];
+        let id = record_conflict(repo.ink_root(), conflict).unwrap();
+        assert_eq!(
+            conflict.with_markers("ours", "theirs"),
+            b"top\n<<<<<<< ours\nours\n=======\ntheirs\n>>>>>>> theirs\nbottom\nend\n"
+        );
+        record_resolution(repo.ink_root(), &id, b"top\nboth\nbottom\nend\n").unwrap();
+
+        let mut merged = merge(&again);
+        assert_eq!(conflict_id(&merged.line_conflicts[0]), id);
+        assert_eq!(
+            replay(repo.ink_root(), &mut merged).unwrap(),
+            [Path::new("a")]
+        );
+        assert!(merged.is_clean());
+        assert_eq!(
+            merged.files[0].read_content(repo.ink_root()).unwrap(),
+            b"top\nboth\nbottom\nEND\n"
+        );
+
+        // the other way around
+        let mut merged = merge_both(&theirs, &ours);
+        assert_eq!(conflict_id(&merged.line_conflicts[0]), id);
+        assert_eq!(
+            replay(repo.ink_root(), &mut merged).unwrap(),
+            [Path::new("a")]
+        );
+        assert_eq!(
+            merged.files[0].read_content(repo.ink_root()).unwrap(),
+            b"top\nboth\nbottom\nend\n"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Snapshots of the working directory taken before operations that can
+//! throw changes away, so they can be undone.
+//!
+//! Before `go --force`, `merge` and `undo`, the files of the working
+//! directory are stored as content objects and listed, with the commit the
+//! cursor was on, in `.ink/safety/<id>`. Snapshots aren't commits, so
+//! nothing in the history leads to them; `ink reflog` lists them and
+//! `ink undo` puts one back. They expire after `expire` days (14 by
+//! default) from the `[safety]` config section, and expired ones are
+//! removed whenever a snapshot is taken.
+use crate::config::Config;
+use crate::filedata::FileData;
+use crate::identity::{IdHasher, InkObject, ObjectId};
+use crate::{InkError, SAFETY_DIR};
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_EXPIRE_DAYS: u64 = 14;
+
+/// The working directory as it was before an operation
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Since the Unix epoch
+    pub time: Duration,
+    /// The operation about to run, like `go --force`
+    pub operation: String,
+    /// The commit the cursor was on
+    pub cursor: [u8; 32],
+    pub files: Vec<FileData>,
+}
+
+impl Snapshot {
+    /// The snapshot's name, a hash of everything in it
+    pub fn id(&self) -> [u8; 32] {
+        self.object_id()
+    }
+}
+
+impl InkObject for Snapshot {
+    fn object_id(&self) -> ObjectId {
+        let mut hasher = IdHasher::new();
+        hasher
+            .u128(self.time.as_nanos())
+            .field(self.operation.as_bytes())
+            .id(&self.cursor);
+        for file in &self.files {
+            hasher.id(&file.object_id());
+        }
+
+        hasher.finish()
+    }
+}
+
+/// Record a snapshot of files whose content is stored, and remove expired
+/// snapshots
+pub fn record(
+    ink_root: &Path,
+    operation: &str,
+    cursor: [u8; 32],
+    files: Vec<FileData>,
+) -> Result<Snapshot, InkError> {
+    let now = SystemTime::now();
+    let snapshot = Snapshot {
+        time: now
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| "Time went backwards")?,
+        operation: operation.to_string(),
+        cursor,
+        files,
+    };
+
+    let days = match Config::load(ink_root)?.get("safety", "expire") {
+        Some(days) => days
+            .parse()
+            .map_err(|_| "safety expire must be a number of days")?,
+        None => DEFAULT_EXPIRE_DAYS,
+    };
+    let cutoff = days
+        .checked_mul(24 * 60 * 60)
+        .and_then(|seconds| now.checked_sub(Duration::from_secs(seconds)))
+        .filter(|cutoff| *cutoff >= UNIX_EPOCH)
+        .ok_or("safety expire is too many days")?;
+
+    let dir = ink_root.join(SAFETY_DIR);
+    fs::create_dir_all(&dir)?;
+    fs::write(
+        dir.join(hex::encode(snapshot.id())),
+        bincode::serialize(&snapshot)?,
+    )?;
+    expire(ink_root, cutoff)?;
+
+    Ok(snapshot)
+}
+
+/// Every snapshot, newest first
+pub fn list(ink_root: &Path) -> Result<Vec<Snapshot>, InkError> {
+    let dir = ink_root.join(SAFETY_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let snapshot: Snapshot = bincode::deserialize(&fs::read(entry?.path())?)?;
+        snapshots.push(snapshot);
+    }
+    snapshots.sort_by_key(|snapshot| Reverse(snapshot.time));
+
+    Ok(snapshots)
+}
+
+/// The snapshot whose id starts with the given hex digits
+pub fn find(ink_root: &Path, prefix: &str) -> Result<Snapshot, InkError> {
+    let mut found = list(ink_root)?
+        .into_iter()
+        .filter(|snapshot| hex::encode(snapshot.id()).starts_with(prefix));
+    match (found.next(), found.next()) {
+        (Some(snapshot), None) => Ok(snapshot),
+        (Some(_), Some(_)) => Err("More than one snapshot starts with that id".into()),
+        (None, _) => Err("No snapshot has that id".into()),
+    }
+}
+
+/// Remove snapshots taken before `older_than`, returning how many there were
+pub fn expire(ink_root: &Path, older_than: SystemTime) -> Result<usize, InkError> {
+    let cutoff = older_than
+        .duration_since(UNIX_EPOCH)
+        .map_err(|_| "Time went backwards")?;
+
+    let mut removed = 0;
+    for snapshot in list(ink_root)? {
+        if snapshot.time < cutoff {
+            fs::remove_file(ink_root.join(SAFETY_DIR).join(hex::encode(snapshot.id())))?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+
+    #[test]
+    fn snapshots_expire() {
+        let repo = TestRepo::new().file("a", "a").commit("first");
+        let head = repo.head();
+        let first = record(
+            repo.ink_root(),
+            "go --force",
+            head.hash(),
+            head.files().to_vec(),
+        )
+        .unwrap();
+        let second = record(repo.ink_root(), "merge", head.hash(), Vec::new()).unwrap();
+
+        assert_eq!(
+            list(repo.ink_root()).unwrap(),
+            [second.clone(), first.clone()]
+        );
+        let prefix = &hex::encode(first.id())[..10];
+        assert_eq!(find(repo.ink_root(), prefix).unwrap(), first);
+        assert!(find(repo.ink_root(), "").is_err());
+
+        fs::write(
+            repo.ink_root().join(crate::CONFIG_FILE),
+            format!("[safety]\nexpire = {}\n", u64::MAX / 3600),
+        )
+        .unwrap();
+        assert!(record(repo.ink_root(), "merge", head.hash(), Vec::new()).is_err());
+
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert_eq!(expire(repo.ink_root(), later).unwrap(), 2);
+        assert!(list(repo.ink_root()).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,194 @@
+//! Recovering what can be recovered from a damaged repository.
+//!
+//! Salvaging reads every commit object it can, without trusting the graph,
+//! cursor or index. It rebuilds the graph from the parent hashes stored in
+//! the commits, rebuilds the index, and points the cursor at the newest
+//! commit if the cursor itself can't be read. Anything that can't be
+//! recovered is reported rather than fixed.
+use crate::commit::Commit;
+use crate::graph::{CommitGraph, MissingParent};
+use crate::index::ReverseIndex;
+use crate::{cursor, objects, InkError, COMMIT_EXT};
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What a salvage found and did
+#[derive(Debug, Default, PartialEq)]
+pub struct SalvageReport {
+    /// Commits read and put in the new graph
+    pub commits: usize,
+    /// Files in the commit directory that aren't readable commits
+    pub unreadable_commits: Vec<PathBuf>,
+    /// Commits and the parent of theirs that wasn't recovered
+    pub missing_parents: Vec<MissingParent>,
+    /// Commits and the path of a file of theirs whose content isn't stored
+    pub missing_content: Vec<([u8; 32], PathBuf)>,
+    /// Content objects whose content doesn't match their hash
+    pub corrupt_content: Vec<[u8; 32]>,
+    /// The commit the cursor was reset to, if it couldn't be read
+    pub cursor_reset: Option<[u8; 32]>,
+}
+
+/// Rebuild the graph, index and, if needed, the cursor from the readable
+/// commit objects
+pub fn salvage(ink_root: &Path) -> Result<SalvageReport, InkError> {
+    let mut report = SalvageReport::default();
+
+    let mut commits = Vec::new();
+    let mut entries = fs::read_dir(ink_root.join(COMMIT_EXT))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort();
+    for path in entries {
+        match read_commit(ink_root, &path) {
+            Some(commit) => commits.push(commit),
+            None => report.unreadable_commits.push(path),
+        }
+    }
+
+    for commit in &commits {
+        for file in commit.files() {
+            if objects::object_location(ink_root, &file.content_hash()).is_err() {
+                report
+                    .missing_content
+                    .push((commit.hash(), file.path().to_path_buf()));
+            }
+        }
+    }
+    report.missing_content.sort();
+    report.corrupt_content = objects::fsck(ink_root)?;
+
+    let (graph, missing_parents) = CommitGraph::from_commits(ink_root, &commits)?;
+    report.commits = commits.len();
+    report.missing_parents = missing_parents;
+    report.missing_parents.sort();
+
+    // a cursor that can't be read goes to the newest commit nothing came
+    // after, or the one with the longest history of those made at once
+    if cursor::get(ink_root).is_err() {
+        let heads = graph.heads();
+        let depths = depths(&commits);
+        let newest = commits
+            .iter()
+            .filter(|commit| heads.contains(&commit.hash()))
+            .max_by_key(|commit| (commit.time(), depths[&commit.hash()]))
+            .ok_or("No commits could be recovered")?;
+        cursor::set(ink_root, newest)?;
+        report.cursor_reset = Some(newest.hash());
+    }
+
+    graph.write()?;
+
+    let mut index = ReverseIndex::default();
+    for commit in &commits {
+        index.add_commit(commit);
+    }
+    index.write(ink_root)?;
+
+    Ok(report)
+}
+
+/// How many commits are in the longest line of recovered ancestors of each
+/// commit, including itself
+fn depths(commits: &[Commit]) -> HashMap<[u8; 32], usize> {
+    let by_hash: HashMap<_, _> = commits.iter().map(|c| (c.hash(), c)).collect();
+    let mut depths = HashMap::new();
+
+    for commit in commits {
+        // walk down to commits whose parents are done, without recursing
+        let mut stack = vec![commit];
+        while let Some(&commit) = stack.last() {
+            if depths.contains_key(&commit.hash()) {
+                stack.pop();
+                continue;
+            }
+
+            let parents: Vec<_> = commit
+                .parents()
+                .iter()
+                .filter_map(|parent| by_hash.get(parent).copied())
+                .collect();
+            let pending: Vec<_> = parents
+                .iter()
+                .filter(|parent| !depths.contains_key(&parent.hash()))
+                .collect();
+
+            if pending.is_empty() {
+                let depth = parents.iter().map(|p| depths[&p.hash()]).max().unwrap_or(0);
+                depths.insert(commit.hash(), depth + 1);
+                stack.pop();
+            } else {
+                stack.extend(pending.into_iter().copied());
+            }
+        }
+    }
+
+    depths
+}
+
+/// Read a commit object, if it is named by a hash and can be read and checked
+fn read_commit(ink_root: &Path, path: &Path) -> Option<Commit> {
+    let name = path.file_name()?.to_str()?;
+    let hash: [u8; 32] = hex::decode(name).ok()?.try_into().ok()?;
+    Commit::from(&hash, ink_root).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testkit::TestRepo;
+    use crate::{CURSOR_FILE, DATA_EXT, GRAPH_FILE, INDEX_FILE};
+
+    #[test]
+    fn salvage_damaged_repository() {
+        let repo = TestRepo::new()
+            .file("a", "first")
+            .commit("first")
+            .file("a", "second")
+            .file("b", "b")
+            .commit("second")
+            .file("a", "third")
+            .commit("third");
+        let ink_dir = repo.ink_root();
+        let third = repo.head();
+        let second = Commit::from(&third.parents()[0], ink_dir).unwrap();
+        let first_hash = second.parents()[0];
+
+        // lose the first commit, the content of "b", and the graph, index
+        // and cursor
+        let commit_dir = ink_dir.join(COMMIT_EXT);
+        fs::remove_file(commit_dir.join(hex::encode(first_hash))).unwrap();
+        fs::write(commit_dir.join("garbage"), b"not a commit").unwrap();
+        let b = second
+            .files()
+            .iter()
+            .find(|file| file.path() == Path::new("b"))
+            .unwrap()
+            .content_hash();
+        fs::remove_file(ink_dir.join(DATA_EXT).join(hex::encode(b))).unwrap();
+        for file in &[GRAPH_FILE, INDEX_FILE, CURSOR_FILE] {
+            fs::write(ink_dir.join(file), b"garbage").unwrap();
+        }
+
+        let report = salvage(ink_dir).unwrap();
+        assert_eq!(report.commits, 3);
+        assert_eq!(report.unreadable_commits, vec![commit_dir.join("garbage")]);
+        assert_eq!(report.missing_parents, vec![(second.hash(), first_hash)]);
+        let mut missing_content = vec![
+            (second.hash(), PathBuf::from("b")),
+            (third.hash(), PathBuf::from("b")),
+        ];
+        missing_content.sort();
+        assert_eq!(report.missing_content, missing_content);
+        assert!(report.corrupt_content.is_empty());
+        assert_eq!(report.cursor_reset, Some(third.hash()));
+
+        assert_eq!(repo.head(), third);
+        let graph = CommitGraph::get(ink_dir).unwrap();
+        assert_eq!(graph.commit_hashes().len(), 3);
+        assert!(ReverseIndex::load(ink_dir).unwrap().is_some());
+    }
+}
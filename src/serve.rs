@@ -0,0 +1,346 @@
+//! A read-only web interface for browsing a repository, started by `ink serve --web`.
+//!
+//! Serves plain HTML over HTTP/1.1, one connection at a time:
+//! - `/` lists every commit in the graph, newest first
+//! - `/commit/<hash>` lists a commit's files and diffs it against its parents
+//!
+//! Diffs are rendered as tables with `diff::render::to_html`, and the page
+//! includes a small stylesheet for them.
+use crate::commit::{Commit, Edit};
+use crate::diff::render::{self, escape, HtmlOptions};
+use crate::diff::Diff;
+use crate::filedata::FileData;
+use crate::graph::CommitGraph;
+use crate::stats::StatsCache;
+use crate::storage::StoragePolicy;
+use crate::{utils, InkError};
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+/// Default address for `ink serve`, only reachable from this machine
+pub const DEFAULT_ADDR: &str = "127.0.0.1:8008";
+
+/// How long a client may take to send its request or read the response
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serve the web interface for the repository at `ink_root` until an IO error occurs
+pub fn web<A: ToSocketAddrs>(ink_root: &Path, addr: A) -> Result<(), InkError> {
+    let listener = TcpListener::bind(addr)?;
+
+    for stream in listener.incoming() {
+        // a client hanging up early is not the server's problem
+        let _ = handle(ink_root, stream?);
+    }
+
+    Ok(())
+}
+
+fn handle(ink_root: &Path, mut stream: TcpStream) -> Result<(), InkError> {
+    // connections are served one at a time, so a client that stops
+    // talking mustn't hold up the others for long
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // skip the headers, nothing in them changes the response
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => respond(ink_root, path),
+        _ => Response::error(405, "Method Not Allowed"),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason,
+        response.body.len()
+    )?;
+    stream.write_all(response.body.as_bytes())?;
+    Ok(())
+}
+
+/// A page to send back to the browser
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub reason: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    fn ok(title: &str, content: String) -> Response {
+        Response {
+            status: 200,
+            reason: "OK",
+            body: page(title, &content),
+        }
+    }
+
+    fn error(status: u16, reason: &'static str) -> Response {
+        Response {
+            status,
+            reason,
+            body: page(reason, &format!("<p>{}</p>", reason)),
+        }
+    }
+}
+
+/// Build the page for a request path
+pub fn respond(ink_root: &Path, path: &str) -> Response {
+    let result = match path.trim_end_matches('/') {
+        "" => index(ink_root),
+        path => match path.strip_prefix("/commit/") {
+            Some(hash) => commit_page(ink_root, hash),
+            None => return Response::error(404, "Not Found"),
+        },
+    };
+
+    match result {
+        Ok(Some(response)) => response,
+        Ok(None) => Response::error(404, "Not Found"),
+        Err(_) => Response::error(500, "Internal Server Error"),
+    }
+}
+
+fn index(ink_root: &Path) -> Result<Option<Response>, InkError> {
+    let graph = CommitGraph::get(ink_root)?;
+    let commits = graph
+        .commit_hashes()
+        .into_iter()
+        .map(|hash| Ok((*hash, Commit::from(hash, ink_root)?)))
+        .collect::<Result<HashMap<[u8; 32], Commit>, InkError>>()?;
+
+    // commits made in the same second are ordered children first
+    let mut generations = HashMap::new();
+    for hash in commits.keys() {
+        generation(hash, &commits, &mut generations);
+    }
+    let mut commits: Vec<Commit> = commits.into_values().collect();
+    commits.sort_by_key(|commit| Reverse((commit.time(), generations[&commit.hash()])));
+
+    let mut content = String::from("<h1>Commits</h1>\n<ul>\n");
+    for commit in commits {
+        content += &format!(
+            "<li><a href=\"/commit/{hash}\"><code>{short}</code></a> {message}</li>\n",
+            hash = hex::encode(commit.hash()),
+            short = &hex::encode(commit.hash())[..10],
+            message = escape(commit.message())
+        );
+    }
+    content += "</ul>\n";
+
+    Ok(Some(Response::ok("Commits", content)))
+}
+
+/// How many commits are between this one and the first commit
+fn generation(
+    hash: &[u8; 32],
+    commits: &HashMap<[u8; 32], Commit>,
+    generations: &mut HashMap<[u8; 32], usize>,
+) -> usize {
+    // walked with a stack of its own, as a long history would overflow the
+    // call stack
+    let mut stack = vec![*hash];
+    while let Some(&top) = stack.last() {
+        if generations.contains_key(&top) {
+            stack.pop();
+            continue;
+        }
+
+        let parents = commits.get(&top).map_or(&[][..], |commit| commit.parents());
+        let unknown: Vec<[u8; 32]> = parents
+            .iter()
+            .filter(|parent| !generations.contains_key(*parent))
+            .copied()
+            .collect();
+        if unknown.is_empty() {
+            let generation = parents
+                .iter()
+                .map(|parent| generations[parent] + 1)
+                .max()
+                .unwrap_or(0);
+            generations.insert(top, generation);
+            stack.pop();
+        } else {
+            stack.extend(unknown);
+        }
+    }
+
+    generations[hash]
+}
+
+fn commit_page(ink_root: &Path, hash: &str) -> Result<Option<Response>, InkError> {
+    let hash: [u8; 32] = match hex::decode(hash).ok().and_then(|h| h.try_into().ok()) {
+        Some(hash) => hash,
+        None => return Ok(None),
+    };
+    if !ink_root
+        .join(crate::COMMIT_EXT)
+        .join(hex::encode(hash))
+        .is_file()
+    {
+        return Ok(None);
+    }
+
+    let commit = Commit::from(&hash, ink_root)?;
+    let mut content = format!(
+        "<h1>{}</h1>\n<p><code>{}</code></p>\n",
+        escape(commit.message()),
+        hex::encode(hash)
+    );
+
+    content += "<h2>Files</h2>\n<ul>\n";
+    for file in commit.files() {
+        content += &format!(
+            "<li><code>{:o}</code> {}</li>\n",
+            file.permissions(),
+            escape(&file.path().display().to_string())
+        );
+    }
+    content += "</ul>\n";
+
+    let stats = StatsCache::open(ink_root)?;
+    let policy = StoragePolicy::load(ink_root)?;
+    for parent in commit.parents() {
+        let parent = Commit::from(parent, ink_root)?;
+        content += &format!(
+            "<h2>Changes from <a href=\"/commit/{hash}\"><code>{short}</code></a></h2>\n",
+            hash = hex::encode(parent.hash()),
+            short = &hex::encode(parent.hash())[..10]
+        );
+
+        let stat = stats.get(&parent, &commit)?;
+        content += &format!(
+            "<p>{} files changed, {} insertions, {} deletions</p>\n",
+            stat.files.len(),
+            stat.insertions(),
+            stat.deletions()
+        );
+
+        for edit in parent.diff(&commit).edits {
+            content += &file_diff(ink_root, &policy, &edit)?;
+        }
+    }
+
+    Ok(Some(Response::ok(commit.message(), content)))
+}
+
+/// Render the change to one file as an HTML diff
+fn file_diff(ink_root: &Path, policy: &StoragePolicy, edit: &Edit) -> Result<String, InkError> {
+    let (original, modified) = match edit {
+        Edit::Insert(file) => (None, Some(file)),
+        Edit::Delete(file) => (Some(file), None),
+        Edit::Modify { original, modified }
+        | Edit::TypeChange { original, modified }
+        | Edit::ChmodOnly { original, modified } => (Some(original), Some(modified)),
+    };
+
+    let original = lines(ink_root, policy, original)?;
+    let modified = lines(ink_root, policy, modified)?;
+    let path = edit.path().display().to_string();
+    let diff = Diff::from(&original, &modified);
+
+    Ok(format!(
+        "<h3>{} {}</h3>\n{}",
+        edit.status(),
+        escape(&path),
+        render::to_html(&diff, &original, &HtmlOptions::default())
+    ))
+}
+
+fn lines(
+    ink_root: &Path,
+    policy: &StoragePolicy,
+    file: Option<&FileData>,
+) -> Result<Vec<String>, InkError> {
+    Ok(match file {
+        Some(file) => utils::to_lines(&file.read_content_with(ink_root, policy)?),
+        None => vec![],
+    })
+}
+
+const STYLE: &str = "\
+.ink-diff { border-collapse: collapse; font-family: monospace; white-space: pre; }
+.ink-diff .number { color: #888; padding: 0 0.5em; text-align: right; }
+.ink-diff .hunk td { background: #eef; color: #558; }
+.ink-diff .removed { background: #fee; }
+.ink-diff .added { background: #efe; }
+.ink-diff .changed { background: #ffd; }
+";
+
+fn page(title: &str, content: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{} - ink</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        content
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Repository;
+    use std::fs;
+    use std::time::SystemTime;
+
+    #[test]
+    fn web_pages() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        fs::write(tmpdir_path.join("example"), b"one\n<two>\n").unwrap();
+        repo.commit("first", Default::default()).unwrap();
+        fs::write(tmpdir_path.join("example"), b"one\nthree\n").unwrap();
+        let second =
+            crate::testkit::created(repo.commit("second & last", Default::default())).commit;
+
+        let index = respond(&ink_dir, "/");
+        assert_eq!(index.status, 200);
+        let first_pos = index.body.find("first").unwrap();
+        let second_pos = index.body.find("second &amp; last").unwrap();
+        assert!(second_pos < first_pos);
+
+        let page = respond(&ink_dir, &format!("/commit/{}", hex::encode(second.hash())));
+        assert_eq!(page.status, 200);
+        assert!(page.body.contains("<h3>M example</h3>"));
+        assert!(page.body.contains("<td>&lt;two&gt;</td>"));
+        assert!(page.body.contains("<td>three</td>"));
+
+        assert_eq!(respond(&ink_dir, "/commit/abcd").status, 404);
+        assert_eq!(respond(&ink_dir, "/nothing").status, 404);
+    }
+
+    #[test]
+    fn long_history_generations() {
+        let mut commits = HashMap::new();
+        let mut parents = vec![];
+        for _ in 0..100_000 {
+            let commit = Commit::from_files(vec![], parents, SystemTime::UNIX_EPOCH, "").unwrap();
+            parents = vec![commit.hash()];
+            commits.insert(commit.hash(), commit);
+        }
+
+        let mut generations = HashMap::new();
+        assert_eq!(generation(&parents[0], &commits, &mut generations), 99_999);
+        assert_eq!(generations.len(), 100_000);
+    }
+}
@@ -0,0 +1,278 @@
+//! In-memory snapshots of a would-be commit
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::atomic;
+use crate::commit::{self, Commit, CommitDiff};
+use crate::filedata::FileData;
+use crate::filter::ContentFilters;
+use crate::ignore::IgnoreRules;
+use crate::large_files::LargeFiles;
+use crate::utils::WalkDir;
+use crate::InkError;
+
+/// A would-be commit held entirely in memory.
+///
+/// Holds the path, permissions and content hash of each file, but never writes
+/// to the object store. File content is only read from the working directory
+/// when asked for with `open`.
+#[derive(Debug)]
+pub struct TreeSnapshot {
+    project_dir: PathBuf,
+    files: Vec<FileData>,
+    // the index of each file by its path
+    paths: HashMap<PathBuf, usize>,
+}
+
+/// Builds a `TreeSnapshot` one file at a time.
+/// This should be created with `TreeSnapshot::builder()`
+pub struct TreeSnapshotBuilder<'a> {
+    ink_root: &'a Path,
+    project_dir: &'a Path,
+    dir_name: &'a str,
+    // loaded with the first file
+    filters: Option<ContentFilters>,
+    files: Vec<FileData>,
+}
+
+impl<'a> TreeSnapshotBuilder<'a> {
+    /// Hash a file in the working directory and add it to the snapshot
+    pub fn file<P: AsRef<Path>>(mut self, path: P) -> Result<Self, InkError> {
+        if self.filters.is_none() {
+            self.filters = Some(ContentFilters::load(self.ink_root, self.project_dir)?);
+        }
+
+        let filters = self.filters.as_ref().unwrap();
+        self.files
+            .push(FileData::new(path.as_ref(), self.dir_name, filters)?);
+        Ok(self)
+    }
+
+    /// Hash a series of files in the working directory and add them to the snapshot
+    pub fn files<P: AsRef<Path>>(mut self, paths: &[P]) -> Result<Self, InkError> {
+        for path in paths {
+            self = self.file(path)?;
+        }
+
+        Ok(self)
+    }
+
+    pub fn build(mut self) -> Result<TreeSnapshot, InkError> {
+        // same ordering a commit uses, so converting to one is free
+        self.files.sort();
+        let paths = self
+            .files
+            .iter()
+            .enumerate()
+            .map(|(index, file)| (file.path().to_path_buf(), index))
+            .collect();
+
+        Ok(TreeSnapshot {
+            project_dir: self.project_dir.to_path_buf(),
+            files: self.files,
+            paths,
+        })
+    }
+}
+
+impl TreeSnapshot {
+    /// Build a snapshot of files in `project_dir`, the working directory of
+    /// the repository in `ink_root`, whose ink directories are named `dir_name`
+    pub fn builder<'a>(
+        ink_root: &'a Path,
+        project_dir: &'a Path,
+        dir_name: &'a str,
+    ) -> TreeSnapshotBuilder<'a> {
+        TreeSnapshotBuilder {
+            ink_root,
+            project_dir,
+            dir_name,
+            filters: None,
+            files: Vec::new(),
+        }
+    }
+
+    /// Snapshot every file in the working directory, excluding the ink
+    /// directory, nested repositories, ignored paths, a large file store and
+    /// the temporary files a checkout writes before moving them into place.
+    /// Those are named `.ink-` and six random letters or digits, so a file
+    /// named like that is never committed.
+    pub fn from_worktree(
+        ink_root: &Path,
+        project_dir: &Path,
+        dir_name: &str,
+    ) -> Result<TreeSnapshot, InkError> {
+        let ignore = IgnoreRules::load(ink_root)?;
+        let store = large_store_within(ink_root, project_dir)?;
+
+        let walk = WalkDir::new(project_dir).links(true).exclude(|path| {
+            path.starts_with(ink_root)
+                || path.file_name() == Some(dir_name.as_ref())
+                || path.join(dir_name).is_dir()
+                || atomic::is_temp(path)
+                || store.as_ref().is_some_and(|store| path.starts_with(store))
+                || path
+                    .strip_prefix(project_dir)
+                    .is_ok_and(|path| ignore.is_ignored(path))
+        });
+
+        let mut builder = TreeSnapshot::builder(ink_root, project_dir, dir_name);
+        for path in walk {
+            builder = builder.file(path?)?;
+        }
+        builder.build()
+    }
+
+    pub fn files(&self) -> &[FileData] {
+        &self.files
+    }
+
+    /// Find a file in the snapshot by its path relative to the project directory
+    pub fn get(&self, path: &Path) -> Option<&FileData> {
+        self.paths.get(path).map(|&index| &self.files[index])
+    }
+
+    /// Open the working directory copy of a file in the snapshot for reading
+    pub fn open(&self, path: &Path) -> Result<File, InkError> {
+        let file = self.get(path).ok_or("Path is not part of the snapshot")?;
+        Ok(File::open(self.project_dir.join(file.path()))?)
+    }
+
+    /// Creates the diff to transform the given commit into this snapshot
+    pub fn diff_from(&self, commit: &Commit) -> CommitDiff {
+        commit::diff_files(commit.files(), &self.files)
+    }
+
+    /// Checks if the snapshot has the exact same files as the given commit
+    pub fn is_clean(&self, commit: &Commit) -> bool {
+        self.diff_from(commit).edits.is_empty()
+    }
+
+    /// Turn the snapshot into a commit with the given parents, timestamp and
+    /// message. Nothing is written to disk.
+    pub fn into_commit(
+        self,
+        parents: Vec<[u8; 32]>,
+        timestamp: SystemTime,
+        message: &str,
+    ) -> Result<Commit, InkError> {
+        Commit::from_files(self.files, parents, timestamp, message)
+    }
+}
+
+/// The large file store, as a path within `project_dir`, if there is one
+/// and it is in there
+pub(crate) fn large_store_within(
+    ink_root: &Path,
+    project_dir: &Path,
+) -> Result<Option<PathBuf>, InkError> {
+    let store = match LargeFiles::load(ink_root)?.store_dir(ink_root)? {
+        Some(store) => store,
+        None => return Ok(None),
+    };
+    // before anything is stored, the store may not exist yet
+    let (store, project) = match (store.canonicalize(), project_dir.canonicalize()) {
+        (Ok(store), Ok(project)) => (store, project),
+        _ => return Ok(None),
+    };
+
+    Ok(store
+        .strip_prefix(&project)
+        .ok()
+        .map(|within| project_dir.join(within)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+
+    #[test]
+    fn snapshot_matches_commit() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+
+        let ex_file_path = tmpdir_path.join("example");
+        fs::write(&ex_file_path, b"this is a test!").unwrap();
+
+        let time = SystemTime::now();
+        let snapshot = TreeSnapshot::from_worktree(&ink_dir, tmpdir_path, ".ink").unwrap();
+        let commit = Commit::new(vec![&ex_file_path], vec![], time, "", &ink_dir).unwrap();
+
+        assert!(snapshot.is_clean(&commit));
+
+        let mut content = String::new();
+        snapshot
+            .open(Path::new("example"))
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "this is a test!");
+
+        assert_eq!(snapshot.into_commit(vec![], time, "").unwrap(), commit);
+        assert_eq!(fs::read_dir(ink_dir.join("data")).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn snapshot_detects_changes() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+
+        let ex_file_path = tmpdir_path.join("example");
+        fs::write(&ex_file_path, b"this is a test!").unwrap();
+        let commit = TreeSnapshot::from_worktree(&ink_dir, tmpdir_path, ".ink")
+            .unwrap()
+            .into_commit(vec![], SystemTime::now(), "")
+            .unwrap();
+
+        fs::write(&ex_file_path, b"this is another test!").unwrap();
+        let snapshot = TreeSnapshot::from_worktree(&ink_dir, tmpdir_path, ".ink").unwrap();
+
+        assert!(!snapshot.is_clean(&commit));
+        assert_eq!(snapshot.diff_from(&commit).edits.len(), 1);
+    }
+
+    #[test]
+    fn snapshot_skips_metadata_and_ignored_paths() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = crate::init(tmpdir_path).unwrap();
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[ignore]\npaths = target *.o\n",
+        )
+        .unwrap();
+
+        for dir in &["src", "target/debug", "nested/src"] {
+            fs::create_dir_all(tmpdir_path.join(dir)).unwrap();
+        }
+        for file in &[
+            "src/main.rs",
+            "src/main.o",
+            "target/debug/ink",
+            "nested/src/lib.rs",
+            "src/.ink-a1b2c3",
+            "src/.ink-notes.md",
+        ] {
+            fs::write(tmpdir_path.join(file), b"").unwrap();
+        }
+        crate::init(&tmpdir_path.join("nested")).unwrap();
+
+        let snapshot = TreeSnapshot::from_worktree(&ink_dir, tmpdir_path, ".ink").unwrap();
+        let mut paths: Vec<&Path> = snapshot.files().iter().map(|f| f.path()).collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            vec![Path::new("src/.ink-notes.md"), Path::new("src/main.rs")]
+        );
+        assert!(snapshot.get(Path::new("src/main.rs")).is_some());
+        assert!(snapshot.get(Path::new("src/.ink-a1b2c3")).is_none());
+    }
+}
@@ -0,0 +1,266 @@
+//! Counts of the lines changed between commits, and a cache of them.
+//!
+//! Counting means diffing every changed file, so the counts for pairs of
+//! commits are cached in `.ink/cache/stats`. Entries are named by a hash of
+//! the two commit hashes, and commit hashes cover every object in them, so an
+//! entry can never go stale: a change anywhere gives a different pair. The
+//! cache is kept under `max-size` bytes (1MB by default) from the `[cache]`
+//! config section, removing the least recently used entries first.
+use crate::commit::{Commit, LineDiff};
+use crate::config::Config;
+use crate::{atomic, InkError, CACHE_DIR};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+const STATS_DIR: &str = "stats";
+const DEFAULT_MAX_SIZE: u64 = 1024 * 1024;
+
+/// The lines changed in one file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStat {
+    #[serde(with = "crate::paths::escaped")]
+    pub path: PathBuf,
+    /// As given by `Edit::status`
+    pub status: char,
+    pub insertions: usize,
+    pub deletions: usize,
+}
+
+/// The lines changed in every changed file, ordered by path
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiffStat {
+    pub files: Vec<FileStat>,
+}
+
+impl DiffStat {
+    pub fn from_line_diffs(line_diffs: &[LineDiff]) -> DiffStat {
+        let files = line_diffs
+            .iter()
+            .map(|line_diff| {
+                let edits = line_diff.diff.edits();
+                FileStat {
+                    path: line_diff.path.clone(),
+                    status: line_diff.status,
+                    insertions: edits.iter().map(|edit| edit.modified.content.len()).sum(),
+                    deletions: edits.iter().map(|edit| edit.original.content.len()).sum(),
+                }
+            })
+            .collect();
+
+        DiffStat { files }
+    }
+
+    pub fn insertions(&self) -> usize {
+        self.files.iter().map(|file| file.insertions).sum()
+    }
+
+    pub fn deletions(&self) -> usize {
+        self.files.iter().map(|file| file.deletions).sum()
+    }
+}
+
+/// A cached stat, with the pair it is for to check against
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    from: [u8; 32],
+    to: [u8; 32],
+    stat: DiffStat,
+}
+
+/// What `StatsCache::check` found
+#[derive(Debug, Default)]
+pub(crate) struct CacheCheck {
+    pub entries: usize,
+    pub size: u64,
+    pub max_size: u64,
+    pub inconsistent: Vec<PathBuf>,
+}
+
+pub struct StatsCache {
+    ink_root: PathBuf,
+    max_size: u64,
+}
+
+impl StatsCache {
+    pub fn open(ink_root: &Path) -> Result<StatsCache, InkError> {
+        let max_size = match Config::load(ink_root)?.get("cache", "max-size") {
+            Some(max_size) => max_size
+                .parse()
+                .map_err(|_| "cache max-size must be a number of bytes")?,
+            None => DEFAULT_MAX_SIZE,
+        };
+
+        Ok(StatsCache {
+            ink_root: ink_root.to_path_buf(),
+            max_size,
+        })
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.ink_root.join(CACHE_DIR).join(STATS_DIR)
+    }
+
+    fn entry_path(&self, from: &[u8; 32], to: &[u8; 32]) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(from);
+        hasher.update(to);
+        self.dir().join(hex::encode(hasher.finalize()))
+    }
+
+    /// The lines changed going from one commit to another, computing and
+    /// caching them if they aren't cached already
+    pub fn get(&self, from: &Commit, to: &Commit) -> Result<DiffStat, InkError> {
+        let path = self.entry_path(&from.hash(), &to.hash());
+
+        // an unreadable entry is just recomputed
+        if let Ok(bytes) = fs::read(&path) {
+            if let Ok(entry) = bincode::deserialize::<Entry>(&bytes) {
+                if entry.from == from.hash() && entry.to == to.hash() {
+                    // mark it as recently used
+                    fs::File::options()
+                        .write(true)
+                        .open(&path)?
+                        .set_modified(SystemTime::now())?;
+                    return Ok(entry.stat);
+                }
+            }
+        }
+
+        let line_diffs = from
+            .diff(to)
+            .compute_line_diffs(&self.ink_root, None, true)?
+            .collect::<Result<Vec<_>, _>>()?;
+        let entry = Entry {
+            from: from.hash(),
+            to: to.hash(),
+            stat: DiffStat::from_line_diffs(&line_diffs),
+        };
+
+        // written elsewhere then moved, so a reader never sees half an entry
+        fs::create_dir_all(self.dir())?;
+        let mut tmp = tempfile::NamedTempFile::new_in(self.dir())?;
+        tmp.write_all(&bincode::serialize(&entry)?)?;
+        atomic::persist(tmp.into_temp_path(), &path)?;
+
+        self.evict()?;
+        Ok(entry.stat)
+    }
+
+    /// Count the entries, their size, and those that can't be read or are
+    /// named for a different pair than they hold
+    pub(crate) fn check(&self) -> Result<CacheCheck, InkError> {
+        let mut check = CacheCheck {
+            max_size: self.max_size,
+            ..CacheCheck::default()
+        };
+        let entries = match fs::read_dir(self.dir()) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(check),
+            Err(err) => return Err(err.into()),
+        };
+
+        for entry in entries {
+            let path = entry?.path();
+            check.entries += 1;
+            check.size += fs::metadata(&path)?.len();
+            let consistent = fs::read(&path)
+                .ok()
+                .and_then(|bytes| bincode::deserialize::<Entry>(&bytes).ok())
+                .is_some_and(|entry| self.entry_path(&entry.from, &entry.to) == path);
+            if !consistent {
+                check.inconsistent.push(path);
+            }
+        }
+
+        check.inconsistent.sort();
+        Ok(check)
+    }
+
+    /// Remove the least recently used entries until the cache fits in its size
+    fn evict(&self) -> Result<(), InkError> {
+        let mut entries = Vec::new();
+        let mut size = 0;
+        for entry in fs::read_dir(self.dir())? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            size += metadata.len();
+            entries.push((metadata.modified()?, metadata.len(), entry.path()));
+        }
+
+        entries.sort();
+        for (_, len, path) in entries {
+            if size <= self.max_size {
+                break;
+            }
+
+            fs::remove_file(path)?;
+            size -= len;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Repository, CONFIG_FILE};
+    use std::time::Duration;
+
+    #[test]
+    fn cached_stats() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let mut commits = vec![repo.current_commit().unwrap()];
+        for content in &["one\ntwo\n", "one\n2\nthree\n", "three\n"] {
+            fs::write(tmpdir_path.join("file"), content).unwrap();
+            commits.push(crate::testkit::created(repo.commit("", Default::default())).commit);
+        }
+
+        let cache = StatsCache::open(&ink_dir).unwrap();
+        let stat = cache.get(&commits[1], &commits[2]).unwrap();
+        assert_eq!(
+            stat.files,
+            vec![FileStat {
+                path: PathBuf::from("file"),
+                status: 'M',
+                insertions: 2,
+                deletions: 1,
+            }]
+        );
+        assert_eq!(cache.get(&commits[1], &commits[2]).unwrap(), stat);
+        assert_eq!(fs::read_dir(cache.dir()).unwrap().count(), 1);
+
+        // a corrupt entry is recomputed rather than trusted
+        let path = cache.entry_path(&commits[1].hash(), &commits[2].hash());
+        fs::write(&path, b"garbage").unwrap();
+        assert_eq!(cache.get(&commits[1], &commits[2]).unwrap(), stat);
+
+        // with room for only one entry, older ones are evicted. The old
+        // entry's time is set rather than waited out, as mtimes can be coarse
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000))
+            .unwrap();
+        fs::write(ink_dir.join(CONFIG_FILE), "[cache]\nmax-size = 150\n").unwrap();
+        let cache = StatsCache::open(&ink_dir).unwrap();
+        let stat = cache.get(&commits[0], &commits[3]).unwrap();
+        assert_eq!((stat.insertions(), stat.deletions()), (1, 0));
+        assert!(!path.exists());
+        assert!(cache
+            .entry_path(&commits[0].hash(), &commits[3].hash())
+            .exists());
+        assert_eq!(fs::read_dir(cache.dir()).unwrap().count(), 1);
+    }
+}
@@ -0,0 +1,513 @@
+//! Choosing which content is stored uncompressed.
+//!
+//! Already compressed formats gain nothing from being deflated again. Set in
+//! the `[storage]` section of the config:
+//! - `uncompressed`, a list of file extensions separated by spaces or commas,
+//!   e.g. `png, jpg, zip, mp4`
+//! - `sniff = true`, to also store content that looks random (and so is most
+//!   likely compressed already) uncompressed
+//! - `verify = true`, to hash content whenever it is read back and fail if it
+//!   doesn't match, at the cost of slower reads
+//! - `durability`, how much is synced to disk as it is written: `none`,
+//!   `metadata` (the default) or `full`. See `Durability`.
+//!
+//! Uncompressed content is kept as `.ink/data/<hash>.raw`. Content in the large
+//! file store is always compressed. Hashes don't depend on how content is
+//! stored, so changing these settings doesn't change any commits.
+//!
+//! Every content object is written by an `ObjectWriter`, wherever the content
+//! comes from.
+use crate::config::Config;
+use crate::identity::IdHasher;
+use crate::large_files::LargeFiles;
+use crate::metrics::Timings;
+use crate::{atomic, objects, utils};
+use crate::{InkError, DATA_EXT, RAW_EXT};
+
+use libflate::deflate::Encoder;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+use tempfile::NamedTempFile;
+
+/// How much of the content is looked at when sniffing
+const SNIFF_LEN: usize = 64 * 1024;
+/// Bits of entropy per byte above which content counts as compressed
+const COMPRESSED_ENTROPY: f64 = 7.5;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StoragePolicy {
+    uncompressed: Vec<String>,
+    sniff: bool,
+    verify: bool,
+    durability: Durability,
+}
+
+impl StoragePolicy {
+    pub fn load(ink_root: &Path) -> Result<StoragePolicy, InkError> {
+        StoragePolicy::from_config(&Config::load(ink_root)?)
+    }
+
+    pub fn from_config(config: &Config) -> Result<StoragePolicy, InkError> {
+        let uncompressed = config
+            .get("storage", "uncompressed")
+            .unwrap_or("")
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|extension| !extension.is_empty())
+            .map(|extension| extension.trim_start_matches('.').to_lowercase())
+            .collect();
+
+        let sniff = match config.get("storage", "sniff") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(_) => return Err("storage sniff must be true or false".into()),
+        };
+
+        let verify = match config.get("storage", "verify") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(_) => return Err("storage verify must be true or false".into()),
+        };
+
+        Ok(StoragePolicy {
+            uncompressed,
+            sniff,
+            verify,
+            durability: Durability::from_config(config)?,
+        })
+    }
+
+    /// Whether stored content is checked against its hash when read
+    pub fn verify(&self) -> bool {
+        self.verify
+    }
+
+    pub fn durability(&self) -> Durability {
+        self.durability
+    }
+
+    /// Whether content at a path should be compressed, given the start of it.
+    /// Only the first 64KB are sniffed.
+    pub fn compress(&self, path: &Path, start: &[u8]) -> bool {
+        let extension = path
+            .extension()
+            .map(|extension| extension.to_string_lossy().to_lowercase());
+        if let Some(extension) = extension {
+            if self.uncompressed.contains(&extension) {
+                return false;
+            }
+        }
+
+        !(self.sniff && entropy(&start[..start.len().min(SNIFF_LEN)]) > COMPRESSED_ENTROPY)
+    }
+}
+
+/// How much of what the repository writes is synced to disk before it is
+/// relied on. Without syncing, a power loss can lose anything the system
+/// hadn't written out yet, even files a finished commit needs.
+///
+/// `metadata` is the default. Committing a thousand small files to ext4 on a
+/// virtual disk took about 1.5 times as long as with `none`, and `full` took
+/// about 1.3 times as long again.
+/// `cargo run --release --example write_bench` measures it on other disks.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Durability {
+    /// Nothing is synced, and the system writes files out when it chooses.
+    /// After a power loss, recent files may be missing, empty or cut short.
+    None,
+    /// Files are synced with `sync_data`, their content and the metadata
+    /// needed to read it back, before they are moved into place, so none is
+    /// found empty or cut short. The latest writes may still be lost, since
+    /// the directories they were moved into aren't synced.
+    #[default]
+    Metadata,
+    /// Files are synced with `sync_all`, and so are the directories they are
+    /// moved into, so a commit that finished survives a power loss.
+    Full,
+}
+
+impl Durability {
+    pub fn load(ink_root: &Path) -> Result<Durability, InkError> {
+        Durability::from_config(&Config::load(ink_root)?)
+    }
+
+    pub fn from_config(config: &Config) -> Result<Durability, InkError> {
+        match config.get("storage", "durability") {
+            None => Ok(Durability::default()),
+            Some("none") => Ok(Durability::None),
+            Some("metadata") => Ok(Durability::Metadata),
+            Some("full") => Ok(Durability::Full),
+            Some(_) => Err("storage durability must be none, metadata or full".into()),
+        }
+    }
+
+    /// Sync a file that was written through `file`
+    pub(crate) fn sync(self, file: &File) -> io::Result<()> {
+        match self {
+            Durability::None => Ok(()),
+            Durability::Metadata => file.sync_data(),
+            Durability::Full => file.sync_all(),
+        }
+    }
+
+    /// Sync the directory `path` is in, so a file created or moved there
+    /// stays. Only done for `Full`, and only where directories can be opened
+    /// to sync.
+    pub(crate) fn sync_dir(self, path: &Path) -> io::Result<()> {
+        if self != Durability::Full || !cfg!(unix) {
+            return Ok(());
+        }
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+
+    /// Replace the file at `path` with `contents`. With `None` it is written
+    /// straight over, and otherwise through a synced temporary file that is
+    /// moved into place, so it is never seen half written.
+    pub(crate) fn write(self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if self == Durability::None {
+            return fs::write(path, contents);
+        }
+
+        let mut tmp = atomic::temp_file_beside(path)?;
+        tmp.write_all(contents)?;
+        self.sync(tmp.as_file())?;
+        atomic::persist(tmp.into_temp_path(), path)?;
+        self.sync_dir(path)
+    }
+}
+
+/// Writes one content object. What is written is hashed as it is spooled to
+/// a temporary file in the data directory, and `finish` checks the hash
+/// before moving the object into the store. Nothing is in the store until
+/// then, so a write that fails part way, or is dropped, leaves no object.
+pub struct ObjectWriter<'a> {
+    ink_root: &'a Path,
+    policy: &'a StoragePolicy,
+    large_files: Option<&'a LargeFiles>,
+    timings: Option<&'a Timings>,
+    spool: BufWriter<NamedTempFile>,
+    hasher: IdHasher,
+    size: u64,
+    /// The start of the content, to sniff
+    start: Vec<u8>,
+}
+
+impl<'a> ObjectWriter<'a> {
+    pub fn new(
+        ink_root: &'a Path,
+        policy: &'a StoragePolicy,
+    ) -> Result<ObjectWriter<'a>, InkError> {
+        Ok(ObjectWriter {
+            ink_root,
+            policy,
+            large_files: None,
+            timings: None,
+            spool: BufWriter::new(temp_object(&ink_root.join(DATA_EXT))?),
+            hasher: IdHasher::new(),
+            size: 0,
+            start: Vec::new(),
+        })
+    }
+
+    /// Put content over the large file threshold in the large file store,
+    /// rather than always in the data directory
+    pub fn large_files(mut self, large_files: &'a LargeFiles) -> ObjectWriter<'a> {
+        self.large_files = Some(large_files);
+        self
+    }
+
+    /// Add the time spent hashing and compressing to `timings`. Compressing
+    /// counts reading the spooled content back and writing it compressed.
+    pub(crate) fn timings(mut self, timings: &'a Timings) -> ObjectWriter<'a> {
+        self.timings = Some(timings);
+        self
+    }
+
+    /// Store what was written, compressed as the policy says for content at
+    /// `path`, unless it is stored already. Fails if the content doesn't have
+    /// the `expected` hash, when one is given. Returns the hash, and the bytes
+    /// the new object takes up or `None` if it was already stored.
+    pub fn finish(
+        self,
+        path: &Path,
+        expected: Option<&[u8; 32]>,
+    ) -> Result<([u8; 32], Option<u64>), InkError> {
+        let hash = self.hasher.finish();
+        if expected.is_some_and(|expected| *expected != hash) {
+            return Err("Content has changed while being stored".into());
+        }
+
+        if objects::object_location(self.ink_root, &hash).is_ok() {
+            return Ok((hash, None));
+        }
+
+        let data_path = self.ink_root.join(DATA_EXT).join(hex::encode(hash));
+        let (target, compress) = match self.large_files {
+            Some(large_files) if large_files.is_large(self.size) => {
+                (large_files.prepare(self.ink_root, &hash, self.size)?, true)
+            }
+            _ if self.policy.compress(path, &self.start) => (data_path, true),
+            _ => (data_path.with_extension(RAW_EXT), false),
+        };
+
+        let mut spool = self.spool.into_inner().map_err(|err| err.into_error())?;
+        let object = if compress {
+            let dir = target.parent().ok_or("Path has no parent directory")?;
+            let mut object = temp_object(dir)?;
+            spool.seek(SeekFrom::Start(0))?;
+            let started = Instant::now();
+            let mut encoder = Encoder::new(BufWriter::new(&mut object));
+            io::copy(&mut spool, &mut encoder)?;
+            encoder.finish().into_result()?.flush()?;
+            if let Some(timings) = self.timings {
+                timings.compressed(started.elapsed());
+            }
+            object
+        } else {
+            spool
+        };
+
+        self.policy.durability().sync(object.as_file())?;
+        atomic::persist(object.into_temp_path(), &target)?;
+        self.policy.durability().sync_dir(&target)?;
+        // stored content is never changed, only removed
+        utils::set_read_only(&target)?;
+        Ok((hash, Some(target.metadata()?.len())))
+    }
+}
+
+impl Write for ObjectWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.spool.write(buf)?;
+        let buf = &buf[..written];
+
+        let started = Instant::now();
+        self.hasher.bytes(buf);
+        if let Some(timings) = self.timings {
+            timings.hashed(started.elapsed());
+        }
+        self.size += written as u64;
+        let sniffed = (SNIFF_LEN - self.start.len()).min(written);
+        self.start.extend_from_slice(&buf[..sniffed]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.spool.flush()
+    }
+}
+
+/// A temporary file for an object being written. Its name starts with a dot,
+/// so it is never taken for an object if it is left behind.
+fn temp_object(dir: &Path) -> Result<NamedTempFile, InkError> {
+    Ok(tempfile::Builder::new()
+        .prefix(atomic::TEMP_PREFIX)
+        .tempfile_in(dir)?)
+}
+
+/// Shannon entropy of the bytes, in bits per byte
+fn entropy(bytes: &[u8]) -> f64 {
+    if bytes.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0usize; 256];
+    for &byte in bytes {
+        counts[byte as usize] += 1;
+    }
+
+    let len = bytes.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storage_policy() {
+        let config =
+            Config::parse("[storage]\nuncompressed = png, .ZIP mp4\nsniff = true\n").unwrap();
+        let policy = StoragePolicy::from_config(&config).unwrap();
+
+        assert!(!policy.compress(Path::new("images/logo.PNG"), b""));
+        assert!(!policy.compress(Path::new("archive.zip"), b""));
+        assert!(policy.compress(Path::new("main.rs"), b"fn main() {}\n"));
+
+        let random: Vec<u8> = (0..4096u32)
+            .map(|i| (i.wrapping_mul(2654435761) >> 13) as u8)
+            .collect();
+        assert!(!policy.compress(Path::new("data.bin"), &random));
+        assert!(StoragePolicy::default().compress(Path::new("data.bin"), &random));
+
+        let config = Config::parse("[storage]\nsniff = maybe\n").unwrap();
+        assert!(StoragePolicy::from_config(&config).is_err());
+
+        assert!(!policy.verify());
+        let config = Config::parse("[storage]\nverify = true\n").unwrap();
+        assert!(StoragePolicy::from_config(&config).unwrap().verify());
+    }
+
+    #[test]
+    fn durability() {
+        assert_eq!(policy_durability(""), Durability::Metadata);
+        assert_eq!(policy_durability("durability = none"), Durability::None);
+        assert_eq!(policy_durability("durability = full"), Durability::Full);
+        let config = Config::parse("[storage]\ndurability = lots\n").unwrap();
+        assert!(StoragePolicy::from_config(&config).is_err());
+
+        // every level writes the same repository
+        for level in &["none", "metadata", "full"] {
+            let repo = crate::testkit::TestRepo::new();
+            std::fs::write(
+                repo.ink_root().join(crate::CONFIG_FILE),
+                format!("[storage]\ndurability = {}\n", level),
+            )
+            .unwrap();
+            let repo = repo.file("a", "first").commit("first");
+            let repo = repo.file("a", "second").commit("second");
+
+            assert_eq!(repo.head().message(), "second");
+            assert_eq!(
+                repo.head().files()[0]
+                    .read_content(repo.ink_root())
+                    .unwrap(),
+                b"second"
+            );
+            let left = std::fs::read_dir(repo.ink_root())
+                .unwrap()
+                .chain(std::fs::read_dir(repo.ink_root().join(crate::DATA_EXT)).unwrap())
+                .filter(|entry| atomic::is_temp(&entry.as_ref().unwrap().path()))
+                .count();
+            assert_eq!(left, 0, "{}", level);
+        }
+    }
+
+    fn policy_durability(storage: &str) -> Durability {
+        let config = Config::parse(&format!("[storage]\n{}\n", storage)).unwrap();
+        StoragePolicy::from_config(&config).unwrap().durability()
+    }
+
+    #[test]
+    fn stores_uncompressed() {
+        let tmpdir = crate::testkit::temp_dir();
+        let tmpdir_path = tmpdir.path();
+        let ink_dir = tmpdir_path.join(".ink");
+        crate::init(tmpdir_path).unwrap();
+        std::fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[storage]\nuncompressed = bin\n",
+        )
+        .unwrap();
+        let repo = crate::Repository::open(&ink_dir).unwrap();
+
+        std::fs::write(tmpdir_path.join("image.bin"), b"not really compressed").unwrap();
+        std::fs::write(tmpdir_path.join("notes.txt"), b"plain text").unwrap();
+        let commit = crate::testkit::created(repo.commit("first", Default::default())).commit;
+
+        let image = &commit.files()[0];
+        let raw_path = ink_dir
+            .join(crate::DATA_EXT)
+            .join(hex::encode(image.content_hash()))
+            .with_extension(crate::RAW_EXT);
+        assert_eq!(std::fs::read(raw_path).unwrap(), b"not really compressed");
+        assert_eq!(
+            image.read_content(&ink_dir).unwrap(),
+            b"not really compressed"
+        );
+
+        let objects = crate::objects::inspect(&ink_dir).unwrap();
+        assert_eq!(objects.len(), 2);
+        let raw = objects
+            .iter()
+            .find(|o| o.hash == image.content_hash())
+            .unwrap();
+        assert_eq!((raw.stored_size, raw.size), (21, 21));
+    }
+
+    /// Gives its content a few bytes at a time, then fails if `fail` is set
+    struct Flaky<'a> {
+        content: &'a [u8],
+        fail: bool,
+    }
+
+    impl io::Read for Flaky<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.content.is_empty() && self.fail {
+                return Err(io::Error::other("injected failure"));
+            }
+
+            let len = self.content.len().min(buf.len()).min(3);
+            buf[..len].copy_from_slice(&self.content[..len]);
+            self.content = &self.content[len..];
+            Ok(len)
+        }
+    }
+
+    #[test]
+    fn object_writes_are_all_or_nothing() {
+        let repo = crate::testkit::TestRepo::new();
+        let ink_dir = repo.ink_root();
+        let data_dir = ink_dir.join(DATA_EXT);
+        let policy = StoragePolicy::default();
+        let entries = || std::fs::read_dir(&data_dir).unwrap().count();
+        let content = b"content written in pieces";
+        let hash = crate::identity::content_id(content);
+
+        // failing part way through, or being dropped, stores nothing
+        let mut writer = ObjectWriter::new(ink_dir, &policy).unwrap();
+        let mut flaky = Flaky {
+            content,
+            fail: true,
+        };
+        assert!(io::copy(&mut flaky, &mut writer).is_err());
+        drop(writer);
+        assert_eq!(entries(), 0);
+
+        // nor does content that doesn't match what was expected
+        let mut writer = ObjectWriter::new(ink_dir, &policy).unwrap();
+        writer.write_all(b"something else").unwrap();
+        assert!(writer.finish(Path::new("file"), Some(&hash)).is_err());
+        assert_eq!(entries(), 0);
+
+        // a temporary file left by a crash isn't taken for an object
+        let writer = ObjectWriter::new(ink_dir, &policy).unwrap();
+        std::mem::forget(writer);
+        assert!(crate::objects::inspect(ink_dir).unwrap().is_empty());
+        assert!(crate::objects::fsck(ink_dir).unwrap().is_empty());
+
+        let mut writer = ObjectWriter::new(ink_dir, &policy).unwrap();
+        let mut flaky = Flaky {
+            content,
+            fail: false,
+        };
+        io::copy(&mut flaky, &mut writer).unwrap();
+        let (stored, bytes) = writer.finish(Path::new("file"), Some(&hash)).unwrap();
+        assert_eq!(stored, hash);
+        assert!(bytes.is_some());
+        assert_eq!(
+            crate::objects::read_object(ink_dir, &hash).unwrap(),
+            content
+        );
+
+        let mut writer = ObjectWriter::new(ink_dir, &policy).unwrap();
+        writer.write_all(content).unwrap();
+        assert_eq!(
+            writer.finish(Path::new("file"), None).unwrap(),
+            (hash, None)
+        );
+        assert!(crate::objects::fsck(ink_dir).unwrap().is_empty());
+    }
+}
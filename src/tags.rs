@@ -0,0 +1,309 @@
+//! Names given to commits, and describing commits relative to them.
+//! Each tag is a file in `.ink/tags` holding the hash of its commit.
+//!
+//! Tags can be protected by `[tag "<pattern>"]` config sections, where the
+//! pattern is a glob matched against tag names:
+//!
+//! ```text
+//! [tag "release-*"]
+//! force-move = false
+//! delete = false
+//! ```
+use crate::commit::Commit;
+use crate::config::Config;
+use crate::graph::{render, CommitGraph};
+use crate::history::History;
+use crate::{utils, InkError, TAGS_DIR};
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Tag names are kept to what git allows in a ref name and no shell or file
+/// system treats specially: ASCII letters, digits and `.`, `_`, `-` and `+`,
+/// not starting with `.` or `-` and without `..`
+fn check_name(name: &str) -> Result<(), InkError> {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '+');
+    if name.is_empty()
+        || name.starts_with('.')
+        || name.starts_with('-')
+        || name.contains("..")
+        || !name.chars().all(allowed)
+    {
+        return Err("Tag names are letters, digits, '.', '_', '-' and '+', \
+                    and can't start with '.' or '-' or contain '..'"
+            .into());
+    }
+
+    Ok(())
+}
+
+/// What may be done to a tag beyond moving it forward
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TagPolicy {
+    /// Move it with `force` to a commit off its history
+    pub force_move: bool,
+    pub delete: bool,
+}
+
+impl Default for TagPolicy {
+    fn default() -> TagPolicy {
+        TagPolicy {
+            force_move: true,
+            delete: true,
+        }
+    }
+}
+
+impl TagPolicy {
+    /// The policy for a tag name, from every config section matching it,
+    /// later sections overriding earlier ones
+    pub fn load(ink_root: &Path, name: &str) -> Result<TagPolicy, InkError> {
+        TagPolicy::from_config(&Config::load(ink_root)?, name)
+    }
+
+    pub fn from_config(config: &Config, name: &str) -> Result<TagPolicy, InkError> {
+        let mut policy = TagPolicy::default();
+        for section in config.sections("tag") {
+            let pattern = section.label().ok_or("Tag sections need a name pattern")?;
+            if !utils::glob_match(pattern, Path::new(name)) {
+                continue;
+            }
+
+            for (key, allowed) in &mut [
+                ("force-move", &mut policy.force_move),
+                ("delete", &mut policy.delete),
+            ] {
+                match section.get(key) {
+                    None => {}
+                    Some("true") => **allowed = true,
+                    Some("false") => **allowed = false,
+                    Some(_) => return Err("Tag policies must be true or false".into()),
+                }
+            }
+        }
+
+        Ok(policy)
+    }
+}
+
+/// Tag a commit, replacing any tag with the same name
+pub fn create(ink_root: &Path, name: &str, commit: &Commit) -> Result<(), InkError> {
+    check_name(name)?;
+    fs::create_dir_all(ink_root.join(TAGS_DIR))?;
+    fs::write(ink_root.join(TAGS_DIR).join(name), commit.hash())?;
+    Ok(())
+}
+
+/// Tag a commit, refusing to move an existing tag to a commit that doesn't
+/// have its old one in its history unless `force` is set, so commits aren't
+/// left untagged without notice
+pub fn update(ink_root: &Path, name: &str, commit: &Commit, force: bool) -> Result<(), InkError> {
+    if let Some(old) = get(ink_root, name)? {
+        if !CommitGraph::get(ink_root)?.is_ancestor(&old, &commit.hash()) {
+            if !force {
+                return Err(
+                    "The tag would move off its commit's history, which --force allows".into(),
+                );
+            }
+            if !TagPolicy::load(ink_root, name)?.force_move {
+                return Err("The tag is protected from moving off its commit's history".into());
+            }
+        }
+    }
+
+    create(ink_root, name, commit)
+}
+
+/// Remove a tag, unless its policy protects it
+pub fn delete(ink_root: &Path, name: &str) -> Result<(), InkError> {
+    if get(ink_root, name)?.is_none() {
+        return Err("No tag with that name".into());
+    }
+    if !TagPolicy::load(ink_root, name)?.delete {
+        return Err("The tag is protected from deletion".into());
+    }
+
+    fs::remove_file(ink_root.join(TAGS_DIR).join(name))?;
+    Ok(())
+}
+
+/// The hash of the commit with the given tag, if there is one
+pub fn get(ink_root: &Path, name: &str) -> Result<Option<[u8; 32]>, InkError> {
+    check_name(name)?;
+    let path = ink_root.join(TAGS_DIR).join(name);
+    if !path.is_file() {
+        return Ok(None);
+    }
+
+    Ok(Some(read_hash(&path)?))
+}
+
+/// Every tag and the hash of its commit, ordered by name
+pub fn list(ink_root: &Path) -> Result<Vec<(String, [u8; 32])>, InkError> {
+    let dir = ink_root.join(TAGS_DIR);
+    if !dir.is_dir() {
+        return Ok(vec![]);
+    }
+
+    let mut tags = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|_| "Tag name is not valid unicode")?;
+        tags.push((name, read_hash(&entry.path())?));
+    }
+
+    tags.sort();
+    Ok(tags)
+}
+
+fn read_hash(path: &Path) -> Result<[u8; 32], InkError> {
+    fs::read(path)?.try_into().map_err(|_| InkError::Malformed {
+        path: path.to_path_buf(),
+        problem: "a tag must be the hash of a commit",
+    })
+}
+
+/// A commit named by the closest tag in its history
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Description {
+    pub tag: String,
+    /// Commits in the history of the described commit but not of the tagged one
+    pub distance: usize,
+    pub hash: [u8; 32],
+}
+
+impl fmt::Display for Description {
+    /// `<tag>` for a tagged commit, `<tag>-<distance>-g<short hash>` otherwise
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.distance == 0 {
+            return write!(f, "{}", self.tag);
+        }
+
+        write!(
+            f,
+            "{}-{}-g{}",
+            self.tag,
+            self.distance,
+            render::short_hash(&self.hash)
+        )
+    }
+}
+
+/// Describe a commit by the most recent tagged commit in its history.
+/// Returns `None` if none of its ancestors are tagged.
+pub fn describe(ink_root: &Path, commit: Commit) -> Result<Option<Description>, InkError> {
+    let tags = list(ink_root)?;
+    if tags.is_empty() {
+        return Ok(None);
+    }
+
+    let hash = commit.hash();
+    for ancestor in History::new(ink_root, commit) {
+        let ancestor = ancestor?;
+
+        // several tags on one commit: use the first by name
+        if let Some((tag, _)) = tags.iter().find(|(_, tagged)| *tagged == ancestor.hash()) {
+            // the tagged commit's history is all part of the described one's
+            let tag_history = count(History::new(ink_root, ancestor))?;
+            let history = count(History::new(ink_root, Commit::from(&hash, ink_root)?))?;
+
+            return Ok(Some(Description {
+                tag: tag.clone(),
+                distance: history - tag_history,
+                hash,
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+fn count(mut history: History) -> Result<usize, InkError> {
+    history.try_fold(0, |count, commit| commit.map(|_| count + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{cursor, Repository};
+
+    #[test]
+    fn describe_from_tags() {
+        let tmpdir = crate::testkit::temp_dir();
+        let ink_dir = tmpdir.path().join(".ink");
+        crate::init(tmpdir.path()).unwrap();
+        let repo = Repository::open(&ink_dir).unwrap();
+
+        let empty = cursor::get(&ink_dir).unwrap();
+        let empty_hash = empty.hash();
+        assert_eq!(describe(&ink_dir, empty).unwrap(), None);
+
+        let tagged = crate::testkit::created(
+            repo.commit("first", crate::CommitOptions::new().allow_empty(true)),
+        )
+        .commit;
+        create(&ink_dir, "v1.0", &tagged).unwrap();
+        for bad in &[
+            "bad/name", "-v1", ".v1", "v1..2", "v$(id)", "v 1", "v\u{e9}",
+        ] {
+            assert!(create(&ink_dir, bad, &tagged).is_err());
+        }
+        assert_eq!(get(&ink_dir, "v1.0").unwrap(), Some(tagged.hash()));
+        // moving it back to the empty commit isn't a fast-forward
+        let empty = Commit::from(&empty_hash, &ink_dir).unwrap();
+        assert!(update(&ink_dir, "v1.0", &empty, false).is_err());
+        update(&ink_dir, "v1.0", &tagged, false).unwrap();
+
+        let description = describe(&ink_dir, Commit::from(&tagged.hash(), &ink_dir).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(description.to_string(), "v1.0");
+
+        repo.commit("second", crate::CommitOptions::new().allow_empty(true))
+            .unwrap();
+        let head = crate::testkit::created(
+            repo.commit("third", crate::CommitOptions::new().allow_empty(true)),
+        )
+        .commit;
+        let description = describe(&ink_dir, head).unwrap().unwrap();
+        assert_eq!(description.distance, 2);
+        assert_eq!(
+            description.to_string(),
+            format!("v1.0-2-g{}", &hex::encode(description.hash)[..10])
+        );
+    }
+
+    #[test]
+    fn protected_tags() {
+        let repo = crate::testkit::TestRepo::new()
+            .file("a", "first")
+            .commit("first")
+            .file("a", "second")
+            .commit("second");
+        let ink_dir = repo.ink_root();
+        let second = repo.head();
+        let first = Commit::from(&second.parents()[0], ink_dir).unwrap();
+        fs::write(
+            ink_dir.join(crate::CONFIG_FILE),
+            "[tag \"release-*\"]\nforce-move = false\ndelete = false\n",
+        )
+        .unwrap();
+
+        // protected tags still move forward, but not back or away
+        update(ink_dir, "release-1", &first, false).unwrap();
+        update(ink_dir, "release-1", &second, false).unwrap();
+        assert!(update(ink_dir, "release-1", &first, true).is_err());
+        assert!(delete(ink_dir, "release-1").is_err());
+        assert_eq!(get(ink_dir, "release-1").unwrap(), Some(second.hash()));
+
+        update(ink_dir, "other", &second, false).unwrap();
+        update(ink_dir, "other", &first, true).unwrap();
+        delete(ink_dir, "other").unwrap();
+        assert_eq!(get(ink_dir, "other").unwrap(), None);
+    }
+}
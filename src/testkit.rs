@@ -0,0 +1,164 @@
+//! Throwaway repositories for tests, with the `testkit` feature.
+//!
+//! ```no_run
+//! use ink::testkit::TestRepo;
+//!
+//! let repo = TestRepo::new()
+//!     .file("a.txt", "first")
+//!     .commit("add a")
+//!     .file("a.txt", "second")
+//!     .commit("change a");
+//! assert_eq!(repo.read("a.txt"), b"second");
+//! ```
+//!
+//! Repositories, like the directories and files from `temp_dir` and
+//! `temp_file`, are made in the system's temporary directory (`TMPDIR` if it
+//! is set) and removed when they are dropped. Each test gets its own, so tests
+//! can run in parallel and from any directory. Helpers panic on failure, as
+//! tests should.
+use crate::commit::Commit;
+use crate::options::CommitOptions;
+use crate::repository::{CommitOutcome, CommitResult, Repository};
+use crate::InkError;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::{NamedTempFile, TempDir};
+
+/// A new empty directory, removed when dropped
+pub fn temp_dir() -> TempDir {
+    tempfile::tempdir().expect("failed to create a temporary directory")
+}
+
+/// A new empty file, removed when dropped
+pub fn temp_file() -> NamedTempFile {
+    NamedTempFile::new().expect("failed to create a temporary file")
+}
+
+/// The outcome of a commit that should have made a new one
+pub fn created(result: Result<CommitResult, InkError>) -> CommitOutcome {
+    match result {
+        Ok(CommitResult::Created(outcome)) => outcome,
+        Ok(CommitResult::NothingToCommit) => panic!("expected a new commit, but nothing changed"),
+        Err(err) => panic!("failed to commit: {}", err),
+    }
+}
+
+pub struct TestRepo {
+    // removed on drop, so kept alongside the repository using it
+    dir: TempDir,
+    repo: Repository,
+}
+
+impl TestRepo {
+    /// An initialized repository with no commits but the empty one
+    pub fn new() -> TestRepo {
+        let dir = temp_dir();
+        let ink_root = crate::init(dir.path()).expect("failed to initialize a repository");
+        let repo = Repository::open(&ink_root).expect("failed to open repository");
+
+        TestRepo { dir, repo }
+    }
+
+    /// Write a file in the working directory, creating its parent directories
+    pub fn file<P: AsRef<Path>, C: AsRef<[u8]>>(self, path: P, content: C) -> TestRepo {
+        let path = self.path().join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).expect("failed to create directories");
+        }
+        fs::write(&path, content).expect("failed to write file");
+        self
+    }
+
+    /// Make a symbolic link in the working directory to `target`
+    #[cfg(unix)]
+    pub fn symlink<P: AsRef<Path>, T: AsRef<Path>>(self, path: P, target: T) -> TestRepo {
+        std::os::unix::fs::symlink(target, self.path().join(path)).expect("failed to make link");
+        self
+    }
+
+    /// Remove a file from the working directory
+    pub fn remove<P: AsRef<Path>>(self, path: P) -> TestRepo {
+        fs::remove_file(self.path().join(path)).expect("failed to remove file");
+        self
+    }
+
+    /// Commit the working directory, even if nothing changed
+    pub fn commit(self, message: &str) -> TestRepo {
+        match self
+            .repo
+            .commit(message, CommitOptions::new().allow_empty(true))
+        {
+            Ok(CommitResult::Created(_)) => self,
+            Ok(CommitResult::NothingToCommit) => unreachable!("empty commits are allowed"),
+            Err(err) => panic!("failed to commit: {}", err),
+        }
+    }
+
+    /// The project directory
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn ink_root(&self) -> &Path {
+        self.repo.ink_root()
+    }
+
+    pub fn repo(&self) -> &Repository {
+        &self.repo
+    }
+
+    /// The current commit
+    pub fn head(&self) -> Commit {
+        self.repo
+            .current_commit()
+            .expect("failed to read the current commit")
+    }
+
+    /// Read a file in the working directory
+    pub fn read<P: AsRef<Path>>(&self, path: P) -> Vec<u8> {
+        fs::read(self.path().join(path)).expect("failed to read file")
+    }
+
+    /// Keep the directory after the repository is dropped, e.g. to look at it
+    /// after a failing test, and return its path
+    pub fn keep(self) -> PathBuf {
+        self.dir.into_path()
+    }
+}
+
+impl Default for TestRepo {
+    fn default() -> TestRepo {
+        TestRepo::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn build_repositories() {
+        let repo = TestRepo::new()
+            .file("a.txt", "first")
+            .file("dir/b.txt", "b")
+            .commit("add files")
+            .file("a.txt", "second")
+            .remove("dir/b.txt")
+            .commit("change files");
+
+        let head = repo.head();
+        assert_eq!(head.message(), "change files");
+        assert_eq!(head.files().len(), 1);
+        assert_eq!(
+            head.files()[0].read_content(repo.ink_root()).unwrap(),
+            b"second"
+        );
+        assert_eq!(repo.repo().log().unwrap().count(), 3);
+        assert!(repo.repo().status().unwrap().edits.is_empty());
+
+        let path = repo.path().to_path_buf();
+        drop(repo);
+        assert!(!path.exists());
+    }
+}
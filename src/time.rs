@@ -0,0 +1,221 @@
+//! When commits are made, and showing it.
+//!
+//! A commit stores its time as seconds since the unix epoch, in UTC, and the
+//! committer's offset from UTC in seconds, so its local time can be shown on
+//! any machine. Both are part of its hash.
+//!
+//! `SOURCE_DATE_EPOCH` fixes the time of new commits, and their offset at
+//! UTC, so builds can make the same commits every time.
+use crate::InkError;
+
+use std::convert::TryInto;
+use std::env;
+use std::time::{Duration, SystemTime};
+
+/// The time to give a new commit: the one in `SOURCE_DATE_EPOCH` if it is
+/// set, otherwise now
+pub fn commit_time() -> Result<SystemTime, InkError> {
+    match reproducible_epoch() {
+        Some(epoch) => parse_epoch(&epoch),
+        None => Ok(SystemTime::now()),
+    }
+}
+
+/// The offset from UTC to give a new commit: none if `SOURCE_DATE_EPOCH` is
+/// set, otherwise the local one
+pub fn commit_offset() -> i32 {
+    match reproducible_epoch() {
+        Some(_) => 0,
+        None => local_offset().unwrap_or(0),
+    }
+}
+
+fn reproducible_epoch() -> Option<String> {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .filter(|epoch| !epoch.is_empty())
+}
+
+/// The local offset from UTC in seconds now, as the C library's
+/// `localtime_r` finds it, if it can. The standard library doesn't know
+/// about time zones.
+#[cfg(unix)]
+pub fn local_offset() -> Option<i32> {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .ok()?
+        .as_secs()
+        .try_into()
+        .ok()?;
+    // SAFETY: tm is plain data that localtime_r fills in, and both pointers
+    // are valid for the call
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&now, &mut tm) }.is_null() {
+        return None;
+    }
+    tm.tm_gmtoff.try_into().ok()
+}
+
+#[cfg(not(unix))]
+pub fn local_offset() -> Option<i32> {
+    None
+}
+
+/// Parse seconds since the unix epoch, optionally written `@<seconds>`
+pub fn parse_epoch(seconds: &str) -> Result<SystemTime, InkError> {
+    let seconds = seconds.strip_prefix('@').unwrap_or(seconds);
+    let seconds: u64 = seconds
+        .trim()
+        .parse()
+        .map_err(|_| "Times must be given in seconds since the unix epoch")?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Parse a time given as seconds since the unix epoch, like `parse_epoch`,
+/// or as a `YYYY-MM-DD` date, taken as its start in UTC
+pub fn parse_time(time: &str) -> Result<SystemTime, InkError> {
+    let parts: Vec<&str> = time.split('-').collect();
+    let (year, month, day) = match parts[..] {
+        [year, month, day] if year.len() == 4 && month.len() == 2 && day.len() == 2 => {
+            (year, month, day)
+        }
+        _ => return parse_epoch(time),
+    };
+
+    let invalid = "Dates must be written YYYY-MM-DD";
+    let year: i64 = year.parse().map_err(|_| invalid)?;
+    let month: u32 = month.parse().map_err(|_| invalid)?;
+    let day: u32 = day.parse().map_err(|_| invalid)?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return Err(invalid.into());
+    }
+
+    let days = days_from_civil(year, month, day);
+    let seconds: u64 = (days * 86400)
+        .try_into()
+        .map_err(|_| "Dates before 1970 can't be used")?;
+    Ok(SystemTime::UNIX_EPOCH + Duration::from_secs(seconds))
+}
+
+/// Parse an offset from UTC written `+hhmm` or `-hhmm`, into seconds
+pub fn parse_offset(offset: &str) -> Result<i32, InkError> {
+    let invalid = "Offsets from UTC must be written +hhmm or -hhmm";
+    let (sign, digits) = match offset.split_at_checked(1) {
+        Some(("+", digits)) => (1, digits),
+        Some(("-", digits)) => (-1, digits),
+        _ => return Err(invalid.into()),
+    };
+    if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(invalid.into());
+    }
+
+    let hours: i32 = digits[..2].parse().map_err(|_| invalid)?;
+    let minutes: i32 = digits[2..].parse().map_err(|_| invalid)?;
+    if minutes >= 60 {
+        return Err(invalid.into());
+    }
+
+    Ok(sign * (hours * 60 + minutes) * 60)
+}
+
+/// Show a time as `YYYY-MM-DD HH:MM:SS +hhmm`, in the time zone `offset`
+/// seconds east of UTC
+pub fn format(seconds: u64, offset: i32) -> String {
+    let local = seconds as i64 + offset as i64;
+    let (days, time) = (local.div_euclid(86400), local.rem_euclid(86400));
+    let (year, month, day) = civil_from_days(days);
+
+    let sign = if offset < 0 { '-' } else { '+' };
+    let offset_minutes = offset.unsigned_abs() / 60;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} {}{:02}{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        time % 3600 / 60,
+        time % 60,
+        sign,
+        offset_minutes / 60,
+        offset_minutes % 60
+    )
+}
+
+/// The year, month and day of a number of days since the unix epoch, from
+/// Howard Hinnant's `civil_from_days`
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month as u32, day as u32)
+}
+
+/// The number of days since the unix epoch of a date, the inverse of
+/// `civil_from_days`
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_times() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        assert_eq!(parse_epoch("1600000000").unwrap(), time);
+        assert_eq!(parse_epoch("@1600000000").unwrap(), time);
+        assert!(parse_epoch("yesterday").is_err());
+        assert!(parse_epoch("-5").is_err());
+
+        assert_eq!(parse_time("1600000000").unwrap(), time);
+        let day = |secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs);
+        assert_eq!(parse_time("2020-09-13").unwrap(), day(1_599_955_200));
+        assert_eq!(parse_time("2000-02-29").unwrap(), day(951_782_400));
+        assert_eq!(parse_time("1970-01-01").unwrap(), day(0));
+        assert!(parse_time("2020-13-01").is_err());
+        assert!(parse_time("1969-12-31").is_err());
+    }
+
+    #[test]
+    fn offsets_and_formatting() {
+        assert_eq!(parse_offset("+0200").unwrap(), 7200);
+        assert_eq!(parse_offset("-0530").unwrap(), -19800);
+        for invalid in &["0200", "+2", "+02:00", "+0260", ""] {
+            assert!(parse_offset(invalid).is_err());
+        }
+
+        assert_eq!(format(0, 0), "1970-01-01 00:00:00 +0000");
+        assert_eq!(format(1_600_000_000, 0), "2020-09-13 12:26:40 +0000");
+        assert_eq!(format(1_600_000_000, 7200), "2020-09-13 14:26:40 +0200");
+        assert_eq!(format(1_600_000_000, -45000), "2020-09-12 23:56:40 -1230");
+        // a leap day
+        assert_eq!(format(951_782_400, 0), "2000-02-29 00:00:00 +0000");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn local_offset_in_range() {
+        let offset = local_offset().unwrap();
+        assert!(offset.abs() <= 14 * 3600);
+    }
+}
@@ -1,7 +1,13 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
 use std::fmt;
 use std::fs;
 use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::vec;
 
 use crate::InkError;
 
@@ -9,20 +15,377 @@ pub fn hex_fmt<T: fmt::Debug + AsRef<[u8]>>(n: &T, f: &mut fmt::Formatter) -> fm
     write!(f, "{}", hex::encode(n))
 }
 
-/// Find all the file paths in a directory
-pub fn find_paths(dir: &Path, v: &mut Vec<PathBuf>) -> io::Result<()> {
-    if dir.is_dir() {
-        for entry in fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() {
-                find_paths(&path, v)?;
-            } else {
-                Vec::push(v, path);
+pub fn hex_list_fmt<L: AsRef<[T]>, T: AsRef<[u8]>>(
+    list: &L,
+    f: &mut fmt::Formatter,
+) -> fmt::Result {
+    f.debug_list()
+        .entries(list.as_ref().iter().map(hex::encode))
+        .finish()
+}
+
+/// The bytes of a path, used when hashing it.
+/// Paths on other platforms are hashed as UTF-8.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(path.as_os_str().as_bytes())
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+/// Stop a file from being written to, so it isn't changed by accident
+pub fn set_read_only(path: &Path) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(true);
+    fs::set_permissions(path, permissions)
+}
+
+/// The unix mode of a file. Platforms without one get a regular,
+/// non-executable file's mode.
+#[cfg(unix)]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode()
+}
+
+#[cfg(not(unix))]
+pub fn file_mode(metadata: &fs::Metadata) -> u32 {
+    if metadata.file_type().is_symlink() {
+        0o120777
+    } else {
+        0o100644
+    }
+}
+
+/// Apply a unix mode to a file. Does nothing on platforms without one.
+#[cfg(unix)]
+pub fn set_file_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+pub fn set_file_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Make a symbolic link at `path` to a target given as the bytes of a path
+#[cfg(unix)]
+pub fn symlink(target: &[u8], path: &Path) -> io::Result<()> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    std::os::unix::fs::symlink(OsStr::from_bytes(target), path)
+}
+
+#[cfg(not(unix))]
+pub fn symlink(_target: &[u8], _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Symbolic links can't be made on this system",
+    ))
+}
+
+/// Run `f` over every item, spread across at most `jobs` threads, collecting
+/// the results in the same order as the items. Stops at the first error each
+/// thread sees and returns one of them.
+pub fn map_bounded<T, R, F>(items: &[T], jobs: usize, f: F) -> Result<Vec<R>, InkError>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> Result<R, InkError> + Sync,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+
+    let chunk_size = items.len().div_ceil(jobs);
+    let f = &f;
+    thread::scope(|scope| {
+        let handles: Vec<_> = items
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().map(f).collect::<Result<Vec<R>, _>>()))
+            .collect();
+
+        let mut results = Vec::with_capacity(items.len());
+        for handle in handles {
+            let chunk = handle
+                .join()
+                .unwrap_or_else(|_| Err("Worker thread panicked".into()))?;
+            results.extend(chunk);
+        }
+        Ok(results)
+    })
+}
+
+/// Run `f` over every item like `map_bounded`, yielding the results in the
+/// same order as they are ready rather than all at once.
+///
+/// Item `i` goes to thread `i % jobs`, which holds at most one result it has
+/// finished until it is taken, so only about two results per thread are in
+/// memory at a time. Dropping the iterator stops the threads after their
+/// current item.
+pub fn map_streamed<T, R, F>(
+    items: Vec<T>,
+    jobs: usize,
+    f: F,
+) -> Box<dyn Iterator<Item = Result<R, InkError>> + Send>
+where
+    T: Send + 'static,
+    R: Send + 'static,
+    F: Fn(T) -> Result<R, InkError> + Send + Sync + 'static,
+{
+    if jobs <= 1 || items.len() <= 1 {
+        return Box::new(items.into_iter().map(f));
+    }
+
+    let count = items.len();
+    let jobs = jobs.min(count);
+    let mut shares: Vec<Vec<T>> = (0..jobs).map(|_| Vec::new()).collect();
+    for (index, item) in items.into_iter().enumerate() {
+        shares[index % jobs].push(item);
+    }
+
+    let f = Arc::new(f);
+    let receivers: Vec<mpsc::Receiver<Result<R, InkError>>> = shares
+        .into_iter()
+        .map(|share| {
+            let (sender, receiver) = mpsc::sync_channel(1);
+            let f = Arc::clone(&f);
+            thread::spawn(move || {
+                for item in share {
+                    if sender.send(f(item)).is_err() {
+                        break;
+                    }
+                }
+            });
+            receiver
+        })
+        .collect();
+
+    Box::new((0..count).map(move |index| {
+        receivers[index % jobs]
+            .recv()
+            .unwrap_or_else(|_| Err("Worker thread panicked".into()))
+    }))
+}
+
+/// Split content into lines, replacing invalid UTF-8
+pub fn to_lines(content: &[u8]) -> Vec<String> {
+    String::from_utf8_lossy(content)
+        .lines()
+        .map(String::from)
+        .collect()
+}
+
+/// Match a path relative to the project directory against a glob pattern.
+/// `*` and `?` match within one path component and `**` matches across them.
+/// Patterns without a `/` are matched against the file name only.
+pub fn glob_match(pattern: &str, path: &Path) -> bool {
+    let path = if pattern.contains('/') {
+        path.components()
+            .map(|c| c.as_os_str().to_string_lossy())
+            .collect::<Vec<_>>()
+            .join("/")
+    } else {
+        match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => return false,
+        }
+    };
+
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    glob_match_chars(&pattern, &path)
+}
+
+fn glob_match_chars(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` also matches no directories at all
+            let rest = rest.strip_prefix(&['/']).unwrap_or(rest);
+            (0..=path.len()).any(|skip| glob_match_chars(rest, &path[skip..]))
+        }
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&skip| skip == 0 || path[skip - 1] != '/')
+            .any(|skip| glob_match_chars(rest, &path[skip..])),
+        ['?', rest @ ..] => {
+            matches!(path, [c, ..] if *c != '/') && glob_match_chars(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && glob_match_chars(rest, &path[1..]),
+    }
+}
+
+/// Walks the files in a directory tree, in sorted order, reading one
+/// directory at a time.
+///
+/// Symbolic links to files are yielded like files. Links to directories are
+/// followed unless `follow_links` is turned off, and no directory is visited
+/// twice, so link loops end. Dangling links are skipped. With `links`, every
+/// link is yielded as itself instead.
+pub struct WalkDir<'a> {
+    root: PathBuf,
+    started: bool,
+    // paths left to visit in each open directory, the deepest last
+    stack: Vec<vec::IntoIter<PathBuf>>,
+    // canonical paths of the directories visited
+    visited: HashSet<PathBuf>,
+    max_depth: Option<usize>,
+    follow_links: bool,
+    links: bool,
+    same_file_system: bool,
+    root_device: Option<u64>,
+    exclude: Option<Exclude<'a>>,
+}
+
+type Exclude<'a> = Box<dyn Fn(&Path) -> bool + 'a>;
+
+impl<'a> WalkDir<'a> {
+    pub fn new<P: AsRef<Path>>(root: P) -> WalkDir<'a> {
+        WalkDir {
+            root: root.as_ref().to_path_buf(),
+            started: false,
+            stack: Vec::new(),
+            visited: HashSet::new(),
+            max_depth: None,
+            follow_links: true,
+            links: false,
+            same_file_system: false,
+            root_device: None,
+            exclude: None,
+        }
+    }
+
+    /// Only yield files up to this many directories down, where the files in
+    /// the root are at depth 1
+    pub fn max_depth(mut self, depth: usize) -> WalkDir<'a> {
+        self.max_depth = Some(depth);
+        self
+    }
+
+    /// Walk into directories that symbolic links point to
+    pub fn follow_links(mut self, follow: bool) -> WalkDir<'a> {
+        self.follow_links = follow;
+        self
+    }
+
+    /// Yield symbolic links themselves, whatever they point to, without
+    /// following them
+    pub fn links(mut self, links: bool) -> WalkDir<'a> {
+        self.links = links;
+        self
+    }
+
+    /// Don't walk into directories on other filesystems than the root's.
+    /// Only has an effect on unix.
+    pub fn same_file_system(mut self, same: bool) -> WalkDir<'a> {
+        self.same_file_system = same;
+        self
+    }
+
+    /// Skip the paths the predicate returns true for. Excluded directories
+    /// aren't read at all.
+    pub fn exclude<F: Fn(&Path) -> bool + 'a>(mut self, exclude: F) -> WalkDir<'a> {
+        self.exclude = Some(Box::new(exclude));
+        self
+    }
+
+    fn push_dir(&mut self, dir: &Path) -> io::Result<()> {
+        if !self.visited.insert(dir.canonicalize()?) {
+            return Ok(());
+        }
+
+        let mut paths = fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+        self.stack.push(paths.into_iter());
+        Ok(())
+    }
+
+    fn start(&mut self) -> io::Result<()> {
+        self.started = true;
+        if !self.root.is_dir() {
+            return Ok(());
+        }
+
+        self.root_device = device(&fs::metadata(&self.root)?);
+        let root = self.root.clone();
+        self.push_dir(&root)
+    }
+}
+
+impl Iterator for WalkDir<'_> {
+    type Item = io::Result<PathBuf>;
+
+    fn next(&mut self) -> Option<io::Result<PathBuf>> {
+        if !self.started {
+            if let Err(err) = self.start() {
+                return Some(Err(err));
+            }
+        }
+
+        loop {
+            let depth = self.stack.len();
+            let path = match self.stack.last_mut()?.next() {
+                Some(path) => path,
+                None => {
+                    self.stack.pop();
+                    continue;
+                }
+            };
+
+            if self.exclude.as_ref().is_some_and(|exclude| exclude(&path)) {
+                continue;
+            }
+
+            let link = match fs::symlink_metadata(&path) {
+                Ok(metadata) => metadata.file_type().is_symlink(),
+                Err(err) => return Some(Err(err)),
+            };
+            if link && self.links {
+                return Some(Ok(path));
+            }
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) if link => continue,
+                Err(err) => return Some(Err(err)),
+            };
+
+            if !metadata.is_dir() {
+                return Some(Ok(path));
+            }
+
+            if (link && !self.follow_links)
+                || self.max_depth.is_some_and(|max| depth >= max)
+                || (self.same_file_system && device(&metadata) != self.root_device)
+            {
+                continue;
+            }
+
+            if let Err(err) = self.push_dir(&path) {
+                return Some(Err(err));
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(unix)]
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+fn device(_metadata: &fs::Metadata) -> Option<u64> {
+    None
 }
 
 /// Creates a new directory at target and copies all subdirectories from source
@@ -31,10 +394,8 @@ fn _copy_subdirs(source: &Path, target: &Path) -> Result<(), InkError> {
         return Err("The target directory already exists".into());
     }
 
-    let mut paths = Vec::new();
-    find_paths(source, &mut paths)?;
-
-    for source_path in paths {
+    for source_path in WalkDir::new(source) {
+        let source_path = source_path?;
         let source_path = source_path.strip_prefix(source).unwrap();
         let path = target.join(source_path);
 
@@ -42,3 +403,99 @@ fn _copy_subdirs(source: &Path, target: &Path) -> Result<(), InkError> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streamed_in_order() {
+        let squares = |n: u64| -> Result<u64, InkError> {
+            // later items finish first
+            thread::sleep(std::time::Duration::from_millis(20 - n));
+            Ok(n * n)
+        };
+        let expected: Vec<u64> = (0..20).map(|n| n * n).collect();
+        for jobs in &[1, 3, 8] {
+            let streamed: Vec<u64> = map_streamed((0..20).collect(), *jobs, squares)
+                .collect::<Result<_, _>>()
+                .unwrap();
+            assert_eq!(streamed, expected);
+        }
+
+        let mut streamed = map_streamed((0..20).collect(), 4, squares);
+        assert_eq!(streamed.next().unwrap().unwrap(), 0);
+        drop(streamed);
+
+        let failing = map_streamed(vec![1, 2, 3], 2, |n: u64| match n {
+            2 => Err("two".into()),
+            n => Ok(n),
+        });
+        assert!(failing.collect::<Result<Vec<_>, _>>().is_err());
+    }
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("*.rs", Path::new("src/main.rs")));
+        assert!(!glob_match("*.rs", Path::new("src/main.rsx")));
+        assert!(glob_match("src/*.rs", Path::new("src/main.rs")));
+        assert!(!glob_match("src/*.rs", Path::new("src/bin/main.rs")));
+        assert!(glob_match("src/**/*.rs", Path::new("src/bin/main.rs")));
+        assert!(glob_match("src/**/*.rs", Path::new("src/main.rs")));
+        assert!(glob_match("docs/**", Path::new("docs/a/b.md")));
+        assert!(glob_match("?.txt", Path::new("a.txt")));
+        assert!(!glob_match("?.txt", Path::new("ab.txt")));
+    }
+
+    #[test]
+    fn walk_dir() {
+        let tmpdir = crate::testkit::temp_dir();
+        let root = tmpdir.path();
+        for dir in &["b/c", "a"] {
+            fs::create_dir_all(root.join(dir)).unwrap();
+        }
+        for file in &["z", "b/c/deep", "a/1", "b/2"] {
+            fs::write(root.join(file), b"").unwrap();
+        }
+
+        let relative = |walk: WalkDir| -> Vec<PathBuf> {
+            walk.map(|path| path.unwrap().strip_prefix(root).unwrap().to_path_buf())
+                .collect()
+        };
+        let expected: Vec<PathBuf> = ["a/1", "b/2", "b/c/deep", "z"]
+            .iter()
+            .map(PathBuf::from)
+            .collect();
+        assert_eq!(relative(WalkDir::new(root)), expected);
+        let mut shallow = expected.clone();
+        shallow.remove(2);
+        assert_eq!(relative(WalkDir::new(root).max_depth(2)), shallow);
+        assert_eq!(
+            relative(WalkDir::new(root).exclude(|path| path.ends_with("b"))),
+            vec![PathBuf::from("a/1"), PathBuf::from("z")]
+        );
+        assert_eq!(WalkDir::new(root.join("missing")).count(), 0);
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(root, root.join("b/loop")).unwrap();
+            std::os::unix::fs::symlink(root.join("missing"), root.join("dangling")).unwrap();
+            assert_eq!(relative(WalkDir::new(root)), expected);
+
+            // linked directories elsewhere are walked unless told not to
+            let elsewhere = crate::testkit::temp_dir();
+            fs::write(elsewhere.path().join("far"), b"").unwrap();
+            std::os::unix::fs::symlink(elsewhere.path(), root.join("linked")).unwrap();
+            assert_eq!(relative(WalkDir::new(root).follow_links(false)), expected);
+            let mut followed = expected.clone();
+            followed.insert(3, PathBuf::from("linked/far"));
+            assert_eq!(relative(WalkDir::new(root)), followed);
+
+            let mut with_links = expected.clone();
+            with_links.insert(3, PathBuf::from("b/loop"));
+            with_links.insert(4, PathBuf::from("dangling"));
+            with_links.insert(5, PathBuf::from("linked"));
+            assert_eq!(relative(WalkDir::new(root).links(true)), with_links);
+        }
+    }
+}
@@ -0,0 +1,134 @@
+//! Identity of the working directory's snapshot, for stamping builds.
+use crate::filter;
+
+use std::fmt::Write;
+
+/// Hex digits of the commit hash in `short_hash`
+const SHORT_HASH_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// Hex hash of the current commit
+    pub hash: String,
+    pub short_hash: String,
+    /// Whether the working directory has changes since the current commit
+    pub dirty: bool,
+    /// Always `None` until ink has branches
+    pub branch: Option<String>,
+    /// Name from the closest tag, as given by `ink describe`
+    pub describe: Option<String>,
+    /// Time of the current commit, in seconds since the unix epoch
+    pub timestamp: u64,
+}
+
+impl VersionInfo {
+    pub(crate) fn new(
+        hash: [u8; 32],
+        dirty: bool,
+        describe: Option<String>,
+        timestamp: u64,
+    ) -> VersionInfo {
+        let hash = hex::encode(hash);
+        VersionInfo {
+            short_hash: hash[..SHORT_HASH_LEN].to_string(),
+            hash,
+            dirty,
+            branch: None,
+            describe,
+            timestamp,
+        }
+    }
+
+    fn fields(&self) -> [(&str, Option<String>); 6] {
+        [
+            ("commit", Some(self.hash.clone())),
+            ("short_commit", Some(self.short_hash.clone())),
+            ("dirty", Some(self.dirty.to_string())),
+            ("branch", self.branch.clone()),
+            ("describe", self.describe.clone()),
+            ("timestamp", Some(self.timestamp.to_string())),
+        ]
+    }
+
+    /// A JSON object, with `null` for fields without a value
+    pub fn to_json(&self) -> String {
+        let mut json = String::from("{");
+        for (index, (key, value)) in self.fields().iter().enumerate() {
+            if index > 0 {
+                json.push_str(", ");
+            }
+
+            let value = match (key, value) {
+                (_, None) => "null".to_string(),
+                (&"dirty", Some(value)) | (&"timestamp", Some(value)) => value.clone(),
+                (_, Some(value)) => json_string(value),
+            };
+            let _ = write!(json, "\"{}\": {}", key, value);
+        }
+        json.push('}');
+        json
+    }
+
+    /// `INK_<FIELD>='<value>'` lines, which can be sourced by a shell. Every
+    /// value is quoted, so none is run as a command. Fields without a value
+    /// are empty.
+    pub fn to_env(&self) -> String {
+        let mut env = String::new();
+        for (key, value) in self.fields().iter() {
+            let _ = writeln!(
+                env,
+                "INK_{}={}",
+                key.to_uppercase(),
+                filter::shell_quote(value.as_deref().unwrap_or(""))
+            );
+        }
+        env
+    }
+}
+
+pub(crate) fn json_string(text: &str) -> String {
+    let mut json = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            c if c.is_control() => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_formats() {
+        let info = VersionInfo::new([0xab; 32], true, Some("v\"1\"".to_string()), 42);
+
+        assert_eq!(info.short_hash, "ababababab");
+        assert_eq!(
+            info.to_json(),
+            format!(
+                "{{\"commit\": \"{}\", \"short_commit\": \"ababababab\", \"dirty\": true, \"branch\": null, \"describe\": \"v\\\"1\\\"\", \"timestamp\": 42}}",
+                info.hash
+            )
+        );
+        assert_eq!(
+            info.to_env(),
+            format!(
+                "INK_COMMIT='{}'\nINK_SHORT_COMMIT='ababababab'\nINK_DIRTY='true'\nINK_BRANCH=''\nINK_DESCRIBE='v\"1\"'\nINK_TIMESTAMP='42'\n",
+                info.hash
+            )
+        );
+
+        // nothing in a value is run by a shell sourcing it
+        let info = VersionInfo::new([0xab; 32], false, Some("v'1$(id)".to_string()), 42);
+        assert!(info.to_env().contains("INK_DESCRIBE='v'\\''1$(id)'\n"));
+    }
+}
@@ -0,0 +1,165 @@
+//! Filesystem backends for the object store.
+//!
+//! Commit objects, the commit graph and the history walk can read and write
+//! through `VirtualFs`, so history can be built and walked without a real filesystem,
+//! for example when compiled to `wasm32-unknown-unknown`. Native code uses
+//! `OsFs`. Hashing and checking out a working directory still go through
+//! `std::fs` directly.
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// The filesystem operations ink needs from a storage backend
+pub trait VirtualFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    /// Create or replace a file. Parent directories must already exist.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Names of the entries directly inside a directory
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>>;
+}
+
+/// The operating system's filesystem
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsFs;
+
+impl VirtualFs for OsFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(path, contents)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.file_name()))
+            .collect()
+    }
+}
+
+/// A filesystem held entirely in memory.
+/// Clones share the same files.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryFs {
+    files: Rc<RefCell<BTreeMap<PathBuf, Vec<u8>>>>,
+    dirs: Rc<RefCell<BTreeSet<PathBuf>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+        path.as_os_str().is_empty() || self.dirs.borrow().contains(path)
+    }
+}
+
+fn not_found() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "No such file or directory")
+}
+
+impl VirtualFs for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.files.borrow().get(path).cloned().ok_or_else(not_found)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        if !self.is_dir(path.parent().unwrap_or_else(|| Path::new(""))) {
+            return Err(not_found());
+        }
+
+        self.files
+            .borrow_mut()
+            .insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.files.borrow().contains_key(path)
+    }
+
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        let mut dirs = self.dirs.borrow_mut();
+        for dir in path.ancestors() {
+            dirs.insert(dir.to_path_buf());
+        }
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.files
+            .borrow_mut()
+            .remove(path)
+            .map(|_| ())
+            .ok_or_else(not_found)
+    }
+
+    fn read_dir(&self, path: &Path) -> io::Result<Vec<OsString>> {
+        if !self.is_dir(path) {
+            return Err(not_found());
+        }
+
+        let files = self.files.borrow();
+        let dirs = self.dirs.borrow();
+        Ok(files
+            .keys()
+            .chain(dirs.iter())
+            .filter(|entry| entry.parent() == Some(path))
+            .filter_map(|entry| entry.file_name().map(|name| name.to_os_string()))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_fs_files_and_dirs() {
+        let fs = MemoryFs::new();
+        let dir = Path::new("/project/.ink/commit");
+
+        assert!(fs.write(&dir.join("a"), b"one").is_err());
+        fs.create_dir_all(dir).unwrap();
+        fs.write(&dir.join("a"), b"one").unwrap();
+        fs.clone().write(&dir.join("b"), b"two").unwrap();
+
+        assert_eq!(fs.read(&dir.join("b")).unwrap(), b"two");
+        assert!(fs.is_file(&dir.join("a")));
+        assert!(!fs.is_file(dir));
+        assert_eq!(fs.read_dir(dir).unwrap(), vec!["a", "b"]);
+        assert_eq!(
+            fs.read_dir(Path::new("/project/.ink")).unwrap(),
+            vec!["commit"]
+        );
+
+        fs.remove_file(&dir.join("a")).unwrap();
+        assert!(fs.read(&dir.join("a")).is_err());
+    }
+}